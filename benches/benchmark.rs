@@ -23,36 +23,44 @@ fn criterion_benchmark(c: &mut Criterion) {
             .unwrap(),
     );
 
-    let env = Arc::new(LunaticEnvironment::new(0));
-    c.bench_function("spawn process", |b| {
-        b.to_async(&rt).iter(|| async {
-            let registry = Arc::new(DashMap::new());
-            let state = DefaultProcessState::new(
-                env.clone(),
-                None,
-                runtime.clone(),
-                module.clone(),
-                config.clone(),
-                registry,
-            )
-            .unwrap();
-            lunatic_process::wasm::spawn_wasm(
-                env.clone(),
-                runtime.clone(),
-                &module,
-                state,
-                "hello",
-                Vec::new(),
-                None,
-            )
-            .await
-            .unwrap()
-            .0
-            .await
-            .unwrap()
-            .ok();
+    // Finished processes leave their resource tables to the next spawned process, compare with
+    // allocating them for every process.
+    let recycling_env = Arc::new(LunaticEnvironment::new(0));
+    let allocating_env = Arc::new(LunaticEnvironment::new(1).with_recycle_capacity(0));
+    for (name, env) in [
+        ("spawn process", recycling_env),
+        ("spawn process without recycling", allocating_env),
+    ] {
+        c.bench_function(name, |b| {
+            b.to_async(&rt).iter(|| async {
+                let registry = Arc::new(DashMap::new());
+                let state = DefaultProcessState::new(
+                    env.clone(),
+                    None,
+                    runtime.clone(),
+                    module.clone(),
+                    config.clone(),
+                    registry,
+                )
+                .unwrap();
+                lunatic_process::wasm::spawn_wasm(
+                    env.clone(),
+                    runtime.clone(),
+                    &module,
+                    state,
+                    "hello",
+                    Vec::new(),
+                    None,
+                )
+                .await
+                .unwrap()
+                .0
+                .await
+                .unwrap()
+                .ok();
+            });
         });
-    });
+    }
 }
 
 criterion_group!(benches, criterion_benchmark);
@@ -1,9 +1,16 @@
-use std::{any::type_name, collections::HashMap, fmt::Debug};
+use std::{
+    any::type_name,
+    collections::{BTreeSet, HashMap},
+    fmt::Debug,
+};
 
 /// HashMap wrapper with incremental ID (u64) assignment.
 pub struct HashMapId<T> {
     id_seed: u64,
     store: HashMap<u64, T>,
+    // Ids of all items, if the map was created to iterate in insertion order. Ids are assigned
+    // incrementally, so their order is the order in which the items were added.
+    order: Option<BTreeSet<u64>>,
 }
 
 impl<T> HashMapId<T>
@@ -14,17 +21,34 @@ where
         Self {
             id_seed: 0,
             store: HashMap::new(),
+            order: None,
+        }
+    }
+
+    /// Creates a map that iterates over the items in the order they were added.
+    ///
+    /// Keeping the order costs an extra index lookup on every add and remove.
+    pub fn with_insertion_order() -> Self {
+        Self {
+            order: Some(BTreeSet::new()),
+            ..Self::new()
         }
     }
 
     pub fn add(&mut self, item: T) -> u64 {
         let id = self.id_seed;
         self.store.insert(id, item);
+        if let Some(order) = self.order.as_mut() {
+            order.insert(id);
+        }
         self.id_seed += 1;
         id
     }
 
     pub fn remove(&mut self, id: u64) -> Option<T> {
+        if let Some(order) = self.order.as_mut() {
+            order.remove(&id);
+        }
         self.store.remove(&id)
     }
 
@@ -35,6 +59,52 @@ where
     pub fn get(&self, id: u64) -> Option<&T> {
         self.store.get(&id)
     }
+
+    /// Iterates over all `(id, item)` pairs.
+    ///
+    /// The items are in insertion order if the map was created with
+    /// [`with_insertion_order`](Self::with_insertion_order), otherwise in arbitrary order.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, &T)> + '_ {
+        // Only one of the two iterators yields items
+        let ordered = self.order.iter().flatten().map(|id| (*id, &self.store[id]));
+        let unordered = self
+            .order
+            .is_none()
+            .then(|| self.store.iter())
+            .into_iter()
+            .flatten();
+        ordered.chain(unordered.map(|(id, item)| (*id, item)))
+    }
+
+    /// Removes all items for which **keep** returns `false`.
+    pub fn retain<F: FnMut(u64, &mut T) -> bool>(&mut self, mut keep: F) {
+        self.store.retain(|id, item| keep(*id, item));
+        if let Some(order) = self.order.as_mut() {
+            order.retain(|id| self.store.contains_key(id));
+        }
+    }
+
+    /// Removes all items and starts assigning IDs from 0 again, but keeps the allocated memory.
+    pub fn clear(&mut self) {
+        self.store.clear();
+        if let Some(order) = self.order.as_mut() {
+            order.clear();
+        }
+        self.id_seed = 0;
+    }
+
+    /// Returns the number of items the map can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.store.capacity()
+    }
+
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.store.is_empty()
+    }
 }
 
 impl<T> Default for HashMapId<T>
@@ -51,6 +121,46 @@ impl<T> Debug for HashMapId<T> {
         f.debug_struct("HashMapId")
             .field("id_seed", &self.id_seed)
             .field("type", &type_name::<T>())
+            .field("ordered", &self.order.is_some())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ordered_iteration_follows_insertion_order() {
+        let mut map = HashMapId::with_insertion_order();
+        let ids: Vec<u64> = ["a", "b", "c", "d", "e"]
+            .into_iter()
+            .map(|item| map.add(item))
+            .collect();
+        assert_eq!(map.remove(ids[2]), Some("c"));
+        let items: Vec<_> = map.iter().map(|(_, item)| *item).collect();
+        assert_eq!(items, vec!["a", "b", "d", "e"]);
+
+        map.retain(|_, item| *item != "a");
+        map.add("f");
+        let items: Vec<_> = map.iter().map(|(_, item)| *item).collect();
+        assert_eq!(items, vec!["b", "d", "e", "f"]);
+    }
+
+    #[test]
+    fn clear_restarts_ids_and_keeps_capacity() {
+        let mut map = HashMapId::with_insertion_order();
+        for item in 0..100 {
+            map.add(item);
+        }
+        let capacity = map.capacity();
+        map.clear();
+        assert!(map.is_empty());
+        assert_eq!(map.capacity(), capacity);
+        assert_eq!(map.add(7), 0);
+        assert_eq!(
+            map.iter().map(|(_, item)| *item).collect::<Vec<_>>(),
+            vec![7]
+        );
+    }
+}
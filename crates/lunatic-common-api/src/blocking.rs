@@ -0,0 +1,159 @@
+//! Bounded thread pool for blocking host operations.
+//!
+//! Host functions run on the async workers that also drive the processes. If a host function
+//! blocks the thread (e.g. syncing a file to disk or resolving a name through the system
+//! resolver), all other processes scheduled on the same worker stall with it. Such operations
+//! are offloaded with [`run`] to tokio's blocking threads instead, but at most
+//! [`pool_size`] of them run at the same time, so that a flood of slow calls can't spawn an
+//! unbounded number of threads. Further calls wait asynchronously for a free slot.
+
+use std::{
+    future::Future,
+    sync::{Arc, OnceLock},
+};
+
+use tokio::sync::Semaphore;
+
+/// Number of blocking operations that can run at the same time, if not configured otherwise.
+pub const DEFAULT_POOL_SIZE: usize = 64;
+
+static POOL: OnceLock<BlockingPool> = OnceLock::new();
+
+/// Limits how many closures are running on blocking threads at the same time.
+#[derive(Clone)]
+pub struct BlockingPool {
+    permits: Arc<Semaphore>,
+    size: usize,
+}
+
+impl BlockingPool {
+    /// Creates a pool running at most **size** closures at the same time.
+    pub fn new(size: usize) -> Self {
+        let size = size.max(1);
+        Self {
+            permits: Arc::new(Semaphore::new(size)),
+            size,
+        }
+    }
+
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Runs the blocking closure **f** on a blocking thread and waits for the result.
+    ///
+    /// If the returned future is dropped, the closure still runs to completion and keeps its
+    /// slot until it's done. A panic inside the closure is resumed on the caller.
+    pub fn run<F, R>(&self, f: F) -> impl Future<Output = R> + Send + 'static
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let permits = self.permits.clone();
+        async move {
+            let permit = permits
+                .acquire_owned()
+                .await
+                .expect("blocking pool semaphore is never closed");
+            let task = tokio::task::spawn_blocking(move || {
+                let result = f();
+                drop(permit);
+                result
+            });
+            match task.await {
+                Ok(result) => result,
+                Err(err) => std::panic::resume_unwind(err.into_panic()),
+            }
+        }
+    }
+}
+
+/// Sets the size of the global pool used by [`run`].
+///
+/// Returns `false` if the pool is already in use, the size can only be set before the first
+/// blocking operation.
+pub fn set_pool_size(size: usize) -> bool {
+    POOL.set(BlockingPool::new(size)).is_ok()
+}
+
+/// Returns the size of the global pool.
+pub fn pool_size() -> usize {
+    pool().size()
+}
+
+/// Runs the blocking closure **f** on the global pool, see [`BlockingPool::run`].
+pub fn run<F, R>(f: F) -> impl Future<Output = R> + Send + 'static
+where
+    F: FnOnce() -> R + Send + 'static,
+    R: Send + 'static,
+{
+    pool().run(f)
+}
+
+fn pool() -> &'static BlockingPool {
+    POOL.get_or_init(|| BlockingPool::new(DEFAULT_POOL_SIZE))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::{Duration, Instant},
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn blocking_work_does_not_stall_other_tasks() {
+        // The test runtime has a single thread, running the sleep inline would block the ticker
+        let pool = BlockingPool::new(1);
+        let ticks = Arc::new(AtomicUsize::new(0));
+        let ticker = {
+            let ticks = ticks.clone();
+            tokio::task::spawn(async move {
+                loop {
+                    ticks.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+        let ticks_during_sleep = pool
+            .run({
+                let ticks = ticks.clone();
+                move || {
+                    let before = ticks.load(Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(300));
+                    ticks.load(Ordering::SeqCst) - before
+                }
+            })
+            .await;
+        ticker.abort();
+        assert!(ticks_during_sleep >= 5, "only {ticks_during_sleep} ticks");
+    }
+
+    #[tokio::test]
+    async fn pool_bounds_concurrent_work() {
+        let pool = BlockingPool::new(2);
+        let running = Arc::new(AtomicUsize::new(0));
+        let max_running = Arc::new(AtomicUsize::new(0));
+        let start = Instant::now();
+        let tasks: Vec<_> = (0..6)
+            .map(|_| {
+                let running = running.clone();
+                let max_running = max_running.clone();
+                pool.run(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    max_running.fetch_max(now, Ordering::SeqCst);
+                    std::thread::sleep(Duration::from_millis(50));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                })
+            })
+            .map(tokio::task::spawn)
+            .collect();
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(max_running.load(Ordering::SeqCst), 2);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+}
@@ -1,6 +1,100 @@
+pub mod blocking;
+pub mod retry;
+
 use anyhow::Result;
-use std::fmt::Display;
-use wasmtime::{Caller, Memory, Trap};
+use std::fmt::{Display, Write};
+use wasmtime::{Caller, Memory, StoreContext, Trap};
+
+/// Formats the wasm frames captured by a trap, one frame per line.
+///
+/// Returns `None` if the trap didn't capture any wasm frames (e.g. it was created by a host
+/// function that was called outside of the guest).
+pub fn wasm_backtrace(trap: &Trap) -> Option<String> {
+    let frames = trap.trace().filter(|frames| !frames.is_empty())?;
+    let mut backtrace = String::new();
+    for (i, frame) in frames.iter().enumerate() {
+        let module = frame.module_name().unwrap_or("<unknown>");
+        let _ = match frame.func_name() {
+            Some(func) => write!(backtrace, "{:>3}: {}!{}", i, module, func),
+            None => write!(
+                backtrace,
+                "{:>3}: {}!<wasm function {}>",
+                i,
+                module,
+                frame.func_index()
+            ),
+        };
+        if let Some(offset) = frame.module_offset() {
+            let _ = write!(backtrace, " @ {:#x}", offset);
+        }
+        backtrace.push('\n');
+    }
+    Some(backtrace)
+}
+
+/// Runs a cleanup closure when dropped.
+///
+/// Async host functions can be canceled at any `.await` point, e.g. if the process is killed while
+/// waiting. Resources owned by the process state are dropped together with it, but anything
+/// registered outside of it (like a waker) needs to be deregistered by a guard that lives across
+/// the `.await`.
+pub struct CancelGuard<F: FnOnce()> {
+    cleanup: Option<F>,
+}
+
+impl<F: FnOnce()> CancelGuard<F> {
+    pub fn new(cleanup: F) -> Self {
+        Self {
+            cleanup: Some(cleanup),
+        }
+    }
+
+    /// Consumes the guard without running the cleanup.
+    pub fn disarm(mut self) {
+        self.cleanup = None;
+    }
+}
+
+impl<F: FnOnce()> Drop for CancelGuard<F> {
+    fn drop(&mut self) {
+        if let Some(cleanup) = self.cleanup.take() {
+            cleanup();
+        }
+    }
+}
+
+/// Describes a host function call, so that traps raised by it point to the failing call.
+///
+/// It's displayed as `namespace::function(arg: value, ...)` and can be used directly as the info
+/// argument of [`IntoTrap::or_trap`], or create traps with a reason through [`HostCall::trap`].
+pub struct HostCall<'a> {
+    function: &'a str,
+    args: &'a [(&'a str, &'a dyn Display)],
+}
+
+impl<'a> HostCall<'a> {
+    pub fn new(function: &'a str, args: &'a [(&'a str, &'a dyn Display)]) -> Self {
+        Self { function, args }
+    }
+
+    /// Creates a trap explaining why the call failed.
+    pub fn trap<S: Display>(&self, reason: S) -> Trap {
+        Trap::new(format!("Trap raised during host call {self}: {reason}."))
+    }
+}
+
+impl<'a> Display for HostCall<'a> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}(", self.function)?;
+        for (i, (name, value)) in self.args.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}: {}", name, value)?;
+        }
+        write!(f, ")")
+    }
+}
 
 // Get exported memory
 pub fn get_memory<T>(caller: &mut Caller<T>) -> std::result::Result<Memory, Trap> {
@@ -11,6 +105,23 @@ pub fn get_memory<T>(caller: &mut Caller<T>) -> std::result::Result<Memory, Trap
         .or_trap("Export `memory` is not a memory")
 }
 
+/// Borrows **len** bytes at **ptr** of the guest memory, without copying them.
+///
+/// Returns `None` if the range is not inside the memory. The slice borrows the store, so it only
+/// lives until the store is needed mutably again (e.g. to write to the memory or to change the
+/// process state). Data that is consumed before that, like bytes appended to a message buffer,
+/// doesn't need to be copied into an intermediate `Vec`.
+pub fn guest_slice<'a, T: 'a>(
+    memory: &Memory,
+    store: impl Into<StoreContext<'a, T>>,
+    ptr: u32,
+    len: u32,
+) -> Option<&'a [u8]> {
+    let start = ptr as usize;
+    let end = start.checked_add(len as usize)?;
+    memory.data(store).get(start..end)
+}
+
 pub trait IntoTrap<T> {
     fn or_trap<S: Display>(self, info: S) -> Result<T, Trap>;
 }
@@ -39,3 +150,25 @@ impl<T> IntoTrap<T> for Option<T> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use wasmtime::{Engine, Memory, MemoryType, Store};
+
+    use super::guest_slice;
+
+    #[test]
+    fn guest_slice_borrows_ranges_inside_memory() {
+        let mut store = Store::new(&Engine::default(), ());
+        let memory = Memory::new(&mut store, MemoryType::new(1, None)).unwrap();
+        memory.write(&mut store, 65531, b"hello").unwrap();
+
+        assert_eq!(guest_slice(&memory, &store, 65531, 5), Some(&b"hello"[..]));
+        assert_eq!(guest_slice(&memory, &store, 65536, 0), Some(&[][..]));
+        assert_eq!(guest_slice(&memory, &store, 65535, 2), None);
+        assert_eq!(guest_slice(&memory, &store, u32::MAX, u32::MAX), None);
+        // The slice points into the linear memory itself
+        let slice = guest_slice(&memory, &store, 0, 1).unwrap();
+        assert_eq!(slice.as_ptr(), memory.data_ptr(&store) as *const u8);
+    }
+}
@@ -0,0 +1,127 @@
+//! Retrying of host I/O operations that were interrupted by a signal.
+//!
+//! A blocking system call can fail with `EINTR` ([`ErrorKind::Interrupted`]) if the host thread
+//! receives a signal while it's waiting. The operation didn't fail and should just be issued
+//! again, so guests never see these errors. To not spin forever on a thread that keeps getting
+//! interrupted, an operation is retried at most [`MAX_INTERRUPTED_RETRIES`] times.
+
+use std::io::{self, ErrorKind};
+
+/// Number of times an interrupted operation is retried before the error is returned.
+pub const MAX_INTERRUPTED_RETRIES: usize = 8;
+
+/// Calls **f** again while it fails with [`ErrorKind::Interrupted`].
+pub fn interrupted<F, R>(mut f: F) -> io::Result<R>
+where
+    F: FnMut() -> io::Result<R>,
+{
+    let mut retries = 0;
+    loop {
+        match f() {
+            Err(error) if should_retry(&error, &mut retries) => continue,
+            result => return result,
+        }
+    }
+}
+
+/// Returns `true` if the operation failing with **error** should be retried, counting the retry
+/// in **retries**.
+pub fn should_retry(error: &io::Error, retries: &mut usize) -> bool {
+    if error.kind() == ErrorKind::Interrupted && *retries < MAX_INTERRUPTED_RETRIES {
+        *retries += 1;
+        true
+    } else {
+        false
+    }
+}
+
+/// Evaluates the I/O expression again while it fails with [`ErrorKind::Interrupted`].
+///
+/// This is the counterpart of [`interrupted`] for async operations, the expression usually
+/// awaits the operation, e.g. `retry_interrupted!(stream.read(buffer).await)`.
+#[macro_export]
+macro_rules! retry_interrupted {
+    ($operation:expr) => {{
+        let mut retries = 0;
+        loop {
+            match $operation {
+                Err(error) if $crate::retry::should_retry(&error, &mut retries) => continue,
+                result => break result,
+            }
+        }
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    // Fails with `EINTR` the first **interrupts** times it's read from.
+    struct InterruptedReader {
+        interrupts: usize,
+        reads: usize,
+    }
+
+    impl Read for InterruptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.reads += 1;
+            if self.reads <= self.interrupts {
+                return Err(ErrorKind::Interrupted.into());
+            }
+            buf[0] = 42;
+            Ok(1)
+        }
+    }
+
+    #[test]
+    fn interrupted_read_is_retried() {
+        let mut reader = InterruptedReader {
+            interrupts: 3,
+            reads: 0,
+        };
+        let mut buf = [0; 1];
+        assert_eq!(interrupted(|| reader.read(&mut buf)).unwrap(), 1);
+        assert_eq!(buf[0], 42);
+        assert_eq!(reader.reads, 4);
+    }
+
+    #[test]
+    fn retries_are_bounded() {
+        let mut reader = InterruptedReader {
+            interrupts: usize::MAX,
+            reads: 0,
+        };
+        let error = interrupted(|| reader.read(&mut [0; 1])).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::Interrupted);
+        assert_eq!(reader.reads, MAX_INTERRUPTED_RETRIES + 1);
+
+        // Other errors are returned right away
+        let mut calls = 0;
+        let error = interrupted(|| -> io::Result<()> {
+            calls += 1;
+            Err(ErrorKind::ConnectionReset.into())
+        })
+        .unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::ConnectionReset);
+        assert_eq!(calls, 1);
+    }
+
+    #[tokio::test]
+    async fn interrupted_async_read_is_retried() {
+        async fn read(reader: &mut InterruptedReader, buf: &mut [u8]) -> io::Result<usize> {
+            tokio::task::yield_now().await;
+            reader.read(buf)
+        }
+
+        let mut reader = InterruptedReader {
+            interrupts: 2,
+            reads: 0,
+        };
+        let mut buf = [0; 1];
+        let result = crate::retry_interrupted!(read(&mut reader, &mut buf).await);
+        assert_eq!(result.unwrap(), 1);
+        assert_eq!(reader.reads, 3);
+    }
+}
@@ -1,7 +1,7 @@
 use std::{future::Future, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Result};
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, guest_slice, HostCall, IntoTrap};
 use lunatic_distributed::{
     distributed::message::{ClientError, Spawn, Val},
     DistributedCtx,
@@ -9,9 +9,10 @@ use lunatic_distributed::{
 use lunatic_error_api::ErrorCtx;
 use lunatic_process::{
     env::Environment,
-    message::{DataMessage, Message},
+    message::{down_message, DataMessage, Message},
+    ExitReason,
 };
-use lunatic_process_api::ProcessCtx;
+use lunatic_process_api::{ProcessConfigCtx, ProcessCtx};
 use tokio::time::timeout;
 use wasmtime::{Caller, Linker, ResourceLimiter, Trap};
 
@@ -19,15 +20,46 @@ use wasmtime::{Caller, Linker, ResourceLimiter, Trap};
 pub fn register<T, E>(linker: &mut Linker<T>) -> Result<()>
 where
     T: DistributedCtx<E> + ProcessCtx<T> + Send + ResourceLimiter + ErrorCtx + 'static,
+    T::Config: ProcessConfigCtx,
     E: Environment + 'static,
     for<'a> &'a T: Send,
 {
     linker.func_wrap("lunatic::distributed", "nodes_count", nodes_count)?;
     linker.func_wrap("lunatic::distributed", "get_nodes", get_nodes)?;
+    linker.func_wrap("lunatic::distributed", "control_status", control_status)?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "cluster_process_count",
+        cluster_process_count,
+    )?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "node_process_count",
+        node_process_count,
+    )?;
+    linker.func_wrap(
+        "lunatic::distributed",
+        "node_capabilities",
+        node_capabilities,
+    )?;
+    linker.func_wrap("lunatic::distributed", "cluster_topology", cluster_topology)?;
+    linker.func_wrap("lunatic::distributed", "is_distributed", is_distributed)?;
     linker.func_wrap("lunatic::distributed", "node_id", node_id)?;
     linker.func_wrap("lunatic::distributed", "module_id", module_id)?;
     linker.func_wrap8_async("lunatic::distributed", "spawn", spawn)?;
+    linker.func_wrap8_async("lunatic::distributed", "spawn_balanced", spawn_balanced)?;
+    linker.func_wrap8_async(
+        "lunatic::distributed",
+        "spawn_with_message",
+        spawn_with_message,
+    )?;
     linker.func_wrap2_async("lunatic::distributed", "send", send)?;
+    linker.func_wrap2_async("lunatic::distributed", "monitor", monitor)?;
+    linker.func_wrap2_async(
+        "lunatic::distributed",
+        "cluster_broadcast",
+        cluster_broadcast,
+    )?;
     linker.func_wrap3_async(
         "lunatic::distributed",
         "send_receive_skip_search",
@@ -43,6 +75,7 @@ where
         "copy_lookup_nodes_results",
         copy_lookup_nodes_results,
     )?;
+    linker.func_wrap5_async("lunatic::distributed", "barrier_wait", barrier_wait)?;
     Ok(())
 }
 
@@ -59,10 +92,131 @@ where
         .unwrap_or(0) as u32
 }
 
+// Returns the status of the connection to the control node.
+//
+// `nodes_count`, `get_nodes` and the process counts keep returning the last known values while
+// the control node is unreachable. This function lets the guest tell an empty cluster apart from
+// a stale view of it.
+//
+// Returns:
+// * 0 if the control node is reachable
+// * 1 if the control node is currently unreachable
+// * 2 if the node is not part of a distributed cluster
+fn control_status<T, E>(caller: Caller<T>) -> u32
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    match caller.data().distributed() {
+        Ok(d) if d.control.is_reachable() => 0,
+        Ok(_) => 1,
+        Err(_) => 2,
+    }
+}
+
+// Returns the number of processes running across all nodes, as last reported by the nodes to the
+// control server.
+fn cluster_process_count<T, E>(caller: Caller<T>) -> u64
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    caller
+        .data()
+        .distributed()
+        .map(|d| d.control.cluster_process_count())
+        .unwrap_or(0)
+}
+
+// Returns the number of processes running on the node **node_id**, as last reported by the node
+// to the control server. Returns 0 if the node is unknown.
+fn node_process_count<T, E>(caller: Caller<T>, node_id: u64) -> u64
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    caller
+        .data()
+        .distributed()
+        .ok()
+        .and_then(|d| d.control.node_process_count(node_id))
+        .unwrap_or(0)
+}
+
+// Writes the capabilities of the node **node_id** to **buf_ptr**, if they fit into **buf_len**
+// bytes. The capabilities (runtime version, enabled WebAssembly proposals and host function
+// namespaces) are reported by the node when it registers with the control server and cached
+// afterwards. See `NodeCapabilities::encode` for the format.
+//
+// Returns:
+// * The length of the encoded capabilities, they are only written if it's <= **buf_len**.
+// * u64::MAX if the node is not registered (e.g. it's down) or this node is not part of a
+//   distributed cluster.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn node_capabilities<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u64, Trap>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let capabilities = match caller
+        .data()
+        .distributed()
+        .ok()
+        .and_then(|d| d.node_capabilities(node_id))
+    {
+        Some(capabilities) => capabilities.encode(),
+        None => return Ok(u64::MAX),
+    };
+    if capabilities.len() <= buf_len as usize {
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, buf_ptr as usize, &capabilities)
+            .or_trap("lunatic::distributed::node_capabilities")?;
+    }
+    Ok(capabilities.len() as u64)
+}
+
+// Writes which nodes can reach which other nodes to **buf_ptr**, if it fits into **buf_len**
+// bytes. Each node reports the health of its connections to other nodes together with its stats
+// to the control server, a guest can use the view to detect network partitions. Connections of
+// nodes that didn't report yet are marked unknown. See `ClusterTopology::encode` for the format.
+//
+// Returns:
+// * The length of the encoded topology, it's only written if it's <= **buf_len**.
+// * u64::MAX if this node is not part of a distributed cluster.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn cluster_topology<T, E>(mut caller: Caller<T>, buf_ptr: u32, buf_len: u32) -> Result<u64, Trap>
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    let topology = match caller.data().distributed() {
+        Ok(distributed) => distributed.control.topology().encode(),
+        Err(_) => return Ok(u64::MAX),
+    };
+    if topology.len() <= buf_len as usize {
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, buf_ptr as usize, &topology)
+            .or_trap("lunatic::distributed::cluster_topology")?;
+    }
+    Ok(topology.len() as u64)
+}
+
 // Copy node ids into guest memory. Returns the number of nodes copied.
 //
 // Traps:
 // * If any memory outside the guest heap space is referenced.
+// * If the memory range overflows the host's address space.
 fn get_nodes<T, E>(mut caller: Caller<T>, nodes_ptr: u32, nodes_len: u32) -> Result<u32, Trap>
 where
     T: DistributedCtx<E>,
@@ -75,13 +229,33 @@ where
         .map(|d| d.control.node_ids())
         .unwrap_or_else(|_| vec![]);
     let copy_nodes_len = node_ids.len().min(nodes_len as usize);
+    let trap = |reason: String| {
+        HostCall::new(
+            "lunatic::distributed::get_nodes",
+            &[("nodes_ptr", &nodes_ptr), ("nodes_len", &nodes_len)],
+        )
+        .trap(reason)
+    };
+    // The end of the range can't be represented on 32 bit hosts if the pointer is close to
+    // `u32::MAX`, don't let it wrap around.
+    let start = nodes_ptr as usize;
+    let end = std::mem::size_of::<u64>()
+        .checked_mul(copy_nodes_len)
+        .and_then(|len| start.checked_add(len))
+        .ok_or_else(|| {
+            trap(format!(
+                "memory range starting at {start} overflows (EFAULT)"
+            ))
+        })?;
+    let memory_size = memory.data_size(&caller);
     memory
         .data_mut(&mut caller)
-        .get_mut(
-            nodes_ptr as usize
-                ..(nodes_ptr as usize + std::mem::size_of::<u64>() * copy_nodes_len as usize),
-        )
-        .or_trap("lunatic::distributed::get_nodes::memory")?
+        .get_mut(start..end)
+        .ok_or_else(|| {
+            trap(format!(
+                "memory range {start}..{end} is outside of the guest memory ({memory_size} bytes)"
+            ))
+        })?
         .copy_from_slice(unsafe { node_ids[..copy_nodes_len].align_to::<u8>().1 });
     Ok(copy_nodes_len as u32)
 }
@@ -141,6 +315,66 @@ where
     })
 }
 
+// Waits on a cluster wide barrier until `expected` processes, possibly running on different nodes,
+// are waiting on the barrier with the same name. All of them are released together and the
+// barrier can be reused afterwards.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027 and the process doesn't count towards the barrier anymore.
+//
+// Returns:
+// * 0 if the barrier was released
+// * 1 if the barrier couldn't be joined, error id is written to **error_ptr**
+// * 9027 if the operation timed out
+//
+// Traps:
+// * If the name is not a valid UTF-8 string
+// * If any memory outside the guest heap space is referenced
+fn barrier_wait<T, E>(
+    mut caller: Caller<T>,
+    name_ptr: u32,
+    name_len: u32,
+    expected: u64,
+    timeout_duration: u64,
+    error_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: DistributedCtx<E> + ErrorCtx + Send + 'static,
+    E: Environment + 'static,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let name = memory
+            .data(&caller)
+            .get(name_ptr as usize..(name_ptr as usize + name_len as usize))
+            .or_trap("lunatic::distributed::barrier_wait::name_ptr")?;
+        let name = std::str::from_utf8(name)
+            .or_trap("lunatic::distributed::barrier_wait::name_utf8")?
+            .to_string();
+        let timeout = match timeout_duration {
+            u64::MAX => None,
+            millis => Some(Duration::from_millis(millis)),
+        };
+        let distributed = caller.data().distributed()?;
+        match distributed
+            .control
+            .barrier_wait(distributed.node_id(), &name, expected, timeout)
+            .await
+        {
+            Ok(true) => Ok(0),
+            Ok(false) => Ok(9027),
+            Err(error) => {
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, error_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::barrier_wait::error_ptr")?;
+                Ok(1)
+            }
+        }
+    })
+}
+
 // Copies node ids to guest memory from the lookup node query result, returns number of node ids copied.
 //
 // Traps:
@@ -199,10 +433,15 @@ where
 // would ordinarily occupy fewer than 16 bytes (e.g. in an i32 or i64), you MUST
 // first convert it to an i128.
 //
+// Process ids are only unique inside a node. Together with `node_id` the process id written to
+// `id_ptr` identifies the new process across the cluster, both can be passed to
+// `lunatic::distributed::send` and `lunatic::distributed::monitor`.
+//
 // Returns:
 // * 0      on success - The ID of the newly created process is written to `id_ptr`
 // * 1      If node does not exist
 // * 2      If module does not exist
+// * 3      If the admission policy of the node rejected the spawn, the error contains the reason
 // * 9027   If node connection error occurred
 //
 // Traps:
@@ -227,98 +466,274 @@ where
     for<'a> &'a T: Send,
 {
     Box::new(async move {
-        if !caller.data().can_spawn() {
-            return Err(anyhow!("Process doesn't have permissions to spawn sub-processes").into());
+        spawn_on_node(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+            id_ptr,
+            None,
+        )
+        .await
+    })
+}
+
+// Same as `lunatic::distributed::spawn`, but the message in the scratch area is put into the
+// mailbox of the new process before it starts running. The first `lunatic::message::receive` of
+// the child returns it, without racing a message sent after the spawn.
+//
+// Returns:
+// * 0      on success - The ID of the newly created process is written to `id_ptr`
+// * 1      If node does not exist
+// * 2      If module does not exist
+// * 3      If the admission policy of the node rejected the spawn, the error contains the reason
+// * 9027   If node connection error occurred
+// * 9028   If the message is bigger than the maximum message size of the process, it's dropped
+//          and no process is spawned
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_with_message<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + ResourceLimiter + Send + ErrorCtx + 'static,
+    T::Config: ProcessConfigCtx,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::distributed::spawn_with_message::no_message")?;
+        if !caller.data().config().allows_message_size(message.size()) {
+            return Ok(9028);
         }
+        let initial_message = match message {
+            Message::Data(DataMessage {
+                tag,
+                buffer,
+                resources,
+                ..
+            }) => {
+                if !resources.is_empty() {
+                    return Err(Trap::new("Cannot send resources to remote nodes."));
+                }
+                (tag, buffer)
+            }
+            _ => return Err(Trap::new("Only Message::Data can be sent across nodes.")),
+        };
+        spawn_on_node(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+            id_ptr,
+            Some(initial_message),
+        )
+        .await
+    })
+}
+
+// Same as `lunatic::distributed::spawn`, but the process is spawned on the registered node with
+// the fewest processes running on it. Nodes this node currently can't reach are skipped. The id
+// of the chosen node is written to `node_id_ptr`, it identifies the new process across the
+// cluster together with the process id.
+//
+// Returns:
+// * 0      on success - The ID of the newly created process is written to `id_ptr`
+// * 1      If no node is available
+// * 2      If module does not exist
+// * 3      If the admission policy of the node rejected the spawn, the error contains the reason
+// * 9027   If node connection error occurred
+//
+// Traps:
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_balanced<T, E>(
+    mut caller: Caller<T>,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    node_id_ptr: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
         let memory = get_memory(&mut caller)?;
-        let func_str = memory
-            .data(&caller)
-            .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
-            .or_trap("lunatic::distributed::spawn::func_str")?;
+        let distributed = caller.data().distributed()?;
+        let least_loaded = distributed
+            .control
+            .least_loaded_node(|node_id| distributed.node_client.is_reachable(node_id));
+        let node_id = match least_loaded {
+            Some(node_id) => node_id,
+            None => {
+                let error = anyhow!("No node is available.");
+                let error_id = caller.data_mut().error_resources_mut().add(error);
+                memory
+                    .write(&mut caller, id_ptr as usize, &error_id.to_le_bytes())
+                    .or_trap("lunatic::distributed::spawn_balanced::write_id")?;
+                return Ok(1);
+            }
+        };
+        memory
+            .write(&mut caller, node_id_ptr as usize, &node_id.to_le_bytes())
+            .or_trap("lunatic::distributed::spawn_balanced::write_node_id")?;
+        spawn_on_node(
+            &mut caller,
+            node_id,
+            config_id,
+            module_id,
+            func_str_ptr,
+            func_str_len,
+            params_ptr,
+            params_len,
+            id_ptr,
+            None,
+        )
+        .await
+    })
+}
 
-        let function =
-            std::str::from_utf8(func_str).or_trap("lunatic::distributed::spawn::func_str_utf8")?;
+#[allow(clippy::too_many_arguments)]
+async fn spawn_on_node<T, E>(
+    caller: &mut Caller<'_, T>,
+    node_id: u64,
+    config_id: i64,
+    module_id: u64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+    initial_message: Option<(Option<i64>, Vec<u8>)>,
+) -> Result<u32, Trap>
+where
+    T: DistributedCtx<E> + ResourceLimiter + Send + ErrorCtx + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    if !caller.data().can_spawn() {
+        return Err(anyhow!("Process doesn't have permissions to spawn sub-processes").into());
+    }
+    let memory = get_memory(caller)?;
+    let func_str = memory
+        .data(&*caller)
+        .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
+        .or_trap("lunatic::distributed::spawn::func_str")?;
 
-        let params = memory
-            .data(&caller)
-            .get(params_ptr as usize..(params_ptr + params_len) as usize)
-            .or_trap("lunatic::distributed::spawn::params")?;
-        let params = params
-            .chunks_exact(17)
-            .map(|chunk| {
-                let value = u128::from_le_bytes(chunk[1..].try_into()?);
-                let result = match chunk[0] {
-                    0x7F => Val::I32(value as i32),
-                    0x7E => Val::I64(value as i64),
-                    0x7B => Val::V128(value),
-                    _ => return Err(anyhow!("Unsupported type ID")),
-                };
-                Ok(result)
-            })
-            .collect::<Result<Vec<_>>>()?;
+    let function =
+        std::str::from_utf8(func_str).or_trap("lunatic::distributed::spawn::func_str_utf8")?;
 
-        let state = caller.data();
+    let params = memory
+        .data(&*caller)
+        .get(params_ptr as usize..(params_ptr + params_len) as usize)
+        .or_trap("lunatic::distributed::spawn::params")?;
+    let params = params
+        .chunks_exact(17)
+        .map(|chunk| {
+            let value = u128::from_le_bytes(chunk[1..].try_into()?);
+            let result = match chunk[0] {
+                0x7F => Val::I32(value as i32),
+                0x7E => Val::I64(value as i64),
+                0x7B => Val::V128(value),
+                _ => return Err(anyhow!("Unsupported type ID")),
+            };
+            Ok(result)
+        })
+        .collect::<Result<Vec<_>>>()?;
 
-        let config = match config_id {
-            -1 => state.config().clone(),
-            config_id => Arc::new(
-                caller
-                    .data()
-                    .config_resources()
-                    .get(config_id as u64)
-                    .or_trap("lunatic::process::spawn: Config ID doesn't exist")?
-                    .clone(),
-            ),
-        };
-        let config: Vec<u8> =
-            bincode::serialize(config.as_ref()).map_err(|_| anyhow!("Error serializing config"))?;
+    let state = caller.data();
 
-        log::debug!("Spawn on node {node_id}, mod {module_id}, fn {function}, params {params:?}");
+    let config = match config_id {
+        -1 => state.config().clone(),
+        config_id => Arc::new(
+            caller
+                .data()
+                .config_resources()
+                .get(config_id as u64)
+                .or_trap("lunatic::process::spawn: Config ID doesn't exist")?
+                .clone(),
+        ),
+    };
+    let config: Vec<u8> =
+        bincode::serialize(config.as_ref()).map_err(|_| anyhow!("Error serializing config"))?;
 
-        let (process_or_error_id, ret) = match state
-            .distributed()?
-            .node_client
-            .spawn(
-                node_id,
-                Spawn {
-                    environment_id: state.environment_id(),
-                    function: function.to_string(),
-                    module_id,
-                    params,
-                    config,
-                },
-            )
-            .await
-        {
-            Ok(process_id) => (process_id, 0),
-            Err(error) => {
-                let (code, message): (u32, String) = match error {
-                    ClientError::Unexpected(cause) => Err(Trap::new(cause)),
-                    ClientError::NodeNotFound => Ok((1, "Node does not exist.".to_string())),
-                    ClientError::ModuleNotFound => Ok((2, "Module does not exist.".to_string())),
-                    ClientError::Connection(cause) => Ok((9027, cause)),
-                    _ => Err(Trap::new("unreachable")),
-                }?;
-                (
-                    caller
-                        .data_mut()
-                        .error_resources_mut()
-                        .add(anyhow!(message)),
-                    code,
-                )
-            }
-        };
+    log::debug!("Spawn on node {node_id}, mod {module_id}, fn {function}, params {params:?}");
 
-        memory
-            .write(
-                &mut caller,
-                id_ptr as usize,
-                &process_or_error_id.to_le_bytes(),
+    let (process_or_error_id, ret) = match state
+        .distributed()?
+        .node_client
+        .spawn(
+            node_id,
+            Spawn {
+                environment_id: state.environment_id(),
+                function: function.to_string(),
+                module_id,
+                params,
+                config,
+                initial_message,
+            },
+        )
+        .await
+    {
+        Ok(process_id) => (process_id, 0),
+        Err(error) => {
+            let (code, message): (u32, String) = match error {
+                ClientError::Unexpected(cause) => Err(Trap::new(cause)),
+                ClientError::NodeNotFound => Ok((1, "Node does not exist.".to_string())),
+                ClientError::ModuleNotFound => Ok((2, "Module does not exist.".to_string())),
+                ClientError::SpawnRejected(reason) => Ok((3, format!("Spawn rejected: {reason}"))),
+                ClientError::Connection(cause) => Ok((9027, cause)),
+                _ => Err(Trap::new("unreachable")),
+            }?;
+            (
+                caller
+                    .data_mut()
+                    .error_resources_mut()
+                    .add(anyhow!(message)),
+                code,
             )
-            .or_trap("lunatic::distributed::spawn::write_id")?;
+        }
+    };
 
-        Ok(ret)
-    })
+    memory
+        .write(caller, id_ptr as usize, &process_or_error_id.to_le_bytes())
+        .or_trap("lunatic::distributed::spawn::write_id")?;
+
+    Ok(ret)
 }
 
 // Sends the message in scratch area to a process running on a node with id `node_id`.
@@ -330,6 +745,8 @@ where
 // * 1      If process_id does not exist
 // * 2      If node_id does not exist
 // * 9027   If node connection error occurred
+// * 9028   If the message is bigger than the maximum message size of the process, it's dropped
+//          without being transmitted
 //
 // Traps:
 // * If it's called before creating the next message.
@@ -341,6 +758,7 @@ fn send<T, E>(
 ) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
 where
     T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
+    T::Config: ProcessConfigCtx,
     E: Environment,
     for<'a> &'a T: Send,
 {
@@ -350,6 +768,9 @@ where
             .message_scratch_area()
             .take()
             .or_trap("lunatic::message::send::no_message")?;
+        if !caller.data().config().allows_message_size(message.size()) {
+            return Ok(9028);
+        }
 
         if let Message::Data(DataMessage {
             tag,
@@ -384,6 +805,146 @@ where
     })
 }
 
+// Monitors the process **process_id** running on the node **node_id**, see
+// `lunatic::process::monitor`. When the process finishes, the node running it forwards the
+// `DOWN_TAG` message with the exit reason to the current process.
+//
+// If the process doesn't exist, the message is delivered right away with the reason `noprocess`.
+// If the node doesn't exist or can't be reached, it's delivered with the reason `noconnection`.
+//
+// Traps:
+// * If the process is not running on a distributed node.
+fn monitor<T, E>(
+    mut caller: Caller<T>,
+    node_id: u64,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<(), Trap>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + 'static,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let state = caller.data();
+        let distributed = state.distributed()?;
+        let result = distributed
+            .node_client
+            .monitor_process(
+                node_id,
+                state.environment_id(),
+                process_id,
+                distributed.node_id(),
+                state.id(),
+            )
+            .await;
+        let reason = match result {
+            Ok(()) => return Ok(()),
+            Err(ClientError::ProcessNotFound) => ExitReason::NoProcess,
+            Err(ClientError::NodeNotFound | ClientError::Connection(_)) => ExitReason::NoConnection,
+            Err(ClientError::Unexpected(cause)) => return Err(Trap::new(cause)),
+            Err(_) => return Err(Trap::new("unreachable")),
+        };
+        let message = down_message(process_id, reason);
+        caller.data_mut().mailbox().push(Message::Data(message));
+        Ok(())
+    })
+}
+
+// How long `cluster_broadcast` waits on a node before skipping it.
+const BROADCAST_NODE_TIMEOUT: Duration = Duration::from_secs(1);
+
+// Sends the message in scratch area to the process registered under **name** on every node in
+// the cluster, including the current one. Each node looks up the name in its own registry.
+//
+// Delivery is best-effort, nodes that this node currently can't reach or that don't respond in
+// time are skipped.
+//
+// Returns:
+// * The number of nodes that delivered the message to a process registered under the name.
+// * u32::MAX if the message is bigger than the maximum message size of the process, it's dropped
+//   without being transmitted.
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the message contains resources.
+// * If the name is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn cluster_broadcast<T, E>(
+    mut caller: Caller<T>,
+    name_ptr: u32,
+    name_len: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: DistributedCtx<E> + ProcessCtx<T> + Send + ErrorCtx + 'static,
+    T::Config: ProcessConfigCtx,
+    E: Environment,
+    for<'a> &'a T: Send,
+{
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let name = guest_slice(&memory, &caller, name_ptr, name_len)
+            .or_trap("lunatic::distributed::cluster_broadcast")?;
+        let name = std::str::from_utf8(name)
+            .or_trap("lunatic::distributed::cluster_broadcast")?
+            .to_string();
+
+        let message = caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::distributed::cluster_broadcast::no_message")?;
+        if !caller.data().config().allows_message_size(message.size()) {
+            return Ok(u32::MAX);
+        }
+        let (tag, buffer) = match message {
+            Message::Data(DataMessage {
+                tag,
+                buffer,
+                resources,
+                ..
+            }) => {
+                if !resources.is_empty() {
+                    return Err(Trap::new("Cannot send resources to remote nodes."));
+                }
+                (tag, buffer)
+            }
+            _ => return Err(Trap::new("Only Message::Data can be sent across nodes.")),
+        };
+
+        let state = caller.data();
+        let environment_id = state.environment_id();
+        let distributed = state.distributed()?;
+        // Send to all nodes concurrently, so that unreachable nodes don't delay the others
+        let deliveries: Vec<_> = distributed
+            .control
+            .node_ids()
+            .into_iter()
+            .filter(|node_id| distributed.node_client.is_reachable(*node_id))
+            .map(|node_id| {
+                let node_client = distributed.node_client.clone();
+                let (name, buffer) = (name.clone(), buffer.clone());
+                tokio::task::spawn(async move {
+                    let send = node_client.message_named_process(
+                        node_id,
+                        environment_id,
+                        name,
+                        tag,
+                        buffer,
+                    );
+                    matches!(timeout(BROADCAST_NODE_TIMEOUT, send).await, Ok(Ok(())))
+                })
+            })
+            .collect();
+        let mut reached = 0;
+        for delivery in deliveries {
+            if let Ok(true) = delivery.await {
+                reached += 1;
+            }
+        }
+        Ok(reached)
+    })
+}
+
 // Sends the message to a process on a node with id `node_id` and waits for a reply,
 // but doesn't look through existing messages in the mailbox queue while waiting.
 // This is an optimization that only makes sense with tagged messages.
@@ -481,6 +1042,16 @@ where
     })
 }
 
+// Returns 1 if the current process runs as part of a cluster, 0 if it runs standalone and the other
+// functions of this namespace are not available.
+fn is_distributed<T, E>(caller: Caller<T>) -> u32
+where
+    T: DistributedCtx<E>,
+    E: Environment,
+{
+    caller.data().distributed().is_ok() as u32
+}
+
 // Returns the id of the node that the current process is running on
 fn node_id<T, E>(caller: Caller<T>) -> u64
 where
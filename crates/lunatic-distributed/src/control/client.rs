@@ -6,19 +6,31 @@ use lunatic_process::runtimes::RawWasm;
 use std::{
     collections::HashMap,
     net::SocketAddr,
-    sync::{atomic, atomic::AtomicU64, Arc, RwLock},
+    sync::{
+        atomic,
+        atomic::{AtomicBool, AtomicU64},
+        Arc, RwLock,
+    },
     time::Duration,
 };
 use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
 
 use crate::{
-    control::message::{Registered, Registration, Request, Response},
-    quic::{self, RecvStream},
-    NodeInfo,
+    control::message::{ModuleHolders, NodeStats, Registered, Registration, Request, Response},
+    quic::{self, RecvStream, SendStream},
+    ClusterTopology, NodeCapabilities, NodeInfo,
 };
 
 use super::server::CTRL_SERVER_NAME;
 
+// How long to wait for the control server to answer a request, before giving up and marking it
+// as unreachable.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+// A participant waiting on a barrier asks the control server if it was released, doubling the
+// interval between the polls up to the maximum.
+const BARRIER_POLL_INTERVAL: Duration = Duration::from_millis(10);
+const MAX_BARRIER_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
 #[derive(Clone)]
 pub struct Client {
     inner: Arc<InnerClient>,
@@ -35,18 +47,37 @@ pub struct InnerClient {
     node_queries: DashMap<u64, Vec<u64>>,
     nodes: DashMap<u64, NodeInfo>,
     node_ids: RwLock<Vec<u64>>,
+    node_stats: DashMap<u64, NodeStats>,
     attributes: HashMap<String, String>,
+    capabilities: NodeCapabilities,
+    // Cleared when the connection to the control server drops or a request times out, and set
+    // again once a response arrives.
+    reachable: AtomicBool,
 }
 
 impl Client {
+    /// Registers the node with the control server.
+    ///
+    /// If the control server is not reachable yet, connecting is retried until `connect_timeout`
+    /// elapses. Once connected, the connection is re-established forever if it drops.
+    #[allow(clippy::too_many_arguments)]
     pub async fn register(
         node_addr: SocketAddr,
         node_name: String,
         attributes: HashMap<String, String>,
+        capabilities: NodeCapabilities,
         control_addr: SocketAddr,
         quic_client: quic::Client,
         signing_request: String,
+        connect_timeout: Duration,
     ) -> Result<(u64, Self, String)> {
+        let connection = quic::try_connect_with_backoff(
+            &quic_client,
+            control_addr,
+            CTRL_SERVER_NAME,
+            connect_timeout,
+        )
+        .await?;
         let (tx, rx) = mpsc::unbounded_channel();
 
         let client = Client {
@@ -61,12 +92,16 @@ impl Client {
                 next_query_id: AtomicU64::new(1),
                 nodes: Default::default(),
                 node_ids: Default::default(),
+                node_stats: Default::default(),
                 attributes,
+                capabilities,
+                reachable: AtomicBool::new(true),
             }),
         };
         // Spawn reader task before register
         tokio::task::spawn(connection_task(
             client.clone(),
+            connection,
             quic_client,
             control_addr,
             CTRL_SERVER_NAME.to_string(),
@@ -98,14 +133,37 @@ impl Client {
         self.inner.control_addr
     }
 
+    /// Returns `false` if the control server is currently unreachable.
+    ///
+    /// While unreachable, the cached node list and stats are still served, but they may be
+    /// stale. Requests are queued and sent once the connection is re-established.
+    pub fn is_reachable(&self) -> bool {
+        self.inner.reachable.load(atomic::Ordering::Relaxed)
+    }
+
+    fn set_reachable(&self, reachable: bool) {
+        self.inner
+            .reachable
+            .store(reachable, atomic::Ordering::Relaxed);
+    }
+
     pub async fn send(&self, req: Request) -> Result<Response> {
         let msg_id = self.next_message_id();
-        self.inner.tx.send((msg_id, req))?;
         let cell = AsyncCell::shared();
         self.inner.pending_requests.insert(msg_id, cell.clone());
-        let response = cell.take().await;
+        if let Err(e) = self.inner.tx.send((msg_id, req)) {
+            self.inner.pending_requests.remove(&msg_id);
+            return Err(e.into());
+        }
+        let response = tokio::time::timeout(REQUEST_TIMEOUT, cell.take()).await;
         self.inner.pending_requests.remove(&msg_id);
-        Ok(response)
+        match response {
+            Ok(response) => Ok(response),
+            Err(_) => {
+                self.set_reachable(false);
+                Err(anyhow!("Control server didn't respond in time"))
+            }
+        }
     }
 
     async fn send_registration(&self, signing_request: String) -> Result<Registered> {
@@ -114,6 +172,8 @@ impl Client {
             node_name: self.inner.node_name.clone(),
             attributes: self.inner.attributes.clone(),
             signing_request,
+            version: crate::VERSION.to_string(),
+            capabilities: self.inner.capabilities.clone(),
         };
         let resp = self.send(Request::Register(reg)).await?;
         match resp {
@@ -124,6 +184,7 @@ impl Client {
     }
 
     fn process_response(&self, id: u64, resp: Response) {
+        self.set_reachable(true);
         if let Some(e) = self.inner.pending_requests.get(&id) {
             e.set(resp);
         };
@@ -146,6 +207,102 @@ impl Client {
         Ok(())
     }
 
+    pub async fn refresh_node_stats(&self) -> Result<()> {
+        match self.send(Request::ListNodeStats).await? {
+            Response::NodeStats(stats) => {
+                self.inner.node_stats.clear();
+                for (node_id, stats) in stats {
+                    self.inner.node_stats.insert(node_id, stats);
+                }
+                Ok(())
+            }
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on list_node_stats.")),
+        }
+    }
+
+    pub async fn update_node_stats(&self, node_id: u64, stats: NodeStats) -> Result<()> {
+        match self.send(Request::UpdateNodeStats(node_id, stats)).await? {
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Periodically reports the stats of this node to the control server.
+    pub fn report_node_stats_task<F>(&self, node_id: u64, stats: F)
+    where
+        F: Fn() -> NodeStats + Send + 'static,
+    {
+        let client = self.clone();
+        tokio::task::spawn(async move {
+            loop {
+                client.update_node_stats(node_id, stats()).await.ok();
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        });
+    }
+
+    /// Returns the last known number of processes running on a node.
+    pub fn node_process_count(&self, node_id: u64) -> Option<u64> {
+        self.inner
+            .node_stats
+            .get(&node_id)
+            .map(|stats| stats.process_count)
+    }
+
+    /// Returns the registered node with the fewest processes running on it.
+    ///
+    /// Nodes that haven't reported their stats yet and nodes for which **is_reachable** returns
+    /// `false` are skipped.
+    pub fn least_loaded_node(&self, is_reachable: impl Fn(u64) -> bool) -> Option<u64> {
+        self.node_ids()
+            .into_iter()
+            .filter(|node_id| is_reachable(*node_id))
+            .filter_map(|node_id| Some((node_id, self.node_process_count(node_id)?)))
+            .min_by_key(|(_, process_count)| *process_count)
+            .map(|(node_id, _)| node_id)
+    }
+
+    /// Returns which registered nodes can reach each other, as last reported by the nodes.
+    ///
+    /// A node always reaches itself. Connections are unknown if the node didn't report its stats
+    /// yet, e.g. because it can't reach the control server either.
+    pub fn topology(&self) -> ClusterTopology {
+        let mut nodes = self.node_ids();
+        nodes.sort_unstable();
+        let links = nodes
+            .iter()
+            .map(|from| {
+                let health = self
+                    .inner
+                    .node_stats
+                    .get(from)
+                    .map(|stats| stats.node_health.clone());
+                nodes
+                    .iter()
+                    .map(|to| match &health {
+                        _ if from == to => Some(true),
+                        Some(health) => health
+                            .iter()
+                            .find(|(node_id, _)| node_id == to)
+                            .map(|(_, healthy)| *healthy),
+                        None => None,
+                    })
+                    .collect()
+            })
+            .collect();
+        ClusterTopology { nodes, links }
+    }
+
+    /// Returns the last known number of processes running across all nodes.
+    pub fn cluster_process_count(&self) -> u64 {
+        self.inner
+            .node_stats
+            .iter()
+            .map(|stats| stats.process_count)
+            .sum()
+    }
+
     pub async fn deregister(&self, node_id: u64) {
         self.send(Request::Deregister(node_id)).await.ok();
     }
@@ -196,6 +353,85 @@ impl Client {
             Err(anyhow::anyhow!("Invalid response type on add_module."))
         }
     }
+
+    /// Registers **node_id** as a holder of the module with the content **hash**.
+    ///
+    /// Other nodes can fetch the module bytes from the node, instead of the control server
+    /// storing them. Returns the cluster wide module id.
+    pub async fn add_module_hash(&self, node_id: u64, hash: String) -> Result<u64> {
+        match self.send(Request::AddModuleHash { node_id, hash }).await? {
+            Response::ModuleId(id) => Ok(id),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on add_module_hash.")),
+        }
+    }
+
+    pub async fn lookup_module(&self, module_id: u64) -> Result<ModuleHolders> {
+        match self.send(Request::LookupModule(module_id)).await? {
+            Response::ModuleHolders(holders) => Ok(holders),
+            Response::Error(message) => Err(anyhow!(message)),
+            _ => Err(anyhow!("Invalid response type on lookup_module.")),
+        }
+    }
+
+    /// Waits on the barrier **name** until **expected** participants across the cluster arrived.
+    ///
+    /// Returns `false` if the barrier wasn't released before the **timeout**. The participant is
+    /// then withdrawn from the barrier, so that it doesn't count towards a later release. The
+    /// participants of **node_id** are also withdrawn if the node deregisters.
+    pub async fn barrier_wait(
+        &self,
+        node_id: u64,
+        name: &str,
+        expected: u64,
+        timeout: Option<Duration>,
+    ) -> Result<bool> {
+        let request = Request::BarrierArrive {
+            node_id,
+            name: name.to_string(),
+            expected,
+        };
+        let generation = match self.send(request).await? {
+            Response::BarrierGeneration(generation) => generation,
+            Response::Error(message) => return Err(anyhow!(message)),
+            _ => return Err(anyhow!("Invalid response type on barrier_wait.")),
+        };
+        let deadline = timeout.map(|timeout| tokio::time::Instant::now() + timeout);
+        let mut interval = BARRIER_POLL_INTERVAL;
+        loop {
+            let request = Request::BarrierPoll {
+                name: name.to_string(),
+                generation,
+            };
+            match self.send(request).await? {
+                Response::BarrierReleased(true) => return Ok(true),
+                Response::BarrierReleased(false) => (),
+                Response::Error(message) => return Err(anyhow!(message)),
+                _ => return Err(anyhow!("Invalid response type on barrier_wait.")),
+            }
+            if matches!(deadline, Some(deadline) if tokio::time::Instant::now() >= deadline) {
+                // The barrier could have been released between the last poll and leaving
+                let request = Request::BarrierLeave {
+                    node_id,
+                    name: name.to_string(),
+                    generation,
+                };
+                return match self.send(request).await? {
+                    Response::BarrierReleased(released) => Ok(released),
+                    Response::Error(message) => Err(anyhow!(message)),
+                    _ => Err(anyhow!("Invalid response type on barrier_wait.")),
+                };
+            }
+            let sleep = match deadline {
+                Some(deadline) => {
+                    interval.min(deadline.saturating_duration_since(tokio::time::Instant::now()))
+                }
+                None => interval,
+            };
+            tokio::time::sleep(sleep).await;
+            interval = (interval * 2).min(MAX_BARRIER_POLL_INTERVAL);
+        }
+    }
 }
 
 async fn reader_task(client: Client, mut recv: RecvStream) -> Result<()> {
@@ -209,6 +445,7 @@ async fn reader_task(client: Client, mut recv: RecvStream) -> Result<()> {
             }
             Err(e) => {
                 log::debug!("Control connection error: {e}");
+                client.set_reachable(false);
                 Err(e)
             }
         }?;
@@ -218,18 +455,19 @@ async fn reader_task(client: Client, mut recv: RecvStream) -> Result<()> {
 async fn refresh_nodes_task(client: Client) -> Result<()> {
     loop {
         client.refresh_nodes().await.ok();
+        client.refresh_node_stats().await.ok();
         tokio::time::sleep(Duration::from_secs(5)).await;
     }
 }
 
 async fn connection_task(
     client: Client,
+    (mut send, recv): (SendStream, RecvStream),
     quic_client: quic::Client,
     addr: SocketAddr,
     name: String,
     mut rx: UnboundedReceiver<(u64, Request)>,
 ) {
-    let (mut send, recv) = quic::try_connect_forever(&quic_client, addr, &name).await;
     tokio::spawn(reader_task(client.clone(), recv));
     while let Some(msg) = rx.recv().await {
         if let Ok(data) = bincode::serialize(&msg) {
@@ -238,6 +476,7 @@ async fn connection_task(
             let bytes: Bytes = data.into();
             while let Err(e) = send.send(&mut [size.clone(), bytes.clone()]).await {
                 log::debug!("Cannot send data to control node: {e}, reconnecting...");
+                client.set_reachable(false);
                 let (new_send, new_recv) =
                     quic::try_connect_forever(&quic_client, addr, &name).await;
                 tokio::spawn(reader_task(client.clone(), new_recv));
@@ -246,3 +485,199 @@ async fn connection_task(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, time::Duration};
+
+    use crate::{control, distributed, quic};
+
+    fn free_addr() -> SocketAddr {
+        let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.local_addr().unwrap()
+    }
+
+    async fn register(control_addr: SocketAddr, timeout: Duration) -> anyhow::Result<u64> {
+        Ok(register_client(control_addr, timeout).await?.0)
+    }
+
+    async fn register_client(
+        control_addr: SocketAddr,
+        timeout: Duration,
+    ) -> anyhow::Result<(u64, super::Client)> {
+        let ca_cert = distributed::server::root_cert(true, None)?;
+        let quic_client = quic::new_quic_client(&ca_cert)?;
+        let node_cert = distributed::server::gen_node_cert("node")?;
+        let (node_id, client, _) = super::Client::register(
+            free_addr(),
+            "node".to_string(),
+            HashMap::new(),
+            Default::default(),
+            control_addr,
+            quic_client,
+            node_cert.serialize_request_pem()?,
+            timeout,
+        )
+        .await?;
+        Ok((node_id, client))
+    }
+
+    fn start_control_server() -> SocketAddr {
+        let control_addr = free_addr();
+        let ca_cert = control::server::root_cert(true, None, None).unwrap();
+        tokio::spawn(control::server::control_server(control_addr, ca_cert));
+        control_addr
+    }
+
+    #[tokio::test]
+    async fn register_retries_until_control_is_up() {
+        let control_addr = free_addr();
+        tokio::spawn(async move {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            let ca_cert = control::server::root_cert(true, None, None).unwrap();
+            control::server::control_server(control_addr, ca_cert).await
+        });
+        let node_id = register(control_addr, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert_eq!(node_id, 1);
+    }
+
+    #[tokio::test]
+    async fn register_fails_after_timeout() {
+        let result = register(free_addr(), Duration::from_millis(500)).await;
+        assert!(result.is_err());
+    }
+
+    #[tokio::test]
+    async fn cluster_process_count_sums_node_stats() {
+        let control_addr = start_control_server();
+        let timeout = Duration::from_secs(10);
+        let (node_a, client_a) = register_client(control_addr, timeout).await.unwrap();
+        let (node_b, client_b) = register_client(control_addr, timeout).await.unwrap();
+
+        let stats = |process_count| control::message::NodeStats {
+            process_count,
+            ..Default::default()
+        };
+        client_a.update_node_stats(node_a, stats(3)).await.unwrap();
+        client_b.update_node_stats(node_b, stats(4)).await.unwrap();
+
+        client_a.refresh_node_stats().await.unwrap();
+        assert_eq!(client_a.node_process_count(node_a), Some(3));
+        assert_eq!(client_a.node_process_count(node_b), Some(4));
+        assert_eq!(client_a.cluster_process_count(), 7);
+    }
+
+    #[tokio::test]
+    async fn least_loaded_node_picks_lighter_node() {
+        let control_addr = start_control_server();
+        let timeout = Duration::from_secs(10);
+        let (node_a, client_a) = register_client(control_addr, timeout).await.unwrap();
+        let (node_b, client_b) = register_client(control_addr, timeout).await.unwrap();
+        // This node never reports stats and should not be picked
+        let (_, _client_c) = register_client(control_addr, timeout).await.unwrap();
+
+        let stats = |process_count| control::message::NodeStats {
+            process_count,
+            ..Default::default()
+        };
+        client_a.update_node_stats(node_a, stats(10)).await.unwrap();
+        client_b.update_node_stats(node_b, stats(2)).await.unwrap();
+
+        client_a.refresh_nodes().await.unwrap();
+        client_a.refresh_node_stats().await.unwrap();
+        assert_eq!(client_a.least_loaded_node(|_| true), Some(node_b));
+    }
+
+    #[tokio::test]
+    async fn least_loaded_node_skips_down_nodes() {
+        let control_addr = start_control_server();
+        let timeout = Duration::from_secs(10);
+        let (node_a, client_a) = register_client(control_addr, timeout).await.unwrap();
+        let (node_b, client_b) = register_client(control_addr, timeout).await.unwrap();
+
+        let stats = |process_count| control::message::NodeStats {
+            process_count,
+            ..Default::default()
+        };
+        client_a.update_node_stats(node_a, stats(10)).await.unwrap();
+        client_b.update_node_stats(node_b, stats(2)).await.unwrap();
+
+        client_a.refresh_nodes().await.unwrap();
+        client_a.refresh_node_stats().await.unwrap();
+        // Node B is lighter, but down
+        assert_eq!(
+            client_a.least_loaded_node(|node| node != node_b),
+            Some(node_a)
+        );
+        assert_eq!(client_a.least_loaded_node(|_| false), None);
+    }
+
+    #[tokio::test]
+    async fn topology_reflects_partitioned_nodes() {
+        let control_addr = start_control_server();
+        let timeout = Duration::from_secs(10);
+        let (node_a, client_a) = register_client(control_addr, timeout).await.unwrap();
+        let (node_b, client_b) = register_client(control_addr, timeout).await.unwrap();
+        let (node_c, client_c) = register_client(control_addr, timeout).await.unwrap();
+        // This node never reports stats, its view of the cluster is unknown
+        let (node_d, _client_d) = register_client(control_addr, timeout).await.unwrap();
+
+        // Node C is partitioned from A and B, which still reach each other
+        let stats = |node_health| control::message::NodeStats {
+            process_count: 0,
+            node_health,
+        };
+        let split_a = stats(vec![(node_b, true), (node_c, false)]);
+        client_a.update_node_stats(node_a, split_a).await.unwrap();
+        let split_b = stats(vec![(node_a, true), (node_c, false)]);
+        client_b.update_node_stats(node_b, split_b).await.unwrap();
+        let split_c = stats(vec![(node_a, false), (node_b, false)]);
+        client_c.update_node_stats(node_c, split_c).await.unwrap();
+
+        client_a.refresh_nodes().await.unwrap();
+        client_a.refresh_node_stats().await.unwrap();
+        let topology = client_a.topology();
+        assert_eq!(topology.nodes, vec![node_a, node_b, node_c, node_d]);
+        assert_eq!(topology.reachable(node_a, node_b), Some(true));
+        assert_eq!(topology.reachable(node_b, node_a), Some(true));
+        assert_eq!(topology.reachable(node_a, node_c), Some(false));
+        assert_eq!(topology.reachable(node_c, node_b), Some(false));
+        assert_eq!(topology.reachable(node_a, node_d), None);
+        assert_eq!(topology.reachable(node_d, node_a), None);
+        assert_eq!(topology.reachable(node_d, node_d), Some(true));
+
+        let encoded = topology.encode();
+        assert_eq!(encoded.len(), 4 + 4 * 8 + 4 * 4);
+        // The row of node C: unreachable, unreachable, itself, unknown
+        assert_eq!(&encoded[4 + 4 * 8 + 8..][..4], &[0, 0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn control_loss_marks_client_unreachable() {
+        let control_addr = free_addr();
+        let ca_cert = control::server::root_cert(true, None, None).unwrap();
+        let (cert_pem, key_pem) = control::server::default_server_certificates(&ca_cert).unwrap();
+        let mut endpoint = quic::new_quic_server(control_addr, &cert_pem, &key_pem).unwrap();
+        let control_endpoint = endpoint.clone();
+        let server = control::server::Server::new(ca_cert);
+        tokio::spawn(async move { quic::handle_accept_control(&mut endpoint, server).await });
+
+        let (_, client) = register_client(control_addr, Duration::from_secs(10))
+            .await
+            .unwrap();
+        assert!(client.is_reachable());
+        assert_eq!(client.node_count(), 1);
+
+        // Simulate the loss of the control node by closing all its connections
+        control_endpoint.close(0u32.into(), b"shutdown");
+        let start = tokio::time::Instant::now();
+        while client.is_reachable() {
+            assert!(start.elapsed() < Duration::from_secs(5));
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        // The last known nodes are still served
+        assert_eq!(client.node_count(), 1);
+    }
+}
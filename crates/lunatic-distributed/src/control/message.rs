@@ -2,7 +2,7 @@ use bytes::Bytes;
 use serde::{Deserialize, Serialize};
 use std::{collections::HashMap, net::SocketAddr};
 
-use crate::NodeInfo;
+use crate::{NodeCapabilities, NodeInfo};
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub enum Request {
@@ -14,6 +14,36 @@ pub enum Request {
     LookupNodes(String),
     AddModule(Vec<u8>),
     GetModule(u64),
+    // Registers the sending node as a holder of the module with the content hash, without
+    // uploading the bytes. Like `Deregister`, the node sends it's own id.
+    AddModuleHash {
+        node_id: u64,
+        hash: String,
+    },
+    // Looks up the content hash of a module and the nodes holding it.
+    LookupModule(u64),
+    // Like `Deregister`, the node sends it's own id.
+    UpdateNodeStats(u64, NodeStats),
+    ListNodeStats,
+    // Arrives at the named barrier that releases once `expected` participants arrived. Returns
+    // the generation of the barrier the participant is waiting for. Like `Deregister`, the node
+    // sends it's own id.
+    BarrierArrive {
+        node_id: u64,
+        name: String,
+        expected: u64,
+    },
+    // Checks if the generation of the named barrier was released.
+    BarrierPoll {
+        name: String,
+        generation: u64,
+    },
+    // Withdraws a participant that gave up waiting, unless the generation was already released.
+    BarrierLeave {
+        node_id: u64,
+        name: String,
+        generation: u64,
+    },
 }
 
 impl Request {
@@ -25,6 +55,13 @@ impl Request {
             Request::LookupNodes(_) => "LookupNodes",
             Request::AddModule(_) => "AddModule",
             Request::GetModule(_) => "GetModule",
+            Request::AddModuleHash { .. } => "AddModuleHash",
+            Request::LookupModule(_) => "LookupModule",
+            Request::UpdateNodeStats(_, _) => "UpdateNodeStats",
+            Request::ListNodeStats => "ListNodeStats",
+            Request::BarrierArrive { .. } => "BarrierArrive",
+            Request::BarrierPoll { .. } => "BarrierPoll",
+            Request::BarrierLeave { .. } => "BarrierLeave",
         }
     }
 }
@@ -35,6 +72,10 @@ pub enum Response {
     Nodes(Vec<NodeInfo>),
     Module(Option<Vec<u8>>),
     ModuleId(u64),
+    ModuleHolders(ModuleHolders),
+    NodeStats(Vec<(u64, NodeStats)>),
+    BarrierGeneration(u64),
+    BarrierReleased(bool),
     Error(String),
     None,
 }
@@ -45,6 +86,9 @@ pub struct Registration {
     pub node_name: String,
     pub signing_request: String,
     pub attributes: HashMap<String, String>,
+    // Runtime version of the node, the control server refuses incompatible nodes.
+    pub version: String,
+    pub capabilities: NodeCapabilities,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -53,6 +97,21 @@ pub struct Registered {
     pub signed_cert: String,
 }
 
+/// Nodes that hold the bytes of a module identified by its content hash.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ModuleHolders {
+    pub hash: String,
+    pub nodes: Vec<u64>,
+}
+
+/// Load information that nodes periodically report to the control server.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NodeStats {
+    pub process_count: u64,
+    // Nodes this node has connected to, and if the connection currently works.
+    pub node_health: Vec<(u64, bool)>,
+}
+
 pub fn pack_response(msg_id: u64, resp: Response) -> [Bytes; 2] {
     let data = bincode::serialize(&(msg_id, resp)).unwrap();
     let size = (data.len() as u32).to_le_bytes();
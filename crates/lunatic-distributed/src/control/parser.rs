@@ -221,6 +221,8 @@ mod tests {
                 node_name: "test01".to_string(),
                 attributes: metadata.clone(),
                 signing_request: "request01".to_string(),
+                version: crate::VERSION.to_string(),
+                capabilities: Default::default(),
             },
         );
 
@@ -232,6 +234,8 @@ mod tests {
                 node_name: "test02".to_string(),
                 attributes: metadata.clone(),
                 signing_request: "request01".to_string(),
+                version: crate::VERSION.to_string(),
+                capabilities: Default::default(),
             },
         );
 
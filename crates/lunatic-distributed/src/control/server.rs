@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     net::SocketAddr,
     path::Path,
     sync::{
@@ -9,7 +10,7 @@ use std::{
 
 use crate::{control::message::Response, NodeInfo};
 use crate::{
-    control::message::{Registered, Registration},
+    control::message::{ModuleHolders, NodeStats, Registered, Registration},
     quic::SendStream,
 };
 use anyhow::Result;
@@ -19,6 +20,34 @@ use rcgen::*;
 
 use super::parser::Parser;
 
+// Participants waiting on a barrier. Each time all expected participants arrived, the
+// generation is released and the barrier can be reused.
+#[derive(Default)]
+struct Barrier {
+    expected: u64,
+    arrived: u64,
+    // Participants of the current generation per node, withdrawn if the node deregisters.
+    arrived_from: HashMap<u64, u64>,
+    generation: u64,
+}
+
+impl Barrier {
+    fn leave(&mut self, node_id: u64, participants: u64) {
+        let participants = match self.arrived_from.get_mut(&node_id) {
+            Some(arrived) => {
+                let participants = participants.min(*arrived);
+                *arrived -= participants;
+                if *arrived == 0 {
+                    self.arrived_from.remove(&node_id);
+                }
+                participants
+            }
+            None => 0,
+        };
+        self.arrived -= participants;
+    }
+}
+
 #[derive(Clone)]
 pub struct Server {
     inner: Arc<InnerServer>,
@@ -30,6 +59,11 @@ struct InnerServer {
     addr_to_node: DashMap<SocketAddr, u64>,
     next_module_id: AtomicU64,
     modules: DashMap<u64, Vec<u8>>,
+    // Modules are identified by their content hash, the same bytes always get the same id.
+    module_ids: DashMap<String, u64>,
+    module_holders: DashMap<u64, ModuleHolders>,
+    node_stats: DashMap<u64, NodeStats>,
+    barriers: DashMap<String, Barrier>,
     ca_cert: Certificate,
 }
 
@@ -42,6 +76,10 @@ impl Server {
                 nodes: DashMap::new(),
                 addr_to_node: DashMap::new(),
                 modules: DashMap::new(),
+                module_ids: DashMap::new(),
+                module_holders: DashMap::new(),
+                node_stats: DashMap::new(),
+                barriers: DashMap::new(),
                 ca_cert,
             }),
         }
@@ -60,6 +98,13 @@ impl Server {
     }
 
     pub fn register(&self, reg: Registration) -> Response {
+        if !crate::compatible_versions(&reg.version, crate::VERSION) {
+            return Response::Error(format!(
+                "Node is running incompatible version {} (control is running {})",
+                reg.version,
+                crate::VERSION
+            ));
+        }
         let node_id = self.next_node_id();
         let signed_cert = CertificateSigningRequest::from_pem(&reg.signing_request)
             .and_then(|sign_request| sign_request.serialize_pem_with_signer(&self.inner.ca_cert));
@@ -69,6 +114,7 @@ impl Server {
                 // details of connection status & reconnecting/registering.
                 if let Some(proc_id) = self.inner.addr_to_node.get(&reg.node_address) {
                     self.inner.nodes.remove(&proc_id);
+                    self.inner.node_stats.remove(&proc_id);
                 }
 
                 self.inner.addr_to_node.insert(reg.node_address, node_id);
@@ -85,9 +131,35 @@ impl Server {
 
     pub fn deregister(&self, node_id: u64) -> Response {
         self.inner.nodes.remove(&node_id);
+        self.inner.node_stats.remove(&node_id);
+        for mut holders in self.inner.module_holders.iter_mut() {
+            holders.nodes.retain(|id| *id != node_id);
+        }
+        for mut barrier in self.inner.barriers.iter_mut() {
+            barrier.leave(node_id, u64::MAX);
+        }
         Response::None
     }
 
+    pub fn update_node_stats(&self, node_id: u64, stats: NodeStats) -> Response {
+        if self.inner.nodes.contains_key(&node_id) {
+            self.inner.node_stats.insert(node_id, stats);
+            Response::None
+        } else {
+            Response::Error(format!("Node {node_id} is not registered"))
+        }
+    }
+
+    pub fn list_node_stats(&self) -> Response {
+        Response::NodeStats(
+            self.inner
+                .node_stats
+                .iter()
+                .map(|e| (*e.key(), e.value().clone()))
+                .collect(),
+        )
+    }
+
     pub fn list_nodes(&self) -> Response {
         Response::Nodes(
             self.inner
@@ -97,6 +169,7 @@ impl Server {
                     id: *e.key(),
                     address: e.value().node_address,
                     name: e.value().node_name.clone(),
+                    capabilities: e.value().capabilities.clone(),
                 })
                 .collect(),
         )
@@ -114,6 +187,7 @@ impl Server {
                         id: *e.key(),
                         address: e.node_address,
                         name: e.node_name.clone(),
+                        capabilities: e.capabilities.clone(),
                     })
                     .collect(),
             ),
@@ -122,14 +196,95 @@ impl Server {
     }
 
     pub fn add_module(&self, bytes: Vec<u8>) -> Response {
-        let module_id = self.next_module_id();
+        let module_id = self.module_id(crate::module_hash(&bytes));
         self.inner.modules.insert(module_id, bytes);
         Response::ModuleId(module_id)
     }
 
+    pub fn add_module_hash(&self, node_id: u64, hash: String) -> Response {
+        if !self.inner.nodes.contains_key(&node_id) {
+            return Response::Error(format!("Node {node_id} is not registered"));
+        }
+        let module_id = self.module_id(hash);
+        if let Some(mut holders) = self.inner.module_holders.get_mut(&module_id) {
+            if !holders.nodes.contains(&node_id) {
+                holders.nodes.push(node_id);
+            }
+        }
+        Response::ModuleId(module_id)
+    }
+
+    pub fn lookup_module(&self, id: u64) -> Response {
+        match self.inner.module_holders.get(&id) {
+            Some(holders) => Response::ModuleHolders(holders.clone()),
+            None => Response::Error(format!("Module {id} does not exist")),
+        }
+    }
+
+    // Returns the id of the module with the content hash, assigning a new one if it's unknown.
+    fn module_id(&self, hash: String) -> u64 {
+        let module_id = *self
+            .inner
+            .module_ids
+            .entry(hash.clone())
+            .or_insert_with(|| self.next_module_id());
+        self.inner
+            .module_holders
+            .entry(module_id)
+            .or_insert_with(|| ModuleHolders {
+                hash,
+                nodes: Vec::new(),
+            });
+        module_id
+    }
+
     pub fn get_module(&self, id: u64) -> Response {
         Response::Module(self.inner.modules.get(&id).map(|e| e.clone()))
     }
+
+    pub fn barrier_arrive(&self, node_id: u64, name: String, expected: u64) -> Response {
+        if expected == 0 {
+            return Response::Error("Barrier needs at least one participant".to_string());
+        }
+        let mut barrier = self.inner.barriers.entry(name.clone()).or_default();
+        if barrier.arrived == 0 {
+            barrier.expected = expected;
+        } else if barrier.expected != expected {
+            return Response::Error(format!(
+                "Barrier {name} is waiting for {} participants, not {expected}",
+                barrier.expected
+            ));
+        }
+        let generation = barrier.generation;
+        barrier.arrived += 1;
+        *barrier.arrived_from.entry(node_id).or_default() += 1;
+        if barrier.arrived == barrier.expected {
+            barrier.arrived = 0;
+            barrier.arrived_from.clear();
+            barrier.generation += 1;
+        }
+        Response::BarrierGeneration(generation)
+    }
+
+    pub fn barrier_poll(&self, name: String, generation: u64) -> Response {
+        let released = match self.inner.barriers.get(&name) {
+            Some(barrier) => barrier.generation > generation,
+            None => false,
+        };
+        Response::BarrierReleased(released)
+    }
+
+    pub fn barrier_leave(&self, node_id: u64, name: String, generation: u64) -> Response {
+        let released = match self.inner.barriers.get_mut(&name) {
+            Some(mut barrier) if barrier.generation == generation => {
+                barrier.leave(node_id, 1);
+                false
+            }
+            Some(barrier) => barrier.generation > generation,
+            None => false,
+        };
+        Response::BarrierReleased(released)
+    }
 }
 
 pub static CTRL_SERVER_NAME: &str = "ctrl.lunatic.cloud";
@@ -189,7 +344,7 @@ fn ctrl_cert() -> Result<Certificate> {
     Ok(Certificate::from_params(ctrl_params)?)
 }
 
-fn default_server_certificates(root_cert: &Certificate) -> Result<(String, String)> {
+pub(crate) fn default_server_certificates(root_cert: &Certificate) -> Result<(String, String)> {
     let ctrl_cert = ctrl_cert()?;
     let cert_pem = ctrl_cert.serialize_pem_with_signer(root_cert)?;
     let key_pem = ctrl_cert.serialize_private_key_pem();
@@ -217,7 +372,22 @@ pub async fn handle_request(
         ListNodes => server.list_nodes(),
         AddModule(bytes) => server.add_module(bytes),
         GetModule(id) => server.get_module(id),
+        AddModuleHash { node_id, hash } => server.add_module_hash(node_id, hash),
+        LookupModule(id) => server.lookup_module(id),
         LookupNodes(query) => server.lookup_nodes(query),
+        UpdateNodeStats(node_id, stats) => server.update_node_stats(node_id, stats),
+        ListNodeStats => server.list_node_stats(),
+        BarrierArrive {
+            node_id,
+            name,
+            expected,
+        } => server.barrier_arrive(node_id, name, expected),
+        BarrierPoll { name, generation } => server.barrier_poll(name, generation),
+        BarrierLeave {
+            node_id,
+            name,
+            generation,
+        } => server.barrier_leave(node_id, name, generation),
     };
     let data = bincode::serialize(&(msg_id, response))?;
     let size = (data.len() as u32).to_le_bytes();
@@ -226,3 +396,147 @@ pub async fn handle_request(
     send.send(&mut [size, bytes]).await?;
     Ok(msg_id)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::{root_cert, Server};
+    use crate::control::message::{Registration, Response};
+    use crate::distributed;
+
+    fn registration(version: &str) -> Registration {
+        let node_cert = distributed::server::gen_node_cert("node").unwrap();
+        Registration {
+            node_address: "127.0.0.1:10000".parse().unwrap(),
+            node_name: "node".to_string(),
+            signing_request: node_cert.serialize_request_pem().unwrap(),
+            attributes: HashMap::new(),
+            version: version.to_string(),
+            capabilities: Default::default(),
+        }
+    }
+
+    #[test]
+    fn register_rejects_incompatible_version() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        match server.register(registration(crate::VERSION)) {
+            Response::Register(registered) => assert_eq!(registered.node_id, 1),
+            _ => panic!("Registration with the same version failed"),
+        }
+        match server.register(registration("999.0.0")) {
+            Response::Error(error) => assert!(error.contains("999.0.0")),
+            _ => panic!("Registration with an incompatible version succeeded"),
+        }
+        assert!(matches!(server.list_nodes(), Response::Nodes(nodes) if nodes.len() == 1));
+    }
+
+    #[test]
+    fn modules_are_identified_by_content_hash() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        let node_id = match server.register(registration(crate::VERSION)) {
+            Response::Register(registered) => registered.node_id,
+            _ => panic!("Registration failed"),
+        };
+
+        let module_id = match server.add_module(b"module".to_vec()) {
+            Response::ModuleId(id) => id,
+            _ => panic!("Adding module failed"),
+        };
+        let hash = crate::module_hash(b"module");
+        assert!(matches!(
+            server.add_module_hash(node_id, hash.clone()),
+            Response::ModuleId(id) if id == module_id
+        ));
+        match server.lookup_module(module_id) {
+            Response::ModuleHolders(holders) => {
+                assert_eq!(holders.hash, hash);
+                assert_eq!(holders.nodes, vec![node_id]);
+            }
+            _ => panic!("Module lookup failed"),
+        }
+
+        server.deregister(node_id);
+        assert!(matches!(
+            server.lookup_module(module_id),
+            Response::ModuleHolders(holders) if holders.nodes.is_empty()
+        ));
+        assert!(matches!(
+            server.lookup_module(module_id + 1),
+            Response::Error(_)
+        ));
+    }
+
+    #[test]
+    fn barrier_releases_when_all_participants_arrived() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        let arrive = |expected| match server.barrier_arrive(1, "sync".to_string(), expected) {
+            Response::BarrierGeneration(generation) => generation,
+            _ => panic!("Arriving at barrier failed"),
+        };
+        let released = |generation| {
+            matches!(
+                server.barrier_poll("sync".to_string(), generation),
+                Response::BarrierReleased(true)
+            )
+        };
+
+        assert_eq!(arrive(3), 0);
+        assert_eq!(arrive(3), 0);
+        assert!(!released(0));
+        assert!(matches!(
+            server.barrier_arrive(1, "sync".to_string(), 2),
+            Response::Error(_)
+        ));
+        assert_eq!(arrive(3), 0);
+        assert!(released(0));
+
+        // The next generation starts empty, a participant leaving doesn't count as arrived
+        assert_eq!(arrive(2), 1);
+        assert!(matches!(
+            server.barrier_leave(1, "sync".to_string(), 1),
+            Response::BarrierReleased(false)
+        ));
+        assert_eq!(arrive(2), 1);
+        assert!(!released(1));
+        assert_eq!(arrive(2), 1);
+        assert!(released(1));
+    }
+
+    #[test]
+    fn barrier_forgets_participants_of_deregistered_node() {
+        let server = Server::new(root_cert(true, None, None).unwrap());
+        let arrive = |node_id| match server.barrier_arrive(node_id, "sync".to_string(), 4) {
+            Response::BarrierGeneration(generation) => generation,
+            _ => panic!("Arriving at barrier failed"),
+        };
+        let released = || {
+            matches!(
+                server.barrier_poll("sync".to_string(), 0),
+                Response::BarrierReleased(true)
+            )
+        };
+
+        assert_eq!(arrive(1), 0);
+        assert_eq!(arrive(2), 0);
+        assert_eq!(arrive(2), 0);
+        server.deregister(2);
+        assert_eq!(arrive(3), 0);
+        assert!(!released());
+        // Leaving for a node that didn't arrive doesn't withdraw other participants
+        server.barrier_leave(2, "sync".to_string(), 0);
+        assert_eq!(arrive(3), 0);
+        assert!(!released());
+        assert_eq!(arrive(1), 0);
+        assert!(released());
+    }
+
+    #[test]
+    fn compatible_versions_follow_semver() {
+        assert!(crate::compatible_versions("0.12.0", "0.12.3"));
+        assert!(!crate::compatible_versions("0.12.0", "0.13.0"));
+        assert!(crate::compatible_versions("1.2.0", "1.5.1"));
+        assert!(!crate::compatible_versions("1.2.0", "2.0.0"));
+        assert!(!crate::compatible_versions("invalid", "0.12.0"));
+    }
+}
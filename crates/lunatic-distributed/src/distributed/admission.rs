@@ -0,0 +1,52 @@
+/// A process that another node asks this node to spawn.
+pub struct SpawnRequest<'a> {
+    pub environment_id: u64,
+    pub module_id: u64,
+    pub function: &'a str,
+    module: &'a [u8],
+}
+
+impl<'a> SpawnRequest<'a> {
+    pub fn new(environment_id: u64, module_id: u64, function: &'a str, module: &'a [u8]) -> Self {
+        Self {
+            environment_id,
+            module_id,
+            function,
+            module,
+        }
+    }
+
+    /// Bytes of the module the process is spawned from.
+    pub fn module_bytes(&self) -> &[u8] {
+        self.module
+    }
+
+    /// Content hash of the module the process is spawned from, see [`module_hash`].
+    ///
+    /// The hash is calculated on every call.
+    ///
+    /// [`module_hash`]: crate::module_hash
+    pub fn module_hash(&self) -> String {
+        crate::module_hash(self.module)
+    }
+}
+
+/// Decides if a process requested by another node can be spawned on this node.
+///
+/// The policy is consulted by the node server before the process is instantiated. Policies that
+/// depend on the load of the node can hold on to the node's environments.
+pub trait AdmissionPolicy: Send + Sync {
+    /// Returns the reason, if the spawn is rejected. It's returned to the node requesting the
+    /// spawn.
+    fn admit(&self, request: &SpawnRequest) -> Result<(), String>;
+}
+
+/// Admits all spawn requests.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct AllowAll;
+
+impl AdmissionPolicy for AllowAll {
+    fn admit(&self, _request: &SpawnRequest) -> Result<(), String> {
+        Ok(())
+    }
+}
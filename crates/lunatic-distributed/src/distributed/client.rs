@@ -2,8 +2,17 @@ use anyhow::Result;
 use async_cell::sync::AsyncCell;
 use bytes::Bytes;
 use dashmap::DashMap;
-use std::sync::{atomic, atomic::AtomicU64, Arc};
-use tokio::sync::mpsc::{self, unbounded_channel, UnboundedReceiver, UnboundedSender};
+use std::{
+    net::SocketAddr,
+    sync::{
+        atomic::{self, AtomicU64, AtomicUsize},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::sync::mpsc::{
+    self, error::SendError, unbounded_channel, UnboundedReceiver, UnboundedSender,
+};
 
 use crate::{
     control,
@@ -19,6 +28,51 @@ struct SendRequest {
     node_id: u64,
     request: Request,
 }
+
+/// Limits of the connections a node keeps open to each other node.
+#[derive(Clone, Copy, Debug)]
+pub struct ConnectionPool {
+    /// Maximum number of connections to each node. Another connection is only opened if all
+    /// existing ones are busy sending. Requests sent over different connections can arrive out
+    /// of order.
+    pub max_connections_per_node: usize,
+    /// Connections that didn't send a request for this long are closed and opened again on
+    /// demand, `None` keeps them open.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for ConnectionPool {
+    fn default() -> Self {
+        Self {
+            max_connections_per_node: 1,
+            idle_timeout: None,
+        }
+    }
+}
+
+// A connection to a node, driven by a `manage_node_connection` task. The sender is closed once
+// the task finished.
+struct PooledConnection {
+    sender: UnboundedSender<(u64, Request)>,
+    // Requests queued on the connection that were not written to the stream yet
+    pending: Arc<AtomicUsize>,
+}
+
+// A node is only reported as unreachable if connecting or sending to it failed and no other
+// connection to it currently works. Connections that are still being opened or that were closed
+// because they were idle don't count as failures.
+#[derive(Default)]
+struct NodeHealth {
+    live_connections: usize,
+    failed: bool,
+}
+
+impl NodeHealth {
+    fn is_healthy(&self) -> bool {
+        self.live_connections > 0 || !self.failed
+    }
+}
+
 #[derive(Clone)]
 pub struct Client {
     inner: Arc<InnerClient>,
@@ -26,8 +80,11 @@ pub struct Client {
 
 pub struct InnerClient {
     next_message_id: AtomicU64,
-    node_message_buffers: DashMap<u64, UnboundedSender<(u64, Request)>>,
+    node_message_buffers: DashMap<u64, Vec<PooledConnection>>,
+    pool: ConnectionPool,
     pending_requests: DashMap<u64, Arc<AsyncCell<Response>>>,
+    // State of the connections to the nodes this node connected to.
+    node_health: DashMap<u64, NodeHealth>,
     control_client: control::Client,
     quic_client: quic::Client,
     tx: UnboundedSender<SendRequest>,
@@ -36,16 +93,34 @@ pub struct InnerClient {
 impl Client {
     // TODO node_id?
     pub async fn new(
+        node_id: u64,
+        control_client: control::Client,
+        quic_client: quic::Client,
+    ) -> Result<Client> {
+        Self::with_pool(
+            node_id,
+            control_client,
+            quic_client,
+            ConnectionPool::default(),
+        )
+        .await
+    }
+
+    /// Creates a client that keeps the connections to other nodes within the **pool** limits.
+    pub async fn with_pool(
         _node_id: u64,
         control_client: control::Client,
         quic_client: quic::Client,
+        pool: ConnectionPool,
     ) -> Result<Client> {
         let (tx, rx) = mpsc::unbounded_channel();
         let client = Client {
             inner: Arc::new(InnerClient {
                 next_message_id: AtomicU64::new(1),
                 node_message_buffers: DashMap::new(),
+                pool,
                 pending_requests: DashMap::new(),
+                node_health: DashMap::new(),
                 control_client,
                 quic_client,
                 tx,
@@ -106,6 +181,110 @@ impl Client {
         }
     }
 
+    pub async fn message_named_process(
+        &self,
+        node_id: u64,
+        environment_id: u64,
+        name: String,
+        tag: Option<i64>,
+        data: Vec<u8>,
+    ) -> Result<(), ClientError> {
+        match self
+            .request(
+                node_id,
+                Request::MessageNamed {
+                    environment_id,
+                    name,
+                    tag,
+                    data,
+                },
+            )
+            .await
+        {
+            Ok(Response::Sent) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for send".to_string(),
+            )),
+        }
+    }
+
+    pub async fn monitor_process(
+        &self,
+        node_id: u64,
+        environment_id: u64,
+        process_id: u64,
+        monitor_node_id: u64,
+        monitor_process_id: u64,
+    ) -> Result<(), ClientError> {
+        match self
+            .request(
+                node_id,
+                Request::Monitor {
+                    environment_id,
+                    process_id,
+                    monitor_node_id,
+                    monitor_process_id,
+                },
+            )
+            .await
+        {
+            Ok(Response::Monitored) => Ok(()),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for monitor".to_string(),
+            )),
+        }
+    }
+
+    /// Returns the nodes this node connected to, and if the connection currently works.
+    pub fn node_health(&self) -> Vec<(u64, bool)> {
+        self.inner
+            .node_health
+            .iter()
+            .map(|entry| (*entry.key(), entry.value().is_healthy()))
+            .collect()
+    }
+
+    /// Returns `false` if connecting or sending to the node failed and no connection to it
+    /// currently works. Nodes this node never connected to are assumed to be reachable.
+    pub fn is_reachable(&self, node_id: u64) -> bool {
+        self.inner
+            .node_health
+            .get(&node_id)
+            .is_none_or(|health| health.is_healthy())
+    }
+
+    /// Returns the number of connections currently open to the node.
+    pub fn open_connections(&self, node_id: u64) -> usize {
+        self.inner
+            .node_message_buffers
+            .get(&node_id)
+            .map_or(0, |connections| {
+                connections
+                    .iter()
+                    .filter(|connection| !connection.sender.is_closed())
+                    .count()
+            })
+    }
+
+    fn connection_opened(&self, node_id: u64) {
+        let mut health = self.inner.node_health.entry(node_id).or_default();
+        health.live_connections += 1;
+        health.failed = false;
+    }
+
+    // A working connection was closed, if **failed** because it broke.
+    fn connection_closed(&self, node_id: u64, failed: bool) {
+        let mut health = self.inner.node_health.entry(node_id).or_default();
+        health.live_connections = health.live_connections.saturating_sub(1);
+        health.failed |= failed;
+    }
+
+    fn connection_failed(&self, node_id: u64) {
+        self.inner.node_health.entry(node_id).or_default().failed = true;
+    }
+
     fn process_response(&self, id: u64, resp: Response) {
         if let Some(e) = self.inner.pending_requests.get(&id) {
             e.set(resp);
@@ -121,6 +300,20 @@ impl Client {
             )),
         }
     }
+
+    pub async fn get_module(
+        &self,
+        node_id: u64,
+        module_id: u64,
+    ) -> Result<Option<Vec<u8>>, ClientError> {
+        match self.request(node_id, Request::GetModule(module_id)).await {
+            Ok(Response::Module(module)) => Ok(module),
+            Ok(Response::Error(error)) | Err(error) => Err(error),
+            Ok(_) => Err(ClientError::Unexpected(
+                "Invalid response type for get_module".to_string(),
+            )),
+        }
+    }
 }
 
 async fn reader_task(client: Client, mut recv: RecvStream) -> Result<()> {
@@ -147,13 +340,50 @@ async fn forward_node_messages(client: Client, mut rx: UnboundedReceiver<SendReq
         request,
     }) = rx.recv().await
     {
-        if let Some(node_buf) = client.inner.node_message_buffers.get(&node_id) {
-            node_buf.value().send((msg_id, request)).ok();
-        } else {
-            let (send, recv) = unbounded_channel();
-            send.send((msg_id, request)).ok();
-            client.inner.node_message_buffers.insert(node_id, send);
-            tokio::spawn(manage_node_connection(node_id, client.clone(), recv));
+        let max_connections = client.inner.pool.max_connections_per_node.max(1);
+        let mut connections = client
+            .inner
+            .node_message_buffers
+            .entry(node_id)
+            .or_default();
+        let mut msg = (msg_id, request);
+        loop {
+            // Connections closed after being idle are dropped from the pool
+            connections.retain(|connection| !connection.sender.is_closed());
+            let least_busy = connections
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, connection)| connection.pending.load(atomic::Ordering::SeqCst))
+                .map(|(index, connection)| {
+                    (index, connection.pending.load(atomic::Ordering::SeqCst))
+                });
+            let index = match least_busy {
+                Some((index, pending)) if pending == 0 || connections.len() >= max_connections => {
+                    index
+                }
+                _ => {
+                    let (sender, recv) = unbounded_channel();
+                    let pending = Arc::new(AtomicUsize::new(0));
+                    tokio::spawn(manage_node_connection(
+                        node_id,
+                        client.clone(),
+                        recv,
+                        pending.clone(),
+                    ));
+                    connections.push(PooledConnection { sender, pending });
+                    connections.len() - 1
+                }
+            };
+            let connection = &connections[index];
+            connection.pending.fetch_add(1, atomic::Ordering::SeqCst);
+            match connection.sender.send(msg) {
+                Ok(()) => break,
+                // The connection was closed in the meantime, try another one
+                Err(SendError(returned)) => {
+                    connection.pending.fetch_sub(1, atomic::Ordering::SeqCst);
+                    msg = returned;
+                }
+            }
         }
     }
 }
@@ -173,23 +403,66 @@ async fn manage_node_connection(
     node_id: u64,
     client: Client,
     mut rx: UnboundedReceiver<(u64, Request)>,
+    pending: Arc<AtomicUsize>,
 ) {
-    let quic_client = client.inner.quic_client.clone();
     let NodeInfo { address, name, .. } = try_node_info_forever(node_id, &client).await;
-    let (mut send, recv) = quic::try_connect_forever(&quic_client, address, &name).await;
+    let (mut send, recv) = connect_to_node(&client, node_id, address, &name).await;
     tokio::spawn(reader_task(client.clone(), recv));
-    while let Some(msg) = rx.recv().await {
+    let mut closing = false;
+    loop {
+        let msg = match client.inner.pool.idle_timeout {
+            Some(idle_timeout) if !closing => {
+                match tokio::time::timeout(idle_timeout, rx.recv()).await {
+                    Ok(msg) => msg,
+                    Err(_) => {
+                        // Stop accepting requests, but still send the ones queued in the meantime
+                        rx.close();
+                        closing = true;
+                        continue;
+                    }
+                }
+            }
+            _ => rx.recv().await,
+        };
+        let msg = match msg {
+            Some(msg) => msg,
+            None => break,
+        };
         if let Ok(data) = bincode::serialize(&msg) {
             let size = (data.len() as u32).to_le_bytes();
             let size: Bytes = Bytes::copy_from_slice(&size[..]);
             let bytes: Bytes = data.into();
             while let Err(e) = send.send(&mut [size.clone(), bytes.clone()]).await {
                 log::debug!("Cannot send data to node: {e}, reconnecting...");
-                let (new_send, new_recv) =
-                    quic::try_connect_forever(&quic_client, address, &name).await;
+                client.connection_closed(node_id, true);
+                let (new_send, new_recv) = connect_to_node(&client, node_id, address, &name).await;
                 tokio::spawn(reader_task(client.clone(), new_recv));
                 send = new_send;
             }
         }
+        pending.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+    log::debug!("Closing idle connection to node {node_id}");
+    send.stream.finish().await.ok();
+    client.connection_closed(node_id, false);
+}
+
+// Connects to the node, retrying until it succeeds. Failed attempts mark the node as unreachable
+// if no other connection to it works.
+async fn connect_to_node(
+    client: &Client,
+    node_id: u64,
+    address: SocketAddr,
+    name: &str,
+) -> (quic::SendStream, quic::RecvStream) {
+    loop {
+        log::info!("Connecting to node {address} - {name}");
+        if let Ok(connection) = client.inner.quic_client.connect(address, name, 1).await {
+            client.connection_opened(node_id);
+            return connection;
+        }
+        client.connection_failed(node_id);
+        log::warn!("Failed to connect to node {address} - {name}, retrying...");
+        tokio::time::sleep(Duration::from_secs(2)).await;
     }
 }
@@ -10,6 +10,23 @@ pub enum Request {
         tag: Option<i64>,
         data: Vec<u8>,
     },
+    // Sends a message to the process registered under `name` on the receiving node.
+    MessageNamed {
+        environment_id: u64,
+        name: String,
+        tag: Option<i64>,
+        data: Vec<u8>,
+    },
+    // Requests the bytes of a module that the node holds.
+    GetModule(u64),
+    // Monitors a process on the receiving node for the process `monitor_process_id` on the node
+    // `monitor_node_id`, which receives the `DOWN_TAG` message when the process finishes.
+    Monitor {
+        environment_id: u64,
+        process_id: u64,
+        monitor_node_id: u64,
+        monitor_process_id: u64,
+    },
 }
 
 impl Request {
@@ -17,6 +34,9 @@ impl Request {
         match self {
             Request::Spawn(_) => "Spawn",
             Request::Message { .. } => "Message",
+            Request::MessageNamed { .. } => "MessageNamed",
+            Request::GetModule(_) => "GetModule",
+            Request::Monitor { .. } => "Monitor",
         }
     }
 }
@@ -28,6 +48,8 @@ pub struct Spawn {
     pub function: String,
     pub params: Vec<Val>,
     pub config: Vec<u8>,
+    // Tag and data of a message that is put into the mailbox before the process starts running.
+    pub initial_message: Option<(Option<i64>, Vec<u8>)>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -37,6 +59,8 @@ pub enum ClientError {
     NodeNotFound,
     ModuleNotFound,
     ProcessNotFound,
+    // The admission policy of the node rejected the spawn, contains the reason.
+    SpawnRejected(String),
 }
 
 impl Default for ClientError {
@@ -50,6 +74,8 @@ pub enum Response {
     Spawned(u64),
     Sent,
     Linked,
+    Monitored,
+    Module(Option<Vec<u8>>),
     Error(ClientError),
 }
 
@@ -59,6 +85,8 @@ impl Response {
             Response::Spawned(_) => "Spawned",
             Response::Sent => "Sent",
             Response::Linked => "Linked",
+            Response::Monitored => "Monitored",
+            Response::Module(_) => "Module",
             Response::Error(_) => "Error",
         }
     }
@@ -1,3 +1,4 @@
+pub mod admission;
 pub mod client;
 pub mod message;
 pub mod server;
@@ -1,13 +1,14 @@
 use std::{net::SocketAddr, sync::Arc};
 
 use anyhow::{anyhow, Result};
+use dashmap::DashMap;
 
 use lunatic_process::{
     env::{Environment, Environments},
     message::{DataMessage, Message},
     runtimes::{wasmtime::WasmtimeRuntime, Modules, RawWasm},
     state::ProcessState,
-    Signal,
+    Process, Signal,
 };
 use rcgen::*;
 use wasmtime::ResourceLimiter;
@@ -18,13 +19,21 @@ use crate::{
     DistributedCtx, DistributedProcessState,
 };
 
-use super::message::{ClientError, Spawn};
+use super::{
+    admission::{AdmissionPolicy, SpawnRequest},
+    client::Client,
+    message::{ClientError, Spawn},
+};
 
 pub struct ServerCtx<T, E: Environment> {
     pub envs: Arc<dyn Environments<Env = E>>,
     pub modules: Modules<T>,
     pub distributed: DistributedProcessState,
     pub runtime: WasmtimeRuntime,
+    // Process registry of the node, shared with the processes running on it
+    pub registry: Arc<DashMap<String, (u64, u64)>>,
+    // Decides which spawn requests from other nodes are accepted
+    pub admission: Arc<dyn AdmissionPolicy>,
 }
 
 impl<T: 'static, E: Environment> Clone for ServerCtx<T, E> {
@@ -34,6 +43,8 @@ impl<T: 'static, E: Environment> Clone for ServerCtx<T, E> {
             modules: self.modules.clone(),
             distributed: self.distributed.clone(),
             runtime: self.runtime.clone(),
+            registry: self.registry.clone(),
+            admission: self.admission.clone(),
         }
     }
 }
@@ -134,6 +145,60 @@ where
                 send.send(&mut data).await?;
             }
         },
+        Request::MessageNamed {
+            environment_id,
+            name,
+            tag,
+            data,
+        } => {
+            let entry = ctx.registry.get(&name).map(|entry| *entry);
+            let response = match entry {
+                Some((node_id, process_id)) if node_id == ctx.distributed.node_id() => {
+                    match handle_process_message(ctx, environment_id, process_id, tag, data).await {
+                        Ok(_) => Response::Sent,
+                        Err(error) => Response::Error(error),
+                    }
+                }
+                // Only processes running on this node can be reached
+                _ => Response::Error(ClientError::ProcessNotFound),
+            };
+            let mut data = super::message::pack_response(msg_id, response);
+            send.send(&mut data).await?;
+        }
+        Request::Monitor {
+            environment_id,
+            process_id,
+            monitor_node_id,
+            monitor_process_id,
+        } => {
+            let process = ctx
+                .envs
+                .get(environment_id)
+                .and_then(|env| env.get_process(process_id));
+            let response = match process {
+                Some(process) => {
+                    let monitor = RemoteMonitor {
+                        client: ctx.distributed.node_client.clone(),
+                        node_id: monitor_node_id,
+                        environment_id,
+                        process_id: monitor_process_id,
+                    };
+                    process.send(Signal::Monitor(Arc::new(monitor)));
+                    Response::Monitored
+                }
+                None => Response::Error(ClientError::ProcessNotFound),
+            };
+            let mut data = super::message::pack_response(msg_id, response);
+            send.send(&mut data).await?;
+        }
+        Request::GetModule(module_id) => {
+            let module = ctx
+                .modules
+                .get(module_id)
+                .map(|module| module.source().bytes.clone());
+            let mut data = super::message::pack_response(msg_id, Response::Module(module));
+            send.send(&mut data).await?;
+        }
     };
     Ok(())
 }
@@ -149,17 +214,40 @@ where
         function,
         params,
         config,
+        initial_message,
     } = spawn;
 
     let config: T::Config = bincode::deserialize(&config[..])?;
     let config = Arc::new(config);
 
+    // Rejected modules are not compiled
+    let admit = |bytes: &[u8]| {
+        let request = SpawnRequest::new(environment_id, module_id, &function, bytes);
+        ctx.admission.admit(&request)
+    };
     let module = match ctx.modules.get(module_id) {
-        Some(module) => module,
+        Some(module) => {
+            if let Err(reason) = admit(&module.source().bytes) {
+                return Ok(Err(ClientError::SpawnRejected(reason)));
+            }
+            module
+        }
         None => {
-            if let Some(bytes) = ctx.distributed.control.get_module(module_id).await {
+            if let Some(bytes) = fetch_module(&ctx, module_id).await {
+                if let Err(reason) = admit(&bytes) {
+                    return Ok(Err(ClientError::SpawnRejected(reason)));
+                }
+                let hash = crate::module_hash(&bytes);
                 let wasm = RawWasm::new(Some(module_id), bytes);
-                ctx.modules.compile(ctx.runtime.clone(), wasm).await??
+                let module = ctx.modules.compile(ctx.runtime.clone(), wasm).await??;
+                // Let other nodes fetch the module from this node too
+                let node_id = ctx.distributed.node_id();
+                ctx.distributed
+                    .control
+                    .add_module_hash(node_id, hash)
+                    .await
+                    .ok();
+                module
             } else {
                 return Ok(Err(ClientError::ModuleNotFound));
             }
@@ -172,7 +260,19 @@ where
         .unwrap_or_else(|| ctx.envs.create(environment_id));
     let distributed = ctx.distributed.clone();
     let runtime = ctx.runtime.clone();
-    let state = T::new_dist_state(env.clone(), distributed, runtime, module.clone(), config)?;
+    let registry = ctx.registry.clone();
+    let state = T::new_dist_state(
+        env.clone(),
+        distributed,
+        runtime,
+        module.clone(),
+        config,
+        registry,
+    )?;
+    if let Some((tag, data)) = initial_message {
+        let message = Message::Data(DataMessage::new_from_vec(tag, data));
+        state.message_mailbox().push(message);
+    }
     let params: Vec<wasmtime::Val> = params.into_iter().map(Into::into).collect();
     let (_handle, proc) = lunatic_process::wasm::spawn_wasm(
         env,
@@ -187,6 +287,29 @@ where
     Ok(Ok(proc.id()))
 }
 
+// Fetches the module bytes from the control server or, if it doesn't store them, from one of the
+// nodes holding the module. Bytes that don't match the registered content hash are rejected.
+async fn fetch_module<T, E>(ctx: &ServerCtx<T, E>, module_id: u64) -> Option<Vec<u8>>
+where
+    T: ProcessState + DistributedCtx<E> + ResourceLimiter + Send + 'static,
+    E: Environment + 'static,
+{
+    let dist = &ctx.distributed;
+    if let Some(bytes) = dist.control.get_module(module_id).await {
+        return Some(bytes);
+    }
+    let holders = dist.control.lookup_module(module_id).await.ok()?;
+    for holder in holders.nodes.into_iter().filter(|id| *id != dist.node_id()) {
+        match dist.node_client.get_module(holder, module_id).await {
+            Ok(Some(bytes)) if crate::module_hash(&bytes) == holders.hash => return Some(bytes),
+            Ok(Some(_)) => log::warn!("Node {holder} sent module {module_id} with a wrong hash"),
+            Ok(None) => {}
+            Err(e) => log::debug!("Fetching module {module_id} from node {holder} failed: {e:?}"),
+        }
+    }
+    None
+}
+
 async fn handle_process_message<T, E>(
     ctx: ServerCtx<T, E>,
     environment_id: u64,
@@ -210,3 +333,40 @@ where
     }
     Ok(())
 }
+
+// Stands in for a process on another node that monitors a process on this node. The `DOWN_TAG`
+// message sent to it is forwarded to the monitoring process.
+struct RemoteMonitor {
+    client: Client,
+    node_id: u64,
+    environment_id: u64,
+    process_id: u64,
+}
+
+impl Process for RemoteMonitor {
+    fn id(&self) -> u64 {
+        self.process_id
+    }
+
+    fn send(&self, signal: Signal) {
+        if let Signal::Message(Message::Data(message)) = signal {
+            let client = self.client.clone();
+            let (node_id, environment_id, process_id) =
+                (self.node_id, self.environment_id, self.process_id);
+            tokio::task::spawn(async move {
+                if let Err(e) = client
+                    .message_process(
+                        node_id,
+                        environment_id,
+                        process_id,
+                        message.tag,
+                        message.buffer,
+                    )
+                    .await
+                {
+                    log::debug!("Forwarding DOWN message to node {node_id} failed: {e:?}");
+                }
+            });
+        }
+    }
+}
@@ -3,13 +3,53 @@ pub mod distributed;
 pub mod quic;
 
 use anyhow::Result;
+use dashmap::DashMap;
 use lunatic_process::{
     env::Environment,
     runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime},
     state::ProcessState,
 };
 use serde::{Deserialize, Serialize};
-use std::{net::SocketAddr, sync::Arc};
+use sha2::{Digest, Sha256};
+use std::{fmt::Write, net::SocketAddr, sync::Arc};
+
+/// Runtime version that nodes send to the control server when registering.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Returns true if nodes running the runtime versions **a** and **b** can be part of the same
+/// cluster.
+///
+/// Following semver, versions are compatible if the major version matches, or the minor version
+/// for `0.x` releases.
+pub fn compatible_versions(a: &str, b: &str) -> bool {
+    let (a, b) = match (parse_version(a), parse_version(b)) {
+        (Some(a), Some(b)) => (a, b),
+        _ => return false,
+    };
+    match (a, b) {
+        ((0, a_minor), (0, b_minor)) => a_minor == b_minor,
+        ((a_major, _), (b_major, _)) => a_major == b_major,
+    }
+}
+
+// Parses the major and minor part of a `major.minor.patch` version.
+fn parse_version(version: &str) -> Option<(u64, u64)> {
+    let mut parts = version.split('.');
+    let major = parts.next()?.parse().ok()?;
+    let minor = parts.next()?.parse().ok()?;
+    Some((major, minor))
+}
+
+/// Returns the content hash (hex encoded SHA-256) that identifies the module **bytes** across
+/// the cluster.
+pub fn module_hash(bytes: &[u8]) -> String {
+    Sha256::digest(bytes)
+        .iter()
+        .fold(String::with_capacity(64), |mut hash, byte| {
+            write!(hash, "{byte:02x}").unwrap();
+            hash
+        })
+}
 
 pub trait DistributedCtx<E: Environment>: ProcessState + Sized {
     fn new_dist_state(
@@ -18,6 +58,7 @@ pub trait DistributedCtx<E: Environment>: ProcessState + Sized {
         runtime: WasmtimeRuntime,
         module: Arc<WasmtimeCompiledModule<Self>>,
         config: Arc<Self::Config>,
+        registry: Arc<DashMap<String, (u64, u64)>>,
     ) -> Result<Self>;
     fn distributed(&self) -> Result<&DistributedProcessState>;
     fn distributed_mut(&mut self) -> Result<&mut DistributedProcessState>;
@@ -31,6 +72,8 @@ pub struct DistributedProcessState {
     node_id: u64,
     pub control: control::Client,
     pub node_client: distributed::Client,
+    // Capabilities of other nodes, they don't change while a node is registered.
+    capabilities: Arc<DashMap<u64, NodeCapabilities>>,
 }
 
 impl DistributedProcessState {
@@ -43,12 +86,29 @@ impl DistributedProcessState {
             node_id,
             control: control_client,
             node_client,
+            capabilities: Arc::new(DashMap::new()),
         })
     }
 
     pub fn node_id(&self) -> u64 {
         self.node_id
     }
+
+    /// Returns the capabilities that the node **node_id** reported when registering.
+    ///
+    /// Returns `None` if the node is not registered (anymore) with the control server.
+    pub fn node_capabilities(&self, node_id: u64) -> Option<NodeCapabilities> {
+        if !self.control.node_ids().contains(&node_id) {
+            self.capabilities.remove(&node_id);
+            return None;
+        }
+        if let Some(capabilities) = self.capabilities.get(&node_id) {
+            return Some(capabilities.clone());
+        }
+        let capabilities = self.control.node_info(node_id)?.capabilities;
+        self.capabilities.insert(node_id, capabilities.clone());
+        Some(capabilities)
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -56,4 +116,93 @@ pub struct NodeInfo {
     pub id: u64,
     pub address: SocketAddr,
     pub name: String,
+    pub capabilities: NodeCapabilities,
+}
+
+/// Features of a node's runtime, sent to the control server when the node registers.
+///
+/// Guests can check them before spawning processes on the node.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct NodeCapabilities {
+    pub version: String,
+    pub simd: bool,
+    pub bulk_memory: bool,
+    pub reference_types: bool,
+    /// Namespaces of the host functions available to processes, e.g. `lunatic::process`.
+    pub host_apis: Vec<String>,
+}
+
+impl NodeCapabilities {
+    /// Collects the capabilities of processes with the state `T` running on **runtime**.
+    pub fn new<T: ProcessState>(runtime: &WasmtimeRuntime) -> Result<Self> {
+        let features = runtime.features();
+        Ok(Self {
+            version: VERSION.to_string(),
+            simd: features.simd,
+            bulk_memory: features.bulk_memory,
+            reference_types: features.reference_types,
+            host_apis: runtime.host_namespaces::<T>()?,
+        })
+    }
+
+    /// Encodes the capabilities for guests, all integers are little endian:
+    ///
+    /// * u32 feature flags: bit 0 SIMD, bit 1 bulk memory, bit 2 reference types.
+    /// * u32 length of the version, followed by the UTF-8 version string.
+    /// * u32 number of host APIs, followed by each namespace as u32 length and UTF-8 string.
+    pub fn encode(&self) -> Vec<u8> {
+        let flags = (self.simd as u32)
+            | ((self.bulk_memory as u32) << 1)
+            | ((self.reference_types as u32) << 2);
+        let mut encoded = flags.to_le_bytes().to_vec();
+        push_str(&mut encoded, &self.version);
+        encoded.extend((self.host_apis.len() as u32).to_le_bytes());
+        for host_api in &self.host_apis {
+            push_str(&mut encoded, host_api);
+        }
+        encoded
+    }
+}
+
+fn push_str(buffer: &mut Vec<u8>, string: &str) {
+    buffer.extend((string.len() as u32).to_le_bytes());
+    buffer.extend(string.as_bytes());
+}
+
+/// Which nodes can reach which other nodes, as last reported by the nodes to the control server.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ClusterTopology {
+    pub nodes: Vec<u64>,
+    /// `links[i][j]` is the health of the connection from `nodes[i]` to `nodes[j]`, `None` if
+    /// it's unknown (e.g. `nodes[i]` didn't report its stats or never connected to `nodes[j]`).
+    pub links: Vec<Vec<Option<bool>>>,
+}
+
+impl ClusterTopology {
+    /// Returns `Some(true)` if **from** reported that it can reach **to**.
+    pub fn reachable(&self, from: u64, to: u64) -> Option<bool> {
+        let from = self.nodes.iter().position(|node| *node == from)?;
+        let to = self.nodes.iter().position(|node| *node == to)?;
+        self.links[from][to]
+    }
+
+    /// Encodes the topology for guests, all integers are little endian:
+    ///
+    /// * u32 number of nodes `n`, followed by the `n` u64 node ids.
+    /// * `n * n` bytes, row by row, where the byte `i * n + j` describes the connection from
+    ///   node `i` to node `j`: 0 unreachable, 1 reachable, 2 unknown.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut encoded = (self.nodes.len() as u32).to_le_bytes().to_vec();
+        for node in &self.nodes {
+            encoded.extend(node.to_le_bytes());
+        }
+        for link in self.links.iter().flatten() {
+            encoded.push(match link {
+                Some(false) => 0,
+                Some(true) => 1,
+                None => 2,
+            });
+        }
+        encoded
+    }
 }
@@ -2,8 +2,14 @@ mod quin;
 
 use std::{net::SocketAddr, time::Duration};
 
+use anyhow::{anyhow, Result};
+use tokio::time::Instant;
+
 pub use quin::*;
 
+// Upper bound for the delay between two connection attempts in `try_connect_with_backoff`.
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+
 pub async fn try_connect_forever(
     quic_client: &self::Client,
     addr: SocketAddr,
@@ -18,3 +24,30 @@ pub async fn try_connect_forever(
         tokio::time::sleep(Duration::from_secs(2)).await;
     }
 }
+
+/// Tries to connect to a node, retrying with an exponential backoff until `timeout` elapses.
+pub async fn try_connect_with_backoff(
+    quic_client: &self::Client,
+    addr: SocketAddr,
+    name: &str,
+    timeout: Duration,
+) -> Result<(self::SendStream, self::RecvStream)> {
+    let deadline = Instant::now() + timeout;
+    let mut backoff = Duration::from_millis(100);
+    loop {
+        log::info!("Connecting to node {addr} - {name}");
+        match tokio::time::timeout_at(deadline, quic_client.connect(addr, name, 1)).await {
+            Ok(Ok(connection)) => return Ok(connection),
+            Ok(Err(e)) => log::warn!("Failed to connect to node {addr} - {name}: {e}"),
+            Err(_) => break,
+        }
+        if Instant::now() + backoff >= deadline {
+            break;
+        }
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+    Err(anyhow!(
+        "Failed to connect to node {addr} - {name} within {timeout:?}"
+    ))
+}
@@ -3,7 +3,7 @@ use std::{net::SocketAddr, sync::Arc, time::Duration};
 use anyhow::{anyhow, Result};
 use bytes::Bytes;
 use lunatic_process::{env::Environment, state::ProcessState};
-use quinn::{ClientConfig, Connecting, ConnectionError, Endpoint, ServerConfig};
+use quinn::{ClientConfig, Connecting, Endpoint, ServerConfig};
 use rustls_pemfile::Item;
 use wasmtime::ResourceLimiter;
 
@@ -130,10 +130,9 @@ async fn handle_quic_stream(
                 let recv = RecvStream { stream: r };
                 tokio::spawn(handle_quic_connection(send, recv, control_server.clone()));
             }
-            Err(ConnectionError::LocallyClosed) => {
-                break;
-            }
-            Err(_) => {}
+            // No more streams can be accepted once the connection failed or was closed by
+            // either side
+            Err(_) => break,
         }
     }
     Ok(())
@@ -186,8 +185,9 @@ where
                 let recv = RecvStream { stream: r };
                 tokio::spawn(handle_quic_stream_node(ctx.clone(), send, recv));
             }
-            Err(ConnectionError::LocallyClosed) => break,
-            Err(_) => {}
+            // No more streams can be accepted once the connection failed or was closed by
+            // either side
+            Err(_) => break,
         }
     }
     Ok(())
@@ -1,6 +1,6 @@
 use anyhow::Result;
 use hash_map_id::HashMapId;
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, wasm_backtrace, IntoTrap};
 use wasmtime::Trap;
 use wasmtime::{Caller, Linker};
 
@@ -16,6 +16,7 @@ pub fn register<T: ErrorCtx + 'static>(linker: &mut Linker<T>) -> Result<()> {
     linker.func_wrap("lunatic::error", "string_size", string_size)?;
     linker.func_wrap("lunatic::error", "to_string", to_string)?;
     linker.func_wrap("lunatic::error", "drop", drop)?;
+    linker.func_wrap("lunatic::error", "trap_backtrace", trap_backtrace)?;
     Ok(())
 }
 
@@ -56,6 +57,41 @@ fn to_string<T: ErrorCtx>(
     Ok(())
 }
 
+// Writes the wasm backtrace of the error to the guest memory, if the error was caused by a trap
+// (e.g. the start function of a spawned module trapped).
+//
+// At most **buf_len** bytes are written to **buf_ptr**. Calling this function with a **buf_len**
+// of 0 can be used to get the backtrace size.
+//
+// Returns:
+// * The full length of the backtrace, 0 if the error doesn't contain one.
+//
+// Traps:
+// * If the error ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn trap_backtrace<T: ErrorCtx>(
+    mut caller: Caller<T>,
+    error_id: u64,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u32, Trap> {
+    let error = caller
+        .data()
+        .error_resources()
+        .get(error_id)
+        .or_trap("lunatic::error::trap_backtrace")?;
+    let backtrace = error
+        .downcast_ref::<Trap>()
+        .and_then(wasm_backtrace)
+        .unwrap_or_default();
+    let len = backtrace.len().min(buf_len as usize);
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, buf_ptr as usize, &backtrace.as_bytes()[..len])
+        .or_trap("lunatic::error::trap_backtrace")?;
+    Ok(backtrace.len() as u32)
+}
+
 // Drops the error resource.
 //
 // Traps:
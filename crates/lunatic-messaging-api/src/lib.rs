@@ -2,25 +2,28 @@ use std::{
     convert::TryInto,
     future::Future,
     io::{Read, Write},
+    sync::atomic::{AtomicI64, Ordering},
 };
 
 use anyhow::Result;
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, guest_slice, IntoTrap};
 use lunatic_networking_api::NetworkingCtx;
-use lunatic_process_api::ProcessCtx;
+use lunatic_process_api::{ProcessConfigCtx, ProcessCtx};
 use tokio::time::{timeout, Duration};
 use wasmtime::{Caller, Linker, Trap};
 
 use lunatic_process::{
-    message::{DataMessage, Message},
+    message::{DataMessage, Message, ReplyTo, DOWN_TAG},
     state::ProcessState,
     Signal,
 };
 
 // Register the mailbox APIs to the linker
-pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static>(
-    linker: &mut Linker<T>,
-) -> Result<()> {
+pub fn register<T>(linker: &mut Linker<T>) -> Result<()>
+where
+    T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static,
+    T::Config: ProcessConfigCtx,
+{
     linker.func_wrap("lunatic::message", "create_data", create_data)?;
     linker.func_wrap("lunatic::message", "write_data", write_data)?;
     linker.func_wrap("lunatic::message", "read_data", read_data)?;
@@ -33,13 +36,26 @@ pub fn register<T: ProcessState + ProcessCtx<T> + NetworkingCtx + Send + 'static
     linker.func_wrap("lunatic::message", "take_tcp_stream", take_tcp_stream)?;
     linker.func_wrap("lunatic::message", "push_tls_stream", push_tls_stream)?;
     linker.func_wrap("lunatic::message", "take_tls_stream", take_tls_stream)?;
-    linker.func_wrap("lunatic::message", "send", send)?;
+    linker.func_wrap1_async("lunatic::message", "send", send)?;
+    linker.func_wrap1_async("lunatic::message", "send_and_yield", send_and_yield)?;
     linker.func_wrap2_async(
         "lunatic::message",
         "send_receive_skip_search",
         send_receive_skip_search,
     )?;
+    linker.func_wrap2_async("lunatic::message", "call", call)?;
+    linker.func_wrap("lunatic::message", "reply_ref", reply_ref)?;
+    linker.func_wrap("lunatic::message", "reply", reply)?;
     linker.func_wrap3_async("lunatic::message", "receive", receive)?;
+    linker.func_wrap("lunatic::message", "peek", peek)?;
+    linker.func_wrap("lunatic::message", "mailbox_len", mailbox_len)?;
+    linker.func_wrap("lunatic::message", "mailbox_high_water", mailbox_high_water)?;
+    linker.func_wrap(
+        "lunatic::message",
+        "mailbox_reset_high_water",
+        mailbox_reset_high_water,
+    )?;
+    linker.func_wrap1_async("lunatic::message", "mailbox_poll", mailbox_poll)?;
     linker.func_wrap("lunatic::message", "push_udp_socket", push_udp_socket)?;
     linker.func_wrap("lunatic::message", "take_udp_socket", take_udp_socket)?;
 
@@ -154,9 +170,8 @@ fn write_data<T: ProcessState + ProcessCtx<T>>(
         .message_scratch_area()
         .take()
         .or_trap("lunatic::message::write_data")?;
-    let buffer = memory
-        .data(&caller)
-        .get(data_ptr as usize..(data_ptr as usize + data_len as usize))
+    // Appended straight from the guest memory to the message buffer
+    let buffer = guest_slice(&memory, &caller, data_ptr, data_len)
         .or_trap("lunatic::message::write_data")?;
     let bytes = match &mut message {
         Message::Data(data) => data.write(buffer).or_trap("lunatic::message::write_data")?,
@@ -433,19 +448,100 @@ fn take_tls_stream<T: ProcessState + ProcessCtx<T> + NetworkingCtx>(
 //
 // There are no guarantees that the message will be received.
 //
+// Returns:
+// * 0    if the message was sent.
+// * 9028 if the message is bigger than the maximum message size of the process. The message is
+//        dropped without being sent.
+// * 9029 if the process exceeded its send rate to the receiver and doesn't block when rate
+//        limited. The message is dropped without being sent.
+//
 // Traps:
-// * If the process ID doesn't exist.
 // * If it's called before creating the next message.
-fn send<T: ProcessState + ProcessCtx<T>>(
+fn send<T>(
     mut caller: Caller<T>,
     process_id: u64,
-) -> Result<u32, Trap> {
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send,
+    T::Config: ProcessConfigCtx,
+{
+    // Without a send rate there is nothing to wait for, the message is sent right away and the
+    // process doesn't need to be suspended.
+    if caller.data().config().max_send_rate().is_none() {
+        return Box::new(std::future::ready(send_unlimited(&mut caller, process_id)));
+    }
+    Box::new(async move { send_message(&mut caller, process_id).await })
+}
+
+// Sends the message to a process and yields back to the scheduler, so that the receiver gets a
+// chance to run before the sender continues. This saves a scheduler round-trip in the common
+// "send, then wait for the reply" pattern.
+//
+// Returns:
+// * 0    if the message was sent.
+// * 9028 if the message is bigger than the maximum message size of the process. The message is
+//        dropped without being sent.
+// * 9029 if the process exceeded its send rate to the receiver and doesn't block when rate
+//        limited. The message is dropped without being sent.
+//
+// Traps:
+// * If it's called before creating the next message.
+fn send_and_yield<T>(
+    mut caller: Caller<T>,
+    process_id: u64,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send,
+    T::Config: ProcessConfigCtx,
+{
+    Box::new(async move {
+        let result = send_message(&mut caller, process_id).await?;
+        tokio::task::yield_now().await;
+        Ok(result)
+    })
+}
+
+async fn send_message<T>(caller: &mut Caller<'_, T>, process_id: u64) -> Result<u32, Trap>
+where
+    T: ProcessState + ProcessCtx<T> + Send,
+    T::Config: ProcessConfigCtx,
+{
     let message = caller
         .data_mut()
         .message_scratch_area()
         .take()
         .or_trap("lunatic::message::send::no_message")?;
 
+    if !caller.data().config().allows_message_size(message.size()) {
+        return Ok(9028);
+    }
+    if !within_send_rate(caller, process_id).await {
+        return Ok(9029);
+    }
+
+    if let Some(process) = caller.data_mut().environment().get_process(process_id) {
+        process.send(Signal::Message(message));
+    }
+
+    Ok(0)
+}
+
+// Same as `send_message`, for processes without a send rate.
+fn send_unlimited<T>(caller: &mut Caller<'_, T>, process_id: u64) -> Result<u32, Trap>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::message::send::no_message")?;
+
+    if !caller.data().config().allows_message_size(message.size()) {
+        return Ok(9028);
+    }
+
     if let Some(process) = caller.data_mut().environment().get_process(process_id) {
         process.send(Signal::Message(message));
     }
@@ -453,6 +549,32 @@ fn send<T: ProcessState + ProcessCtx<T>>(
     Ok(0)
 }
 
+// Takes a token from the bucket of the receiving process, if the config limits the send rate.
+// Depending on the config it waits for the bucket to refill or returns false right away if it's
+// empty.
+async fn within_send_rate<T>(caller: &mut Caller<'_, T>, process_id: u64) -> bool
+where
+    T: ProcessState + ProcessCtx<T> + Send,
+    T::Config: ProcessConfigCtx,
+{
+    let rate = match caller.data().config().max_send_rate() {
+        Some(rate) => rate,
+        None => return true,
+    };
+    let block = caller.data().config().block_when_rate_limited();
+    loop {
+        match caller
+            .data_mut()
+            .send_rate_limiter()
+            .try_take(process_id, rate)
+        {
+            Ok(()) => return true,
+            Err(wait) if block => tokio::time::sleep(wait).await,
+            Err(_) => return false,
+        }
+    }
+}
+
 // Sends the message to a process and waits for a reply, but doesn't look through existing
 // messages in the mailbox queue while waiting. This is an optimization that only makes sense
 // with tagged messages. In a request/reply scenario we can tag the request message with an
@@ -467,21 +589,33 @@ fn send<T: ProcessState + ProcessCtx<T>>(
 // Returns:
 // * 0    if message arrived.
 // * 9027 if call timed out.
+// * 9028 if the message is bigger than the maximum message size of the process.
+// * 9029 if the process exceeded its send rate to the receiver and doesn't block when rate
+//        limited.
 //
 // Traps:
-// * If the process ID doesn't exist.
 // * If it's called with wrong data in the scratch area.
-fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
+fn send_receive_skip_search<T>(
     mut caller: Caller<T>,
     process_id: u64,
     timeout_duration: u64,
-) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send,
+    T::Config: ProcessConfigCtx,
+{
     Box::new(async move {
         let message = caller
             .data_mut()
             .message_scratch_area()
             .take()
             .or_trap("lunatic::message::send_receive_skip_search")?;
+        if !caller.data().config().allows_message_size(message.size()) {
+            return Ok(9028);
+        }
+        if !within_send_rate(&mut caller, process_id).await {
+            return Ok(9029);
+        }
         let mut _tags = [0; 1];
         let tags = if let Some(tag) = message.tag() {
             _tags = [tag];
@@ -510,6 +644,165 @@ fn send_receive_skip_search<T: ProcessState + ProcessCtx<T> + Send>(
     })
 }
 
+// Tags of replies are taken from the bottom of the i64 range, right above the reserved
+// `SHUTDOWN_TAG` and `DOWN_TAG`, so that they don't collide with tags picked by guests.
+static NEXT_REPLY_TAG: AtomicI64 = AtomicI64::new(DOWN_TAG + 1);
+
+// Sends the message in the scratch area as a request to a process and waits for the reply.
+//
+// The request is received like any other message and can be answered by the receiving side with
+// `lunatic::message::reply_ref` and `lunatic::message::reply`. The reply is tagged with a unique
+// tag that is managed by the host, other messages in the mailbox are not touched while waiting.
+// Once the reply arrives, it's put into the scratch area and can be read with
+// `lunatic::message::read_data()`. A reply that arrives after the call timed out ends up in the
+// mailbox like a regular message.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0    if the reply arrived.
+// * 1    if the process doesn't exist.
+// * 9027 if call timed out.
+// * 9028 if the request is bigger than the maximum message size of the process.
+// * 9029 if the process exceeded its send rate to the receiver and doesn't block when rate
+//        limited.
+//
+// Traps:
+// * If it's called without a data message being inside of the scratch area.
+fn call<T>(
+    mut caller: Caller<T>,
+    process_id: u64,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + Send,
+    T::Config: ProcessConfigCtx,
+{
+    Box::new(async move {
+        let mut message = match caller
+            .data_mut()
+            .message_scratch_area()
+            .take()
+            .or_trap("lunatic::message::call")?
+        {
+            Message::Data(message) => message,
+            Message::LinkDied(_) => {
+                return Err(Trap::new("Unexpected `Message::LinkDied` in scratch area"))
+            }
+        };
+
+        if !caller.data().config().allows_message_size(message.size()) {
+            return Ok(9028);
+        }
+        let process = match caller.data().environment().get_process(process_id) {
+            Some(process) => process,
+            None => return Ok(1),
+        };
+        if !within_send_rate(&mut caller, process_id).await {
+            return Ok(9029);
+        }
+        let tag = NEXT_REPLY_TAG.fetch_add(1, Ordering::Relaxed);
+        message.reply_to = Some(ReplyTo {
+            process_id: caller.data().id(),
+            tag,
+        });
+        process.send(Signal::Message(Message::Data(message)));
+
+        let tags = [tag];
+        let pop_reply = caller.data_mut().mailbox().pop_skip_search(Some(&tags));
+        if let Ok(message) = match timeout_duration {
+            // Without timeout
+            u64::MAX => Ok(pop_reply.await),
+            // With timeout
+            t => timeout(Duration::from_millis(t), pop_reply).await,
+        } {
+            // Put the reply into the scratch area
+            caller.data_mut().message_scratch_area().replace(message);
+            Ok(0)
+        } else {
+            Ok(9027)
+        }
+    })
+}
+
+// Returns a reference to the caller waiting on the request in the scratch area. The reference
+// can be used with `lunatic::message::reply` to answer the request after the scratch area was
+// reused to create the reply.
+//
+// Returns:
+// * -1 if the message is not a request or the reference was already taken.
+//
+// Traps:
+// * If it's called without a message being inside of the scratch area.
+fn reply_ref<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<i64, Trap> {
+    let message = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_mut()
+        .or_trap("lunatic::message::reply_ref")?;
+    let reply_to = match message {
+        Message::Data(message) => message.reply_to.take(),
+        Message::LinkDied(_) => None,
+    };
+    Ok(match reply_to {
+        Some(reply_to) => caller.data_mut().reply_ref_resources_mut().add(reply_to) as i64,
+        None => -1,
+    })
+}
+
+// Sends the message in the scratch area as the reply to the request referenced by
+// **request_ref**. The reference is consumed.
+//
+// Returns:
+// * 0    if the reply was sent.
+// * 1    if the calling process doesn't exist anymore.
+// * 9028 if the reply is bigger than the maximum message size of the process. The reference is
+//        kept in this case, so that a smaller reply can be sent.
+//
+// Traps:
+// * If the request reference doesn't exist.
+// * If it's called without a data message being inside of the scratch area.
+fn reply<T>(mut caller: Caller<T>, request_ref: u64) -> Result<u32, Trap>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let size = caller
+        .data_mut()
+        .message_scratch_area()
+        .as_ref()
+        .map_or(0, Message::size);
+    if !caller.data().config().allows_message_size(size) {
+        return Ok(9028);
+    }
+    let reply_to = caller
+        .data_mut()
+        .reply_ref_resources_mut()
+        .remove(request_ref)
+        .or_trap("lunatic::message::reply: Request reference doesn't exist")?;
+    let mut message = match caller
+        .data_mut()
+        .message_scratch_area()
+        .take()
+        .or_trap("lunatic::message::reply")?
+    {
+        Message::Data(message) => message,
+        Message::LinkDied(_) => {
+            return Err(Trap::new("Unexpected `Message::LinkDied` in scratch area"))
+        }
+    };
+    message.tag = Some(reply_to.tag);
+
+    match caller.data().environment().get_process(reply_to.process_id) {
+        Some(process) => {
+            process.send(Signal::Message(Message::Data(message)));
+            Ok(0)
+        }
+        None => Ok(1),
+    }
+}
+
 // Takes the next message out of the queue or blocks until the next message is received if queue
 // is empty.
 //
@@ -574,6 +867,115 @@ fn receive<T: ProcessState + ProcessCtx<T> + Send>(
     })
 }
 
+// Blocks until the mailbox contains a message, without taking it out of the queue.
+//
+// Timers created with `lunatic::timer` deliver messages too, so this can be used to wait on
+// timers and incoming messages at the same time. The message can be taken out afterwards with
+// `lunatic::message::receive()`.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0    if a message is available.
+// * 9027 if call timed out.
+fn mailbox_poll<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    timeout_duration: u64,
+) -> Box<dyn Future<Output = u32> + Send + '_> {
+    Box::new(async move {
+        let ready = caller.data_mut().mailbox().ready();
+        match timeout_duration {
+            // Without timeout
+            u64::MAX => {
+                ready.await;
+                0
+            }
+            // With timeout
+            t => match timeout(Duration::from_millis(t), ready).await {
+                Ok(()) => 0,
+                Err(_) => 9027,
+            },
+        }
+    })
+}
+
+// Inspects the next message in the queue without removing it.
+//
+// The tag of the message (or 0 if no tag was set) is written to **tag_ptr** as little endian i64
+// and the size of the message buffer to **size_ptr** as little endian u64. Up to **buf_len**
+// bytes of the message buffer are copied to **buf_ptr**. The message can be afterwards taken out
+// of the queue with `lunatic::message::receive`.
+//
+// Returns:
+// * 0 if the queue is empty.
+// * 1 if it's a data message.
+// * 2 if it's a signal turned into a message.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn peek<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    buf_ptr: u32,
+    buf_len: u32,
+    tag_ptr: u32,
+    size_ptr: u32,
+) -> Result<u32, Trap> {
+    let peeked = caller.data_mut().mailbox().peek(|message| match message {
+        Message::Data(data) => {
+            let len = data.buffer.len().min(buf_len as usize);
+            (
+                1,
+                message.tag(),
+                data.buffer.len(),
+                data.buffer[..len].to_vec(),
+            )
+        }
+        Message::LinkDied(tag) => (2, *tag, 0, Vec::new()),
+    });
+    let (result, tag, size, bytes) = match peeked {
+        Some(peeked) => peeked,
+        None => return Ok(0),
+    };
+
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, buf_ptr as usize, &bytes)
+        .or_trap("lunatic::message::peek")?;
+    memory
+        .write(
+            &mut caller,
+            tag_ptr as usize,
+            &tag.unwrap_or(0).to_le_bytes(),
+        )
+        .or_trap("lunatic::message::peek")?;
+    memory
+        .write(&mut caller, size_ptr as usize, &(size as u64).to_le_bytes())
+        .or_trap("lunatic::message::peek")?;
+    Ok(result)
+}
+
+// Returns the number of messages waiting in the queue of the calling process.
+//
+// Messages are moved into the queue when the process is suspended, e.g. while waiting on
+// `lunatic::message::receive`. Messages sent while the process is running are only counted
+// after its next suspension point.
+fn mailbox_len<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> u64 {
+    caller.data_mut().mailbox().len() as u64
+}
+
+// Returns the highest number of messages that were waiting in the queue of the calling process at
+// the same time, since it was spawned or `mailbox_reset_high_water` was called. Like
+// `mailbox_len`, it only counts messages that were moved into the queue.
+fn mailbox_high_water<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> u64 {
+    caller.data_mut().mailbox().high_water() as u64
+}
+
+// Resets the high-water mark returned by `mailbox_high_water` to the current queue length.
+fn mailbox_reset_high_water<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) {
+    caller.data_mut().mailbox().reset_high_water()
+}
+
 // Adds a udp socket resource to the message that is currently in the scratch area and returns
 // the new location of it. This will remove the socket from the current process' resources.
 //
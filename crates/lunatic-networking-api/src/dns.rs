@@ -1,5 +1,5 @@
 use std::future::Future;
-use std::net::SocketAddr;
+use std::net::{SocketAddr, ToSocketAddrs};
 use std::time::Duration;
 use std::vec::IntoIter;
 
@@ -8,7 +8,7 @@ use tokio::time::timeout;
 use wasmtime::Trap;
 use wasmtime::{Caller, Linker};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{blocking, get_memory, retry, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 
 use crate::NetworkingCtx;
@@ -74,10 +74,11 @@ fn resolve<T: NetworkingCtx + ErrorCtx + Send>(
             .get(name_str_ptr as usize..(name_str_ptr + name_str_len) as usize)
             .or_trap("lunatic::network::resolve")?;
         let name = std::str::from_utf8(buffer)
-            .or_trap("lunatic::network::resolve::not_valid_utf8_string")?;
+            .or_trap("lunatic::network::resolve::not_valid_utf8_string")?
+            .to_string();
 
-        // Check for timeout during lookup
-        let lookup_host = tokio::net::lookup_host(name);
+        // The system resolver blocks, run it on the blocking pool. Check for timeout during lookup
+        let lookup_host = blocking::run(move || retry::interrupted(|| name.to_socket_addrs()));
         let (iter_or_error_id, result) = if let Ok(result) = match timeout_duration {
             // Without timeout
             u64::MAX => Ok(lookup_host.await),
@@ -1,4 +1,5 @@
 mod dns;
+mod socket;
 mod tcp;
 mod tls_tcp;
 mod udp;
@@ -31,6 +32,10 @@ pub struct TcpConnection {
     pub read_timeout: Mutex<Option<Duration>>,
     pub write_timeout: Mutex<Option<Duration>>,
     pub peek_timeout: Mutex<Option<Duration>>,
+    // The addresses don't change while the stream is connected. They are looked up once, so
+    // that reading them doesn't wait on a read or write in progress.
+    pub local_addr: Option<SocketAddr>,
+    pub peer_addr: Option<SocketAddr>,
 }
 
 /// This encapsulates the TCP-level connection, some connection
@@ -68,6 +73,8 @@ impl TlsConnection {
 
 impl TcpConnection {
     pub fn new(stream: TcpStream) -> Self {
+        let local_addr = stream.local_addr().ok();
+        let peer_addr = stream.peer_addr().ok();
         let (read_half, write_half) = stream.into_split();
         TcpConnection {
             reader: Mutex::new(read_half),
@@ -75,6 +82,8 @@ impl TcpConnection {
             read_timeout: Mutex::new(None),
             write_timeout: Mutex::new(None),
             peek_timeout: Mutex::new(None),
+            local_addr,
+            peer_addr,
         }
     }
 }
@@ -106,6 +115,7 @@ pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     linker: &mut Linker<T>,
 ) -> Result<()> {
     dns::register(linker)?;
+    socket::register(linker)?;
     tcp::register(linker)?;
     tls_tcp::register(linker)?;
     udp::register(linker)?;
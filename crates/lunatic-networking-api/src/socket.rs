@@ -0,0 +1,172 @@
+use std::net::SocketAddr;
+
+use anyhow::Result;
+use wasmtime::Trap;
+use wasmtime::{Caller, Linker};
+
+use lunatic_common_api::{get_memory, IntoTrap};
+
+use crate::NetworkingCtx;
+
+// Resource kinds, the same codes are used by `lunatic::process::list_own_resources`.
+const TCP_LISTENER: u32 = 5;
+const TCP_STREAM: u32 = 6;
+const UDP_SOCKET: u32 = 9;
+
+// Register the socket APIs shared between protocols to the linker
+pub fn register<T: NetworkingCtx + Send + 'static>(linker: &mut Linker<T>) -> Result<()> {
+    linker.func_wrap(
+        "lunatic::networking",
+        "socket_local_addr",
+        socket_local_addr,
+    )?;
+    linker.func_wrap("lunatic::networking", "socket_peer_addr", socket_peer_addr)?;
+    Ok(())
+}
+
+// Writes the local address of the socket **socket_id** to **buf_ptr**, if it fits into
+// **buf_len** bytes. If the buffer is too small nothing is written, the guest can use the
+// returned length to allocate a bigger one and retry.
+//
+// The **kind** of the socket uses the codes of `lunatic::process::list_own_resources`: 5 for a TCP
+// listener, 6 for a TCP stream and 9 for a UDP socket.
+//
+// The address is encoded as:
+// * IPv4: u8 `4`, 4 bytes of the address and the u16 port (7 bytes).
+// * IPv6: u8 `6`, 16 bytes of the address, the u16 port, the u32 flow info and the u32 scope id
+//   (27 bytes).
+// All integers are little endian.
+//
+// Returns:
+// * The length of the encoded address.
+// * u64::MAX if the address can't be determined.
+//
+// Traps:
+// * If the kind is not a socket.
+// * If the socket ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn socket_local_addr<T: NetworkingCtx>(
+    mut caller: Caller<T>,
+    kind: u32,
+    socket_id: u64,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u64, Trap> {
+    let state = caller.data();
+    let addr = match kind {
+        TCP_LISTENER => state
+            .tcp_listener_resources()
+            .get(socket_id)
+            .or_trap("lunatic::networking::socket_local_addr")?
+            .local_addr()
+            .ok(),
+        TCP_STREAM => {
+            state
+                .tcp_stream_resources()
+                .get(socket_id)
+                .or_trap("lunatic::networking::socket_local_addr")?
+                .local_addr
+        }
+        UDP_SOCKET => state
+            .udp_resources()
+            .get(socket_id)
+            .or_trap("lunatic::networking::socket_local_addr")?
+            .local_addr()
+            .ok(),
+        _ => {
+            return Err(Trap::new(
+                "lunatic::networking::socket_local_addr: not a socket",
+            ))
+        }
+    };
+    write_addr(&mut caller, addr, buf_ptr, buf_len)
+}
+
+// Writes the address of the peer connected to the socket **socket_id** to **buf_ptr**, see
+// `socket_local_addr` for the encoding and the kinds of sockets. Listeners don't have a peer,
+// for them the local address is written.
+//
+// Returns:
+// * The length of the encoded address.
+// * u64::MAX if the address can't be determined, e.g. the UDP socket is not connected.
+//
+// Traps:
+// * If the kind is not a socket.
+// * If the socket ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn socket_peer_addr<T: NetworkingCtx>(
+    mut caller: Caller<T>,
+    kind: u32,
+    socket_id: u64,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u64, Trap> {
+    let state = caller.data();
+    let addr = match kind {
+        TCP_LISTENER => state
+            .tcp_listener_resources()
+            .get(socket_id)
+            .or_trap("lunatic::networking::socket_peer_addr")?
+            .local_addr()
+            .ok(),
+        TCP_STREAM => {
+            state
+                .tcp_stream_resources()
+                .get(socket_id)
+                .or_trap("lunatic::networking::socket_peer_addr")?
+                .peer_addr
+        }
+        UDP_SOCKET => state
+            .udp_resources()
+            .get(socket_id)
+            .or_trap("lunatic::networking::socket_peer_addr")?
+            .peer_addr()
+            .ok(),
+        _ => {
+            return Err(Trap::new(
+                "lunatic::networking::socket_peer_addr: not a socket",
+            ))
+        }
+    };
+    write_addr(&mut caller, addr, buf_ptr, buf_len)
+}
+
+fn write_addr<T: NetworkingCtx>(
+    caller: &mut Caller<T>,
+    addr: Option<SocketAddr>,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u64, Trap> {
+    let addr = match addr {
+        Some(addr) => encode_addr(addr),
+        None => return Ok(u64::MAX),
+    };
+    if addr.len() <= buf_len as usize {
+        let memory = get_memory(caller)?;
+        memory
+            .data_mut(caller)
+            .get_mut(buf_ptr as usize..buf_ptr as usize + addr.len())
+            .or_trap("lunatic::networking::write_addr")?
+            .copy_from_slice(&addr);
+    }
+    Ok(addr.len() as u64)
+}
+
+fn encode_addr(addr: SocketAddr) -> Vec<u8> {
+    match addr {
+        SocketAddr::V4(v4) => {
+            let mut encoded = vec![4];
+            encoded.extend(v4.ip().octets());
+            encoded.extend(v4.port().to_le_bytes());
+            encoded
+        }
+        SocketAddr::V6(v6) => {
+            let mut encoded = vec![6];
+            encoded.extend(v6.ip().octets());
+            encoded.extend(v6.port().to_le_bytes());
+            encoded.extend(v6.flowinfo().to_le_bytes());
+            encoded.extend(v6.scope_id().to_le_bytes());
+            encoded
+        }
+    }
+}
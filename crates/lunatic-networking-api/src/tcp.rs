@@ -1,10 +1,11 @@
 use std::convert::TryInto;
 use std::future::Future;
-use std::io::IoSlice;
+use std::io::{self, ErrorKind, IoSlice};
 use std::sync::Arc;
 use std::time::Duration;
 
 use anyhow::Result;
+use socket2::SockRef;
 use tokio::time::timeout;
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
@@ -13,7 +14,7 @@ use tokio::{
 use wasmtime::Trap;
 use wasmtime::{Caller, Linker};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, retry_interrupted, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 
 use crate::dns::DnsIterator;
@@ -56,9 +57,26 @@ pub fn register<T: NetworkingCtx + ErrorCtx + Send + 'static>(
     )?;
     linker.func_wrap1_async("lunatic::networking", "get_peek_timeout", get_peek_timeout)?;
     linker.func_wrap2_async("lunatic::networking", "tcp_flush", tcp_flush)?;
+    linker.func_wrap4_async(
+        "lunatic::networking",
+        "socket_set_option",
+        socket_set_option,
+    )?;
+    linker.func_wrap3_async(
+        "lunatic::networking",
+        "socket_get_option",
+        socket_get_option,
+    )?;
     Ok(())
 }
 
+// Options of TCP sockets, see `socket_set_option`.
+const TCP_NODELAY: u32 = 0;
+const SO_KEEPALIVE: u32 = 1;
+const READ_TIMEOUT: u32 = 2;
+const WRITE_TIMEOUT: u32 = 3;
+const SO_REUSEADDR: u32 = 4;
+
 // Creates a new TCP listener, which will be bound to the specified address. The returned listener
 // is ready for accepting connections.
 //
@@ -383,9 +401,14 @@ fn tcp_write_vectored<T: NetworkingCtx + ErrorCtx + Send>(
 
         if let Ok(write_result) = match *write_timeout {
             Some(write_timeout) => {
-                timeout(write_timeout, stream.write_vectored(vec_slices.as_slice())).await
+                let write = async {
+                    retry_interrupted!(stream.write_vectored(vec_slices.as_slice()).await)
+                };
+                timeout(write_timeout, write).await
             }
-            None => Ok(stream.write_vectored(vec_slices.as_slice()).await),
+            None => Ok(retry_interrupted!(
+                stream.write_vectored(vec_slices.as_slice()).await
+            )),
         } {
             let (opaque, return_) = match write_result {
                 Ok(bytes) => (bytes as u64, 0),
@@ -601,8 +624,11 @@ fn tcp_read<T: NetworkingCtx + ErrorCtx + Send>(
             .or_trap("lunatic::networking::tcp_read")?;
 
         if let Ok(read_result) = match *read_timeout {
-            Some(read_timeout) => timeout(read_timeout, stream.read(buffer)).await,
-            None => Ok(stream.read(buffer).await),
+            Some(read_timeout) => {
+                let read = async { retry_interrupted!(stream.read(buffer).await) };
+                timeout(read_timeout, read).await
+            }
+            None => Ok(retry_interrupted!(stream.read(buffer).await)),
         } {
             let (opaque, return_) = match read_result {
                 Ok(bytes) => (bytes as u64, 0),
@@ -657,8 +683,11 @@ fn tcp_peek<T: NetworkingCtx + ErrorCtx + Send>(
             .or_trap("lunatic::networking::tcp_peek")?;
 
         if let Ok(read_result) = match *peek_timeout {
-            Some(peek_timeout) => timeout(peek_timeout, stream.peek(buffer)).await,
-            None => Ok(stream.read(buffer).await),
+            Some(peek_timeout) => {
+                let peek = async { retry_interrupted!(stream.peek(buffer).await) };
+                timeout(peek_timeout, peek).await
+            }
+            None => Ok(retry_interrupted!(stream.read(buffer).await)),
         } {
             let (opaque, return_) = match read_result {
                 Ok(bytes) => (bytes as u64, 0),
@@ -714,3 +743,156 @@ fn tcp_flush<T: NetworkingCtx + ErrorCtx + Send>(
         Ok(result)
     })
 }
+
+// Sets the **option** of the TCP socket **socket_id** to **value**. Options taking a boolean are
+// turned off with 0 and on with any other value.
+//
+// Options:
+// * 0 `TCP_NODELAY` of a stream
+// * 1 `SO_KEEPALIVE` of a stream
+// * 2 Read timeout of a stream in milliseconds, u64::MAX disables it (see `set_read_timeout`)
+// * 3 Write timeout of a stream in milliseconds, u64::MAX disables it (see `set_write_timeout`)
+// * 4 `SO_REUSEADDR` of a listener
+//
+// Returns:
+// * 0 on success
+// * 1 on error   - The error ID is written to **error_id_ptr**, also if the option is unsupported
+//
+// Traps:
+// * If the stream or listener ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn socket_set_option<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    socket_id: u64,
+    option: u32,
+    value: u64,
+    error_id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let result = match option {
+            TCP_NODELAY | SO_KEEPALIVE | READ_TIMEOUT | WRITE_TIMEOUT => {
+                let stream = caller
+                    .data()
+                    .tcp_stream_resources()
+                    .get(socket_id)
+                    .or_trap("lunatic::networking::socket_set_option")?
+                    .clone();
+                match option {
+                    TCP_NODELAY => stream.writer.lock().await.as_ref().set_nodelay(value != 0),
+                    SO_KEEPALIVE => {
+                        let writer = stream.writer.lock().await;
+                        SockRef::from(writer.as_ref()).set_keepalive(value != 0)
+                    }
+                    READ_TIMEOUT => {
+                        *stream.read_timeout.lock().await = timeout_from_millis(value);
+                        Ok(())
+                    }
+                    _ => {
+                        *stream.write_timeout.lock().await = timeout_from_millis(value);
+                        Ok(())
+                    }
+                }
+            }
+            SO_REUSEADDR => {
+                let listener = caller
+                    .data()
+                    .tcp_listener_resources()
+                    .get(socket_id)
+                    .or_trap("lunatic::networking::socket_set_option")?;
+                SockRef::from(listener).set_reuse_address(value != 0)
+            }
+            option => Err(unsupported_option(option)),
+        };
+
+        let (error_id, result) = match result {
+            Ok(()) => (0, 0),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, error_id_ptr as usize, &error_id.to_le_bytes())
+            .or_trap("lunatic::networking::socket_set_option")?;
+        Ok(result)
+    })
+}
+
+// Reads the **option** of the TCP socket **socket_id**, see `socket_set_option` for the options.
+// Options taking a boolean are read as 0 or 1.
+//
+// Returns:
+// * 0 on success - The u64 value of the option is written to **opaque_ptr**
+// * 1 on error   - The error ID is written to **opaque_ptr**, also if the option is unsupported
+//
+// Traps:
+// * If the stream or listener ID doesn't exist.
+// * If any memory outside the guest heap space is referenced.
+fn socket_get_option<T: NetworkingCtx + ErrorCtx + Send>(
+    mut caller: Caller<T>,
+    socket_id: u64,
+    option: u32,
+    opaque_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let value = match option {
+            TCP_NODELAY | SO_KEEPALIVE | READ_TIMEOUT | WRITE_TIMEOUT => {
+                let stream = caller
+                    .data()
+                    .tcp_stream_resources()
+                    .get(socket_id)
+                    .or_trap("lunatic::networking::socket_get_option")?
+                    .clone();
+                match option {
+                    TCP_NODELAY => stream.writer.lock().await.as_ref().nodelay().map(u64::from),
+                    SO_KEEPALIVE => {
+                        let writer = stream.writer.lock().await;
+                        SockRef::from(writer.as_ref()).keepalive().map(u64::from)
+                    }
+                    READ_TIMEOUT => Ok(timeout_to_millis(*stream.read_timeout.lock().await)),
+                    _ => Ok(timeout_to_millis(*stream.write_timeout.lock().await)),
+                }
+            }
+            SO_REUSEADDR => {
+                let listener = caller
+                    .data()
+                    .tcp_listener_resources()
+                    .get(socket_id)
+                    .or_trap("lunatic::networking::socket_get_option")?;
+                SockRef::from(listener).reuse_address().map(u64::from)
+            }
+            option => Err(unsupported_option(option)),
+        };
+
+        let (value_or_error_id, result) = match value {
+            Ok(value) => (value, 0),
+            Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
+        };
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(
+                &mut caller,
+                opaque_ptr as usize,
+                &value_or_error_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::networking::socket_get_option")?;
+        Ok(result)
+    })
+}
+
+fn unsupported_option(option: u32) -> io::Error {
+    io::Error::new(
+        ErrorKind::InvalidInput,
+        format!("Unsupported TCP socket option {option}"),
+    )
+}
+
+// Timeouts are passed to guests in milliseconds, u64::MAX stands for no timeout.
+fn timeout_from_millis(millis: u64) -> Option<Duration> {
+    match millis {
+        u64::MAX => None,
+        millis => Some(Duration::from_millis(millis)),
+    }
+}
+
+fn timeout_to_millis(timeout: Option<Duration>) -> u64 {
+    timeout.map_or(u64::MAX, |t| t.as_millis() as u64)
+}
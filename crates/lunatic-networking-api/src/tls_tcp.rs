@@ -13,7 +13,7 @@ use tokio::{
 use wasmtime::Trap;
 use wasmtime::{Caller, Linker};
 
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, retry_interrupted, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 
 use crate::dns::DnsIterator;
@@ -545,9 +545,14 @@ fn tls_write_vectored<T: NetworkingCtx + ErrorCtx + Send>(
 
         if let Ok(write_result) = match *write_timeout {
             Some(write_timeout) => {
-                timeout(write_timeout, stream.write_vectored(vec_slices.as_slice())).await
+                let write = async {
+                    retry_interrupted!(stream.write_vectored(vec_slices.as_slice()).await)
+                };
+                timeout(write_timeout, write).await
             }
-            None => Ok(stream.write_vectored(vec_slices.as_slice()).await),
+            None => Ok(retry_interrupted!(
+                stream.write_vectored(vec_slices.as_slice()).await
+            )),
         } {
             let (opaque, return_) = match write_result {
                 Ok(bytes) => (bytes as u64, 0),
@@ -709,8 +714,11 @@ fn tls_read<T: NetworkingCtx + ErrorCtx + Send>(
             .or_trap("lunatic::networking::tls_read")?;
 
         if let Ok(read_result) = match *read_timeout {
-            Some(read_timeout) => timeout(read_timeout, stream.read(buffer)).await,
-            None => Ok(stream.read(buffer).await),
+            Some(read_timeout) => {
+                let read = async { retry_interrupted!(stream.read(buffer).await) };
+                timeout(read_timeout, read).await
+            }
+            None => Ok(retry_interrupted!(stream.read(buffer).await)),
         } {
             let (opaque, return_) = match read_result {
                 Ok(bytes) => (bytes as u64, 0),
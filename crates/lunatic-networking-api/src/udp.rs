@@ -10,7 +10,7 @@ use wasmtime::{Caller, Linker};
 
 use crate::dns::DnsIterator;
 use crate::{socket_address, NetworkingCtx};
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, retry_interrupted, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 
 // Register UDP networking APIs to the linker
@@ -150,7 +150,7 @@ fn udp_receive<T: NetworkingCtx + ErrorCtx + Send>(
             .get(socket_id)
             .or_trap("lunatic::network::udp_receive")?;
 
-        let (opaque, return_) = match socket.recv(buffer).await {
+        let (opaque, return_) = match retry_interrupted!(socket.recv(buffer).await) {
             Ok(bytes) => (bytes as u64, 0),
             Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
         };
@@ -195,14 +195,15 @@ fn udp_receive_from<T: NetworkingCtx + ErrorCtx + Send>(
             .get(socket_id)
             .or_trap("lunatic::network::udp_receive_from")?;
 
-        let (opaque, socket_result, return_) = match socket.recv_from(buffer).await {
-            Ok((bytes, socket)) => (bytes as u64, Some(socket), 0),
-            Err(error) => (
-                caller.data_mut().error_resources_mut().add(error.into()),
-                None,
-                1,
-            ),
-        };
+        let (opaque, socket_result, return_) =
+            match retry_interrupted!(socket.recv_from(buffer).await) {
+                Ok((bytes, socket)) => (bytes as u64, Some(socket), 0),
+                Err(error) => (
+                    caller.data_mut().error_resources_mut().add(error.into()),
+                    None,
+                    1,
+                ),
+            };
 
         let memory = get_memory(&mut caller)?;
         memory
@@ -438,7 +439,8 @@ fn udp_send_to<T: NetworkingCtx + ErrorCtx + Send>(
             .or_trap("lunatic::network::udp_send_to")?
             .clone();
 
-        let (opaque, return_) = match stream.send_to(buffer, socket_addr).await {
+        let (opaque, return_) = match retry_interrupted!(stream.send_to(buffer, socket_addr).await)
+        {
             Ok(bytes) => (bytes as u64, 0),
             Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
         };
@@ -485,7 +487,7 @@ fn udp_send<T: NetworkingCtx + ErrorCtx + Send>(
             .or_trap("lunatic::network::udp_send")?
             .clone();
 
-        let (opaque, return_) = match stream.send(buffer).await {
+        let (opaque, return_) = match retry_interrupted!(stream.send(buffer).await) {
             Ok(bytes) => (bytes as u64, 0),
             Err(error) => (caller.data_mut().error_resources_mut().add(error.into()), 1),
         };
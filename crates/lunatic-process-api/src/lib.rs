@@ -1,4 +1,5 @@
 use std::{
+    collections::HashMap,
     convert::{TryFrom, TryInto},
     future::Future,
     sync::Arc,
@@ -7,22 +8,199 @@ use std::{
 
 use anyhow::{anyhow, Result};
 use hash_map_id::HashMapId;
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, guest_slice, IntoTrap};
 use lunatic_error_api::ErrorCtx;
 use lunatic_process::{
-    config::ProcessConfig,
-    env::Environment,
+    config::{
+        ProcessConfig, MAX_QUANTUM_IN_INSTRUCTIONS, MIN_QUANTUM_IN_INSTRUCTIONS,
+        UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+    },
+    env::{Environment, SharedRegion},
     mailbox::MessageMailbox,
-    message::Message,
+    message::{down_message, Message, ReplyTo},
     runtimes::{wasmtime::WasmtimeCompiledModule, RawWasm},
+    snapshot::ProcessSnapshot,
     state::ProcessState,
-    DeathReason, Process, Signal, WasmProcess,
+    DeathReason, ExitReason, Process, Signal, WasmProcess,
 };
-use lunatic_wasi_api::LunaticWasiCtx;
+use lunatic_wasi_api::{LunaticWasiConfigCtx, LunaticWasiCtx};
 use wasmtime::{Caller, Linker, ResourceLimiter, Trap, Val};
 
 pub type ProcessResources = HashMapId<Arc<dyn Process>>;
 pub type ModuleResources<S> = HashMapId<Arc<WasmtimeCompiledModule<S>>>;
+pub type ReplyRefResources = HashMapId<ReplyTo>;
+pub type ModuleUploadResources = HashMapId<ModuleUpload>;
+pub type SharedRegionResources = HashMapId<SharedRegion>;
+/// Key/value pairs that a process stores on the host for the duration of its lifetime.
+pub type KvStore = HashMap<Vec<u8>, Vec<u8>>;
+
+/// Uploads that didn't receive a chunk for this long are dropped.
+pub const MODULE_UPLOAD_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Bytes of a module that is uploaded in chunks, before it's compiled.
+#[derive(Debug)]
+pub struct ModuleUpload {
+    bytes: Vec<u8>,
+    last_chunk: Instant,
+}
+
+impl ModuleUpload {
+    pub fn new() -> Self {
+        Self {
+            bytes: Vec::new(),
+            last_chunk: Instant::now(),
+        }
+    }
+
+    pub fn push_chunk(&mut self, chunk: &[u8]) {
+        self.bytes.extend_from_slice(chunk);
+        self.last_chunk = Instant::now();
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.last_chunk.elapsed() >= MODULE_UPLOAD_TIMEOUT
+    }
+
+    pub fn len(&self) -> usize {
+        self.bytes.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.bytes
+    }
+}
+
+impl Default for ModuleUpload {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Allows bursts of up to `rate` messages and refills at `rate` messages per second.
+#[derive(Debug)]
+pub struct TokenBucket {
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    /// Creates a full bucket.
+    pub fn new(rate: u32) -> Self {
+        Self {
+            tokens: rate.max(1) as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Takes a token if one is available, otherwise returns how long it takes until the next one
+    /// is.
+    pub fn try_take(&mut self, rate: u32) -> Result<(), Duration> {
+        let rate = rate.max(1) as f64;
+        let now = Instant::now();
+        let refilled = now.duration_since(self.last_refill).as_secs_f64() * rate;
+        self.tokens = (self.tokens + refilled).min(rate);
+        self.last_refill = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+
+    /// Returns true if the bucket refilled completely by **now**.
+    pub fn is_full(&self, rate: u32, now: Instant) -> bool {
+        let rate = rate.max(1) as f64;
+        let refilled = now.duration_since(self.last_refill).as_secs_f64() * rate;
+        self.tokens + refilled >= rate
+    }
+}
+
+/// The limiter only looks for buckets to evict after it grew to this many buckets.
+const MIN_BUCKETS_BEFORE_EVICTION: usize = 64;
+
+/// Token buckets limiting how fast a process sends messages, keyed by the ID of the receiving
+/// process. Each process holds its own limiter, so the buckets are tracked per sender and target.
+///
+/// Buckets that refilled completely are dropped, because a new bucket is full too. To keep sends
+/// cheap, they are only looked for after the number of buckets doubled.
+#[derive(Debug, Default)]
+pub struct SendRateLimiter {
+    buckets: HashMap<u64, TokenBucket>,
+    next_eviction: usize,
+}
+
+impl SendRateLimiter {
+    /// Takes a token from the bucket of the receiving process, see [`TokenBucket::try_take`].
+    pub fn try_take(&mut self, process_id: u64, rate: u32) -> Result<(), Duration> {
+        if self.buckets.len() >= self.next_eviction {
+            let now = Instant::now();
+            self.buckets.retain(|_, bucket| !bucket.is_full(rate, now));
+            self.next_eviction = (2 * self.buckets.len()).max(MIN_BUCKETS_BEFORE_EVICTION);
+        }
+        self.buckets
+            .entry(process_id)
+            .or_insert_with(|| TokenBucket::new(rate))
+            .try_take(rate)
+    }
+
+    /// Number of receivers that the process is currently limited for.
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
+
+/// Kind of a resource held by a process, see [`ProcessCtx::resources`].
+///
+/// The discriminant is the code reported by `lunatic::process::list_own_resources`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ResourceKind {
+    Config = 1,
+    Module = 2,
+    Timer = 3,
+    DnsIterator = 4,
+    TcpListener = 5,
+    TcpStream = 6,
+    TlsListener = 7,
+    TlsStream = 8,
+    UdpSocket = 9,
+    Error = 10,
+    ReplyRef = 11,
+    ModuleUpload = 12,
+    SharedRegion = 13,
+}
+
+impl ResourceKind {
+    /// Returns the kind with the code used by `lunatic::process::list_own_resources`.
+    pub fn from_code(code: u32) -> Option<Self> {
+        use ResourceKind::*;
+        [
+            Config,
+            Module,
+            Timer,
+            DnsIterator,
+            TcpListener,
+            TcpStream,
+            TlsListener,
+            TlsStream,
+            UdpSocket,
+            Error,
+            ReplyRef,
+            ModuleUpload,
+            SharedRegion,
+        ]
+        .into_iter()
+        .find(|kind| *kind as u32 == code)
+    }
+}
 
 pub trait ProcessConfigCtx {
     fn can_compile_modules(&self) -> bool;
@@ -31,14 +209,79 @@ pub trait ProcessConfigCtx {
     fn set_can_create_configs(&mut self, can: bool);
     fn can_spawn_processes(&self) -> bool;
     fn set_can_spawn_processes(&mut self, can: bool);
+    /// Detached processes keep running after the process that spawned them finished.
+    fn detached(&self) -> bool;
+    fn set_detached(&mut self, detached: bool);
+    /// Maximum combined size of all keys and values in the process' [`KvStore`] in bytes.
+    fn max_kv_store_size(&self) -> usize;
+    fn set_max_kv_store_size(&mut self, size: usize);
+    /// Maximum size of the buffer of messages sent by the process in bytes, `None` if unlimited.
+    fn max_message_size(&self) -> Option<usize>;
+    fn set_max_message_size(&mut self, size: Option<usize>);
+    /// Maximum number of messages per second the process can send to each other process, `None`
+    /// if unlimited. Short bursts of up to one second worth of messages are allowed.
+    fn max_send_rate(&self) -> Option<u32>;
+    fn set_max_send_rate(&mut self, rate: Option<u32>);
+    /// If true, sends above the rate limit wait until they are allowed, otherwise they fail.
+    fn block_when_rate_limited(&self) -> bool;
+    fn set_block_when_rate_limited(&mut self, block: bool);
+    /// Memory in bytes that `lunatic::process::available_memory` reports at most, `None` to
+    /// report all available host memory.
+    fn memory_budget(&self) -> Option<u64>;
+    fn set_memory_budget(&mut self, budget: Option<u64>);
+
+    /// Returns true if a message of **size** bytes can be sent by the process.
+    fn allows_message_size(&self, size: usize) -> bool {
+        self.max_message_size().is_none_or(|max| size <= max)
+    }
 }
 
 pub trait ProcessCtx<S: ProcessState> {
     fn mailbox(&mut self) -> &mut MessageMailbox;
     fn message_scratch_area(&mut self) -> &mut Option<Message>;
+    fn kv_store(&mut self) -> &mut KvStore;
+    fn send_rate_limiter(&mut self) -> &mut SendRateLimiter;
+    /// Seed of the ids returned by `lunatic::process::unique_id`.
+    fn unique_id_seed(&mut self) -> &mut u64;
+    /// Number of instructions the process runs before yielding, see `lunatic::process::set_quantum`.
+    fn quantum(&self) -> u64;
+    fn quantum_mut(&mut self) -> &mut u64;
+    /// If true, linked processes dying are turned into messages instead of killing the process.
+    ///
+    /// Tracks the `DieWhenLinkDies` signals that the process sends to itself, the flag used by the
+    /// process loop can't be read from host functions.
+    fn trap_exit(&mut self) -> &mut bool;
+    fn reply_ref_resources_mut(&mut self) -> &mut ReplyRefResources;
     fn module_resources(&self) -> &ModuleResources<S>;
     fn module_resources_mut(&mut self) -> &mut ModuleResources<S>;
+    fn module_upload_resources_mut(&mut self) -> &mut ModuleUploadResources;
+    fn shared_region_resources(&self) -> &SharedRegionResources;
+    fn shared_region_resources_mut(&mut self) -> &mut SharedRegionResources;
     fn environment(&self) -> Arc<dyn Environment>;
+    /// Kinds and IDs of all resources the process currently holds.
+    fn resources(&self) -> Vec<(ResourceKind, u64)>;
+    /// Adds a second handle to the resource **id** of **kind** and returns its ID.
+    ///
+    /// Both handles refer to the same underlying resource. Fails if the resource doesn't exist or
+    /// its kind can't be shared.
+    fn clone_resource(&mut self, kind: ResourceKind, id: u64) -> Result<u64>;
+
+    /// Shares the compiled module **module_id** of the process **from** with this process.
+    ///
+    /// The processes can be part of different environments. Compiled modules are reference
+    /// counted, so the module is not recompiled. Returns the module ID inside of this process or
+    /// `None` if **from** doesn't have a module with this ID.
+    fn import_module(&mut self, from: &S, module_id: u64) -> Option<u64>
+    where
+        S: ProcessCtx<S>,
+    {
+        let module = from.module_resources().get(module_id)?.clone();
+
+        #[cfg(feature = "metrics")]
+        metrics::increment_gauge!("lunatic.process.modules.active", 1.0);
+
+        Some(self.module_resources_mut().add(module))
+    }
 }
 
 // Register the process APIs to the linker
@@ -46,7 +289,7 @@ pub fn register<T>(linker: &mut Linker<T>) -> Result<()>
 where
     T: ProcessState + ProcessCtx<T> + ErrorCtx + LunaticWasiCtx + Send + ResourceLimiter + 'static,
     for<'a> &'a T: Send,
-    T::Config: ProcessConfigCtx,
+    T::Config: ProcessConfigCtx + LunaticWasiConfigCtx,
 {
     #[cfg(feature = "metrics")]
     lunatic_process::describe_metrics();
@@ -81,6 +324,21 @@ where
 
     linker.func_wrap("lunatic::process", "compile_module", compile_module)?;
     linker.func_wrap("lunatic::process", "drop_module", drop_module)?;
+    linker.func_wrap(
+        "lunatic::process",
+        "module_upload_begin",
+        module_upload_begin,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "module_upload_chunk",
+        module_upload_chunk,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "module_upload_finish",
+        module_upload_finish,
+    )?;
 
     #[cfg(feature = "metrics")]
     metrics::describe_counter!(
@@ -155,17 +413,84 @@ where
         "config_set_can_spawn_processes",
         config_set_can_spawn_processes,
     )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_worker_affinity",
+        config_set_worker_affinity,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_get_worker_affinity",
+        config_get_worker_affinity,
+    )?;
+    linker.func_wrap("lunatic::process", "config_trap_exit", config_trap_exit)?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_trap_exit",
+        config_set_trap_exit,
+    )?;
+    linker.func_wrap("lunatic::process", "config_detached", config_detached)?;
+    linker.func_wrap(
+        "lunatic::process",
+        "config_set_detached",
+        config_set_detached,
+    )?;
 
     linker.func_wrap8_async("lunatic::process", "spawn", spawn)?;
+    linker.func_wrap8_async("lunatic::process", "spawn_suspended", spawn_suspended)?;
+    linker.func_wrap8_async("lunatic::process", "spawn_with_message", spawn_with_message)?;
+    linker.func_wrap("lunatic::process", "start", start)?;
 
     linker.func_wrap1_async("lunatic::process", "sleep_ms", sleep_ms)?;
     linker.func_wrap("lunatic::process", "die_when_link_dies", die_when_link_dies)?;
+    linker.func_wrap("lunatic::process", "set_trap_exit", set_trap_exit)?;
+    linker.func_wrap("lunatic::process", "get_trap_exit", get_trap_exit)?;
+    linker.func_wrap("lunatic::process", "detach", detach)?;
 
     linker.func_wrap("lunatic::process", "process_id", process_id)?;
     linker.func_wrap("lunatic::process", "environment_id", environment_id)?;
+    linker.func_wrap("lunatic::process", "env_config_blob", env_config_blob)?;
+    linker.func_wrap("lunatic::process", "unique_id", unique_id)?;
+    linker.func_wrap("lunatic::process", "worker_id", worker_id)?;
+    linker.func_wrap("lunatic::process", "current_worker", current_worker)?;
+    linker.func_wrap("lunatic::process", "available_memory", available_memory)?;
+    linker.func_wrap("lunatic::process", "list_own_resources", list_own_resources)?;
+    linker.func_wrap("lunatic::process", "clone_resource", clone_resource)?;
+    linker.func_wrap("lunatic::process", "set_process_label", set_process_label)?;
+    linker.func_wrap("lunatic::process", "snapshot_state", snapshot_state)?;
+    linker.func_wrap("lunatic::process", "restore_state", restore_state)?;
+    linker.func_wrap("lunatic::process", "abort", abort)?;
+    linker.func_wrap("lunatic::process", "remaining_fuel", remaining_fuel)?;
+    linker.func_wrap("lunatic::process", "set_quantum", set_quantum)?;
+    linker.func_wrap("lunatic::process", "get_quantum", get_quantum)?;
+    linker.func_wrap("lunatic::process", "cpu_time_ns", cpu_time_ns)?;
+    linker.func_wrap("lunatic::process", "kv_set", kv_set)?;
+    linker.func_wrap("lunatic::process", "kv_get", kv_get)?;
+    linker.func_wrap("lunatic::process", "kv_cas", kv_cas)?;
+    linker.func_wrap(
+        "lunatic::process",
+        "shared_region_create",
+        shared_region_create,
+    )?;
+    linker.func_wrap(
+        "lunatic::process",
+        "shared_region_attach",
+        shared_region_attach,
+    )?;
+    linker.func_wrap("lunatic::process", "shared_region_size", shared_region_size)?;
+    linker.func_wrap("lunatic::process", "shared_region_read", shared_region_read)?;
+    linker.func_wrap(
+        "lunatic::process",
+        "shared_region_write",
+        shared_region_write,
+    )?;
+    linker.func_wrap("lunatic::process", "drop_shared_region", drop_shared_region)?;
     linker.func_wrap("lunatic::process", "link", link)?;
     linker.func_wrap("lunatic::process", "unlink", unlink)?;
+    linker.func_wrap1_async("lunatic::process", "join_all_links", join_all_links)?;
+    linker.func_wrap("lunatic::process", "monitor", monitor)?;
     linker.func_wrap("lunatic::process", "kill", kill)?;
+    linker.func_wrap("lunatic::process", "request_shutdown", request_shutdown)?;
     linker.func_wrap("lunatic::process", "exists", exists)?;
     Ok(())
 }
@@ -189,11 +514,30 @@ where
     T: ProcessState + ProcessCtx<T> + ErrorCtx,
     T::Config: ProcessConfigCtx,
 {
-    // TODO: Module compilation is CPU intensive and should be done on the blocking task thread pool.
     if !caller.data().config().can_compile_modules() {
         return Ok(-1);
     }
 
+    let mut module = vec![0; module_data_len as usize];
+    let memory = get_memory(&mut caller)?;
+    memory
+        .read(&caller, module_data_ptr as usize, module.as_mut_slice())
+        .or_trap("lunatic::process::compile_module")?;
+
+    let (mod_or_error_id, result) = add_compiled_module(&mut caller, module);
+    memory
+        .write(&mut caller, id_ptr as usize, &mod_or_error_id.to_le_bytes())
+        .or_trap("lunatic::process::compile_module")?;
+    Ok(result)
+}
+
+// Compiles the module **bytes** and adds it to the module resources. Returns the module ID and 0
+// on success, or the error ID and 1.
+fn add_compiled_module<T>(caller: &mut Caller<T>, bytes: Vec<u8>) -> (u64, i32)
+where
+    T: ProcessState + ProcessCtx<T> + ErrorCtx,
+{
+    // TODO: Module compilation is CPU intensive and should be done on the blocking task thread pool.
     #[cfg(feature = "metrics")]
     metrics::increment_counter!("lunatic.process.modules.compiled");
 
@@ -202,14 +546,8 @@ where
 
     let start = Instant::now();
 
-    let mut module = vec![0; module_data_len as usize];
-    let memory = get_memory(&mut caller)?;
-    memory
-        .read(&caller, module_data_ptr as usize, module.as_mut_slice())
-        .or_trap("lunatic::process::compile_module")?;
-
-    let module = RawWasm::new(None, module);
-    let (mod_or_error_id, result) = match caller.data().runtime().compile_module(module) {
+    let module = RawWasm::new(None, bytes);
+    let result = match caller.data().runtime().compile_module(module) {
         Ok(module) => (
             caller
                 .data_mut()
@@ -225,9 +563,94 @@ where
     #[cfg(feature = "metrics")]
     metrics::histogram!("lunatic.process.modules.compiled.duration", duration);
 
+    result
+}
+
+// Starts uploading a module in chunks, so that large modules don't need to be kept in guest
+// memory all at once. Chunks are added with `module_upload_chunk` and the module is compiled by
+// `module_upload_finish`. Uploads that don't receive a chunk for 60 seconds are dropped.
+//
+// Returns:
+// * ID of the upload in case of success
+// * -1 in case the process doesn't have permission to compile modules.
+fn module_upload_begin<T>(mut caller: Caller<T>) -> i64
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    if !caller.data().config().can_compile_modules() {
+        return -1;
+    }
+    let uploads = caller.data_mut().module_upload_resources_mut();
+    uploads.retain(|_, upload| !upload.is_expired());
+    uploads.add(ModuleUpload::new()) as i64
+}
+
+// Appends **chunk_len** bytes found at **chunk_ptr** to the upload.
+//
+// All pending uploads of a process together can't grow past the process' maximum memory. If the
+// chunk would exceed it, the upload is dropped.
+//
+// Returns:
+// * 0 on success
+// * 1 if the upload would exceed the maximum size and was dropped.
+//
+// Traps:
+// * If the upload ID doesn't exist or the upload expired.
+// * If any memory outside the guest heap space is referenced.
+fn module_upload_chunk<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    upload_id: u64,
+    chunk_ptr: u32,
+    chunk_len: u32,
+) -> Result<i32, Trap> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let chunk = memory_slice
+        .get(chunk_ptr as usize..(chunk_ptr as usize + chunk_len as usize))
+        .or_trap("lunatic::process::module_upload_chunk")?;
+    let max_size = state.config().get_max_memory();
+    let uploads = state.module_upload_resources_mut();
+    uploads.retain(|_, upload| !upload.is_expired());
+    let pending: usize = uploads.iter().map(|(_, upload)| upload.len()).sum();
+    if pending.saturating_add(chunk.len()) > max_size {
+        uploads
+            .remove(upload_id)
+            .or_trap("lunatic::process::module_upload_chunk: Upload ID doesn't exist")?;
+        return Ok(1);
+    }
+    uploads
+        .get_mut(upload_id)
+        .or_trap("lunatic::process::module_upload_chunk: Upload ID doesn't exist")?
+        .push_chunk(chunk);
+    Ok(0)
+}
+
+// Compiles the uploaded module and removes the upload.
+//
+// Returns:
+// * 0 on success - The ID of the newly created module is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**
+//
+// Traps:
+// * If the upload ID doesn't exist or the upload expired.
+// * If any memory outside the guest heap space is referenced.
+fn module_upload_finish<T>(mut caller: Caller<T>, upload_id: u64, id_ptr: u32) -> Result<i32, Trap>
+where
+    T: ProcessState + ProcessCtx<T> + ErrorCtx,
+{
+    let upload = caller
+        .data_mut()
+        .module_upload_resources_mut()
+        .remove(upload_id)
+        .filter(|upload| !upload.is_expired())
+        .or_trap("lunatic::process::module_upload_finish: Upload ID doesn't exist")?;
+
+    let (mod_or_error_id, result) = add_compiled_module(&mut caller, upload.into_bytes());
+    let memory = get_memory(&mut caller)?;
     memory
         .write(&mut caller, id_ptr as usize, &mod_or_error_id.to_le_bytes())
-        .or_trap("lunatic::process::compile_module")?;
+        .or_trap("lunatic::process::module_upload_finish")?;
     Ok(result)
 }
 
@@ -381,6 +804,81 @@ fn config_get_max_fuel<T: ProcessState + ProcessCtx<T>>(
     }
 }
 
+// Pins processes spawned from this configuration to the dedicated scheduler thread **worker**, so
+// that they keep running on the same core. This is only a hint, if the worker doesn't exist the
+// processes run on the shared scheduler. A negative value removes the affinity.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_worker_affinity<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    worker: i64,
+) -> Result<(), Trap> {
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_worker_affinity: Config ID doesn't exist")?
+        .set_worker_affinity(usize::try_from(worker).ok());
+    Ok(())
+}
+
+// Returns the worker that processes spawned from this configuration are pinned to, or -1 if they
+// run on the shared scheduler.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_get_worker_affinity<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<i64, Trap> {
+    let worker = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_get_worker_affinity: Config ID doesn't exist")?
+        .get_worker_affinity();
+    Ok(worker.map_or(-1, |worker| worker as i64))
+}
+
+// Returns 1 if processes spawned from this configuration trap exits, otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_trap_exit<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    config_id: u64,
+) -> Result<u32, Trap> {
+    let trap_exit = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_trap_exit: Config ID doesn't exist")?
+        .get_trap_exit();
+    Ok(trap_exit as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration receive a `LinkDied`
+// message when a linked process fails, instead of failing together with it. This has the same
+// effect as calling `die_when_link_dies(0)` as first thing in the new process.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_trap_exit<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    trap_exit: u32,
+) -> Result<(), Trap> {
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_trap_exit: Config ID doesn't exist")?
+        .set_trap_exit(trap_exit != 0);
+    Ok(())
+}
+
 // Returns 1 if processes spawned from this configuration can compile Wasm modules, otherwise 0.
 //
 // Traps:
@@ -505,6 +1003,44 @@ where
     Ok(())
 }
 
+// Returns 1 if processes spawned from this configuration keep running after the parent finished,
+// otherwise 0.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_detached<T>(caller: Caller<T>, config_id: u64) -> Result<u32, Trap>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let detached = caller
+        .data()
+        .config_resources()
+        .get(config_id)
+        .or_trap("lunatic::process::config_detached: Config ID doesn't exist")?
+        .detached();
+    Ok(detached as u32)
+}
+
+// If set to a value >0 (true), processes spawned from this configuration will keep running after
+// the parent process finished. By default children are killed together with the parent.
+//
+// Traps:
+// * If the config ID doesn't exist.
+fn config_set_detached<T>(mut caller: Caller<T>, config_id: u64, detached: u32) -> Result<(), Trap>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::process::config_set_detached: Config ID doesn't exist")?
+        .set_detached(detached != 0);
+    Ok(())
+}
+
 // Spawns a new process using the passed in function inside a module as the entry point.
 //
 // If **link** is not 0, it will link the child and parent processes. The value of the **link**
@@ -533,7 +1069,7 @@ where
 // * If any memory outside the guest heap space is referenced.
 #[allow(clippy::too_many_arguments)]
 fn spawn<T>(
-    mut caller: Caller<T>,
+    caller: Caller<T>,
     link: i64,
     config_id: i64,
     module_id: i64,
@@ -548,22 +1084,163 @@ where
     for<'a> &'a T: Send,
     T::Config: ProcessConfigCtx,
 {
-    Box::new(async move {
-        if !caller.data().config().can_spawn_processes() {
-            return Err(anyhow!("Process doesn't have permissions to spawn sub-processes").into());
-        }
-
-        let state = caller.data();
+    spawn_process(
+        caller,
+        false,
+        false,
+        link,
+        config_id,
+        module_id,
+        func_str_ptr,
+        func_str_len,
+        params_ptr,
+        params_len,
+        id_ptr,
+    )
+}
 
-        if !state.is_initialized() {
-            return Err(anyhow!("Cannot spawn process during module initialization").into());
-        }
+// Same as `lunatic::process::spawn`, but the process is created suspended. It doesn't run any
+// code until it's started with `lunatic::process::start`. This allows the caller to set up links
+// before the process starts running, without racing the process.
+//
+// Returns:
+// * 0 on success - The ID of the newly created process is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**
+//
+// Traps:
+// * If the module ID doesn't exist.
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_suspended<T>(
+    caller: Caller<T>,
+    link: i64,
+    config_id: i64,
+    module_id: i64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + ErrorCtx + LunaticWasiCtx + ResourceLimiter + Send + 'static,
+    for<'a> &'a T: Send,
+    T::Config: ProcessConfigCtx,
+{
+    spawn_process(
+        caller,
+        true,
+        false,
+        link,
+        config_id,
+        module_id,
+        func_str_ptr,
+        func_str_len,
+        params_ptr,
+        params_len,
+        id_ptr,
+    )
+}
 
-        let config = match config_id {
-            -1 => state.config().clone(),
-            config_id => Arc::new(
-                caller
-                    .data()
+// Same as `lunatic::process::spawn`, but the message in the scratch area is put into the mailbox
+// of the new process before it starts running. The first `lunatic::message::receive` of the child
+// returns it, without racing a message sent after the spawn.
+//
+// Returns:
+// * 0    on success - The ID of the newly created process is written to **id_ptr**
+// * 1    on error   - The error ID is written to **id_ptr**
+// * 9028 if the message is bigger than the maximum message size of the process. The message is
+//        dropped and no process is spawned.
+//
+// Traps:
+// * If it's called before creating the next message.
+// * If the module ID doesn't exist.
+// * If the function string is not a valid utf8 string.
+// * If the params array is in a wrong format.
+// * If any memory outside the guest heap space is referenced.
+#[allow(clippy::too_many_arguments)]
+fn spawn_with_message<T>(
+    caller: Caller<T>,
+    link: i64,
+    config_id: i64,
+    module_id: i64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + ErrorCtx + LunaticWasiCtx + ResourceLimiter + Send + 'static,
+    for<'a> &'a T: Send,
+    T::Config: ProcessConfigCtx,
+{
+    spawn_process(
+        caller,
+        false,
+        true,
+        link,
+        config_id,
+        module_id,
+        func_str_ptr,
+        func_str_len,
+        params_ptr,
+        params_len,
+        id_ptr,
+    )
+}
+
+#[allow(clippy::too_many_arguments)]
+fn spawn_process<T>(
+    mut caller: Caller<T>,
+    suspended: bool,
+    with_message: bool,
+    link: i64,
+    config_id: i64,
+    module_id: i64,
+    func_str_ptr: u32,
+    func_str_len: u32,
+    params_ptr: u32,
+    params_len: u32,
+    id_ptr: u32,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_>
+where
+    T: ProcessState + ProcessCtx<T> + ErrorCtx + LunaticWasiCtx + ResourceLimiter + Send + 'static,
+    for<'a> &'a T: Send,
+    T::Config: ProcessConfigCtx,
+{
+    Box::new(async move {
+        if !caller.data().config().can_spawn_processes() {
+            return Err(anyhow!("Process doesn't have permissions to spawn sub-processes").into());
+        }
+
+        let initial_message = if with_message {
+            let message = caller
+                .data_mut()
+                .message_scratch_area()
+                .take()
+                .or_trap("lunatic::process::spawn_with_message::no_message")?;
+            if !caller.data().config().allows_message_size(message.size()) {
+                return Ok(9028);
+            }
+            Some(message)
+        } else {
+            None
+        };
+
+        let state = caller.data();
+
+        if !state.is_initialized() {
+            return Err(anyhow!("Cannot spawn process during module initialization").into());
+        }
+
+        let config = match config_id {
+            -1 => state.config().clone(),
+            config_id => Arc::new(
+                caller
+                    .data()
                     .config_resources()
                     .get(config_id as u64)
                     .or_trap("lunatic::process::spawn: Config ID doesn't exist")?
@@ -581,14 +1258,28 @@ where
                 .clone(),
         };
 
+        let detached = config.detached();
         let mut state = state.new_state(module.clone(), config)?;
+        if suspended {
+            // Handled before the process gets a chance to run, because signals take precedence.
+            state
+                .signal_mailbox()
+                .0
+                .send(Signal::Suspend)
+                .expect("The receiver is owned by the new state and must exist at this point");
+        }
+        if let Some(message) = initial_message {
+            state.message_mailbox().push(message);
+        }
 
         let memory = get_memory(&mut caller)?;
         let func_str = memory
             .data(&caller)
             .get(func_str_ptr as usize..(func_str_ptr + func_str_len) as usize)
             .or_trap("lunatic::process::spawn")?;
-        let function = std::str::from_utf8(func_str).or_trap("lunatic::process::spawn")?;
+        let function = std::str::from_utf8(func_str)
+            .or_trap("lunatic::process::spawn")?
+            .to_owned();
         let params = memory
             .data(&caller)
             .get(params_ptr as usize..(params_ptr + params_len) as usize)
@@ -647,14 +1338,34 @@ where
             }
         }
 
+        // Move the file descriptors marked with `lunatic::wasi::inherit_fd` into the child.
+        let inherited_fds = std::mem::take(caller.data_mut().inherited_fds_mut());
+        lunatic_wasi_api::transfer_fds(
+            caller.data_mut().wasi_mut(),
+            state.wasi_mut(),
+            &inherited_fds,
+        );
+
         // set state instead of config TODO
         let env = caller.data().environment();
         let (proc_or_error_id, result) = match lunatic_process::wasm::spawn_wasm(
-            env, runtime, &module, state, function, params, link,
+            env, runtime, &module, state, &function, params, link,
         )
         .await
         {
-            Ok((_, process)) => (process.id(), 0),
+            Ok((_, process)) => {
+                let id = process.id();
+                // Unless detached, the child is killed when this process finishes.
+                if !detached {
+                    caller
+                        .data()
+                        .signal_mailbox()
+                        .0
+                        .send(Signal::AddChild(process))
+                        .expect("The signal is sent to itself and the receiver must exist at this point");
+                }
+                (id, 0)
+            }
             Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
         };
 
@@ -689,6 +1400,7 @@ fn sleep_ms<T: ProcessState + ProcessCtx<T>>(
 //
 // The default behaviour for a newly spawned process is 2.
 fn die_when_link_dies<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, trap: u32) {
+    *caller.data_mut().trap_exit() = trap == 0;
     caller
         .data_mut()
         .signal_mailbox()
@@ -697,6 +1409,30 @@ fn die_when_link_dies<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>, tr
         .expect("The signal is sent to itself and the receiver must exist at this point");
 }
 
+// Turns linked processes dying into messages if **enabled** is not 0, like the `trap_exit` option
+// of the config, but it can be changed at any time. It's the inverse of `die_when_link_dies`.
+// Signals that are already queued are handled with the previous setting.
+fn set_trap_exit<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, enabled: u32) {
+    die_when_link_dies(caller, (enabled == 0) as u32)
+}
+
+// Returns 1 if linked processes dying are turned into messages, 0 if the process dies with them.
+fn get_trap_exit<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> u32 {
+    *caller.data_mut().trap_exit() as u32
+}
+
+// Lets the process currently running keep going after the process that spawned it finishes. By
+// default processes are killed together with their parent, unless they were spawned from a
+// detached config.
+fn detach<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) {
+    caller
+        .data_mut()
+        .signal_mailbox()
+        .0
+        .send(Signal::Detach)
+        .expect("The signal is sent to itself and the receiver must exist at this point");
+}
+
 // Returns ID of the process currently running
 fn process_id<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
     caller.data().id()
@@ -707,6 +1443,661 @@ fn environment_id<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
     caller.data().environment().id()
 }
 
+// Writes the read-only configuration shared by all processes of the current environment to
+// **buf_ptr**, if it fits into **buf_len** bytes. If the buffer is too small nothing is written,
+// the guest can use the returned length to allocate a bigger one and retry.
+//
+// Returns:
+// * The length of the configuration.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn env_config_blob<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u64, Trap> {
+    let environment = caller.data().environment();
+    let blob = environment.config_blob();
+    if blob.len() <= buf_len as usize {
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, buf_ptr as usize, blob)
+            .or_trap("lunatic::process::env_config_blob")?;
+    }
+    Ok(blob.len() as u64)
+}
+
+// Returns an ID that is unique inside of the process currently running. IDs are strictly
+// increasing, starting at 0.
+//
+// Traps:
+// * If the process exhausted all IDs below u64::MAX.
+fn unique_id<T: ProcessState + ProcessCtx<T>>(mut caller: Caller<T>) -> Result<u64, Trap> {
+    let seed = caller.data_mut().unique_id_seed();
+    let id = *seed;
+    *seed = id
+        .checked_add(1)
+        .or_trap("lunatic::process::unique_id: ids exhausted")?;
+    Ok(id)
+}
+
+// Returns the dedicated worker thread that the process currently running is pinned to, or -1 if
+// it runs on the shared scheduler.
+fn worker_id<T: ProcessState + ProcessCtx<T>>(_caller: Caller<T>) -> i64 {
+    lunatic_process::workers::current().map_or(-1, |worker| worker as i64)
+}
+
+// Returns the id of the scheduler thread executing the process currently running. Processes that
+// are not pinned to a worker can move to another thread between host calls, so the id is only a
+// snapshot.
+fn current_worker<T: ProcessState + ProcessCtx<T>>(_caller: Caller<T>) -> u32 {
+    lunatic_process::workers::thread_id()
+}
+
+// Returns the memory currently available on the host in bytes, clamped to the memory budget of the
+// process' config. It's only a hint for sizing caches or the number of workers, the memory of the
+// process itself is still limited by the maximum memory of its config.
+//
+// If the host doesn't report its available memory, the budget or u64::MAX is returned.
+fn available_memory<T>(caller: Caller<T>) -> u64
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let available = host_available_memory().unwrap_or(u64::MAX);
+    match caller.data().config().memory_budget() {
+        Some(budget) => available.min(budget),
+        None => available,
+    }
+}
+
+// Memory that can be allocated without swapping, as reported by the `MemAvailable` entry of
+// `/proc/meminfo`. Returns `None` on hosts without it.
+fn host_available_memory() -> Option<u64> {
+    let meminfo = std::fs::read_to_string("/proc/meminfo").ok()?;
+    let kilobytes = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .strip_suffix("kB")?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    kilobytes.checked_mul(1024)
+}
+
+// Lists the resources held by the process currently running, to help with tracking down leaks.
+//
+// Each resource is written to **buf_ptr** as a 16 byte entry, the kind code followed by the
+// resource ID, both as little endian u64. Only as many entries as fit into **buf_len** bytes are
+// written, the returned count can be used to allocate a big enough buffer. The kind codes are:
+// 1 config, 2 module, 3 timer, 4 DNS iterator, 5 TCP listener, 6 TCP stream, 7 TLS listener,
+// 8 TLS stream, 9 UDP socket, 10 error, 11 reply reference, 12 module upload, 13 shared region.
+//
+// Returns:
+// * The number of resources.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn list_own_resources<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u64, Trap> {
+    let resources = caller.data().resources();
+    let fitting = resources.len().min(buf_len as usize / 16);
+    let mut entries = Vec::with_capacity(fitting * 16);
+    for (kind, id) in &resources[..fitting] {
+        entries.extend((*kind as u64).to_le_bytes());
+        entries.extend(id.to_le_bytes());
+    }
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, buf_ptr as usize, &entries)
+        .or_trap("lunatic::process::list_own_resources")?;
+    Ok(resources.len() as u64)
+}
+
+// Adds a second handle to the resource **id** of **kind**, so that one can be handed off while the
+// other is kept. Both refer to the same underlying resource. The kind codes are the ones used by
+// `list_own_resources`. Modules, TCP streams, TLS streams, UDP sockets and shared regions can be
+// cloned.
+//
+// Returns:
+// * 0 on success - The ID of the new handle is written to **id_ptr**
+// * 1 on error   - The error ID is written to **id_ptr**, e.g. if the resource doesn't exist or
+//                  can't be cloned
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn clone_resource<T: ProcessState + ProcessCtx<T> + ErrorCtx>(
+    mut caller: Caller<T>,
+    kind: u32,
+    id: u64,
+    id_ptr: u32,
+) -> Result<u32, Trap> {
+    let clone = match ResourceKind::from_code(kind) {
+        Some(kind) => caller.data_mut().clone_resource(kind, id),
+        None => Err(anyhow!("Unknown resource kind {kind}")),
+    };
+    #[cfg(feature = "metrics")]
+    if clone.is_ok() && kind == ResourceKind::Module as u32 {
+        metrics::increment_gauge!("lunatic.process.modules.active", 1.0);
+    }
+    let (clone_or_error_id, result) = match clone {
+        Ok(clone_id) => (clone_id, 0),
+        Err(error) => (caller.data_mut().error_resources_mut().add(error), 1),
+    };
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(
+            &mut caller,
+            id_ptr as usize,
+            &clone_or_error_id.to_le_bytes(),
+        )
+        .or_trap("lunatic::process::clone_resource")?;
+    Ok(result)
+}
+
+// Attaches a human-readable label to the process currently running. Labels don't need to be
+// unique and are only used for debugging. Labels longer than 128 bytes are truncated.
+//
+// Traps:
+// * If the label is not a valid utf8 string.
+// * If any memory outside the guest heap space is referenced.
+fn set_process_label<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    label_ptr: u32,
+    label_len: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let label = guest_slice(&memory, &caller, label_ptr, label_len)
+        .or_trap("lunatic::process::set_process_label")?;
+    let label = std::str::from_utf8(label)
+        .or_trap("lunatic::process::set_process_label")?
+        .to_string();
+    caller.data().stats().set_label(label);
+    Ok(())
+}
+
+// Writes a snapshot of the state lunatic manages for the process to **buf_ptr**, if it fits into
+// **buf_len** bytes. The snapshot contains the label, the pending messages, the key/value store,
+// the `trap_exit` setting and the WASI environment, it can be restored into another process with
+// `restore_state`, e.g. after sending it to a process on another node. The linear memory, links
+// and monitors are not part of it.
+//
+// Returns:
+// * The length of the snapshot, it's only written if it's <= **buf_len**.
+// * u64::MAX if a pending message carries resources, they can't leave the process.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn snapshot_state<T>(mut caller: Caller<T>, buf_ptr: u32, buf_len: u32) -> Result<u64, Trap>
+where
+    T: ProcessState + ProcessCtx<T> + LunaticWasiCtx,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let state = caller.data_mut();
+    let mailbox = match state.message_mailbox().snapshot() {
+        Some(mailbox) => mailbox,
+        None => return Ok(u64::MAX),
+    };
+    let mut kv_store: Vec<_> = state
+        .kv_store()
+        .iter()
+        .map(|(key, value)| (key.clone(), value.clone()))
+        .collect();
+    kv_store.sort_unstable();
+    let snapshot = ProcessSnapshot {
+        label: state.stats().label(),
+        mailbox,
+        kv_store,
+        trap_exit: *state.trap_exit(),
+        environment_variables: state.config().environment_variables().to_vec(),
+        cwd: state.cwd().map(str::to_string),
+    }
+    .encode();
+    if snapshot.len() <= buf_len as usize {
+        let memory = get_memory(&mut caller)?;
+        memory
+            .write(&mut caller, buf_ptr as usize, &snapshot)
+            .or_trap("lunatic::process::snapshot_state")?;
+    }
+    Ok(snapshot.len() as u64)
+}
+
+// Restores the snapshot found at **snapshot_ptr**, taken with `snapshot_state`, into the calling
+// process. The label, `trap_exit` setting and key/value entries of the snapshot replace the
+// current ones, the messages of the snapshot are put into the mailbox after the messages it
+// already holds. The WASI environment can't change after the process was created, it needs to be
+// spawned with the environment of the snapshot.
+//
+// Returns:
+// * 0 on success.
+// * 1 if the snapshot is not valid.
+// * 2 if the key/value entries would exceed the configured maximum size of the store. Nothing is
+//   restored in this case.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn restore_state<T>(
+    mut caller: Caller<T>,
+    snapshot_ptr: u32,
+    snapshot_len: u32,
+) -> Result<u32, Trap>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let snapshot = memory
+        .data(&caller)
+        .get(snapshot_ptr as usize..(snapshot_ptr as usize + snapshot_len as usize))
+        .or_trap("lunatic::process::restore_state")?;
+    let snapshot = match ProcessSnapshot::decode(snapshot) {
+        Ok(snapshot) => snapshot,
+        Err(_) => return Ok(1),
+    };
+    let max_size = caller.data().config().max_kv_store_size();
+    let entries = snapshot.kv_store.into_iter().collect();
+    if kv_store_size_with(caller.data_mut().kv_store(), &entries) > max_size {
+        return Ok(2);
+    }
+    if let Some(label) = snapshot.label {
+        caller.data().stats().set_label(label);
+    }
+    caller
+        .data()
+        .message_mailbox()
+        .reinject(snapshot.mailbox.into_iter().map(Message::from));
+    caller.data_mut().kv_store().extend(entries);
+    set_trap_exit(caller, snapshot.trap_exit as u32);
+    Ok(0)
+}
+
+// Terminates the process with a failure carrying the utf8 message found at **msg_ptr**. The
+// message ends up in the process failure that is logged and returned to the host. If the message
+// is not a valid utf8 string, only its length is reported.
+//
+// Traps:
+// * Always, to terminate the process.
+// * If any memory outside the guest heap space is referenced.
+fn abort<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    msg_ptr: u32,
+    msg_len: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let message =
+        guest_slice(&memory, &caller, msg_ptr, msg_len).or_trap("lunatic::process::abort")?;
+    let message = match std::str::from_utf8(message) {
+        Ok(message) => message.to_string(),
+        Err(_) => format!("<{msg_len} bytes of invalid utf8>"),
+    };
+    Err(Trap::new(format!("Process aborted: {message}")))
+}
+
+// Returns the fuel left before the process runs out of its compute budget. Unlike the config's
+// fuel limit, which is expressed in units of 100k instructions, the value is the raw fuel amount
+// consumed by wasm instructions.
+//
+// Returns:
+// * u64::MAX if the process doesn't have a fuel limit.
+fn remaining_fuel<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
+    match caller.data().config().get_max_fuel() {
+        Some(max_fuel) => {
+            let budget = max_fuel.saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS);
+            budget.saturating_sub(caller.fuel_consumed().unwrap_or(0))
+        }
+        None => u64::MAX,
+    }
+}
+
+// Sets the number of instructions the process runs before it yields back to the scheduler, so
+// that other processes get a chance to run. Shorter quanta let latency-sensitive processes react
+// faster at the cost of more scheduler overhead. The value is clamped between 10k and 10M
+// instructions, by default processes yield every 100k instructions.
+//
+// The quantum is a number of instructions and not a duration, because processes are preempted
+// when they run out of fuel and not through epoch interruption. Fuel only advances while the
+// process runs, so the quantum doesn't depend on the load of the host.
+//
+// The new quantum takes effect once the current one is used up. If the config limits the fuel,
+// the remaining budget is kept, rounded down to a whole number of quanta.
+//
+// Returns the quantum in effect after clamping.
+//
+// Traps:
+// * If fuel consumption is disabled in the runtime.
+fn set_quantum<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    instructions: u64,
+) -> Result<u64, Trap> {
+    let quantum = instructions.clamp(MIN_QUANTUM_IN_INSTRUCTIONS, MAX_QUANTUM_IN_INSTRUCTIONS);
+    let injections = match caller.data().config().get_max_fuel() {
+        Some(max_fuel) => {
+            let budget = max_fuel.saturating_mul(UNIT_OF_COMPUTE_IN_INSTRUCTIONS);
+            let consumed = caller.fuel_consumed().unwrap_or(0);
+            // Fuel left in the current quantum is used before the next injection
+            let current = caller
+                .consume_fuel(0)
+                .or_trap("lunatic::process::set_quantum")?;
+            budget.saturating_sub(consumed).saturating_sub(current) / quantum
+        }
+        None => u64::MAX,
+    };
+    caller.out_of_fuel_async_yield(injections, quantum);
+    *caller.data_mut().quantum_mut() = quantum;
+    Ok(quantum)
+}
+
+// Returns the number of instructions the process runs before it yields back to the scheduler.
+fn get_quantum<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
+    caller.data().quantum()
+}
+
+// Returns the nanoseconds the process spent running so far. Time spent waiting for messages,
+// timers or other async host calls doesn't count. It's updated every time the process yields,
+// the currently running slice is not included.
+fn cpu_time_ns<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>) -> u64 {
+    caller.data().stats().cpu_time().as_nanos() as u64
+}
+
+// Stores the value found at **val_ptr** under the key found at **key_ptr** in the process-local
+// key/value store, replacing any previous value. The store is only visible to the process itself
+// and is dropped together with the process.
+//
+// Returns:
+// * 0 on success
+// * 1 if the combined size of all keys and values would exceed the configured maximum. The store
+//   is left unchanged in this case.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn kv_set<T>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    val_ptr: u32,
+    val_len: u32,
+) -> Result<u32, Trap>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let key = guest_slice(&memory, &caller, key_ptr, key_len)
+        .or_trap("lunatic::process::kv_set")?
+        .to_vec();
+    let value = guest_slice(&memory, &caller, val_ptr, val_len)
+        .or_trap("lunatic::process::kv_set")?
+        .to_vec();
+
+    let max_size = caller.data().config().max_kv_store_size();
+    let store = caller.data_mut().kv_store();
+    let entry = KvStore::from([(key, value)]);
+    if kv_store_size_with(store, &entry) > max_size {
+        return Ok(1);
+    }
+    store.extend(entry);
+    Ok(0)
+}
+
+// Returns the combined size of all keys and values of **store** after inserting **entries**.
+fn kv_store_size_with(store: &KvStore, entries: &KvStore) -> usize {
+    let kept: usize = store
+        .iter()
+        .filter(|(key, _)| !entries.contains_key(*key))
+        .map(|(key, value)| key.len() + value.len())
+        .sum();
+    let inserted: usize = entries
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum();
+    kept + inserted
+}
+
+// Looks up the key found at **key_ptr** in the process-local key/value store and writes the
+// value to **buf_ptr**, if it fits into **buf_len** bytes. If the buffer is too small nothing is
+// written, the guest can use the returned length to allocate a bigger one and retry.
+//
+// Returns:
+// * The length of the value.
+// * u64::MAX if the key is not in the store.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn kv_get<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    buf_ptr: u32,
+    buf_len: u32,
+) -> Result<u64, Trap> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let key_end = key_ptr
+        .checked_add(key_len)
+        .or_trap("lunatic::process::kv_get")?;
+    let key = memory_slice
+        .get(key_ptr as usize..key_end as usize)
+        .or_trap("lunatic::process::kv_get")?;
+    let value = match state.kv_store().get(key) {
+        Some(value) => value,
+        None => return Ok(u64::MAX),
+    };
+    if value.len() <= buf_len as usize {
+        memory_slice
+            .get_mut(buf_ptr as usize..buf_ptr as usize + value.len())
+            .or_trap("lunatic::process::kv_get")?
+            .copy_from_slice(value);
+    }
+    Ok(value.len() as u64)
+}
+
+// Replaces the value of the key found at **key_ptr** in the process-local key/value store with
+// the value found at **new_ptr**, but only if the current value is equal to the one found at
+// **expected_ptr**. A missing key never matches. Because guest code of a process runs
+// sequentially, the compare and the swap can't be interleaved with other writes to the store.
+//
+// Returns:
+// * 1 if the value was replaced.
+// * 0 if the current value didn't match or the key is not in the store.
+// * 2 if the combined size of all keys and values would exceed the configured maximum. The store
+//   is left unchanged in this case.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn kv_cas<T>(
+    mut caller: Caller<T>,
+    key_ptr: u32,
+    key_len: u32,
+    expected_ptr: u32,
+    expected_len: u32,
+    new_ptr: u32,
+    new_len: u32,
+) -> Result<u32, Trap>
+where
+    T: ProcessState + ProcessCtx<T>,
+    T::Config: ProcessConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let slice = |ptr: u32, len: u32| {
+        let end = ptr.checked_add(len)?;
+        memory_slice.get(ptr as usize..end as usize)
+    };
+    let key = slice(key_ptr, key_len).or_trap("lunatic::process::kv_cas")?;
+    let expected = slice(expected_ptr, expected_len).or_trap("lunatic::process::kv_cas")?;
+    let new = slice(new_ptr, new_len).or_trap("lunatic::process::kv_cas")?;
+
+    let max_size = state.config().max_kv_store_size();
+    let store = state.kv_store();
+    match store.get(key) {
+        Some(current) if current == expected => (),
+        _ => return Ok(0),
+    }
+    let size: usize = store
+        .iter()
+        .map(|(key, value)| key.len() + value.len())
+        .sum();
+    if size - expected.len() + new.len() > max_size {
+        return Ok(2);
+    }
+    store.insert(key.to_vec(), new.to_vec());
+    Ok(1)
+}
+
+// Creates a region of **size** zero-initialized bytes that other processes of the same environment
+// can attach to with `shared_region_attach`. The region ID is written to **region_id_ptr** and can
+// be sent to other processes. Wasm can't map host memory into the linear memory of a process, so
+// the region is accessed through `shared_region_read` and `shared_region_write`. The region is
+// freed once all handles to it are dropped.
+//
+// Returns:
+// * The handle of the region inside the process.
+// * -1 if **size** exceeds the maximum memory of the process.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn shared_region_create<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    size: u64,
+    region_id_ptr: u32,
+) -> Result<i64, Trap> {
+    if size > caller.data().config().get_max_memory() as u64 {
+        return Ok(-1);
+    }
+    let (region_id, region) = caller
+        .data()
+        .environment()
+        .create_shared_region(size as usize);
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(
+            &mut caller,
+            region_id_ptr as usize,
+            &region_id.to_le_bytes(),
+        )
+        .or_trap("lunatic::process::shared_region_create")?;
+    Ok(caller.data_mut().shared_region_resources_mut().add(region) as i64)
+}
+
+// Attaches the process to the shared region **region_id** created by a process of the same
+// environment.
+//
+// Returns:
+// * The handle of the region inside the process.
+// * -1 if the region doesn't exist or was already freed.
+fn shared_region_attach<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    region_id: u64,
+) -> i64 {
+    match caller.data().environment().shared_region(region_id) {
+        Some(region) => caller.data_mut().shared_region_resources_mut().add(region) as i64,
+        None => -1,
+    }
+}
+
+// Returns the size of the shared region in bytes.
+//
+// Traps:
+// * If the region handle doesn't exist.
+fn shared_region_size<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    handle: u64,
+) -> Result<u64, Trap> {
+    let region = caller
+        .data()
+        .shared_region_resources()
+        .get(handle)
+        .or_trap("lunatic::process::shared_region_size: Region handle doesn't exist")?;
+    let size = region.lock().unwrap().len();
+    Ok(size as u64)
+}
+
+// Copies **len** bytes starting at **offset** of the shared region to **buf_ptr**.
+//
+// Traps:
+// * If the region handle doesn't exist.
+// * If the range is outside of the region.
+// * If any memory outside the guest heap space is referenced.
+fn shared_region_read<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    handle: u64,
+    offset: u64,
+    buf_ptr: u32,
+    len: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let buf = memory_slice
+        .get_mut(buf_ptr as usize..(buf_ptr as usize + len as usize))
+        .or_trap("lunatic::process::shared_region_read")?;
+    let region = state
+        .shared_region_resources()
+        .get(handle)
+        .or_trap("lunatic::process::shared_region_read: Region handle doesn't exist")?
+        .lock()
+        .unwrap();
+    let bytes = region
+        .get(offset as usize..)
+        .and_then(|bytes| bytes.get(..len as usize))
+        .or_trap("lunatic::process::shared_region_read: Range is outside of the region")?;
+    buf.copy_from_slice(bytes);
+    Ok(())
+}
+
+// Copies **len** bytes found at **buf_ptr** into the shared region, starting at **offset**.
+//
+// Traps:
+// * If the region handle doesn't exist.
+// * If the range is outside of the region.
+// * If any memory outside the guest heap space is referenced.
+fn shared_region_write<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    handle: u64,
+    offset: u64,
+    buf_ptr: u32,
+    len: u32,
+) -> Result<(), Trap> {
+    let memory = get_memory(&mut caller)?;
+    let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+    let buf = memory_slice
+        .get(buf_ptr as usize..(buf_ptr as usize + len as usize))
+        .or_trap("lunatic::process::shared_region_write")?;
+    let mut region = state
+        .shared_region_resources()
+        .get(handle)
+        .or_trap("lunatic::process::shared_region_write: Region handle doesn't exist")?
+        .lock()
+        .unwrap();
+    region
+        .get_mut(offset as usize..)
+        .and_then(|bytes| bytes.get_mut(..len as usize))
+        .or_trap("lunatic::process::shared_region_write: Range is outside of the region")?
+        .copy_from_slice(buf);
+    Ok(())
+}
+
+// Drops the handle of the shared region. The region itself is freed once all processes dropped
+// their handles.
+//
+// Traps:
+// * If the region handle doesn't exist.
+fn drop_shared_region<T: ProcessState + ProcessCtx<T>>(
+    mut caller: Caller<T>,
+    handle: u64,
+) -> Result<(), Trap> {
+    caller
+        .data_mut()
+        .shared_region_resources_mut()
+        .remove(handle)
+        .or_trap("lunatic::process::drop_shared_region: Region handle doesn't exist")?;
+    Ok(())
+}
+
 // Link current process to **process_id**. This is not an atomic operation, any of the 2 processes
 // could fail before processing the `Link` signal and may not notify the other.
 //
@@ -752,6 +2143,65 @@ fn link<T: ProcessState + ProcessCtx<T>>(
     Ok(())
 }
 
+// Waits until all processes currently linked to this one finished, e.g. before shutting down.
+// Processes linked after the call started are not waited for, and unlinked processes stop
+// counting once they are unlinked.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0    if all linked processes finished.
+// * 9027 if the wait timed out.
+fn join_all_links<T: ProcessState + ProcessCtx<T> + Send>(
+    caller: Caller<T>,
+    timeout: u64,
+) -> Box<dyn Future<Output = u32> + Send + '_> {
+    Box::new(async move {
+        let (joined, all_finished) = tokio::sync::oneshot::channel();
+        caller
+            .data()
+            .signal_mailbox()
+            .0
+            .send(Signal::JoinLinks(joined))
+            .expect("The signal is sent to itself and the receiver must exist at this point");
+        match timeout {
+            u64::MAX => {
+                all_finished.await.ok();
+                0
+            }
+            t => match tokio::time::timeout(Duration::from_millis(t), all_finished).await {
+                Ok(_) => 0,
+                Err(_) => 9027,
+            },
+        }
+    })
+}
+
+// Monitors **process_id** without linking to it. When the process finishes, a message tagged with
+// `DOWN_TAG` (`i64::MIN + 1`) is put into the mailbox of the current process. It contains the
+// process ID as u64, followed by the exit reason code as u32 and its detail as i32, all little
+// endian.
+//
+// If the process doesn't exist, the message is delivered right away with the reason `noprocess`.
+fn monitor<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) {
+    let id = caller.data().id();
+    let signal_mailbox = caller.data().signal_mailbox().clone();
+    match caller.data().environment().get_process(process_id) {
+        Some(process) => {
+            let this_process = WasmProcess::new(id, signal_mailbox.0);
+            process.send(Signal::Monitor(Arc::new(this_process)));
+        }
+        None => {
+            let message = down_message(process_id, ExitReason::NoProcess);
+            signal_mailbox
+                .0
+                .send(Signal::Message(Message::Data(message)))
+                .expect("The signal is sent to itself and the receiver must exist at this point");
+        }
+    }
+}
+
 // Unlink current process from **process_id**. This is not an atomic operation.
 //
 // Traps:
@@ -783,6 +2233,21 @@ fn unlink<T: ProcessState + ProcessCtx<T>>(
     Ok(())
 }
 
+// Starts **process_id** if it was spawned suspended.
+//
+// Returns:
+// * 0 if the start signal was sent.
+// * 1 if the process doesn't exist.
+fn start<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -> u32 {
+    match caller.data().environment().get_process(process_id) {
+        Some(process) => {
+            process.send(Signal::Start);
+            0
+        }
+        None => 1,
+    }
+}
+
 // Send a Kill signal to **process_id**.
 //
 // Traps:
@@ -795,6 +2260,28 @@ fn kill<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) ->
     Ok(())
 }
 
+// Asks **process_id** to shut down gracefully.
+//
+// The process receives a message tagged with `i64::MIN`, containing **grace_ms** encoded as a
+// little endian u64. If it doesn't finish within **grace_ms** milliseconds, it's killed.
+//
+// Returns:
+// * 0 if the shutdown request was sent.
+// * 1 if the process doesn't exist.
+fn request_shutdown<T: ProcessState + ProcessCtx<T>>(
+    caller: Caller<T>,
+    process_id: u64,
+    grace_ms: u64,
+) -> u32 {
+    match caller.data().environment().get_process(process_id) {
+        Some(process) => {
+            lunatic_process::request_shutdown(process, Duration::from_millis(grace_ms));
+            0
+        }
+        None => 1,
+    }
+}
+
 // Checks to see if a process exists
 fn exists<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -> i32 {
     caller
@@ -803,3 +2290,48 @@ fn exists<T: ProcessState + ProcessCtx<T>>(caller: Caller<T>, process_id: u64) -
         .get_process(process_id)
         .is_some() as i32
 }
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{kv_store_size_with, KvStore, SendRateLimiter};
+
+    #[test]
+    fn send_rate_limiter_evicts_full_buckets() {
+        let mut limiter = SendRateLimiter::default();
+        for process_id in 0..64 {
+            limiter.try_take(process_id, 1000).unwrap();
+        }
+        // Refills all buckets
+        std::thread::sleep(Duration::from_millis(10));
+        for process_id in 64..128 {
+            limiter.try_take(process_id, 1000).unwrap();
+        }
+        assert_eq!(limiter.len(), 64);
+    }
+
+    #[test]
+    fn send_rate_limiter_keeps_drained_buckets() {
+        let mut limiter = SendRateLimiter::default();
+        for process_id in 0..128 {
+            limiter.try_take(process_id, 1).unwrap();
+        }
+        assert_eq!(limiter.len(), 128);
+        assert!(limiter.try_take(0, 1).is_err());
+    }
+
+    #[test]
+    fn kv_store_size_counts_replaced_keys_once() {
+        let store = KvStore::from([
+            (b"a".to_vec(), b"123".to_vec()),
+            (b"b".to_vec(), b"1".to_vec()),
+        ]);
+        let entries = KvStore::from([
+            (b"a".to_vec(), b"1".to_vec()),
+            (b"c".to_vec(), b"12".to_vec()),
+        ]);
+        // "b1" is kept, "a1" replaces "a123" and "c12" is added
+        assert_eq!(kv_store_size_with(&store, &entries), 2 + 2 + 3);
+    }
+}
@@ -3,6 +3,11 @@ use serde::{de::DeserializeOwned, Serialize};
 // One unit of fuel represents around 100k instructions.
 pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
 
+// Bounds of the number of instructions a process can run before yielding to the scheduler, see
+// `lunatic::process::set_quantum`. By default processes yield after each unit of compute.
+pub const MIN_QUANTUM_IN_INSTRUCTIONS: u64 = 10_000;
+pub const MAX_QUANTUM_IN_INSTRUCTIONS: u64 = 10_000_000;
+
 /// Common process configuration.
 ///
 /// Each process in lunatic can have specific limits and permissions. These properties are set
@@ -12,8 +17,9 @@ pub const UNIT_OF_COMPUTE_IN_INSTRUCTIONS: u64 = 100_000;
 /// the process. This host functions are the ones that consider specific configuration while
 /// performing operations.
 ///
-/// However, two properties of a process are enforced by the runtime (maximum memory and maximum
-/// fuel usage). This two properties need to be part of every configuration.
+/// However, some properties of a process are enforced by the runtime (maximum memory, maximum
+/// fuel usage, the worker affinity, trapping exits, deterministic scheduling and exit logging).
+/// These properties need to be part of every configuration.
 ///
 /// `ProcessConfig` must be serializable in case it is used to spawn processes on other nodes.
 pub trait ProcessConfig: Clone + Serialize + DeserializeOwned {
@@ -21,4 +27,34 @@ pub trait ProcessConfig: Clone + Serialize + DeserializeOwned {
     fn get_max_fuel(&self) -> Option<u64>;
     fn set_max_memory(&mut self, max_memory: usize);
     fn get_max_memory(&self) -> usize;
+    /// Pins spawned processes to a dedicated worker thread, see [`crate::workers`].
+    ///
+    /// By default the hint is ignored and processes run on any worker.
+    fn set_worker_affinity(&mut self, _worker: Option<usize>) {}
+    fn get_worker_affinity(&self) -> Option<usize> {
+        None
+    }
+    /// Processes that trap exits receive a message if a linked process fails, instead of failing
+    /// together with it.
+    ///
+    /// By default exits are not trapped.
+    fn set_trap_exit(&mut self, _trap_exit: bool) {}
+    fn get_trap_exit(&self) -> bool {
+        false
+    }
+    /// Runs spawned processes on the deterministic scheduler, see [`crate::workers`]. Meant for
+    /// reproducing concurrency bugs in tests, it takes precedence over the worker affinity.
+    ///
+    /// By default processes run on the regular workers.
+    fn set_deterministic_seed(&mut self, _seed: Option<u64>) {}
+    fn get_deterministic_seed(&self) -> Option<u64> {
+        None
+    }
+    /// Logs processes that finish normally at the debug level. Failures are always logged.
+    ///
+    /// By default normal exits are not logged.
+    fn set_log_normal_exits(&mut self, _log: bool) {}
+    fn get_log_normal_exits(&self) -> bool {
+        false
+    }
 }
@@ -1,19 +1,109 @@
 use dashmap::DashMap;
-use std::sync::{
-    atomic::{AtomicU64, Ordering},
-    Arc,
+use std::{
+    any::Any,
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex, RwLock, Weak,
+    },
+    time::{Duration, Instant},
 };
 
-use crate::{Process, Signal};
+use tokio::task::JoinHandle;
+
+use crate::{mailbox::MessageMailbox, Process, Signal};
+
+/// Zero-initialized bytes that multiple processes of an environment can read and write.
+///
+/// The region is freed when the last handle to it is dropped.
+pub type SharedRegion = Arc<Mutex<Vec<u8>>>;
+
+/// Number of values an environment keeps for reuse, if not configured otherwise.
+pub const DEFAULT_RECYCLE_CAPACITY: usize = 64;
+
+/// Bounded pool of values left behind by finished processes, e.g. emptied resource tables.
+///
+/// New processes of the same environment take them out again instead of allocating new ones,
+/// which takes pressure off the allocator if processes are spawned and exit at a high rate.
+/// Values that don't fit into the pool anymore are dropped.
+#[derive(Clone)]
+pub struct RecyclePool {
+    capacity: usize,
+    values: Arc<Mutex<Vec<Box<dyn Any + Send>>>>,
+}
+
+impl RecyclePool {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            values: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Puts **value** into the pool. Returns `false` if the pool is full and the value was dropped.
+    pub fn put<T: Any + Send>(&self, value: T) -> bool {
+        let mut values = self.values.lock().unwrap();
+        if values.len() >= self.capacity {
+            return false;
+        }
+        values.push(Box::new(value));
+        true
+    }
+
+    /// Takes a value of type `T` out of the pool.
+    pub fn take<T: Any + Send>(&self) -> Option<T> {
+        let mut values = self.values.lock().unwrap();
+        let index = values.iter().position(|value| value.is::<T>())?;
+        values
+            .swap_remove(index)
+            .downcast()
+            .ok()
+            .map(|value| *value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.values.lock().unwrap().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+}
 
 pub trait Environment: Send + Sync {
     fn id(&self) -> u64;
     fn get_next_process_id(&self) -> u64;
     fn get_process(&self, id: u64) -> Option<Arc<dyn Process>>;
-    fn add_process(&self, id: u64, proc: Arc<dyn Process>);
+    fn add_process(&self, id: u64, proc: Arc<dyn Process>, stats: ProcessStats);
+    /// Removes the process and frees the slot it reserved.
     fn remove_process(&self, id: u64);
     fn process_count(&self) -> usize;
+    /// Maximum number of processes that can run at the same time inside this environment.
+    fn max_processes(&self) -> Option<usize>;
+    /// Reserves a slot for a process that is about to be added, returns false if all
+    /// `max_processes` slots are taken.
+    ///
+    /// The slot is freed by [`Environment::remove_process`], or by
+    /// [`Environment::release_process`] if the process is never added.
+    fn reserve_process(&self) -> bool;
+    fn release_process(&self);
     fn send(&self, id: u64, signal: Signal);
+    /// Returns a snapshot of all live processes, ordered by id. Names are resolved through
+    /// `registry`.
+    fn list_processes(&self, registry: &DashMap<String, (u64, u64)>) -> Vec<ProcessInfo>;
+    /// Read-only configuration shared by all processes inside this environment.
+    fn config_blob(&self) -> &[u8];
+    /// Creates a [`SharedRegion`] of **size** bytes and returns it together with its ID.
+    ///
+    /// The environment doesn't keep the region alive, it only remembers it while some process
+    /// holds a handle, so that others can look it up with [`Environment::shared_region`].
+    fn create_shared_region(&self, size: usize) -> (u64, SharedRegion);
+    /// Returns the shared region with **id**, if it's still alive.
+    fn shared_region(&self, id: u64) -> Option<SharedRegion>;
 }
 
 pub trait Environments: Send + Sync {
@@ -22,30 +112,216 @@ pub trait Environments: Send + Sync {
     fn get(&self, id: u64) -> Option<Arc<Self::Env>>;
 }
 
+/// Accounting data of a process that is shared between the process state and the environment.
+#[derive(Clone)]
+pub struct ProcessStats {
+    spawned_at: Instant,
+    memory_usage: Arc<AtomicUsize>,
+    mailbox: MessageMailbox,
+    label: Arc<RwLock<Option<String>>>,
+    watchers: Arc<AtomicUsize>,
+    // Nanoseconds spent running the process
+    cpu_time: Arc<AtomicU64>,
+}
+
+/// Labels longer than this (in bytes) are truncated.
+pub const MAX_LABEL_LEN: usize = 128;
+
+impl ProcessStats {
+    pub fn new(mailbox: MessageMailbox) -> Self {
+        Self {
+            spawned_at: Instant::now(),
+            memory_usage: Arc::new(AtomicUsize::new(0)),
+            mailbox,
+            label: Arc::new(RwLock::new(None)),
+            watchers: Arc::new(AtomicUsize::new(0)),
+            cpu_time: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Records the current size of the process' linear memory in bytes.
+    pub fn set_memory_usage(&self, bytes: usize) {
+        self.memory_usage.store(bytes, Ordering::Relaxed);
+    }
+
+    pub fn memory_usage(&self) -> usize {
+        self.memory_usage.load(Ordering::Relaxed)
+    }
+
+    pub fn mailbox_len(&self) -> usize {
+        self.mailbox.len()
+    }
+
+    pub fn uptime(&self) -> Duration {
+        self.spawned_at.elapsed()
+    }
+
+    /// Returns how long the process has been blocked waiting for a message, or `None` if it's
+    /// not waiting.
+    pub fn idle_for(&self) -> Option<Duration> {
+        self.mailbox.waiting_for()
+    }
+
+    /// Records the number of processes linked to or monitoring the process.
+    pub fn set_watchers(&self, watchers: usize) {
+        self.watchers.store(watchers, Ordering::Relaxed);
+    }
+
+    pub fn watchers(&self) -> usize {
+        self.watchers.load(Ordering::Relaxed)
+    }
+
+    /// Adds **time** the process spent running until it yielded back to the scheduler.
+    pub fn add_cpu_time(&self, time: Duration) {
+        self.cpu_time
+            .fetch_add(time.as_nanos() as u64, Ordering::Relaxed);
+    }
+
+    /// Returns the time the process spent running, including host calls, but not the time it
+    /// was waiting (e.g. for messages or timers). The slice it's currently running isn't included.
+    pub fn cpu_time(&self) -> Duration {
+        Duration::from_nanos(self.cpu_time.load(Ordering::Relaxed))
+    }
+
+    /// Attaches a human-readable label to the process, truncated to [`MAX_LABEL_LEN`] bytes.
+    pub fn set_label(&self, mut label: String) {
+        if label.len() > MAX_LABEL_LEN {
+            let mut end = MAX_LABEL_LEN;
+            while !label.is_char_boundary(end) {
+                end -= 1;
+            }
+            label.truncate(end);
+        }
+        *self.label.write().expect("label lock is never poisoned") = Some(label);
+    }
+
+    pub fn label(&self) -> Option<String> {
+        self.label
+            .read()
+            .expect("label lock is never poisoned")
+            .clone()
+    }
+}
+
+/// A point-in-time view of a live process, returned by [`Environment::list_processes`].
+#[derive(Debug, Clone)]
+pub struct ProcessInfo {
+    pub id: u64,
+    pub name: Option<String>,
+    pub label: Option<String>,
+    pub memory_usage: usize,
+    pub mailbox_len: usize,
+    pub uptime: Duration,
+    pub cpu_time: Duration,
+}
+
+type ProcessEntry = (Arc<dyn Process>, ProcessStats);
+
 #[derive(Clone)]
 pub struct LunaticEnvironment {
     environment_id: u64,
     next_process_id: Arc<AtomicU64>,
-    processes: Arc<DashMap<u64, Arc<dyn Process>>>,
+    processes: Arc<DashMap<u64, ProcessEntry>>,
+    // Running processes and the ones that are still being spawned
+    reserved_processes: Arc<AtomicUsize>,
+    max_processes: Option<usize>,
+    config_blob: Arc<[u8]>,
+    next_region_id: Arc<AtomicU64>,
+    shared_regions: Arc<DashMap<u64, Weak<Mutex<Vec<u8>>>>>,
+    recycle_pool: RecyclePool,
 }
 
 impl LunaticEnvironment {
     pub fn new(id: u64) -> Self {
+        Self::with_max_processes(id, None)
+    }
+
+    /// Creates an environment that refuses to spawn more than `max_processes` processes.
+    pub fn with_max_processes(id: u64, max_processes: Option<usize>) -> Self {
         Self {
             environment_id: id,
             processes: Arc::new(DashMap::new()),
             next_process_id: Arc::new(AtomicU64::new(1)),
+            reserved_processes: Arc::new(AtomicUsize::new(0)),
+            max_processes,
+            config_blob: Arc::from(Vec::new()),
+            next_region_id: Arc::new(AtomicU64::new(1)),
+            shared_regions: Arc::new(DashMap::new()),
+            recycle_pool: RecyclePool::new(DEFAULT_RECYCLE_CAPACITY),
         }
     }
+
+    /// Terminates processes that have been blocked waiting for a message for at least
+    /// **threshold** and that nobody else can reach.
+    ///
+    /// A process is only considered unreachable if it has no links or monitors, isn't registered
+    /// under a name in **registry** and its handle isn't held by anyone except the environment
+    /// (e.g. a parent, a timer or another process' resources). Processes that only know the ID
+    /// can't be tracked, so this is opt-in. The process checks again that it's still idle before
+    /// finishing with [`ExitReason::IdleTimeout`](crate::ExitReason::IdleTimeout).
+    ///
+    /// Returns the IDs of the processes that were asked to finish.
+    pub fn reap_idle(
+        &self,
+        threshold: Duration,
+        registry: &DashMap<String, (u64, u64)>,
+    ) -> Vec<u64> {
+        let idle: Vec<(u64, Arc<dyn Process>)> = self
+            .processes
+            .iter()
+            .filter(|entry| {
+                let (process, stats) = entry.value();
+                stats.idle_for().is_some_and(|idle| idle >= threshold)
+                    && stats.watchers() == 0
+                    && Arc::strong_count(process) == 1
+            })
+            .map(|entry| (*entry.key(), entry.value().0.clone()))
+            .collect();
+        idle.into_iter()
+            .filter(|(id, _)| {
+                !registry
+                    .iter()
+                    .any(|name| *name.value() == (self.environment_id, *id))
+            })
+            .map(|(id, process)| {
+                process.send(Signal::IdleTimeout(threshold));
+                id
+            })
+            .collect()
+    }
+
+    /// Sets the read-only configuration that all processes of the environment can read.
+    pub fn with_config_blob(mut self, config_blob: Vec<u8>) -> Self {
+        self.config_blob = config_blob.into();
+        self
+    }
+
+    /// Limits how many values of finished processes are kept for reuse, 0 disables recycling.
+    pub fn with_recycle_capacity(mut self, capacity: usize) -> Self {
+        self.recycle_pool = RecyclePool::new(capacity);
+        self
+    }
+
+    /// Returns the time the process with **id** spent running, see [`ProcessStats::cpu_time`].
+    ///
+    /// Returns `None` if the process doesn't exist (anymore).
+    pub fn cpu_time(&self, id: u64) -> Option<Duration> {
+        self.processes.get(&id).map(|entry| entry.1.cpu_time())
+    }
+
+    /// Returns the pool of values that finished processes left for new processes to reuse.
+    pub fn recycle_pool(&self) -> &RecyclePool {
+        &self.recycle_pool
+    }
 }
 
 impl Environment for LunaticEnvironment {
     fn get_process(&self, id: u64) -> Option<Arc<dyn Process>> {
-        self.processes.get(&id).map(|x| x.clone())
+        self.processes.get(&id).map(|x| x.0.clone())
     }
 
-    fn add_process(&self, id: u64, proc: Arc<dyn Process>) {
-        self.processes.insert(id, proc);
+    fn add_process(&self, id: u64, proc: Arc<dyn Process>, stats: ProcessStats) {
+        self.processes.insert(id, (proc, stats));
         #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
         let labels: [(String, String); 0] = [];
         #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
@@ -59,7 +335,9 @@ impl Environment for LunaticEnvironment {
     }
 
     fn remove_process(&self, id: u64) {
-        self.processes.remove(&id);
+        if self.processes.remove(&id).is_some() {
+            self.release_process();
+        }
         #[cfg(all(feature = "metrics", not(feature = "detailed_metrics")))]
         let labels: [(String, String); 0] = [];
         #[cfg(all(feature = "metrics", feature = "detailed_metrics"))]
@@ -76,10 +354,76 @@ impl Environment for LunaticEnvironment {
         self.processes.len()
     }
 
+    fn max_processes(&self) -> Option<usize> {
+        self.max_processes
+    }
+
+    fn reserve_process(&self) -> bool {
+        let max_processes = self.max_processes.unwrap_or(usize::MAX);
+        self.reserved_processes
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |reserved| {
+                (reserved < max_processes).then_some(reserved + 1)
+            })
+            .is_ok()
+    }
+
+    fn release_process(&self) {
+        self.reserved_processes.fetch_sub(1, Ordering::AcqRel);
+    }
+
     fn send(&self, id: u64, signal: Signal) {
         if let Some(proc) = self.processes.get(&id) {
-            proc.send(signal);
+            proc.0.send(signal);
+        }
+    }
+
+    fn list_processes(&self, registry: &DashMap<String, (u64, u64)>) -> Vec<ProcessInfo> {
+        // Look up the names of this environment's processes once, instead of per process
+        let mut names = HashMap::new();
+        for name in registry.iter() {
+            let (environment_id, id) = *name.value();
+            if environment_id == self.environment_id {
+                names.entry(id).or_insert_with(|| name.key().clone());
+            }
         }
+        let mut processes: Vec<ProcessInfo> = self
+            .processes
+            .iter()
+            .map(|entry| {
+                let (id, (_, stats)) = entry.pair();
+                let name = names.remove(id);
+                ProcessInfo {
+                    id: *id,
+                    name,
+                    label: stats.label(),
+                    memory_usage: stats.memory_usage(),
+                    mailbox_len: stats.mailbox_len(),
+                    uptime: stats.uptime(),
+                    cpu_time: stats.cpu_time(),
+                }
+            })
+            .collect();
+        // Process ids are assigned incrementally, this lists them in the order they were spawned
+        processes.sort_unstable_by_key(|process| process.id);
+        processes
+    }
+
+    fn config_blob(&self) -> &[u8] {
+        &self.config_blob
+    }
+
+    fn create_shared_region(&self, size: usize) -> (u64, SharedRegion) {
+        // Forget regions whose last handle was dropped
+        self.shared_regions
+            .retain(|_, region| region.strong_count() > 0);
+        let id = self.next_region_id.fetch_add(1, Ordering::Relaxed);
+        let region = Arc::new(Mutex::new(vec![0; size]));
+        self.shared_regions.insert(id, Arc::downgrade(&region));
+        (id, region)
+    }
+
+    fn shared_region(&self, id: u64) -> Option<SharedRegion> {
+        self.shared_regions.get(&id)?.upgrade()
     }
 
     fn get_next_process_id(&self) -> u64 {
@@ -94,12 +438,52 @@ impl Environment for LunaticEnvironment {
 #[derive(Clone, Default)]
 pub struct LunaticEnvironments {
     envs: Arc<DashMap<u64, Arc<LunaticEnvironment>>>,
+    max_processes: Option<usize>,
+}
+
+impl LunaticEnvironments {
+    /// Every environment created by this collection will be limited to `max_processes`.
+    pub fn with_max_processes(max_processes: Option<usize>) -> Self {
+        Self {
+            envs: Arc::new(DashMap::new()),
+            max_processes,
+        }
+    }
+
+    /// Returns the number of processes running across all environments.
+    pub fn process_count(&self) -> usize {
+        self.envs.iter().map(|env| env.process_count()).sum()
+    }
+
+    /// Periodically terminates idle processes in all environments, see
+    /// [`LunaticEnvironment::reap_idle`].
+    pub fn spawn_idle_reaper(
+        &self,
+        threshold: Duration,
+        registry: Arc<DashMap<String, (u64, u64)>>,
+    ) -> JoinHandle<()> {
+        let envs = self.envs.clone();
+        // Processes are reaped at most half a threshold late.
+        let interval = (threshold / 2).max(Duration::from_millis(10));
+        tokio::task::spawn(async move {
+            loop {
+                tokio::time::sleep(interval).await;
+                let envs: Vec<_> = envs.iter().map(|env| env.value().clone()).collect();
+                for env in envs {
+                    env.reap_idle(threshold, &registry);
+                }
+            }
+        })
+    }
 }
 
 impl Environments for LunaticEnvironments {
     type Env = LunaticEnvironment;
     fn create(&self, id: u64) -> Arc<Self::Env> {
-        let env = Arc::new(LunaticEnvironment::new(id));
+        let env = Arc::new(LunaticEnvironment::with_max_processes(
+            id,
+            self.max_processes,
+        ));
         self.envs.insert(id, env.clone());
         #[cfg(feature = "metrics")]
         metrics::gauge!("lunatic.process.environment.count", self.envs.len() as f64);
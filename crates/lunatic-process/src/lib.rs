@@ -3,24 +3,36 @@ pub mod env;
 pub mod mailbox;
 pub mod message;
 pub mod runtimes;
+pub mod snapshot;
 pub mod state;
 pub mod wasm;
-
-use std::{collections::HashMap, fmt::Debug, future::Future, hash::Hash, sync::Arc};
+pub mod workers;
+
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Debug,
+    future::Future,
+    hash::Hash,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 use anyhow::{anyhow, Result};
-use env::Environment;
-use log::{debug, log_enabled, trace, warn, Level};
+use env::{Environment, ProcessStats};
+use log::{debug, info, log, log_enabled, trace, warn, Level};
 
 use tokio::{
     sync::{
         mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender},
-        Mutex,
+        oneshot, Mutex,
     },
     task::JoinHandle,
 };
 
-use crate::{mailbox::MessageMailbox, message::Message};
+use crate::{
+    mailbox::MessageMailbox,
+    message::{down_message, DataMessage, Message, SHUTDOWN_TAG},
+};
 
 #[cfg(feature = "metrics")]
 pub fn describe_metrics() {
@@ -80,6 +92,12 @@ pub fn describe_metrics() {
         "Number of LinkDied messages send since startup"
     );
 
+    describe_counter!(
+        "lunatic.process.idle_reaped",
+        Unit::Count,
+        "Number of processes terminated by the idle reaper since startup"
+    );
+
     describe_gauge!(
         "lunatic.process.environment.process.count",
         Unit::Count,
@@ -131,11 +149,34 @@ pub enum Signal {
     Link(Option<i64>, Arc<dyn Process>),
     // Request from a process to be unlinked
     UnLink { process_id: u64 },
+    // Sent by a process to itself after spawning a child that shares its lifetime. All children
+    // that are still running receive a `ParentDied` signal when the process finishes.
+    AddChild(Arc<dyn Process>),
+    // Sent to children when the parent finishes. The child dies too, unless it detached itself.
+    ParentDied,
+    // Sent by a process to itself to keep running after the parent finishes.
+    Detach,
+    // Stops executing the process until a `Start` signal is received. Signals are still handled
+    // while the process is suspended. If it's put into the mailbox before the process is spawned,
+    // the process is created suspended and doesn't run any code until started.
+    Suspend,
+    // Resumes the execution of a suspended process.
+    Start,
+    // Sent from a process that wants to be notified when this process finishes, without being
+    // linked to it. The monitor receives a `DOWN_TAG` message carrying the `ExitReason`.
+    Monitor(Arc<dyn Process>),
+    // Sent by the idle reaper to a process that has been waiting for a message for at least the
+    // duration and has no links or monitors. The process finishes if this still holds when the
+    // signal is handled.
+    IdleTimeout(Duration),
     // Sent to linked processes when the link dies. Contains the tag used when the link was
     // established. Depending on the value of `die_when_link_dies` (default is `true`) and
     // the death reason, the receiving process will turn this signal into a message or the
     // process will immediately die as well.
     LinkDied(u64, Option<i64>, DeathReason),
+    // Sent by a process to itself to be notified once all processes that are currently linked to
+    // it finished. Processes linked later are not waited for, unlinked ones stop counting.
+    JoinLinks(oneshot::Sender<()>),
 }
 
 impl Debug for Signal {
@@ -146,7 +187,15 @@ impl Debug for Signal {
             Self::DieWhenLinkDies(_) => write!(f, "DieWhenLinkDies"),
             Self::Link(_, p) => write!(f, "Link {}", p.id()),
             Self::UnLink { process_id } => write!(f, "UnLink {process_id}"),
+            Self::AddChild(p) => write!(f, "AddChild {}", p.id()),
+            Self::Monitor(p) => write!(f, "Monitor {}", p.id()),
+            Self::ParentDied => write!(f, "ParentDied"),
+            Self::Detach => write!(f, "Detach"),
+            Self::Suspend => write!(f, "Suspend"),
+            Self::Start => write!(f, "Start"),
+            Self::IdleTimeout(threshold) => write!(f, "IdleTimeout {:?}", threshold),
             Self::LinkDied(_, _, reason) => write!(f, "LinkDied {:?}", reason),
+            Self::JoinLinks(_) => write!(f, "JoinLinks"),
         }
     }
 }
@@ -160,6 +209,46 @@ pub enum DeathReason {
     NoProcess,
 }
 
+/// The way a process finished, as reported to the processes monitoring it.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExitReason {
+    /// The entry function returned.
+    Normal,
+    /// The process failed, contains the cause of the trap if it's known.
+    Trap(Option<TrapReason>),
+    /// The process was killed, e.g. because a linked process failed.
+    Killed,
+    /// The node running the process couldn't be reached.
+    NoConnection,
+    /// The process didn't exist when the monitor was set up.
+    NoProcess,
+    /// The process was terminated by the idle reaper, see
+    /// [`LunaticEnvironment::reap_idle`](crate::env::LunaticEnvironment::reap_idle).
+    IdleTimeout,
+}
+
+impl ExitReason {
+    /// Encodes the reason as the `(code, detail)` pair carried by [`DOWN_TAG`] messages.
+    ///
+    /// Codes are `0` normal, `1` trap, `2` killed, `3` noconnection, `4` noprocess, `5` exit and
+    /// `6` idle timeout.
+    /// The detail is the [`TrapReason`] code for traps, the exit status for failures caused by
+    /// `proc_exit` and `0` otherwise.
+    ///
+    /// [`DOWN_TAG`]: crate::message::DOWN_TAG
+    pub fn encode(&self) -> (u32, i32) {
+        match self {
+            ExitReason::Normal => (0, 0),
+            ExitReason::Trap(Some(TrapReason::Exit(status))) => (5, *status),
+            ExitReason::Trap(reason) => (1, reason.map_or(0, |reason| reason.code())),
+            ExitReason::Killed => (2, 0),
+            ExitReason::NoConnection => (3, 0),
+            ExitReason::NoProcess => (4, 0),
+            ExitReason::IdleTimeout => (6, 0),
+        }
+    }
+}
+
 /// The reason of a process finishing
 pub enum Finished<T> {
     /// This just means that the process finished without external interaction.
@@ -168,6 +257,9 @@ pub enum Finished<T> {
     Normal(T),
     /// The process was terminated by an external `Kill` signal.
     KillSignal,
+    /// The process was blocked waiting for a message for longer than the contained threshold and
+    /// nobody could reach it.
+    IdleTimeout(Duration),
 }
 
 /// A `WasmProcess` represents an instance of a Wasm module that is being executed.
@@ -211,6 +303,9 @@ impl Process for WasmProcess {
     }
 }
 
+// Number of children a process tracks before it forgets the finished ones for the first time.
+const MIN_PRUNE_CHILDREN_AT: usize = 64;
+
 /// Turns a `Future` into a process, enabling signals (e.g. kill).
 ///
 /// This function represents the core execution loop of lunatic processes:
@@ -233,6 +328,8 @@ pub(crate) async fn new<F, S, R>(
     env: Arc<dyn Environment>,
     signal_mailbox: Arc<Mutex<UnboundedReceiver<Signal>>>,
     message_mailbox: MessageMailbox,
+    stats: Option<ProcessStats>,
+    log_normal_exits: bool,
 ) -> Result<S>
 where
     R: Into<ExecutionResult<S>>,
@@ -240,6 +337,17 @@ where
 {
     trace!("Process {} spawned", id);
     tokio::pin!(fut);
+    // Every poll runs the process until it yields, the time is accounted as its CPU time.
+    let cpu_stats = stats.clone();
+    let fut = std::future::poll_fn(move |cx| {
+        let started = Instant::now();
+        let poll = fut.as_mut().poll(cx);
+        if let Some(stats) = &cpu_stats {
+            stats.add_cpu_time(started.elapsed());
+        }
+        poll
+    });
+    tokio::pin!(fut);
 
     // Defines what happens if one of the linked processes dies.
     // If the value is set to false, instead of dying too the process will receive a message about
@@ -247,6 +355,21 @@ where
     let mut die_when_link_dies = true;
     // Process linked to this one
     let mut links = HashMap::new();
+    // Children that are killed together with this process
+    let mut children: HashMap<u64, Arc<dyn Process>> = HashMap::new();
+    // Finished children are only forgotten once there are this many, so that spawning stays
+    // amortized O(1) while long running processes spawning many short lived children don't
+    // accumulate them.
+    let mut prune_children_at = MIN_PRUNE_CHILDREN_AT;
+    // Processes notified when this process finishes. IDs of remote monitors can collide with
+    // local ones, so they are not keyed by ID.
+    let mut monitors: Vec<Arc<dyn Process>> = Vec::new();
+    // Pending `JoinLinks` requests with the links each of them still waits for
+    let mut link_joins: Vec<(HashSet<u64>, oneshot::Sender<()>)> = Vec::new();
+    // If set to true, the process keeps running after the parent finished.
+    let mut detached = false;
+    // While suspended, only signals are handled and the `Future` is not polled.
+    let mut suspended = false;
     // TODO: Maybe wrapping this in some kind of `std::panic::catch_unwind` wold be a good idea,
     //       to protect against panics in host function calls that unwind through Wasm code.
     //       Currently a panic would just kill the task, but not notify linked processes.
@@ -290,16 +413,52 @@ where
                     // Remove process from list
                     Ok(Signal::UnLink { process_id }) => {
                         links.remove(&process_id);
+                        link_finished(&mut link_joins, process_id);
 
                         #[cfg(feature = "metrics")]
                         metrics::gauge!("lunatic.process.links.alive", links.len() as f64, &labels);
                     }
+                    Ok(Signal::AddChild(child)) => {
+                        if children.len() >= prune_children_at {
+                            children.retain(|child_id, _| env.get_process(*child_id).is_some());
+                            prune_children_at = (children.len() * 2).max(MIN_PRUNE_CHILDREN_AT);
+                        }
+                        children.insert(child.id(), child);
+                    }
+                    Ok(Signal::Monitor(monitor)) => monitors.push(monitor),
+                    Ok(Signal::JoinLinks(joined)) => {
+                        if links.is_empty() {
+                            joined.send(()).ok();
+                        } else {
+                            link_joins.push((links.keys().copied().collect(), joined));
+                        }
+                    }
+                    Ok(Signal::Detach) => detached = true,
+                    Ok(Signal::Suspend) => suspended = true,
+                    Ok(Signal::Start) => suspended = false,
+                    // The reaper's view can be outdated, a message or a watcher could have
+                    // arrived in the meantime.
+                    Ok(Signal::IdleTimeout(threshold)) => {
+                        let idle = message_mailbox
+                            .waiting_for()
+                            .is_some_and(|idle| idle >= threshold);
+                        if idle && links.is_empty() && monitors.is_empty() {
+                            break Finished::IdleTimeout(threshold)
+                        }
+                    }
+                    // Dying together with the parent has the same effect as a **kill** signal.
+                    Ok(Signal::ParentDied) => {
+                        if !detached {
+                            break Finished::KillSignal
+                        }
+                    }
                     // Exit loop and don't poll anymore the future if Signal::Kill received.
                     Ok(Signal::Kill) => break Finished::KillSignal,
                     // Depending if `die_when_link_dies` is set, process will die or turn the
                     // signal into a message
                     Ok(Signal::LinkDied(id, tag, reason)) => {
                         links.remove(&id);
+                        link_finished(&mut link_joins, id);
 
                         #[cfg(feature = "metrics")]
                         metrics::gauge!("lunatic.process.links.alive", links.len() as f64, &labels);
@@ -329,21 +488,55 @@ where
                         has_sender = false;
                     }
                 }
+                if let Some(stats) = &stats {
+                    stats.set_watchers(links.len() + monitors.len());
+                }
             }
             // Run process
-            output = &mut fut => { break Finished::Normal(output); }
+            output = &mut fut, if !suspended => { break Finished::Normal(output); }
         }
     };
 
     env.remove_process(id);
 
+    // Children spawned right before the process finished can still be queued, the other signals
+    // don't matter anymore.
+    while let Ok(signal) = signal_mailbox.try_recv() {
+        if let Signal::AddChild(child) = signal {
+            children.insert(child.id(), child);
+        }
+    }
+    // Tear down children that are still running, so that they don't outlive this process.
+    children
+        .values()
+        .for_each(|child| child.send(Signal::ParentDied));
+
+    let notify_monitors = |reason: ExitReason| {
+        monitors.iter().for_each(|monitor| {
+            let message = down_message(id, reason);
+            monitor.send(Signal::Message(Message::Data(message)));
+        });
+    };
+
+    // Labeled processes are reported as "<id> (<label>)" to make the logs easier to follow.
+    let process_name = match stats.and_then(|stats| stats.label()) {
+        Some(label) => format!("{id} ({label})"),
+        None => id.to_string(),
+    };
+
     match result {
         Finished::Normal(result) => {
             let result = result.into();
             if let Some(failure) = result.failure() {
-                warn!(
-                    "Process {} failed, notifying: {} links {}",
-                    id,
+                let (level, reason) = match result.trap_reason() {
+                    Some(reason) => (reason.log_level(), format!("{reason:?}")),
+                    None => (Level::Error, "unknown".to_string()),
+                };
+                log!(
+                    level,
+                    "Process {} failed ({}), notifying: {} links {}",
+                    process_name,
+                    reason,
                     links.len(),
                     // If the log level is WARN instruct user how to display the stacktrace
                     if !log_enabled!(Level::Debug) {
@@ -357,27 +550,62 @@ where
                 links.iter().for_each(|(_, (proc, tag))| {
                     proc.send(Signal::LinkDied(id, *tag, DeathReason::Failure));
                 });
-                Err(anyhow!(failure.to_string()))
+                notify_monitors(ExitReason::Trap(result.trap_reason()));
+                Err(ProcessFailure {
+                    message: failure.to_string(),
+                    backtrace: result.backtrace().map(|backtrace| backtrace.to_string()),
+                    reason: result.trap_reason(),
+                }
+                .into())
             } else {
+                if log_normal_exits {
+                    debug!("Process {} finished normally", process_name);
+                }
                 // Notify all links that we finished normally
                 links.iter().for_each(|(_, (proc, tag))| {
                     proc.send(Signal::LinkDied(id, *tag, DeathReason::Normal));
                 });
+                notify_monitors(ExitReason::Normal);
                 Ok(result.state())
             }
         }
         Finished::KillSignal => {
             warn!(
                 "Process {} was killed, notifying: {} links",
-                id,
+                process_name,
                 links.len()
             );
             // Notify all links that we finished because of a kill signal
             links.iter().for_each(|(_, (proc, tag))| {
                 proc.send(Signal::LinkDied(id, *tag, DeathReason::Failure));
             });
+            notify_monitors(ExitReason::Killed);
             Err(anyhow!("Process received Kill signal"))
         }
+        Finished::IdleTimeout(threshold) => {
+            info!(
+                "Process {} was idle for more than {:?}, terminating it",
+                process_name, threshold
+            );
+            #[cfg(feature = "metrics")]
+            metrics::increment_counter!("lunatic.process.idle_reaped", &labels);
+            notify_monitors(ExitReason::IdleTimeout);
+            Err(anyhow!("Process was idle for more than {:?}", threshold))
+        }
+    }
+}
+
+// Stops waiting for the link **id** and notifies the joins that don't wait for any other links.
+fn link_finished(link_joins: &mut Vec<(HashSet<u64>, oneshot::Sender<()>)>, id: u64) {
+    for (pending, _) in link_joins.iter_mut() {
+        pending.remove(&id);
+    }
+    let (joined, waiting): (Vec<_>, Vec<_>) = std::mem::take(link_joins)
+        .into_iter()
+        .partition(|(pending, _)| pending.is_empty());
+    *link_joins = waiting;
+    for (_, joined) in joined {
+        joined.send(()).ok();
     }
 }
 
@@ -388,6 +616,21 @@ pub struct NativeProcess {
     signal_mailbox: UnboundedSender<Signal>,
 }
 
+/// Asks a process to shut down gracefully.
+///
+/// A message tagged with [`SHUTDOWN_TAG`] is put into the mailbox of the process, giving it a
+/// chance to clean up and exit on its own. If it's still running after `grace`, it's killed.
+pub fn request_shutdown(process: Arc<dyn Process>, grace: Duration) {
+    let grace_ms = grace.as_millis() as u64;
+    let message = DataMessage::new_from_vec(Some(SHUTDOWN_TAG), grace_ms.to_le_bytes().to_vec());
+    process.send(Signal::Message(Message::Data(message)));
+    tokio::task::spawn(async move {
+        tokio::time::sleep(grace).await;
+        // If the process already finished, the signal is just dropped.
+        process.send(Signal::Kill);
+    });
+}
+
 /// Spawns a process from a closure.
 ///
 /// ## Example:
@@ -422,7 +665,15 @@ where
     };
     let fut = func(process.clone(), message_mailbox.clone());
     let signal_mailbox = Arc::new(Mutex::new(signal_mailbox));
-    let join = tokio::task::spawn(new(fut, id, env.clone(), signal_mailbox, message_mailbox));
+    let join = tokio::task::spawn(new(
+        fut,
+        id,
+        env.clone(),
+        signal_mailbox,
+        message_mailbox,
+        None,
+        false,
+    ));
     (join, process)
 }
 
@@ -450,12 +701,103 @@ impl Process for NativeProcess {
     }
 }
 
+/// The error returned from a process that finished with a failure.
+///
+/// If the failure was caused by a trap, the captured wasm backtrace can be retrieved with
+/// [`ProcessFailure::backtrace`] and the cause with [`ProcessFailure::reason`].
+#[derive(Debug)]
+pub struct ProcessFailure {
+    message: String,
+    backtrace: Option<String>,
+    reason: Option<TrapReason>,
+}
+
+impl ProcessFailure {
+    pub fn backtrace(&self) -> Option<&str> {
+        self.backtrace.as_deref()
+    }
+
+    /// Returns the cause of the trap, or `None` if the process failed without trapping.
+    pub fn reason(&self) -> Option<TrapReason> {
+        self.reason
+    }
+}
+
+/// The cause of a trap that terminated a process.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TrapReason {
+    /// An `unreachable` instruction was executed.
+    Unreachable,
+    StackOverflow,
+    /// A load or store outside of the linear memory or a misaligned atomic access.
+    OutOfBoundsMemory,
+    OutOfBoundsTable,
+    /// An indirect call to a null table entry or with a mismatching signature.
+    BadIndirectCall,
+    /// An integer overflow or a float to integer conversion that doesn't fit the integer.
+    IntegerOverflow,
+    IntegerDivisionByZero,
+    /// The process used up all of its fuel.
+    OutOfFuel,
+    /// The epoch deadline of the process was reached.
+    EpochDeadline,
+    /// The process called `proc_exit` with the contained non-zero code.
+    Exit(i32),
+    /// A host function returned an error.
+    HostError,
+    /// Any other trap raised by the runtime.
+    Other,
+}
+
+impl TrapReason {
+    /// Numeric code of the reason, starting at `1` in the order of the variants. `Exit` has the
+    /// code `10`, the exit status is not part of it.
+    pub fn code(&self) -> i32 {
+        match self {
+            TrapReason::Unreachable => 1,
+            TrapReason::StackOverflow => 2,
+            TrapReason::OutOfBoundsMemory => 3,
+            TrapReason::OutOfBoundsTable => 4,
+            TrapReason::BadIndirectCall => 5,
+            TrapReason::IntegerOverflow => 6,
+            TrapReason::IntegerDivisionByZero => 7,
+            TrapReason::OutOfFuel => 8,
+            TrapReason::EpochDeadline => 9,
+            TrapReason::Exit(_) => 10,
+            TrapReason::HostError => 11,
+            TrapReason::Other => 12,
+        }
+    }
+
+    /// Level at which a process failing for this reason is logged.
+    ///
+    /// Exiting with a non-zero code or running out of fuel or time are expected ways for a
+    /// process to end and are logged as warnings. Any other trap points to a bug and is logged as
+    /// an error.
+    pub fn log_level(&self) -> Level {
+        match self {
+            TrapReason::Exit(_) | TrapReason::OutOfFuel | TrapReason::EpochDeadline => Level::Warn,
+            _ => Level::Error,
+        }
+    }
+}
+
+impl std::fmt::Display for ProcessFailure {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl std::error::Error for ProcessFailure {}
+
 // Contains the result of a process execution.
 //
 // Can be also used to extract the state of a process after the execution is done.
 pub struct ExecutionResult<T> {
     state: T,
     result: ResultValue,
+    backtrace: Option<String>,
+    trap_reason: Option<TrapReason>,
 }
 
 impl<T> ExecutionResult<T> {
@@ -468,6 +810,16 @@ impl<T> ExecutionResult<T> {
         }
     }
 
+    // Returns the wasm backtrace if the process trapped.
+    pub fn backtrace(&self) -> Option<&str> {
+        self.backtrace.as_deref()
+    }
+
+    // Returns the cause of the trap if the process trapped.
+    pub fn trap_reason(&self) -> Option<TrapReason> {
+        self.trap_reason
+    }
+
     // Returns the process state
     pub fn state(self) -> T {
         self.state
@@ -484,10 +836,14 @@ where
             Ok(t) => ExecutionResult {
                 state: t,
                 result: ResultValue::Ok,
+                backtrace: None,
+                trap_reason: None,
             },
             Err(e) => ExecutionResult {
                 state: T::default(),
                 result: ResultValue::Failed(e.to_string()),
+                backtrace: None,
+                trap_reason: None,
             },
         }
     }
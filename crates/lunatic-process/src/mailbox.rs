@@ -3,8 +3,11 @@ use std::future::Future;
 use std::pin::Pin;
 use std::sync::{Arc, Mutex};
 use std::task::{Context, Poll, Waker};
+use std::time::{Duration, Instant};
 
-use crate::message::Message;
+use lunatic_common_api::CancelGuard;
+
+use crate::message::{Message, SerializedMessage};
 
 /// The `MessageMailbox` is a data structure holding all messages of a process.
 ///
@@ -27,6 +30,16 @@ struct InnerMessageMailbox {
     tags: Option<Vec<i64>>,
     found: Option<Message>,
     messages: VecDeque<Message>,
+    // Set while a `pop` is blocked waiting for a message.
+    waiting_since: Option<Instant>,
+    // Highest number of messages the mailbox held since it was created or the mark was reset.
+    high_water: usize,
+}
+
+impl InnerMessageMailbox {
+    fn len(&self) -> usize {
+        self.messages.len() + self.found.is_some() as usize
+    }
 }
 
 impl MessageMailbox {
@@ -69,7 +82,9 @@ impl MessageMailbox {
             }
             // Mark the tags to wait on.
             mailbox.tags = tags.map(|tags| tags.into());
+            mailbox.waiting_since = Some(Instant::now());
         }
+        let _guard = self.wait_guard();
         self.await
     }
 
@@ -105,10 +120,27 @@ impl MessageMailbox {
 
             // Mark the tags to wait on.
             mailbox.tags = tags.map(|tags| tags.into());
+            mailbox.waiting_since = Some(Instant::now());
         }
+        let _guard = self.wait_guard();
         self.await
     }
 
+    // Deregisters the waker and tags when the wait finishes or is canceled, so that a killed
+    // process doesn't leave its waker behind. A message found after the last poll is put back
+    // into the queue.
+    fn wait_guard(&self) -> CancelGuard<impl FnOnce() + '_> {
+        CancelGuard::new(move || {
+            let mut mailbox = self.inner.lock().expect("only accessed by one process");
+            mailbox.waker = None;
+            mailbox.tags = None;
+            mailbox.waiting_since = None;
+            if let Some(found) = mailbox.found.take() {
+                mailbox.messages.push_back(found);
+            }
+        })
+    }
+
     /// Pushes a message into the mailbox.
     ///
     /// If the message is being .awaited on, this call will immediately notify the waker that it's
@@ -128,6 +160,7 @@ impl MessageMailbox {
                         .contains(&message.tag().unwrap()))
             {
                 mailbox.found = Some(message);
+                mailbox.high_water = mailbox.high_water.max(mailbox.len());
                 waker.wake();
                 return;
             } else {
@@ -137,20 +170,105 @@ impl MessageMailbox {
         }
         // Otherwise put message into queue
         mailbox.messages.push_back(message);
+        mailbox.high_water = mailbox.high_water.max(mailbox.len());
+    }
+
+    /// Removes all messages from the mailbox and returns them in the order they would be popped.
+    ///
+    /// Together with [`reinject`](Self::reinject) it moves the pending messages of a process to
+    /// another mailbox. Messages without resources can be converted into a
+    /// [`SerializedMessage`](crate::message::SerializedMessage) to move them across nodes or
+    /// take a snapshot of the queue.
+    pub fn drain(&self) -> Vec<Message> {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        if let Some(found) = mailbox.found.take() {
+            mailbox.messages.push_back(found);
+        }
+        mailbox.messages.drain(..).collect()
+    }
+
+    /// Copies all messages in the order they would be popped, without removing them.
+    ///
+    /// Returns `None` if any of the messages carries resources, they can't leave the process.
+    pub fn snapshot(&self) -> Option<Vec<SerializedMessage>> {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox
+            .messages
+            .iter()
+            .chain(mailbox.found.iter())
+            .map(SerializedMessage::copy_of)
+            .collect()
+    }
+
+    /// Pushes the **messages** into the mailbox in order, after the messages it already holds.
+    pub fn reinject(&self, messages: impl IntoIterator<Item = Message>) {
+        for message in messages {
+            self.push(message);
+        }
+    }
+
+    /// Blocks until the mailbox contains at least one message, without removing it.
+    ///
+    /// This is cancellation safe, a message that arrives while waiting stays in the mailbox.
+    pub async fn ready(&self) {
+        let message = self.pop(None).await;
+        // Put it back to the front, so that the next `pop` returns it.
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.messages.push_front(message);
+    }
+
+    /// Calls `f` with a reference to the message that the next `pop(None)` would return,
+    /// without removing it from the mailbox.
+    ///
+    /// Returns `None` if the mailbox is empty.
+    pub fn peek<R>(&self, f: impl FnOnce(&Message) -> R) -> Option<R> {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        // A found message would be put back into the queue by the next `pop`, so do it here
+        // too to see the same order.
+        if let Some(found) = mailbox.found.take() {
+            mailbox.messages.push_back(found);
+        }
+        mailbox.messages.front().map(f)
+    }
+
+    /// Returns how long the process has been blocked waiting for a message, or `None` if it's
+    /// not waiting.
+    pub fn waiting_for(&self) -> Option<Duration> {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        // A found message wakes the process up, even if it didn't return from `pop` yet.
+        if mailbox.found.is_some() {
+            return None;
+        }
+        mailbox.waiting_since.map(|since| since.elapsed())
     }
 
     /// Returns the number of messages currently available
+    ///
+    /// This includes a message that was handed to a waiting `pop` that didn't return it yet.
     pub fn len(&self) -> usize {
         let mailbox = self.inner.lock().expect("only accessed by one process");
 
-        mailbox.messages.len()
+        mailbox.len()
+    }
+
+    /// Returns the highest number of messages the mailbox held since it was created or the mark
+    /// was last reset.
+    pub fn high_water(&self) -> usize {
+        let mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.high_water
+    }
+
+    /// Resets the high-water mark to the current number of messages.
+    pub fn reset_high_water(&self) {
+        let mut mailbox = self.inner.lock().expect("only accessed by one process");
+        mailbox.high_water = mailbox.len();
     }
 
     /// Returns true if the mailbox has no available messages
     pub fn is_empty(&self) -> bool {
         let mailbox = self.inner.lock().expect("only accessed by one process");
 
-        mailbox.messages.is_empty()
+        mailbox.messages.is_empty() && mailbox.found.is_none()
     }
 }
 
@@ -173,7 +291,7 @@ mod tests {
     use std::{
         future::Future,
         sync::{Arc, Mutex},
-        task::{Context, Poll, Wake},
+        task::{Context, Poll, Wake, Waker},
     };
 
     use super::{Message, MessageMailbox};
@@ -252,6 +370,54 @@ mod tests {
         assert_eq!(message.tag(), Some(tag5));
     }
 
+    #[tokio::test]
+    async fn peek_does_not_remove_message() {
+        let mailbox = MessageMailbox::default();
+        assert_eq!(mailbox.peek(|message| message.tag()), None);
+        mailbox.push(Message::LinkDied(Some(1)));
+        mailbox.push(Message::LinkDied(Some(2)));
+        assert_eq!(mailbox.peek(|message| message.tag()), Some(Some(1)));
+        assert_eq!(mailbox.len(), 2);
+        let message = mailbox.pop(None).await;
+        assert_eq!(message.tag(), Some(1));
+        assert_eq!(mailbox.peek(|message| message.tag()), Some(Some(2)));
+    }
+
+    #[tokio::test]
+    async fn high_water_tracks_peak_depth() {
+        let mailbox = MessageMailbox::default();
+        for tag in 1..=3 {
+            mailbox.push(Message::LinkDied(Some(tag)));
+        }
+        mailbox.pop(None).await;
+        mailbox.pop(None).await;
+        mailbox.push(Message::LinkDied(Some(4)));
+        assert_eq!(mailbox.len(), 2);
+        assert_eq!(mailbox.high_water(), 3);
+        mailbox.reset_high_water();
+        assert_eq!(mailbox.high_water(), 2);
+        mailbox.pop(None).await;
+        assert_eq!(mailbox.high_water(), 2);
+        mailbox.push(Message::LinkDied(Some(5)));
+        mailbox.push(Message::LinkDied(Some(6)));
+        assert_eq!(mailbox.high_water(), 3);
+    }
+
+    #[tokio::test]
+    async fn ready_waits_without_removing_message() {
+        let mailbox = MessageMailbox::default();
+        let waiting = mailbox.clone();
+        let ready = tokio::spawn(async move { waiting.ready().await });
+        tokio::task::yield_now().await;
+        mailbox.push(Message::LinkDied(Some(1)));
+        ready.await.unwrap();
+        mailbox.push(Message::LinkDied(Some(2)));
+        mailbox.ready().await;
+        assert_eq!(mailbox.len(), 2);
+        assert_eq!(mailbox.pop(None).await.tag(), Some(1));
+        assert_eq!(mailbox.pop(None).await.tag(), Some(2));
+    }
+
     #[derive(Clone)]
     struct FlagWaker(Arc<Mutex<bool>>);
     impl Wake for FlagWaker {
@@ -317,6 +483,70 @@ mod tests {
         assert!(result.is_ready());
     }
 
+    #[test]
+    fn canceled_pop_releases_waker() {
+        let mailbox = MessageMailbox::default();
+        let flag_waker = Arc::new(FlagWaker(Arc::new(Mutex::new(false))));
+        let weak_waker = Arc::downgrade(&flag_waker);
+        let waker: Waker = flag_waker.into();
+        let mut context = Context::from_waker(&waker);
+        let mut fut = Box::pin(mailbox.pop(Some(&[1337])));
+        assert!(fut.as_mut().poll(&mut context).is_pending());
+        // Canceling the future must not leave the waker registered in the mailbox
+        drop(fut);
+        drop(waker);
+        assert!(weak_waker.upgrade().is_none());
+        // A message pushed afterwards is queued, even if it has the awaited tag
+        mailbox.push(Message::LinkDied(Some(1337)));
+        assert_eq!(mailbox.len(), 1);
+    }
+
+    #[test]
+    fn len_counts_message_found_by_waiting_pop() {
+        let mailbox = MessageMailbox::default();
+        let flag_waker = Arc::new(FlagWaker(Arc::new(Mutex::new(false))));
+        let waker: Waker = flag_waker.into();
+        let mut context = Context::from_waker(&waker);
+        let mut fut = Box::pin(mailbox.pop(None));
+        assert!(fut.as_mut().poll(&mut context).is_pending());
+        // The message is handed to the waiting `pop`, but it wasn't polled again yet
+        mailbox.push(Message::LinkDied(Some(1)));
+        assert_eq!(mailbox.len(), 1);
+        assert!(!mailbox.is_empty());
+        drop(fut);
+        assert_eq!(mailbox.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn drained_messages_are_reinjected_in_order() {
+        use crate::message::{DataMessage, SerializedMessage};
+
+        let mailbox = MessageMailbox::default();
+        for tag in [3, 1, 2] {
+            let message = DataMessage::new_from_vec(Some(tag), vec![tag as u8; 4]);
+            mailbox.push(Message::Data(message));
+        }
+        let serialized: Vec<SerializedMessage> = mailbox
+            .drain()
+            .into_iter()
+            .map(|message| SerializedMessage::try_from(message).unwrap())
+            .collect();
+        assert!(mailbox.is_empty());
+
+        let fresh = MessageMailbox::default();
+        fresh.reinject(serialized.into_iter().map(Message::from));
+        assert_eq!(fresh.len(), 3);
+        for tag in [3, 1, 2] {
+            match fresh.pop(None).await {
+                Message::Data(message) => {
+                    assert_eq!(message.tag, Some(tag));
+                    assert_eq!(message.buffer, vec![tag as u8; 4]);
+                }
+                _ => panic!("Wrong message received"),
+            }
+        }
+    }
+
     #[test]
     fn cancellation_safety() {
         let mailbox = MessageMailbox::default();
@@ -12,12 +12,44 @@ use std::{
 };
 
 use lunatic_networking_api::{TcpConnection, TlsConnection};
+use serde::{Deserialize, Serialize};
 use tokio::net::UdpSocket;
 
-use crate::runtimes::wasmtime::WasmtimeCompiledModule;
+use crate::{runtimes::wasmtime::WasmtimeCompiledModule, ExitReason};
 
 pub type Resource = dyn Any + Send + Sync;
 
+/// Tag of the [`DataMessage`] put into the mailbox of a process that was asked to shut down with
+/// [`request_shutdown`](crate::request_shutdown). The message buffer contains the grace period in
+/// milliseconds, encoded as a little endian `u64`.
+pub const SHUTDOWN_TAG: i64 = i64::MIN;
+
+/// Tag of the [`DataMessage`] that processes receive when a process they monitor finishes, see
+/// [`down_message`].
+pub const DOWN_TAG: i64 = i64::MIN + 1;
+
+/// Creates the message sent to the monitors of process **process_id** when it finishes.
+///
+/// The message is tagged with [`DOWN_TAG`] and the buffer contains the process ID (`u64`),
+/// followed by the code (`u32`) and detail (`i32`) of the [`ExitReason`], all little endian.
+pub fn down_message(process_id: u64, reason: ExitReason) -> DataMessage {
+    let (code, detail) = reason.encode();
+    let mut buffer = Vec::with_capacity(16);
+    buffer.extend(process_id.to_le_bytes());
+    buffer.extend(code.to_le_bytes());
+    buffer.extend(detail.to_le_bytes());
+    DataMessage::new_from_vec(Some(DOWN_TAG), buffer)
+}
+
+/// Address of a process waiting for the reply to a request sent with `lunatic::message::call`.
+///
+/// The reply needs to be tagged with `tag`, the caller only waits on this specific tag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ReplyTo {
+    pub process_id: u64,
+    pub tag: i64,
+}
+
 /// Can be sent between processes by being embedded into a  [`Signal::Message`][0]
 ///
 /// A [`Message`] has 2 variants:
@@ -39,6 +71,14 @@ impl Message {
         }
     }
 
+    /// Returns the size of the message buffer in bytes.
+    pub fn size(&self) -> usize {
+        match self {
+            Message::Data(message) => message.size(),
+            Message::LinkDied(_) => 0,
+        }
+    }
+
     #[cfg(feature = "metrics")]
     pub fn write_metrics(&self) {
         match self {
@@ -50,6 +90,73 @@ impl Message {
     }
 }
 
+/// A [`Message`] without resources, that can be serialized and turned back into a message, e.g.
+/// to move the mailbox of a process with [`MessageMailbox::drain`][0] and
+/// [`MessageMailbox::reinject`][1].
+///
+/// [0]: crate::mailbox::MessageMailbox::drain
+/// [1]: crate::mailbox::MessageMailbox::reinject
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SerializedMessage {
+    Data {
+        tag: Option<i64>,
+        buffer: Vec<u8>,
+        reply_to: Option<ReplyTo>,
+    },
+    LinkDied(Option<i64>),
+}
+
+impl SerializedMessage {
+    /// Copies the **message**, returns `None` if it carries resources.
+    pub fn copy_of(message: &Message) -> Option<Self> {
+        match message {
+            Message::Data(data) if data.resources.iter().any(Option::is_some) => None,
+            Message::Data(data) => Some(SerializedMessage::Data {
+                tag: data.tag,
+                buffer: data.buffer.clone(),
+                reply_to: data.reply_to,
+            }),
+            Message::LinkDied(tag) => Some(SerializedMessage::LinkDied(*tag)),
+        }
+    }
+}
+
+impl TryFrom<Message> for SerializedMessage {
+    type Error = Message;
+
+    /// Fails with the original message if it carries resources, they are local to the node.
+    fn try_from(message: Message) -> Result<Self, Self::Error> {
+        match message {
+            Message::Data(data) if data.resources.iter().any(Option::is_some) => {
+                Err(Message::Data(data))
+            }
+            Message::Data(data) => Ok(SerializedMessage::Data {
+                tag: data.tag,
+                buffer: data.buffer,
+                reply_to: data.reply_to,
+            }),
+            Message::LinkDied(tag) => Ok(SerializedMessage::LinkDied(tag)),
+        }
+    }
+}
+
+impl From<SerializedMessage> for Message {
+    fn from(message: SerializedMessage) -> Self {
+        match message {
+            SerializedMessage::Data {
+                tag,
+                buffer,
+                reply_to,
+            } => {
+                let mut data = DataMessage::new_from_vec(tag, buffer);
+                data.reply_to = reply_to;
+                Message::Data(data)
+            }
+            SerializedMessage::LinkDied(tag) => Message::LinkDied(tag),
+        }
+    }
+}
+
 /// A variant of a [`Message`] that has a buffer of data and resources attached to it.
 ///
 /// It implements the [`Read`](std::io::Read) and [`Write`](std::io::Write) traits.
@@ -60,6 +167,8 @@ pub struct DataMessage {
     pub read_ptr: usize,
     pub buffer: Vec<u8>,
     pub resources: Vec<Option<Arc<Resource>>>,
+    // Set if the message is a request expecting a reply.
+    pub reply_to: Option<ReplyTo>,
 }
 
 impl DataMessage {
@@ -70,6 +179,7 @@ impl DataMessage {
             read_ptr: 0,
             buffer: Vec::with_capacity(buffer_capacity),
             resources: Vec::new(),
+            reply_to: None,
         }
     }
 
@@ -80,6 +190,7 @@ impl DataMessage {
             read_ptr: 0,
             buffer,
             resources: Vec::new(),
+            reply_to: None,
         }
     }
 
@@ -98,6 +98,11 @@ impl<T: ProcessState + 'static> Modules<T> {
         self.modules.get(&module_id).map(|m| m.clone())
     }
 
+    /// Adds an already compiled module under **module_id**.
+    pub fn insert(&self, module_id: u64, module: Arc<WasmtimeCompiledModule<T>>) {
+        self.modules.insert(module_id, module);
+    }
+
     pub fn compile(
         &self,
         runtime: WasmtimeRuntime,
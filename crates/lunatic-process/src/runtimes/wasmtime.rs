@@ -1,12 +1,13 @@
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
+use lunatic_common_api::wasm_backtrace;
 use wasmtime::ResourceLimiter;
 
 use crate::{
     config::{ProcessConfig, UNIT_OF_COMPUTE_IN_INSTRUCTIONS},
     state::ProcessState,
-    ExecutionResult, ResultValue,
+    ExecutionResult, ResultValue, TrapReason,
 };
 
 use super::RawWasm;
@@ -14,12 +15,81 @@ use super::RawWasm;
 #[derive(Clone)]
 pub struct WasmtimeRuntime {
     engine: wasmtime::Engine,
+    features: WasmFeatures,
+    // Resolve unknown WASI imports with stubs instead of rejecting the module
+    stub_missing_wasi: bool,
 }
 
+/// Namespace of the WASI functions that can be stubbed, see
+/// [`WasmtimeRuntime::set_stub_missing_wasi`].
+pub const WASI_NAMESPACE: &str = "wasi_snapshot_preview1";
+
+/// WASI errno returned by stubbed functions: "Function not supported."
+pub const WASI_ENOSYS: i32 = 52;
+
 impl WasmtimeRuntime {
     pub fn new(config: &wasmtime::Config) -> Result<Self> {
         let engine = wasmtime::Engine::new(config)?;
-        Ok(Self { engine })
+        Ok(Self {
+            engine,
+            features: WasmFeatures::default(),
+            stub_missing_wasi: false,
+        })
+    }
+
+    /// Creates a runtime from the [`default_config`] with only the given WebAssembly proposals
+    /// enabled.
+    pub fn with_features(features: WasmFeatures) -> Result<Self> {
+        Self::with_opt_level(features, OptLevel::default())
+    }
+
+    /// Like [`with_features`](Self::with_features), but modules are compiled with the optimization
+    /// level **opt_level**.
+    pub fn with_opt_level(features: WasmFeatures, opt_level: OptLevel) -> Result<Self> {
+        let mut config = default_config();
+        features.apply(&mut config);
+        opt_level.apply(&mut config);
+        let engine = wasmtime::Engine::new(&config)?;
+        Ok(Self {
+            engine,
+            features,
+            stub_missing_wasi: false,
+        })
+    }
+
+    /// If set, modules importing `wasi_snapshot_preview1` functions that the runtime doesn't
+    /// implement are still accepted. The missing functions return [`WASI_ENOSYS`] when called.
+    ///
+    /// Off by default, the compilation fails with [`UnresolvedImports`] instead.
+    pub fn set_stub_missing_wasi(&mut self, stub: bool) {
+        self.stub_missing_wasi = stub;
+    }
+
+    pub fn stubs_missing_wasi(&self) -> bool {
+        self.stub_missing_wasi
+    }
+
+    /// Returns the WebAssembly proposals enabled for this runtime.
+    pub fn features(&self) -> WasmFeatures {
+        self.features
+    }
+
+    /// Returns the sorted namespaces of the host functions available to processes with the
+    /// state `T`, e.g. `lunatic::process`.
+    pub fn host_namespaces<T>(&self) -> Result<Vec<String>>
+    where
+        T: ProcessState,
+    {
+        let mut linker = wasmtime::Linker::new(&self.engine);
+        <T as ProcessState>::register(&mut linker)?;
+        let mut store = wasmtime::Store::new(&self.engine, T::state_for_instantiation());
+        let mut namespaces: Vec<String> = linker
+            .iter(&mut store)
+            .map(|(namespace, _, _)| namespace.to_string())
+            .collect();
+        namespaces.sort();
+        namespaces.dedup();
+        Ok(namespaces)
     }
 
     /// Compiles a wasm module to machine code and performs type-checking on host functions.
@@ -27,6 +97,7 @@ impl WasmtimeRuntime {
     where
         T: ProcessState,
     {
+        self.features.validate(data.as_slice())?;
         let module = wasmtime::Module::new(&self.engine, data.as_slice())?;
         let mut linker = wasmtime::Linker::new(&self.engine);
         // Register host functions to linker.
@@ -38,11 +109,90 @@ impl WasmtimeRuntime {
         // `default_state` should never be accessed and it's safe to use a "fake" state here.
         let default_state = T::state_for_instantiation();
         let mut store = wasmtime::Store::new(&self.engine, default_state);
-        let instance_pre = linker.instantiate_pre(&mut store, &module)?;
+        if self.stub_missing_wasi {
+            stub_missing_wasi(&mut linker, &mut store, &module)?;
+        }
+        let instance_pre = match linker.instantiate_pre(&mut store, &module) {
+            Ok(instance_pre) => instance_pre,
+            Err(error) => {
+                // Wasmtime only reports the first missing import, list all of them instead.
+                let missing: Vec<_> = module
+                    .imports()
+                    .filter(|import| {
+                        linker
+                            .get(&mut store, import.module(), import.name())
+                            .is_none()
+                    })
+                    .map(|import| (import.module().to_string(), import.name().to_string()))
+                    .collect();
+                if missing.is_empty() {
+                    return Err(error);
+                }
+                return Err(UnresolvedImports(missing).into());
+            }
+        };
         let compiled_module = WasmtimeCompiledModule::new(data, module, instance_pre);
         Ok(compiled_module)
     }
 
+    /// Checks that the module would be accepted by this runtime, without compiling it.
+    ///
+    /// Besides validating the module and the enabled WebAssembly features, this also checks the
+    /// import namespaces and the entry function of `requirements`.
+    pub fn validate_module(
+        &self,
+        wasm: &[u8],
+        requirements: &ModuleRequirements,
+    ) -> Result<(), ValidationError> {
+        // Validate with all features first, so that broken modules are not reported as using a
+        // disabled feature.
+        wasmparser::Validator::new_with_features(wasmparser::WasmFeatures {
+            multi_memory: true,
+            ..Default::default()
+        })
+        .validate_all(wasm)
+        .map_err(|error| ValidationError::Invalid(error.to_string()))?;
+        self.features
+            .validate(wasm)
+            .map_err(|error| ValidationError::DisabledFeature(error.to_string()))?;
+        wasmtime::Module::validate(&self.engine, wasm)
+            .map_err(|error| ValidationError::Invalid(error.to_string()))?;
+
+        let mut has_entry = requirements.entry.is_none();
+        for payload in wasmparser::Parser::new(0).parse_all(wasm) {
+            match payload.map_err(|error| ValidationError::Invalid(error.to_string()))? {
+                wasmparser::Payload::ImportSection(imports) => {
+                    for import in imports {
+                        let import =
+                            import.map_err(|error| ValidationError::Invalid(error.to_string()))?;
+                        if !requirements.allows_import(import.module) {
+                            return Err(ValidationError::ImportNotAllowed {
+                                module: import.module.to_string(),
+                                name: import.name.to_string(),
+                            });
+                        }
+                    }
+                }
+                wasmparser::Payload::ExportSection(exports) => {
+                    for export in exports {
+                        let export =
+                            export.map_err(|error| ValidationError::Invalid(error.to_string()))?;
+                        if export.kind == wasmparser::ExternalKind::Func
+                            && requirements.entry.as_deref() == Some(export.name)
+                        {
+                            has_entry = true;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        match requirements.entry {
+            Some(ref entry) if !has_entry => Err(ValidationError::MissingEntry(entry.clone())),
+            _ => Ok(()),
+        }
+    }
+
     pub async fn instantiate<T>(
         &self,
         compiled_module: &WasmtimeCompiledModule<T>,
@@ -76,6 +226,45 @@ impl WasmtimeRuntime {
     }
 }
 
+// Defines a stub for every function the module imports from the WASI namespace that is not in
+// the linker yet. Stubs return `WASI_ENOSYS` if the function returns an errno and trap otherwise.
+fn stub_missing_wasi<T>(
+    linker: &mut wasmtime::Linker<T>,
+    store: &mut wasmtime::Store<T>,
+    module: &wasmtime::Module,
+) -> Result<()> {
+    for import in module.imports() {
+        let func_ty = match import.ty() {
+            wasmtime::ExternType::Func(func_ty) if import.module() == WASI_NAMESPACE => func_ty,
+            _ => continue,
+        };
+        if linker
+            .get(&mut *store, WASI_NAMESPACE, import.name())
+            .is_some()
+        {
+            continue;
+        }
+        let name = import.name().to_string();
+        let returns_errno = func_ty.results().eq([wasmtime::ValType::I32]);
+        linker.func_new(
+            WASI_NAMESPACE,
+            import.name(),
+            func_ty,
+            move |_, _, results| {
+                if returns_errno {
+                    results[0] = wasmtime::Val::I32(WASI_ENOSYS);
+                    Ok(())
+                } else {
+                    Err(wasmtime::Trap::new(format!(
+                        "{WASI_NAMESPACE}::{name} is not supported by this runtime"
+                    )))
+                }
+            },
+        )?;
+    }
+    Ok(())
+}
+
 pub struct WasmtimeCompiledModule<T> {
     inner: Arc<WasmtimeCompiledModuleInner<T>>,
 }
@@ -140,6 +329,8 @@ where
             return ExecutionResult {
                 state: self.store.into_data(),
                 result: ResultValue::SpawnError(format!("Function '{}' not found", function)),
+                backtrace: None,
+                trap_reason: None,
             };
         }
 
@@ -148,8 +339,17 @@ where
             .call_async(&mut self.store, &params, &mut [])
             .await;
 
+        let trap = result
+            .as_ref()
+            .err()
+            .and_then(|err| err.downcast_ref::<wasmtime::Trap>());
+        let backtrace = trap.and_then(wasm_backtrace);
+        let trap_reason = trap.map(classify_trap);
+
         ExecutionResult {
             state: self.store.into_data(),
+            backtrace,
+            trap_reason,
             result: match result {
                 Ok(()) => ResultValue::Ok,
                 Err(err) => {
@@ -175,6 +375,221 @@ where
     }
 }
 
+// Classifies the cause of a trap from its trap code.
+fn classify_trap(trap: &wasmtime::Trap) -> TrapReason {
+    use wasmtime::TrapCode;
+
+    if let Some(status) = trap.i32_exit_status() {
+        return TrapReason::Exit(status);
+    }
+    match trap.trap_code() {
+        Some(TrapCode::UnreachableCodeReached) => TrapReason::Unreachable,
+        Some(TrapCode::StackOverflow) => TrapReason::StackOverflow,
+        Some(TrapCode::MemoryOutOfBounds) | Some(TrapCode::HeapMisaligned) => {
+            TrapReason::OutOfBoundsMemory
+        }
+        Some(TrapCode::TableOutOfBounds) => TrapReason::OutOfBoundsTable,
+        Some(TrapCode::IndirectCallToNull) | Some(TrapCode::BadSignature) => {
+            TrapReason::BadIndirectCall
+        }
+        Some(TrapCode::IntegerOverflow) | Some(TrapCode::BadConversionToInteger) => {
+            TrapReason::IntegerOverflow
+        }
+        Some(TrapCode::IntegerDivisionByZero) => TrapReason::IntegerDivisionByZero,
+        Some(TrapCode::Interrupt) => TrapReason::EpochDeadline,
+        Some(_) => TrapReason::Other,
+        // Running out of fuel is reported as a trap without a code
+        None if trap.to_string().contains("all fuel consumed") => TrapReason::OutOfFuel,
+        None => TrapReason::HostError,
+    }
+}
+
+/// How much effort Cranelift spends on optimizing the code of compiled modules.
+///
+/// Less optimization makes compilation faster, which pays off for short-lived processes. Long
+/// running processes profit from the faster code of the optimizing levels.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OptLevel {
+    /// No optimizations, the fastest compilation.
+    None,
+    /// Optimizes for the execution speed of the generated code.
+    Speed,
+    /// Optimizes for execution speed and code size, the default.
+    #[default]
+    SpeedAndSize,
+}
+
+impl OptLevel {
+    /// Sets the Cranelift optimization level on a wasmtime `Config`.
+    pub fn apply(&self, config: &mut wasmtime::Config) {
+        config.cranelift_opt_level(match self {
+            OptLevel::None => wasmtime::OptLevel::None,
+            OptLevel::Speed => wasmtime::OptLevel::Speed,
+            OptLevel::SpeedAndSize => wasmtime::OptLevel::SpeedAndSize,
+        });
+    }
+}
+
+impl std::str::FromStr for OptLevel {
+    type Err = String;
+
+    fn from_str(level: &str) -> Result<Self, Self::Err> {
+        match level {
+            "none" => Ok(OptLevel::None),
+            "speed" => Ok(OptLevel::Speed),
+            "speed-and-size" => Ok(OptLevel::SpeedAndSize),
+            _ => Err(format!(
+                "unknown optimization level `{level}`, expected `none`, `speed` or `speed-and-size`"
+            )),
+        }
+    }
+}
+
+/// WebAssembly proposals that can be turned off for a runtime.
+///
+/// All of them are enabled by default.
+#[derive(Clone, Copy, Debug)]
+pub struct WasmFeatures {
+    pub simd: bool,
+    pub bulk_memory: bool,
+    pub reference_types: bool,
+}
+
+impl Default for WasmFeatures {
+    fn default() -> Self {
+        Self {
+            simd: true,
+            bulk_memory: true,
+            reference_types: true,
+        }
+    }
+}
+
+impl WasmFeatures {
+    /// Sets the feature flags on a wasmtime `Config`.
+    ///
+    /// Wasmtime requires bulk memory for reference types, so disabling bulk memory without also
+    /// disabling reference types results in an error when the engine is created.
+    pub fn apply(&self, config: &mut wasmtime::Config) {
+        config
+            .wasm_simd(self.simd)
+            .wasm_bulk_memory(self.bulk_memory)
+            .wasm_reference_types(self.reference_types);
+    }
+
+    /// Checks that the module doesn't use any of the disabled proposals.
+    ///
+    /// Wasmtime would also reject such modules, but this gives an error naming the feature.
+    pub fn validate(&self, wasm: &[u8]) -> Result<()> {
+        type Disable = fn(&mut wasmparser::WasmFeatures);
+        let proposals: [(&str, bool, Disable); 3] = [
+            ("simd", self.simd, |features| features.simd = false),
+            ("bulk-memory", self.bulk_memory, |features| {
+                features.bulk_memory = false
+            }),
+            ("reference-types", self.reference_types, |features| {
+                features.reference_types = false
+            }),
+        ];
+        for (proposal, enabled, disable) in proposals {
+            if enabled {
+                continue;
+            }
+            // Only disable one proposal at a time to find out which one the module depends on.
+            let mut features = wasmparser::WasmFeatures {
+                multi_memory: true,
+                ..Default::default()
+            };
+            disable(&mut features);
+            if wasmparser::Validator::new_with_features(features)
+                .validate_all(wasm)
+                .is_err()
+            {
+                return Err(anyhow!(
+                    "Module uses the `{}` WebAssembly feature, but it's disabled",
+                    proposal
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Policy checked by [`WasmtimeRuntime::validate_module`].
+#[derive(Clone, Debug, Default)]
+pub struct ModuleRequirements {
+    /// Name of a function that the module needs to export, e.g. `_start`.
+    pub entry: Option<String>,
+    /// Import namespaces the module is allowed to use. A namespace ending with `*` matches all
+    /// namespaces starting with it (e.g. `lunatic::*`). If `None`, all imports are allowed.
+    pub allowed_imports: Option<Vec<String>>,
+}
+
+impl ModuleRequirements {
+    fn allows_import(&self, module: &str) -> bool {
+        match self.allowed_imports {
+            Some(ref allowed) => {
+                allowed
+                    .iter()
+                    .any(|namespace| match namespace.strip_suffix('*') {
+                        Some(prefix) => module.starts_with(prefix),
+                        None => module == namespace,
+                    })
+            }
+            None => true,
+        }
+    }
+}
+
+/// The reason a module was rejected by [`WasmtimeRuntime::validate_module`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ValidationError {
+    /// The module is not valid WebAssembly.
+    Invalid(String),
+    /// The module uses a WebAssembly feature that is disabled for the runtime.
+    DisabledFeature(String),
+    /// The module imports from a namespace that is not allowed.
+    ImportNotAllowed { module: String, name: String },
+    /// The module doesn't export the required entry function.
+    MissingEntry(String),
+}
+
+impl std::fmt::Display for ValidationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationError::Invalid(error) => write!(f, "Invalid module: {}", error),
+            ValidationError::DisabledFeature(error) => write!(f, "{}", error),
+            ValidationError::ImportNotAllowed { module, name } => {
+                write!(f, "Import `{}::{}` is not allowed", module, name)
+            }
+            ValidationError::MissingEntry(entry) => {
+                write!(f, "Module doesn't export the entry function `{}`", entry)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ValidationError {}
+
+/// Imports of a module that are not provided by the host, as `(module, name)` pairs.
+#[derive(Debug, PartialEq, Eq)]
+pub struct UnresolvedImports(pub Vec<(String, String)>);
+
+impl std::fmt::Display for UnresolvedImports {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "Module imports functions that are not provided by the host:"
+        )?;
+        for (module, name) in &self.0 {
+            write!(f, " `{}::{}`", module, name)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for UnresolvedImports {}
+
 pub fn default_config() -> wasmtime::Config {
     let mut config = wasmtime::Config::new();
     config
@@ -186,6 +601,9 @@ pub fn default_config() -> wasmtime::Config {
         .wasm_bulk_memory(true)
         .wasm_multi_value(true)
         .wasm_multi_memory(true)
+        // Backtraces are always captured on traps, `WASMTIME_BACKTRACE_DETAILS=1` also resolves
+        // source locations from the debug info.
+        .wasm_backtrace_details(wasmtime::WasmBacktraceDetails::Environment)
         .cranelift_opt_level(wasmtime::OptLevel::SpeedAndSize)
         // Allocate resources on demand because we can't predict how many process will exist
         .allocation_strategy(wasmtime::InstanceAllocationStrategy::OnDemand)
@@ -193,3 +611,120 @@ pub fn default_config() -> wasmtime::Config {
         .static_memory_forced(true);
     config
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        default_config, ModuleRequirements, OptLevel, ValidationError, WasmFeatures,
+        WasmtimeRuntime,
+    };
+
+    const SIMD_MODULE: &str = r#"
+        (module
+            (func (export "hello") (result v128)
+                (v128.const i32x4 1 2 3 4)))
+    "#;
+
+    #[test]
+    fn simd_module_rejected_when_disabled() {
+        let wasm = wat::parse_str(SIMD_MODULE).unwrap();
+        let features = WasmFeatures {
+            simd: false,
+            ..Default::default()
+        };
+        let error = features.validate(&wasm).unwrap_err();
+        assert!(error.to_string().contains("`simd`"));
+    }
+
+    #[test]
+    fn simd_module_accepted_when_enabled() {
+        let wasm = wat::parse_str(SIMD_MODULE).unwrap();
+        WasmFeatures::default().validate(&wasm).unwrap();
+        WasmFeatures {
+            bulk_memory: false,
+            reference_types: false,
+            ..Default::default()
+        }
+        .validate(&wasm)
+        .unwrap();
+    }
+
+    #[test]
+    fn opt_level_parses_from_str() {
+        assert_eq!("none".parse(), Ok(OptLevel::None));
+        assert_eq!("speed".parse(), Ok(OptLevel::Speed));
+        assert_eq!("speed-and-size".parse(), Ok(OptLevel::SpeedAndSize));
+        assert!("fast".parse::<OptLevel>().is_err());
+    }
+
+    const VALID_MODULE: &str = r#"
+        (module
+            (import "lunatic::process" "sleep_ms" (func (param i64)))
+            (func (export "_start")))
+    "#;
+
+    fn requirements() -> ModuleRequirements {
+        ModuleRequirements {
+            entry: Some("_start".to_string()),
+            allowed_imports: Some(vec!["lunatic::*".to_string()]),
+        }
+    }
+
+    #[test]
+    fn validate_module_accepts_valid_module() {
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let wasm = wat::parse_str(VALID_MODULE).unwrap();
+        runtime.validate_module(&wasm, &requirements()).unwrap();
+        runtime
+            .validate_module(&wasm, &ModuleRequirements::default())
+            .unwrap();
+    }
+
+    #[test]
+    fn validate_module_rejects_each_violation() {
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let error = runtime
+            .validate_module(b"\0asm garbage", &requirements())
+            .unwrap_err();
+        assert!(matches!(error, ValidationError::Invalid(_)));
+
+        let wasm = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "proc_exit" (func (param i32)))
+                (func (export "_start")))
+            "#,
+        )
+        .unwrap();
+        let error = runtime.validate_module(&wasm, &requirements()).unwrap_err();
+        assert_eq!(
+            error,
+            ValidationError::ImportNotAllowed {
+                module: "wasi_snapshot_preview1".to_string(),
+                name: "proc_exit".to_string()
+            }
+        );
+
+        let wasm = wat::parse_str(VALID_MODULE).unwrap();
+        let requirements = ModuleRequirements {
+            entry: Some("main".to_string()),
+            ..requirements()
+        };
+        let error = runtime.validate_module(&wasm, &requirements).unwrap_err();
+        assert_eq!(error, ValidationError::MissingEntry("main".to_string()));
+    }
+
+    #[test]
+    fn validate_module_rejects_disabled_feature() {
+        let runtime = WasmtimeRuntime::with_features(WasmFeatures {
+            simd: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let wasm = wat::parse_str(SIMD_MODULE).unwrap();
+        let error = runtime
+            .validate_module(&wasm, &ModuleRequirements::default())
+            .unwrap_err();
+        assert!(matches!(error, ValidationError::DisabledFeature(_)));
+    }
+}
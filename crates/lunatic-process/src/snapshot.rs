@@ -0,0 +1,36 @@
+//! Snapshots of the state that lunatic keeps for a process, as a foundation for moving processes
+//! between nodes.
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+use crate::message::SerializedMessage;
+
+/// The state lunatic manages for a process, that can be restored into another process.
+///
+/// The linear memory of the guest is not part of the snapshot, neither are resources that are
+/// local to the node (e.g. sockets or messages carrying them). Links and monitors are held by the
+/// running process and need to be set up again by the process the snapshot is restored into.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ProcessSnapshot {
+    pub label: Option<String>,
+    /// Pending messages in the order they would be received.
+    pub mailbox: Vec<SerializedMessage>,
+    /// Entries of the process-local key/value store, sorted by key.
+    pub kv_store: Vec<(Vec<u8>, Vec<u8>)>,
+    pub trap_exit: bool,
+    /// The WASI environment is fixed when a process is created, the restoring side needs to
+    /// spawn the process with the same environment variables and working directory.
+    pub environment_variables: Vec<(String, String)>,
+    pub cwd: Option<String>,
+}
+
+impl ProcessSnapshot {
+    pub fn encode(&self) -> Vec<u8> {
+        bincode::serialize(self).expect("snapshot can always be serialized")
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Self> {
+        Ok(bincode::deserialize(bytes)?)
+    }
+}
@@ -11,6 +11,7 @@ use wasmtime::Linker;
 
 use crate::{
     config::ProcessConfig,
+    env::ProcessStats,
     mailbox::MessageMailbox,
     runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime},
     Signal,
@@ -59,6 +60,8 @@ pub trait ProcessState: Sized {
     fn signal_mailbox(&self) -> &(SignalSender, SignalReceiver);
     // Returns message mailbox
     fn message_mailbox(&self) -> &MessageMailbox;
+    // Returns the accounting data shared with the environment
+    fn stats(&self) -> &ProcessStats;
 
     // Config resources
     fn config_resources(&self) -> &ConfigResources<Self::Config>;
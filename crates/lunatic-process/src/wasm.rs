@@ -1,10 +1,11 @@
 use std::sync::Arc;
 
-use anyhow::Result;
+use anyhow::{anyhow, Result};
 use log::trace;
 use tokio::task::JoinHandle;
 use wasmtime::{ResourceLimiter, Val};
 
+use crate::config::ProcessConfig;
 use crate::env::Environment;
 use crate::runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
 use crate::state::ProcessState;
@@ -33,16 +34,49 @@ where
 {
     let id = state.id();
     trace!("Spawning process: {}", id);
+    // The slot is taken before instantiating, so that concurrent spawns can't exceed the limit
+    if !env.reserve_process() {
+        return Err(anyhow!(
+            "Resource exhausted: environment {} is limited to {} processes",
+            env.id(),
+            env.max_processes().unwrap_or(usize::MAX)
+        ));
+    }
     let signal_mailbox = state.signal_mailbox().clone();
     let message_mailbox = state.message_mailbox().clone();
+    let stats = state.stats().clone();
+    let worker = state.config().get_worker_affinity();
+    let deterministic_seed = state.config().get_deterministic_seed();
+    let log_normal_exits = state.config().get_log_normal_exits();
+    if state.config().get_trap_exit() {
+        // Handled before the process gets a chance to run, because signals take precedence.
+        signal_mailbox
+            .0
+            .send(Signal::DieWhenLinkDies(false))
+            .expect("receiver must exist at this point");
+    }
 
-    let instance = runtime.instantiate(module, state).await?;
+    let instance = match runtime.instantiate(module, state).await {
+        Ok(instance) => instance,
+        Err(error) => {
+            env.release_process();
+            return Err(error);
+        }
+    };
     let function = function.to_string();
     let fut = async move { instance.call(&function, params).await };
-    let child_process = crate::new(fut, id, env.clone(), signal_mailbox.1, message_mailbox);
+    let child_process = crate::new(
+        fut,
+        id,
+        env.clone(),
+        signal_mailbox.1,
+        message_mailbox,
+        Some(stats.clone()),
+        log_normal_exits,
+    );
     let child_process_handle = Arc::new(WasmProcess::new(id, signal_mailbox.0.clone()));
 
-    env.add_process(id, child_process_handle.clone());
+    env.add_process(id, child_process_handle.clone(), stats);
 
     // **Child link guarantees**:
     // The link signal is going to be put inside of the child's mailbox and is going to be
@@ -81,6 +115,9 @@ where
 
     // Spawn a background process
     trace!("Process size: {}", std::mem::size_of_val(&child_process));
-    let join = tokio::task::spawn(child_process);
+    let join = match deterministic_seed {
+        Some(seed) => crate::workers::spawn_deterministic(seed ^ id, child_process),
+        None => crate::workers::spawn(worker, child_process),
+    };
     Ok((join, child_process_handle))
 }
@@ -0,0 +1,145 @@
+//! Dedicated scheduler threads for processes with a worker affinity.
+//!
+//! Processes are usually spawned onto the shared multi-threaded tokio runtime and can move between
+//! its threads. A process whose config requests a worker is instead spawned onto one of these
+//! single-threaded runtimes and stays on the same OS thread until it finishes. There is one
+//! worker per available CPU core, they are started the first time a process requests one.
+//!
+//! For reproducing concurrency bugs, processes with a deterministic seed run together on a
+//! separate single-threaded runtime. Each time such a process yields, it is delayed by a number
+//! of scheduler rounds derived from the seed. Processes yield at fixed points (after each unit of
+//! fuel and in host calls), so runs with the same seed interleave the processes the same way, as
+//! long as they don't depend on timers or I/O.
+
+use std::{
+    cell::Cell,
+    future::Future,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        OnceLock,
+    },
+    task::{Context, Poll},
+    thread,
+};
+
+use tokio::{runtime::Handle, task::JoinHandle};
+
+static WORKERS: OnceLock<Vec<Handle>> = OnceLock::new();
+static DETERMINISTIC: OnceLock<Handle> = OnceLock::new();
+static NEXT_THREAD_ID: AtomicU32 = AtomicU32::new(0);
+
+thread_local! {
+    static CURRENT_WORKER: Cell<Option<usize>> = const { Cell::new(None) };
+    static THREAD_ID: u32 = NEXT_THREAD_ID.fetch_add(1, Ordering::Relaxed);
+}
+
+/// Returns the index of the worker driving the current thread, or `None` if the current thread
+/// belongs to the shared runtime.
+pub fn current() -> Option<usize> {
+    CURRENT_WORKER.with(Cell::get)
+}
+
+/// Returns an id of the OS thread currently running, assigned the first time it's requested.
+///
+/// Unlike [`current`], this also identifies threads of the shared runtime. Processes on the
+/// shared runtime can move to another thread whenever they yield, so the id is only a snapshot.
+pub fn thread_id() -> u32 {
+    THREAD_ID.with(|id| *id)
+}
+
+/// Spawns **future** onto the dedicated **worker**.
+///
+/// The worker is only a hint, if it's `None` or doesn't exist the future is spawned onto the
+/// shared runtime.
+pub fn spawn<F>(worker: Option<usize>, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    match worker.and_then(|worker| workers().get(worker)) {
+        Some(handle) => handle.spawn(future),
+        None => tokio::task::spawn(future),
+    }
+}
+
+/// Spawns **future** onto the deterministic runtime, see the [module](self) documentation.
+///
+/// **seed** should differ between the processes of a run, e.g. by mixing in the process ID.
+pub fn spawn_deterministic<F>(seed: u64, future: F) -> JoinHandle<F::Output>
+where
+    F: Future + Send + 'static,
+    F::Output: Send + 'static,
+{
+    DETERMINISTIC
+        .get_or_init(|| start_runtime("lunatic-deterministic".to_string(), None))
+        .spawn(Shuffled {
+            inner: Box::pin(future),
+            rng: seed,
+            skip: 0,
+        })
+}
+
+fn workers() -> &'static [Handle] {
+    WORKERS.get_or_init(|| {
+        let count = thread::available_parallelism().map_or(1, |count| count.get());
+        (0..count)
+            .map(|index| start_runtime(format!("lunatic-worker-{index}"), Some(index)))
+            .collect()
+    })
+}
+
+fn start_runtime(name: String, worker: Option<usize>) -> Handle {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("worker runtime can be created");
+    let handle = runtime.handle().clone();
+    thread::Builder::new()
+        .name(name)
+        .spawn(move || {
+            CURRENT_WORKER.with(|current| current.set(worker));
+            runtime.block_on(std::future::pending::<()>());
+        })
+        .expect("worker thread can be spawned");
+    handle
+}
+
+// Upper bound (exclusive) of scheduler rounds a deterministic process is delayed by.
+const MAX_SKIPPED_ROUNDS: u64 = 4;
+
+// Wraps the future of a deterministic process. After the inner future yields, the next polls
+// are skipped a seeded number of times by re-queuing the task at the end of the run queue.
+struct Shuffled<F> {
+    inner: Pin<Box<F>>,
+    rng: u64,
+    skip: u64,
+}
+
+impl<F> Shuffled<F> {
+    // splitmix64
+    fn next_random(&mut self) -> u64 {
+        self.rng = self.rng.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = self.rng;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    }
+}
+
+impl<F: Future> Future for Shuffled<F> {
+    type Output = F::Output;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<F::Output> {
+        if self.skip > 0 {
+            self.skip -= 1;
+            cx.waker().wake_by_ref();
+            return Poll::Pending;
+        }
+        let result = self.inner.as_mut().poll(cx);
+        if result.is_pending() {
+            self.skip = self.next_random() % MAX_SKIPPED_ROUNDS;
+        }
+        result
+    }
+}
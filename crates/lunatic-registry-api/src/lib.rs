@@ -1,14 +1,28 @@
+use std::{future::Future, sync::OnceLock, time::Duration};
+
 use anyhow::Result;
-use lunatic_common_api::{get_memory, IntoTrap};
+use lunatic_common_api::{get_memory, guest_slice, IntoTrap};
 use lunatic_process::state::ProcessState;
 use lunatic_process_api::ProcessCtx;
+use tokio::sync::Notify;
 use wasmtime::Trap;
 use wasmtime::{Caller, Linker};
 
+// Notified every time a process is registered, to wake up processes waiting in `await_name`. It's
+// shared by all registries, so waiters check again if their name is the one that got registered.
+static REGISTERED: OnceLock<Notify> = OnceLock::new();
+
+fn registered() -> &'static Notify {
+    REGISTERED.get_or_init(Notify::new)
+}
+
 // Register the registry APIs to the linker
-pub fn register<T: ProcessState + ProcessCtx<T> + 'static>(linker: &mut Linker<T>) -> Result<()> {
+pub fn register<T: ProcessState + ProcessCtx<T> + Send + 'static>(
+    linker: &mut Linker<T>,
+) -> Result<()> {
     linker.func_wrap("lunatic::registry", "put", put)?;
     linker.func_wrap("lunatic::registry", "get", get)?;
+    linker.func_wrap5_async("lunatic::registry", "await_name", await_name)?;
     linker.func_wrap("lunatic::registry", "remove", remove)?;
 
     #[cfg(feature = "metrics")]
@@ -61,6 +75,7 @@ fn put<T: ProcessState + ProcessCtx<T>>(
     state
         .registry()
         .insert(name.to_owned(), (node_id, process_id));
+    registered().notify_waiters();
     #[cfg(feature = "metrics")]
     metrics::increment_counter!("lunatic.registry.write");
 
@@ -111,6 +126,71 @@ fn get<T: ProcessState + ProcessCtx<T>>(
     Ok(0)
 }
 
+// Waits until a process is registered under `name`, so that clients don't race servers that are
+// still starting up. Returns right away if the name is already registered.
+//
+// If timeout is specified (value different from `u64::MAX`), the function will return on timeout
+// expiration with value 9027.
+//
+// Returns:
+// * 0    if the process was found. Its node and process ID are written to **node_id_ptr** and
+//        **process_id_ptr**.
+// * 9027 if the wait timed out.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn await_name<T: ProcessState + ProcessCtx<T> + Send>(
+    mut caller: Caller<T>,
+    name_str_ptr: u32,
+    name_str_len: u32,
+    node_id_ptr: u32,
+    process_id_ptr: u32,
+    timeout: u64,
+) -> Box<dyn Future<Output = Result<u32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let name = guest_slice(&memory, &caller, name_str_ptr, name_str_len)
+            .or_trap("lunatic::registry::await_name")?;
+        let name = std::str::from_utf8(name)
+            .or_trap("lunatic::registry::await_name")?
+            .to_owned();
+
+        let registry = caller.data().registry().clone();
+        let lookup = async {
+            loop {
+                // Created before the lookup, so that a registration in between isn't missed
+                let notified = registered().notified();
+                if let Some(process) = registry.get(&name) {
+                    return *process;
+                }
+                notified.await;
+            }
+        };
+        let (node_id, process_id) = match timeout {
+            u64::MAX => lookup.await,
+            t => match tokio::time::timeout(Duration::from_millis(t), lookup).await {
+                Ok(process) => process,
+                Err(_) => return Ok(9027),
+            },
+        };
+
+        #[cfg(feature = "metrics")]
+        metrics::increment_counter!("lunatic.registry.read");
+
+        memory
+            .write(&mut caller, node_id_ptr as usize, &node_id.to_le_bytes())
+            .or_trap("lunatic::registry::await_name")?;
+        memory
+            .write(
+                &mut caller,
+                process_id_ptr as usize,
+                &process_id.to_le_bytes(),
+            )
+            .or_trap("lunatic::registry::await_name")?;
+        Ok(0)
+    })
+}
+
 // Removes process under `name` if it exists.
 //
 // Traps:
@@ -1,3 +1,7 @@
+mod line_buffered;
+
+pub use line_buffered::{LineBuffered, LinePrefix, LineSink};
+
 use std::{
     any::Any,
     fmt::{Display, Formatter},
@@ -106,6 +110,18 @@ impl StdoutCapture {
         let mut stream = streams[self.index].lock().unwrap();
         write!(stream, "{}", content).unwrap();
     }
+
+    /// Flushes the echoed output.
+    ///
+    /// Captured writes are never buffered, but the echo goes through the line-buffered stdout of
+    /// the host and can hold back the last line if it's not terminated by a newline.
+    pub fn flush(&self) -> std::io::Result<()> {
+        if self.echo {
+            stdout().flush()
+        } else {
+            Ok(())
+        }
+    }
 }
 
 #[wiggle::async_trait]
@@ -114,10 +130,10 @@ impl WasiFile for StdoutCapture {
         self
     }
     async fn datasync(&mut self) -> Result<(), Error> {
-        Ok(())
+        Ok(self.flush()?)
     }
     async fn sync(&mut self) -> Result<(), Error> {
-        Ok(())
+        Ok(self.flush()?)
     }
     async fn get_filetype(&mut self) -> Result<FileType, Error> {
         Ok(FileType::Pipe)
@@ -0,0 +1,188 @@
+use std::{
+    any::Any,
+    io::{stderr, stdout, IoSlice, IoSliceMut, SeekFrom, Write},
+    sync::{Arc, Mutex},
+};
+
+use wasi_common::{
+    file::{Advice, FdFlags, FileType, Filestat},
+    Error, ErrorExt, SystemTimeSpec, WasiFile,
+};
+
+/// Output sink that can be shared between multiple [`LineBuffered`] streams.
+pub type LineSink = Arc<Mutex<dyn Write + Send>>;
+
+/// Returns the prefix written in front of each line.
+///
+/// It's called every time lines are written out, so the prefix can change during the lifetime of
+/// the stream (e.g. when a process sets its label).
+pub type LinePrefix = Box<dyn Fn() -> String + Send + Sync>;
+
+/// `LineBuffered` holds back the output of a process until a line is complete and then writes it
+/// to the sink, prefixed with a tag.
+///
+/// Every write to the sink only contains complete lines, so that the output of multiple
+/// processes writing concurrently to the console doesn't get mangled. A partial line that is
+/// still buffered when the stream is dropped is terminated with a newline and written out.
+pub struct LineBuffered {
+    prefix: LinePrefix,
+    buffer: Vec<u8>,
+    sink: LineSink,
+}
+
+impl LineBuffered {
+    /// Creates a stream prefixing each line with `[tag] `.
+    pub fn new(tag: String, sink: LineSink) -> Self {
+        let prefix = format!("[{tag}] ");
+        Self::with_prefix(Box::new(move || prefix.clone()), sink)
+    }
+
+    /// Creates a stream prefixing each line with the result of **prefix**.
+    pub fn with_prefix(prefix: LinePrefix, sink: LineSink) -> Self {
+        Self {
+            prefix,
+            buffer: Vec::new(),
+            sink,
+        }
+    }
+
+    /// Returns a sink writing to the stdout of the host.
+    pub fn stdout_sink() -> LineSink {
+        Arc::new(Mutex::new(stdout()))
+    }
+
+    /// Returns a sink writing to the stderr of the host.
+    pub fn stderr_sink() -> LineSink {
+        Arc::new(Mutex::new(stderr()))
+    }
+
+    /// Writes the buffered partial line to the sink, terminated by a newline.
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        if !self.buffer.is_empty() && !self.buffer.ends_with(b"\n") {
+            self.buffer.push(b'\n');
+        }
+        self.write_lines()
+    }
+
+    // Writes all complete lines with one call, so that they also stay together if multiple
+    // sinks point to the same output.
+    fn write_lines(&mut self) -> std::io::Result<()> {
+        let end = match self.buffer.iter().rposition(|byte| *byte == b'\n') {
+            Some(last_newline) => last_newline + 1,
+            None => return Ok(()),
+        };
+        let prefix = (self.prefix)();
+        let mut lines = Vec::with_capacity(end);
+        for line in self.buffer[..end].split_inclusive(|byte| *byte == b'\n') {
+            lines.extend_from_slice(prefix.as_bytes());
+            lines.extend_from_slice(line);
+        }
+        {
+            let mut sink = self.sink.lock().unwrap();
+            sink.write_all(&lines)?;
+            sink.flush()?;
+        }
+        self.buffer.drain(..end);
+        Ok(())
+    }
+}
+
+impl Drop for LineBuffered {
+    fn drop(&mut self) {
+        self.flush().ok();
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiFile for LineBuffered {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+    async fn datasync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn sync(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+    async fn get_filetype(&mut self) -> Result<FileType, Error> {
+        Ok(FileType::Pipe)
+    }
+    async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        Ok(FdFlags::APPEND)
+    }
+    async fn set_fdflags(&mut self, _fdflags: FdFlags) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    async fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        Ok(Filestat {
+            device_id: 0,
+            inode: 0,
+            filetype: self.get_filetype().await?,
+            nlink: 0,
+            size: 0,
+            atim: None,
+            mtim: None,
+            ctim: None,
+        })
+    }
+    async fn set_filestat_size(&mut self, _size: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    async fn advise(&mut self, _offset: u64, _len: u64, _advice: Advice) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    async fn allocate(&mut self, _offset: u64, _len: u64) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    async fn read_vectored<'a>(&mut self, _bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+    async fn read_vectored_at<'a>(
+        &mut self,
+        _bufs: &mut [IoSliceMut<'a>],
+        _offset: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+    async fn write_vectored<'a>(&mut self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        let n = self.buffer.write_vectored(bufs)?;
+        self.write_lines()?;
+        Ok(n.try_into()?)
+    }
+    async fn write_vectored_at<'a>(
+        &mut self,
+        _bufs: &[IoSlice<'a>],
+        _offset: u64,
+    ) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+    async fn seek(&mut self, _pos: SeekFrom) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+    async fn peek(&mut self, _buf: &mut [u8]) -> Result<u64, Error> {
+        Err(Error::badf())
+    }
+    async fn set_times(
+        &mut self,
+        _atime: Option<SystemTimeSpec>,
+        _mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        Ok(0)
+    }
+    fn isatty(&mut self) -> bool {
+        false
+    }
+    async fn readable(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+    async fn writable(&self) -> Result<(), Error> {
+        Err(Error::badf())
+    }
+
+    async fn sock_accept(&mut self, _fdflags: FdFlags) -> Result<Box<dyn WasiFile>, Error> {
+        Err(Error::badf())
+    }
+}
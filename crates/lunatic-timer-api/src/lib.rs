@@ -2,17 +2,25 @@ use std::{
     cmp::Ordering,
     collections::BinaryHeap,
     future::Future,
+    sync::Arc,
     time::{Duration, Instant},
 };
 
 use anyhow::Result;
 use hash_map_id::HashMapId;
 use lunatic_common_api::IntoTrap;
-use lunatic_process::{state::ProcessState, Signal};
+use lunatic_process::{
+    message::{DataMessage, Message},
+    state::ProcessState,
+    Process, Signal, WasmProcess,
+};
 use lunatic_process_api::ProcessCtx;
 use tokio::task::JoinHandle;
 use wasmtime::{Caller, Linker, Trap};
 
+/// Longer delays of timers are clamped to this, it's practically forever.
+pub const MAX_TIMER_DELAY: Duration = Duration::from_secs(30 * 365 * 24 * 60 * 60);
+
 #[derive(Debug)]
 struct HeapValue {
     instant: Instant,
@@ -39,12 +47,21 @@ impl PartialEq for HeapValue {
 
 impl Eq for HeapValue {}
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct TimerResources {
     hash_map: HashMapId<JoinHandle<()>>,
     heap: BinaryHeap<HeapValue>,
 }
 
+impl Default for TimerResources {
+    fn default() -> Self {
+        Self {
+            hash_map: HashMapId::with_insertion_order(),
+            heap: BinaryHeap::new(),
+        }
+    }
+}
+
 impl TimerResources {
     pub fn add(&mut self, handle: JoinHandle<()>, target_time: Instant) -> u64 {
         self.cleanup_expired_timers();
@@ -77,6 +94,19 @@ impl TimerResources {
     pub fn remove(&mut self, id: u64) -> Option<JoinHandle<()>> {
         self.hash_map.remove(id)
     }
+
+    /// Forgets all timers, like dropping the resources, but keeps the allocated memory.
+    pub fn clear(&mut self) {
+        self.hash_map.clear();
+        self.heap.clear();
+    }
+
+    /// Returns the IDs of all timers that weren't canceled.
+    ///
+    /// Expired timers are only cleaned up when the next timer is added, so they can be included.
+    pub fn ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.hash_map.iter().map(|(id, _)| id)
+    }
 }
 
 pub trait TimerCtx {
@@ -89,6 +119,9 @@ pub fn register<T: ProcessState + ProcessCtx<T> + TimerCtx + Send + 'static>(
 ) -> Result<()> {
     linker.func_wrap("lunatic::timer", "send_after", send_after)?;
     linker.func_wrap1_async("lunatic::timer", "cancel_timer", cancel_timer)?;
+    linker.func_wrap("lunatic::timer", "timer_set", timer_set)?;
+    // Timers set with `timer_set` share the resources with `send_after`.
+    linker.func_wrap1_async("lunatic::timer", "timer_cancel", cancel_timer)?;
 
     #[cfg(feature = "metrics")]
     metrics::describe_counter!(
@@ -138,8 +171,66 @@ fn send_after<T: ProcessState + ProcessCtx<T> + TimerCtx>(
 
     let process = caller.data_mut().environment().get_process(process_id);
 
-    let target_time = Instant::now() + Duration::from_millis(delay);
-    let timer_handle = tokio::task::spawn(async move {
+    let target_time = target_time(delay).or_trap("lunatic::message::send_after")?;
+    let timer_handle = spawn_timer(process, message, target_time);
+
+    let id = caller
+        .data_mut()
+        .timer_resources_mut()
+        .add(timer_handle, target_time);
+    Ok(id)
+}
+
+// Sends a message with the tag **tag** and no data to the calling process after **delay_ms**.
+//
+// This function doesn't block, the message is put into the mailbox once the timer fires. The
+// timer can be canceled with `lunatic::timer::timer_cancel`.
+//
+// Delays longer than 30 years are clamped.
+//
+// Returns:
+// * The ID of the timer.
+fn timer_set<T: ProcessState + ProcessCtx<T> + TimerCtx>(
+    mut caller: Caller<T>,
+    delay_ms: u64,
+    tag: i64,
+) -> Result<u64, Trap> {
+    let message = Message::Data(DataMessage::new_from_vec(Some(tag), Vec::new()));
+    // Holding the environment's handle keeps the idle reaper away while the timer is pending.
+    let id = caller.data().id();
+    let this_process = caller
+        .data()
+        .environment()
+        .get_process(id)
+        .unwrap_or_else(|| {
+            Arc::new(WasmProcess::new(
+                id,
+                caller.data().signal_mailbox().0.clone(),
+            ))
+        });
+
+    let target_time = target_time(delay_ms).or_trap("lunatic::timer::timer_set")?;
+    let timer_handle = spawn_timer(Some(this_process), message, target_time);
+
+    Ok(caller
+        .data_mut()
+        .timer_resources_mut()
+        .add(timer_handle, target_time))
+}
+
+// Returns the instant **delay_ms** from now, clamped to `MAX_TIMER_DELAY`. `None` if even that
+// can't be represented on the platform.
+fn target_time(delay_ms: u64) -> Option<Instant> {
+    let delay = Duration::from_millis(delay_ms).min(MAX_TIMER_DELAY);
+    Instant::now().checked_add(delay)
+}
+
+fn spawn_timer(
+    process: Option<Arc<dyn Process>>,
+    message: Message,
+    target_time: Instant,
+) -> JoinHandle<()> {
+    tokio::task::spawn(async move {
         #[cfg(feature = "metrics")]
         metrics::increment_counter!("lunatic.timers.started");
         #[cfg(feature = "metrics")]
@@ -155,13 +246,7 @@ fn send_after<T: ProcessState + ProcessCtx<T> + TimerCtx>(
             metrics::decrement_gauge!("lunatic.timers.active", 1.0);
             process.send(Signal::Message(message));
         }
-    });
-
-    let id = caller
-        .data_mut()
-        .timer_resources_mut()
-        .add(timer_handle, target_time);
-    Ok(id)
+    })
 }
 
 // Cancels the specified timer.
@@ -1,10 +1,12 @@
-use wasmtime::Linker;
+use lunatic_common_api::{get_memory, IntoTrap};
+use wasmtime::{Caller, Linker, Trap};
 
 /// Links the `version` APIs.
-pub fn register<T>(linker: &mut Linker<T>) -> anyhow::Result<()> {
+pub fn register<T: 'static>(linker: &mut Linker<T>) -> anyhow::Result<()> {
     linker.func_wrap("lunatic::version", "major", major)?;
     linker.func_wrap("lunatic::version", "minor", minor)?;
     linker.func_wrap("lunatic::version", "patch", patch)?;
+    linker.func_wrap("lunatic::version", "runtime_version", runtime_version)?;
     Ok(())
 }
 
@@ -19,3 +21,20 @@ fn minor() -> u32 {
 fn patch() -> u32 {
     env!("CARGO_PKG_VERSION_PATCH").parse::<u32>().unwrap()
 }
+
+// Writes the full runtime version string (e.g. `0.12.0`) to **buf_ptr**.
+//
+// At most **buf_len** bytes are written. The full length of the version string is returned, if
+// it's bigger than **buf_len** the call can be repeated with a bigger buffer.
+//
+// Traps:
+// * If any memory outside the guest heap space is referenced.
+fn runtime_version<T>(mut caller: Caller<T>, buf_ptr: u32, buf_len: u32) -> Result<u32, Trap> {
+    let version = env!("CARGO_PKG_VERSION").as_bytes();
+    let copy_len = version.len().min(buf_len as usize);
+    let memory = get_memory(&mut caller)?;
+    memory
+        .write(&mut caller, buf_ptr as usize, &version[..copy_len])
+        .or_trap("lunatic::version::runtime_version")?;
+    Ok(version.len() as u32)
+}
@@ -0,0 +1,323 @@
+//! Preopened directories whose files are read, written and synced on the blocking pool.
+//!
+//! The files of `wasmtime_wasi` call into the OS directly from the async worker running the
+//! process, so a slow disk stalls every other process scheduled on the same worker. [`PooledDir`]
+//! wraps a directory and every file opened through it in a `PooledFile`, which runs reads,
+//! writes and syncs with [`blocking::run`]. All other operations are passed through unchanged.
+
+use std::{
+    any::Any,
+    io::{IoSlice, IoSliceMut, SeekFrom},
+    path::PathBuf,
+    sync::Arc,
+};
+
+use lunatic_common_api::blocking;
+use wasi_common::{
+    dir::{ReaddirCursor, ReaddirEntity},
+    file::{Advice, FdFlags, FileType, Filestat, OFlags},
+    Error, SystemTimeSpec, WasiDir, WasiFile,
+};
+
+/// A directory that opens files as `PooledFile`s.
+pub struct PooledDir(Box<dyn WasiDir>);
+
+impl PooledDir {
+    pub fn new(dir: Box<dyn WasiDir>) -> Self {
+        Self(dir)
+    }
+}
+
+// `rename` and `hard_link` of the wrapped directory downcast the other directory to its own type,
+// so it needs to be unwrapped first.
+fn unwrap_dir(dir: &dyn WasiDir) -> &dyn WasiDir {
+    match dir.as_any().downcast_ref::<PooledDir>() {
+        Some(dir) => dir.0.as_ref(),
+        None => dir,
+    }
+}
+
+#[wiggle::async_trait]
+impl WasiDir for PooledDir {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn open_file(
+        &self,
+        symlink_follow: bool,
+        path: &str,
+        oflags: OFlags,
+        read: bool,
+        write: bool,
+        fdflags: FdFlags,
+    ) -> Result<Box<dyn WasiFile>, Error> {
+        let file = self
+            .0
+            .open_file(symlink_follow, path, oflags, read, write, fdflags)
+            .await?;
+        pooled_file(file)
+    }
+
+    async fn open_dir(&self, symlink_follow: bool, path: &str) -> Result<Box<dyn WasiDir>, Error> {
+        let dir = self.0.open_dir(symlink_follow, path).await?;
+        Ok(Box::new(PooledDir(dir)))
+    }
+
+    async fn create_dir(&self, path: &str) -> Result<(), Error> {
+        self.0.create_dir(path).await
+    }
+
+    async fn readdir(
+        &self,
+        cursor: ReaddirCursor,
+    ) -> Result<Box<dyn Iterator<Item = Result<ReaddirEntity, Error>> + Send>, Error> {
+        self.0.readdir(cursor).await
+    }
+
+    async fn symlink(&self, old_path: &str, new_path: &str) -> Result<(), Error> {
+        self.0.symlink(old_path, new_path).await
+    }
+
+    async fn remove_dir(&self, path: &str) -> Result<(), Error> {
+        self.0.remove_dir(path).await
+    }
+
+    async fn unlink_file(&self, path: &str) -> Result<(), Error> {
+        self.0.unlink_file(path).await
+    }
+
+    async fn read_link(&self, path: &str) -> Result<PathBuf, Error> {
+        self.0.read_link(path).await
+    }
+
+    async fn get_filestat(&self) -> Result<Filestat, Error> {
+        self.0.get_filestat().await
+    }
+
+    async fn get_path_filestat(
+        &self,
+        path: &str,
+        follow_symlinks: bool,
+    ) -> Result<Filestat, Error> {
+        self.0.get_path_filestat(path, follow_symlinks).await
+    }
+
+    async fn rename(
+        &self,
+        path: &str,
+        dest_dir: &dyn WasiDir,
+        dest_path: &str,
+    ) -> Result<(), Error> {
+        self.0.rename(path, unwrap_dir(dest_dir), dest_path).await
+    }
+
+    async fn hard_link(
+        &self,
+        path: &str,
+        target_dir: &dyn WasiDir,
+        target_path: &str,
+    ) -> Result<(), Error> {
+        self.0
+            .hard_link(path, unwrap_dir(target_dir), target_path)
+            .await
+    }
+
+    async fn set_times(
+        &self,
+        path: &str,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+        follow_symlinks: bool,
+    ) -> Result<(), Error> {
+        self.0.set_times(path, atime, mtime, follow_symlinks).await
+    }
+}
+
+/// Wraps **file** in a `PooledFile`, if it's backed by a descriptor. Other files are returned
+/// unchanged.
+#[cfg(unix)]
+pub fn pooled_file(file: Box<dyn WasiFile>) -> Result<Box<dyn WasiFile>, Error> {
+    let pooled = match file.pollable() {
+        Some(fd) => std::fs::File::from(fd.try_clone_to_owned()?),
+        None => return Ok(file),
+    };
+    Ok(Box::new(PooledFile {
+        file,
+        pooled: Arc::new(pooled),
+    }))
+}
+
+#[cfg(not(unix))]
+pub fn pooled_file(file: Box<dyn WasiFile>) -> Result<Box<dyn WasiFile>, Error> {
+    Ok(file)
+}
+
+/// A file that is read, written and synced on the blocking pool.
+///
+/// The pool runs the operations on a duplicate of the file's descriptor, which shares the offset
+/// and flags with the original one. Data is copied through an owned buffer, because the closure
+/// can outlive the guest buffers if the process is dropped while it waits.
+#[cfg(unix)]
+pub struct PooledFile {
+    file: Box<dyn WasiFile>,
+    pooled: Arc<std::fs::File>,
+}
+
+#[cfg(unix)]
+#[wiggle::async_trait]
+impl WasiFile for PooledFile {
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    async fn get_filetype(&mut self) -> Result<FileType, Error> {
+        self.file.get_filetype().await
+    }
+
+    fn pollable(&self) -> Option<cap_std::io_lifetimes::BorrowedFd<'_>> {
+        self.file.pollable()
+    }
+
+    fn isatty(&mut self) -> bool {
+        self.file.isatty()
+    }
+
+    async fn datasync(&mut self) -> Result<(), Error> {
+        let file = self.pooled.clone();
+        blocking::run(move || file.sync_data()).await?;
+        Ok(())
+    }
+
+    async fn sync(&mut self) -> Result<(), Error> {
+        let file = self.pooled.clone();
+        blocking::run(move || file.sync_all()).await?;
+        Ok(())
+    }
+
+    async fn get_fdflags(&mut self) -> Result<FdFlags, Error> {
+        self.file.get_fdflags().await
+    }
+
+    async fn set_fdflags(&mut self, flags: FdFlags) -> Result<(), Error> {
+        self.file.set_fdflags(flags).await
+    }
+
+    async fn get_filestat(&mut self) -> Result<Filestat, Error> {
+        self.file.get_filestat().await
+    }
+
+    async fn set_filestat_size(&mut self, size: u64) -> Result<(), Error> {
+        self.file.set_filestat_size(size).await
+    }
+
+    async fn advise(&mut self, offset: u64, len: u64, advice: Advice) -> Result<(), Error> {
+        self.file.advise(offset, len, advice).await
+    }
+
+    async fn allocate(&mut self, offset: u64, len: u64) -> Result<(), Error> {
+        self.file.allocate(offset, len).await
+    }
+
+    async fn set_times(
+        &mut self,
+        atime: Option<SystemTimeSpec>,
+        mtime: Option<SystemTimeSpec>,
+    ) -> Result<(), Error> {
+        self.file.set_times(atime, mtime).await
+    }
+
+    async fn read_vectored<'a>(&mut self, bufs: &mut [IoSliceMut<'a>]) -> Result<u64, Error> {
+        use std::io::Read;
+
+        let file = self.pooled.clone();
+        let mut buf = vec![0; bufs.iter().map(|buf| buf.len()).sum()];
+        let (buf, read) = blocking::run(move || {
+            let read = (&*file).read(&mut buf);
+            (buf, read)
+        })
+        .await;
+        Ok(scatter(&buf[..read?], bufs))
+    }
+
+    async fn read_vectored_at<'a>(
+        &mut self,
+        bufs: &mut [IoSliceMut<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        use std::os::unix::fs::FileExt;
+
+        let file = self.pooled.clone();
+        let mut buf = vec![0; bufs.iter().map(|buf| buf.len()).sum()];
+        let (buf, read) = blocking::run(move || {
+            let read = file.read_at(&mut buf, offset);
+            (buf, read)
+        })
+        .await;
+        Ok(scatter(&buf[..read?], bufs))
+    }
+
+    async fn write_vectored<'a>(&mut self, bufs: &[IoSlice<'a>]) -> Result<u64, Error> {
+        use std::io::Write;
+
+        let file = self.pooled.clone();
+        let buf = gather(bufs);
+        let written = blocking::run(move || (&*file).write(&buf)).await?;
+        Ok(written as u64)
+    }
+
+    async fn write_vectored_at<'a>(
+        &mut self,
+        bufs: &[IoSlice<'a>],
+        offset: u64,
+    ) -> Result<u64, Error> {
+        use std::os::unix::fs::FileExt;
+
+        let file = self.pooled.clone();
+        let buf = gather(bufs);
+        let written = blocking::run(move || file.write_at(&buf, offset)).await?;
+        Ok(written as u64)
+    }
+
+    async fn seek(&mut self, pos: SeekFrom) -> Result<u64, Error> {
+        self.file.seek(pos).await
+    }
+
+    async fn peek(&mut self, buf: &mut [u8]) -> Result<u64, Error> {
+        self.file.peek(buf).await
+    }
+
+    async fn num_ready_bytes(&self) -> Result<u64, Error> {
+        self.file.num_ready_bytes().await
+    }
+
+    async fn readable(&self) -> Result<(), Error> {
+        self.file.readable().await
+    }
+
+    async fn writable(&self) -> Result<(), Error> {
+        self.file.writable().await
+    }
+}
+
+// Copies **data** into the consecutive buffers **bufs** and returns the number of bytes copied.
+#[cfg(unix)]
+fn scatter(mut data: &[u8], bufs: &mut [IoSliceMut]) -> u64 {
+    let len = data.len();
+    for buf in bufs {
+        let n = buf.len().min(data.len());
+        buf[..n].copy_from_slice(&data[..n]);
+        data = &data[n..];
+    }
+    len as u64
+}
+
+// Copies the consecutive buffers **bufs** into one.
+#[cfg(unix)]
+fn gather(bufs: &[IoSlice]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(bufs.iter().map(|buf| buf.len()).sum());
+    for buf in bufs {
+        data.extend_from_slice(buf);
+    }
+    data
+}
@@ -1,15 +1,41 @@
-use anyhow::Result;
-use lunatic_common_api::{get_memory, IntoTrap};
+mod fs;
+
+use std::{
+    collections::HashSet,
+    future::Future,
+    path::{Component, Path, PathBuf},
+};
+
+use anyhow::{anyhow, Result};
+use cap_rand::{rngs::StdRng, SeedableRng};
+use lunatic_common_api::{get_memory, guest_slice, IntoTrap};
 use lunatic_process::state::ProcessState;
 use lunatic_stdout_capture::StdoutCapture;
+use wasi_common::{
+    dir::DirCaps, file::FileCaps, snapshots::preview_1::wasi_snapshot_preview1, WasiDir, WasiFile,
+};
 use wasmtime::{Caller, Linker, Trap};
 use wasmtime_wasi::{ambient_authority, Dir, WasiCtx, WasiCtxBuilder};
+use wiggle::wasmtime::WasmtimeGuestMemory;
+
+use crate::fs::PooledDir;
 
 /// Create a `WasiCtx` from configuration settings.
+///
+/// If a working directory **cwd** is set, it's preopened under the guest path `.` after all
+/// **dirs**, so that relative paths resolve against it. See [`cwd_fd`].
+///
+/// Files opened through the preopened directories are backed by `std::fs::File`. Reads, writes
+/// and syncs of these files run on the blocking pool of `lunatic_common_api`. `fd_sync`
+/// calls `File::sync_all` and `fd_datasync` calls `File::sync_data`, so only `fd_sync` waits
+/// for metadata to be written. `sync_data` maps to a real `fdatasync` on Linux, Android, FreeBSD
+/// and NetBSD. Other Unix platforms fall back to `fsync`, macOS and iOS use `F_FULLFSYNC` for both
+/// and on Windows both call `FlushFileBuffers`.
 pub fn build_wasi(
     args: Option<&Vec<String>>,
     envs: Option<&Vec<(String, String)>>,
     dirs: &[String],
+    cwd: Option<&str>,
 ) -> Result<WasiCtx> {
     let mut wasi = WasiCtxBuilder::new().inherit_stdio();
     if let Some(envs) = envs {
@@ -18,17 +44,111 @@ pub fn build_wasi(
     if let Some(args) = args {
         wasi = wasi.args(args)?;
     }
+    let mut wasi = wasi.build();
     for preopen_dir_path in dirs {
         let preopen_dir = Dir::open_ambient_dir(preopen_dir_path, ambient_authority())?;
-        wasi = wasi.preopened_dir(preopen_dir, preopen_dir_path)?;
+        wasi.push_preopened_dir(pooled_dir(preopen_dir), preopen_dir_path)?;
+    }
+    if let Some(cwd) = cwd {
+        wasi.push_preopened_dir(pooled_dir(open_cwd(dirs, Path::new(cwd))?), ".")?;
+    }
+    Ok(wasi)
+}
+
+fn pooled_dir(dir: Dir) -> Box<dyn WasiDir> {
+    Box::new(PooledDir::new(Box::new(
+        wasmtime_wasi::sync::dir::Dir::from_cap_std(dir),
+    )))
+}
+
+/// Opens the host file at **path** to be used as stdout or stderr of a process.
+///
+/// The file is created if it doesn't exist. Output is appended to it if **append** is set,
+/// otherwise the file is truncated. Writes are not buffered by the runtime, so all output is in the
+/// file once the process exits.
+pub fn open_stdio_file(path: &str, append: bool) -> Result<Box<dyn WasiFile>> {
+    let file = std::fs::OpenOptions::new()
+        .create(true)
+        .write(true)
+        .append(append)
+        .truncate(!append)
+        .open(path)
+        .map_err(|error| anyhow!("Can't open {path} for process output: {error}"))?;
+    Ok(Box::new(wasmtime_wasi::sync::file::File::from_cap_std(
+        cap_std::fs::File::from_std(file),
+    )))
+}
+
+/// Returns the fd of the working directory preopen, it directly follows stdio and **dirs**.
+pub fn cwd_fd(dirs: &[String]) -> u32 {
+    3 + dirs.len() as u32
+}
+
+/// Opens the working directory **cwd** through the preopened directory that contains it.
+///
+/// The directory is opened relative to the preopen, so that `..` components or symlinks can't
+/// be used to escape the sandbox.
+pub fn open_cwd(dirs: &[String], cwd: &Path) -> Result<Dir> {
+    let cwd = normalize_path(cwd);
+    for preopen_dir_path in dirs {
+        if let Ok(relative) = cwd.strip_prefix(normalize_path(Path::new(preopen_dir_path))) {
+            let preopen_dir = Dir::open_ambient_dir(preopen_dir_path, ambient_authority())?;
+            if relative.as_os_str().is_empty() {
+                return Ok(preopen_dir);
+            }
+            return Ok(preopen_dir.open_dir(relative)?);
+        }
+    }
+    Err(anyhow!(
+        "Working directory {} is not inside a preopened directory",
+        cwd.display()
+    ))
+}
+
+/// Returns true if **cwd** is inside one of the preopened **dirs**.
+///
+/// This only compares the paths and doesn't check if the directory exists.
+pub fn is_inside_preopened_dir(dirs: &[String], cwd: &str) -> bool {
+    let cwd = normalize_path(Path::new(cwd));
+    dirs.iter()
+        .any(|dir| cwd.starts_with(normalize_path(Path::new(dir))))
+}
+
+// Lexically resolves `.` and `..` components, without touching the filesystem.
+fn normalize_path(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if matches!(
+                    normalized.components().next_back(),
+                    Some(Component::Normal(_))
+                ) {
+                    normalized.pop();
+                } else {
+                    normalized.push(component);
+                }
+            }
+            component => normalized.push(component),
+        }
     }
-    Ok(wasi.build())
+    normalized
 }
 
 pub trait LunaticWasiConfigCtx {
     fn add_environment_variable(&mut self, key: String, value: String);
+    fn environment_variable(&self, key: &str) -> Option<&str>;
+    fn environment_variables(&self) -> &[(String, String)];
     fn add_command_line_argument(&mut self, argument: String);
     fn preopen_dir(&mut self, dir: String);
+    fn preopened_dirs(&self) -> &[String];
+    fn set_cwd(&mut self, cwd: String);
+    /// Maximum number of files and sockets a process can open through WASI at the same time,
+    /// `None` if unlimited. Descriptors the process starts with (stdio, preopened directories and
+    /// inherited descriptors) are not counted.
+    fn max_open_fds(&self) -> Option<usize>;
+    fn set_max_open_fds(&mut self, max: Option<usize>);
 }
 
 pub trait LunaticWasiCtx {
@@ -38,6 +158,26 @@ pub trait LunaticWasiCtx {
     fn get_stdout(&self) -> Option<&StdoutCapture>;
     fn set_stderr(&mut self, stderr: StdoutCapture);
     fn get_stderr(&self) -> Option<&StdoutCapture>;
+    fn cwd(&self) -> Option<&str>;
+    fn set_cwd(&mut self, cwd: String);
+    /// File descriptors that are moved into the next spawned process, as `(fd, child_fd)` pairs.
+    fn inherited_fds_mut(&mut self) -> &mut Vec<(u32, u32)>;
+    /// Descriptors opened by the process, counted against
+    /// [`LunaticWasiConfigCtx::max_open_fds`]. Closed descriptors are removed lazily.
+    fn opened_fds_mut(&mut self) -> &mut HashSet<u32>;
+}
+
+/// Moves the file descriptors **fds** from the table of **from** into the table of **to**.
+///
+/// Each `(fd, to_fd)` pair removes `fd` from **from** and inserts it as `to_fd`, replacing any
+/// entry that already uses this number. The underlying handle is transferred, not duplicated, so
+/// it's closed when the receiving side closes it. Descriptors that don't exist are skipped.
+pub fn transfer_fds(from: &mut WasiCtx, to: &mut WasiCtx, fds: &[(u32, u32)]) {
+    for &(fd, to_fd) in fds {
+        if let Some(entry) = from.table().delete(fd) {
+            to.table().insert_at(to_fd, entry);
+        }
+    }
 }
 
 // Register WASI APIs to the linker
@@ -51,6 +191,18 @@ where
         linker,
         |ctx| ctx.wasi_mut(),
     )?;
+    // Replace the ones that open new descriptors, to enforce the limit of open descriptors
+    linker.allow_shadowing(true);
+    linker.func_wrap9_async("wasi_snapshot_preview1", "path_open", path_open)?;
+    linker.func_wrap3_async("wasi_snapshot_preview1", "sock_accept", sock_accept)?;
+    // Replace the ones that read, write or sync files, so that they can wait for the blocking pool
+    linker.func_wrap4_async("wasi_snapshot_preview1", "fd_read", fd_read)?;
+    linker.func_wrap5_async("wasi_snapshot_preview1", "fd_pread", fd_pread)?;
+    linker.func_wrap4_async("wasi_snapshot_preview1", "fd_write", fd_write)?;
+    linker.func_wrap5_async("wasi_snapshot_preview1", "fd_pwrite", fd_pwrite)?;
+    linker.func_wrap1_async("wasi_snapshot_preview1", "fd_sync", fd_sync)?;
+    linker.func_wrap1_async("wasi_snapshot_preview1", "fd_datasync", fd_datasync)?;
+    linker.allow_shadowing(false);
 
     // Register host functions to configure wasi
     linker.func_wrap(
@@ -64,10 +216,288 @@ where
         add_command_line_argument,
     )?;
     linker.func_wrap("lunatic::wasi", "config_preopen_dir", preopen_dir)?;
+    linker.func_wrap("lunatic::wasi", "config_set_cwd", config_set_cwd)?;
+
+    linker.func_wrap("lunatic::wasi", "env_get", env_get)?;
+    linker.func_wrap("lunatic::wasi", "env_nth", env_nth)?;
+    linker.func_wrap("lunatic::wasi", "set_cwd", set_cwd)?;
+    linker.func_wrap("lunatic::wasi", "seed_random", seed_random)?;
+    linker.func_wrap("lunatic::wasi", "inherit_fd", inherit_fd)?;
+
+    Ok(())
+}
+
+const ERRNO_MFILE: i32 = 33;
+
+// Opens a file or directory, like WASI's `path_open`.
+//
+// Returns:
+// * 33 (mfile) if the process already has the maximum number of descriptors open.
+// * Otherwise the result of WASI's `path_open`.
+#[allow(clippy::too_many_arguments)]
+fn path_open<T>(
+    mut caller: Caller<T>,
+    dirfd: i32,
+    dirflags: i32,
+    path_ptr: i32,
+    path_len: i32,
+    oflags: i32,
+    fs_rights_base: i64,
+    fs_rights_inheriting: i64,
+    fdflags: i32,
+    fd_ptr: i32,
+) -> Box<dyn Future<Output = Result<i32, Trap>> + Send + '_>
+where
+    T: ProcessState + LunaticWasiCtx + Send,
+    T::Config: LunaticWasiConfigCtx,
+{
+    Box::new(async move {
+        if !has_fd_capacity(caller.data_mut()) {
+            return Ok(ERRNO_MFILE);
+        }
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        let errno = wasi_snapshot_preview1::path_open(
+            state.wasi_mut(),
+            &WasmtimeGuestMemory::new(memory_slice),
+            dirfd,
+            dirflags,
+            path_ptr,
+            path_len,
+            oflags,
+            fs_rights_base,
+            fs_rights_inheriting,
+            fdflags,
+            fd_ptr,
+        )
+        .await
+        .map_err(into_trap)?;
+        if errno == 0 {
+            record_opened_fd(&mut caller, fd_ptr)?;
+        }
+        Ok(errno)
+    })
+}
+
+// Accepts a connection on a preopened socket, like WASI's `sock_accept`.
+//
+// Returns:
+// * 33 (mfile) if the process already has the maximum number of descriptors open.
+// * Otherwise the result of WASI's `sock_accept`.
+fn sock_accept<T>(
+    mut caller: Caller<T>,
+    fd: i32,
+    flags: i32,
+    fd_ptr: i32,
+) -> Box<dyn Future<Output = Result<i32, Trap>> + Send + '_>
+where
+    T: ProcessState + LunaticWasiCtx + Send,
+    T::Config: LunaticWasiConfigCtx,
+{
+    Box::new(async move {
+        if !has_fd_capacity(caller.data_mut()) {
+            return Ok(ERRNO_MFILE);
+        }
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        let errno = wasi_snapshot_preview1::sock_accept(
+            state.wasi_mut(),
+            &WasmtimeGuestMemory::new(memory_slice),
+            fd,
+            flags,
+            fd_ptr,
+        )
+        .await
+        .map_err(into_trap)?;
+        if errno == 0 {
+            record_opened_fd(&mut caller, fd_ptr)?;
+        }
+        Ok(errno)
+    })
+}
+
+// Reads from a descriptor, like WASI's `fd_read`. Files of preopened directories are read on the
+// blocking pool.
+fn fd_read<T: LunaticWasiCtx + Send>(
+    mut caller: Caller<T>,
+    fd: i32,
+    iovs_ptr: i32,
+    iovs_len: i32,
+    nread_ptr: i32,
+) -> Box<dyn Future<Output = Result<i32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        wasi_snapshot_preview1::fd_read(
+            state.wasi_mut(),
+            &WasmtimeGuestMemory::new(memory_slice),
+            fd,
+            iovs_ptr,
+            iovs_len,
+            nread_ptr,
+        )
+        .await
+        .map_err(into_trap)
+    })
+}
+
+// Reads from a descriptor at an offset, like WASI's `fd_pread`. Files of preopened directories
+// are read on the blocking pool.
+fn fd_pread<T: LunaticWasiCtx + Send>(
+    mut caller: Caller<T>,
+    fd: i32,
+    iovs_ptr: i32,
+    iovs_len: i32,
+    offset: i64,
+    nread_ptr: i32,
+) -> Box<dyn Future<Output = Result<i32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        wasi_snapshot_preview1::fd_pread(
+            state.wasi_mut(),
+            &WasmtimeGuestMemory::new(memory_slice),
+            fd,
+            iovs_ptr,
+            iovs_len,
+            offset,
+            nread_ptr,
+        )
+        .await
+        .map_err(into_trap)
+    })
+}
+
+// Writes to a descriptor, like WASI's `fd_write`. Files of preopened directories are written on
+// the blocking pool.
+fn fd_write<T: LunaticWasiCtx + Send>(
+    mut caller: Caller<T>,
+    fd: i32,
+    ciovs_ptr: i32,
+    ciovs_len: i32,
+    nwritten_ptr: i32,
+) -> Box<dyn Future<Output = Result<i32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        wasi_snapshot_preview1::fd_write(
+            state.wasi_mut(),
+            &WasmtimeGuestMemory::new(memory_slice),
+            fd,
+            ciovs_ptr,
+            ciovs_len,
+            nwritten_ptr,
+        )
+        .await
+        .map_err(into_trap)
+    })
+}
+
+// Writes to a descriptor at an offset, like WASI's `fd_pwrite`. Files of preopened directories
+// are written on the blocking pool.
+fn fd_pwrite<T: LunaticWasiCtx + Send>(
+    mut caller: Caller<T>,
+    fd: i32,
+    ciovs_ptr: i32,
+    ciovs_len: i32,
+    offset: i64,
+    nwritten_ptr: i32,
+) -> Box<dyn Future<Output = Result<i32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        wasi_snapshot_preview1::fd_pwrite(
+            state.wasi_mut(),
+            &WasmtimeGuestMemory::new(memory_slice),
+            fd,
+            ciovs_ptr,
+            ciovs_len,
+            offset,
+            nwritten_ptr,
+        )
+        .await
+        .map_err(into_trap)
+    })
+}
+
+// Writes the data and metadata of a file to disk, like WASI's `fd_sync`. Files of preopened
+// directories are synced on the blocking pool.
+fn fd_sync<T: LunaticWasiCtx + Send>(
+    mut caller: Caller<T>,
+    fd: i32,
+) -> Box<dyn Future<Output = Result<i32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        wasi_snapshot_preview1::fd_sync(
+            state.wasi_mut(),
+            &WasmtimeGuestMemory::new(memory_slice),
+            fd,
+        )
+        .await
+        .map_err(into_trap)
+    })
+}
 
+// Writes the data of a file to disk, like WASI's `fd_datasync`. Files of preopened directories
+// are synced on the blocking pool.
+fn fd_datasync<T: LunaticWasiCtx + Send>(
+    mut caller: Caller<T>,
+    fd: i32,
+) -> Box<dyn Future<Output = Result<i32, Trap>> + Send + '_> {
+    Box::new(async move {
+        let memory = get_memory(&mut caller)?;
+        let (memory_slice, state) = memory.data_and_store_mut(&mut caller);
+        wasi_snapshot_preview1::fd_datasync(
+            state.wasi_mut(),
+            &WasmtimeGuestMemory::new(memory_slice),
+            fd,
+        )
+        .await
+        .map_err(into_trap)
+    })
+}
+
+// Returns true if the process can open another descriptor. Descriptors that were closed, moved to
+// another process or renumbered away since they were opened are forgotten first.
+fn has_fd_capacity<T>(state: &mut T) -> bool
+where
+    T: ProcessState + LunaticWasiCtx,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let max = match state.config().max_open_fds() {
+        Some(max) => max,
+        None => return true,
+    };
+    let mut opened = std::mem::take(state.opened_fds_mut());
+    let table = state.wasi_mut().table();
+    opened.retain(|fd| table.contains_key(*fd));
+    let has_capacity = opened.len() < max;
+    *state.opened_fds_mut() = opened;
+    has_capacity
+}
+
+// Remembers the descriptor that WASI wrote to **fd_ptr**.
+fn record_opened_fd<T: LunaticWasiCtx>(caller: &mut Caller<T>, fd_ptr: i32) -> Result<(), Trap> {
+    let memory = get_memory(caller)?;
+    let mut fd = [0; 4];
+    memory
+        .read(&mut *caller, fd_ptr as usize, &mut fd)
+        .or_trap("lunatic::wasi::record_opened_fd")?;
+    caller
+        .data_mut()
+        .opened_fds_mut()
+        .insert(u32::from_le_bytes(fd));
     Ok(())
 }
 
+fn into_trap(trap: wiggle::Trap) -> Trap {
+    match trap {
+        wiggle::Trap::I32Exit(status) => Trap::i32_exit(status),
+        wiggle::Trap::String(message) => Trap::new(message),
+    }
+}
+
 // Adds environment variable to a configuration.
 //
 // Traps:
@@ -178,3 +608,211 @@ where
         .preopen_dir(dir);
     Ok(())
 }
+
+// Looks up the environment variable **name** of the calling process.
+//
+// If the variable exists, up to **value_len** bytes of its value are copied to **value_ptr** and
+// the full length of the value is written to **len_ptr** as little endian u64. If the written
+// length is bigger than **value_len**, the call can be repeated with a bigger buffer.
+//
+// Returns:
+// * 0 if the variable was found.
+// * 1 if the variable doesn't exist.
+//
+// Traps:
+// * If the name string is not a valid utf8 string.
+// * If any of the memory slices falls outside the memory.
+fn env_get<T>(
+    mut caller: Caller<T>,
+    name_ptr: u32,
+    name_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+    len_ptr: u32,
+) -> Result<u32, Trap>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let name_str =
+        guest_slice(&memory, &caller, name_ptr, name_len).or_trap("lunatic::wasi::env_get")?;
+    let name = std::str::from_utf8(name_str).or_trap("lunatic::wasi::env_get")?;
+
+    let value = match caller.data().config().environment_variable(name) {
+        Some(value) => value.as_bytes().to_vec(),
+        None => return Ok(1),
+    };
+    let copy_len = value.len().min(value_len as usize);
+    memory
+        .write(&mut caller, value_ptr as usize, &value[..copy_len])
+        .or_trap("lunatic::wasi::env_get")?;
+    memory
+        .write(
+            &mut caller,
+            len_ptr as usize,
+            &(value.len() as u64).to_le_bytes(),
+        )
+        .or_trap("lunatic::wasi::env_get")?;
+    Ok(0)
+}
+
+// Looks up the environment variable at **index** of the calling process. Variables are ordered
+// as they were added to the configuration, the same order `environ_get` uses, so iterating from
+// index 0 until 1 is returned visits the whole environment.
+//
+// If the variable exists, up to **key_len** bytes of its name are copied to **key_ptr** and up to
+// **value_len** bytes of its value to **value_ptr**. The full lengths of the name and value are
+// written to **lens_ptr** as two little endian u64 values. If a written length is bigger than the
+// buffer, the call can be repeated with a bigger one.
+//
+// Returns:
+// * 0 if the variable was found.
+// * 1 if the index is past the last variable.
+//
+// Traps:
+// * If any of the memory slices falls outside the memory.
+fn env_nth<T>(
+    mut caller: Caller<T>,
+    index: u32,
+    key_ptr: u32,
+    key_len: u32,
+    value_ptr: u32,
+    value_len: u32,
+    lens_ptr: u32,
+) -> Result<u32, Trap>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let config = caller.data().config().clone();
+    let (key, value) = match config.environment_variables().get(index as usize) {
+        Some((key, value)) => (key.as_bytes(), value.as_bytes()),
+        None => return Ok(1),
+    };
+    let key_copy_len = key.len().min(key_len as usize);
+    memory
+        .write(&mut caller, key_ptr as usize, &key[..key_copy_len])
+        .or_trap("lunatic::wasi::env_nth")?;
+    let value_copy_len = value.len().min(value_len as usize);
+    memory
+        .write(&mut caller, value_ptr as usize, &value[..value_copy_len])
+        .or_trap("lunatic::wasi::env_nth")?;
+    let mut lens = [0; 16];
+    lens[..8].copy_from_slice(&(key.len() as u64).to_le_bytes());
+    lens[8..].copy_from_slice(&(value.len() as u64).to_le_bytes());
+    memory
+        .write(&mut caller, lens_ptr as usize, &lens)
+        .or_trap("lunatic::wasi::env_nth")?;
+    Ok(0)
+}
+
+// Sets the working directory of processes spawned with this configuration.
+//
+// The directory needs to be inside one of the preopened directories of the configuration. It's
+// exposed to the guest as an additional preopen with the path `.`, following the preopened
+// directories, so that relative paths are resolved against it.
+//
+// Traps:
+// * If the config ID doesn't exist.
+// * If the directory string is not a valid utf8 string.
+// * If any of the memory slices falls outside the memory.
+fn config_set_cwd<T>(
+    mut caller: Caller<T>,
+    config_id: u64,
+    dir_ptr: u32,
+    dir_len: u32,
+) -> Result<(), Trap>
+where
+    T: ProcessState,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let dir_str =
+        guest_slice(&memory, &caller, dir_ptr, dir_len).or_trap("lunatic::wasi::config_set_cwd")?;
+    let dir = std::str::from_utf8(dir_str)
+        .or_trap("lunatic::wasi::config_set_cwd")?
+        .to_string();
+
+    caller
+        .data_mut()
+        .config_resources_mut()
+        .get_mut(config_id)
+        .or_trap("lunatic::wasi::config_set_cwd: Config ID doesn't exist")?
+        .set_cwd(dir);
+    Ok(())
+}
+
+// Changes the working directory of the calling process to **path**.
+//
+// A relative path is resolved against the current working directory. The `.` preopen is
+// replaced with the new directory, so that following relative `path_open` calls resolve
+// inside of it.
+//
+// Returns:
+// * 0 if the working directory was changed.
+// * 1 if the process was spawned without a working directory.
+// * 2 if the directory doesn't exist or is outside of the preopened directories.
+//
+// Traps:
+// * If the path string is not a valid utf8 string.
+// * If any of the memory slices falls outside the memory.
+fn set_cwd<T>(mut caller: Caller<T>, path_ptr: u32, path_len: u32) -> Result<u32, Trap>
+where
+    T: ProcessState + LunaticWasiCtx,
+    T::Config: LunaticWasiConfigCtx,
+{
+    let memory = get_memory(&mut caller)?;
+    let path_str =
+        guest_slice(&memory, &caller, path_ptr, path_len).or_trap("lunatic::wasi::set_cwd")?;
+    let path = std::str::from_utf8(path_str).or_trap("lunatic::wasi::set_cwd")?;
+
+    let cwd = match caller.data().cwd() {
+        Some(cwd) => normalize_path(&Path::new(cwd).join(path)),
+        None => return Ok(1),
+    };
+    let config = caller.data().config().clone();
+    let dirs = config.preopened_dirs();
+    let dir = match open_cwd(dirs, &cwd) {
+        Ok(dir) => dir,
+        Err(_) => return Ok(2),
+    };
+    let state = caller.data_mut();
+    state.wasi_mut().insert_dir(
+        cwd_fd(dirs),
+        Box::new(wasmtime_wasi::sync::dir::Dir::from_cap_std(dir)),
+        DirCaps::all(),
+        FileCaps::all(),
+        PathBuf::from("."),
+    );
+    state.set_cwd(cwd.to_string_lossy().into_owned());
+    Ok(0)
+}
+
+// Switches `random_get` of the calling process to a deterministic generator seeded with **seed**.
+//
+// All following `random_get` calls return the same sequence of bytes in every process that was
+// seeded with the same value, so anyone who knows the seed can predict them. The bytes must not be
+// used for secrets. Processes that never call this function keep using the OS generator.
+fn seed_random<T: LunaticWasiCtx>(mut caller: Caller<T>, seed: u64) {
+    caller.data_mut().wasi_mut().random = Box::new(StdRng::seed_from_u64(seed));
+}
+
+// Marks the file descriptor **fd** of the calling process to be inherited by the next process it
+// spawns, where it will be available as **child_fd**.
+//
+// The descriptor is moved, not duplicated. When the next process is spawned it's removed from
+// the calling process and any entry using **child_fd** in the child is replaced.
+//
+// Returns:
+// * 0 on success.
+// * 1 if the file descriptor doesn't exist.
+fn inherit_fd<T: LunaticWasiCtx>(mut caller: Caller<T>, fd: u32, child_fd: u32) -> u32 {
+    let state = caller.data_mut();
+    if !state.wasi_mut().table().contains_key(fd) {
+        return 1;
+    }
+    state.inherited_fds_mut().push((fd, child_fd));
+    0
+}
@@ -1,8 +1,9 @@
-use std::fmt::Debug;
+use std::{fmt::Debug, sync::OnceLock};
 
+use anyhow::{anyhow, Result};
 use lunatic_process::config::ProcessConfig;
 use lunatic_process_api::ProcessConfigCtx;
-use lunatic_wasi_api::LunaticWasiConfigCtx;
+use lunatic_wasi_api::{is_inside_preopened_dir, LunaticWasiConfigCtx};
 use serde::{Deserialize, Serialize};
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -17,10 +18,99 @@ pub struct DefaultProcessConfig {
     can_create_configs: bool,
     // Can this process spawn sub-processes
     can_spawn_processes: bool,
+    // Do processes keep running after the parent process finished
+    detached: bool,
+    // Dedicated worker thread that processes are pinned to, if it exists
+    worker_affinity: Option<usize>,
+    // Do processes receive a message instead of failing if a linked process fails
+    trap_exit: bool,
+    // Are processes finishing normally logged
+    log_normal_exits: bool,
+    // Seed of the deterministic scheduler, used for reproducing concurrency bugs
+    deterministic_seed: Option<u64>,
+    // Maximum combined size of keys and values in the process-local key/value store in bytes
+    max_kv_store_size: usize,
+    // Maximum size of sent messages in bytes
+    max_message_size: Option<usize>,
+    // Maximum number of messages per second sent to each process
+    max_send_rate: Option<u32>,
+    // Do sends above the rate limit wait instead of failing
+    block_when_rate_limited: bool,
+    // Upper bound of the host memory reported to processes in bytes
+    memory_budget: Option<u64>,
     // WASI configs
     preopened_dirs: Vec<String>,
     command_line_arguments: Vec<String>,
     environment_variables: Vec<(String, String)>,
+    cwd: Option<String>,
+    max_open_fds: Option<usize>,
+    // Buffering of the process' stdout and stderr
+    stdout_mode: StdoutMode,
+    // Prefix of each line, if the output is line buffered
+    output_prefix: OutputPrefix,
+    // Host files that the process' stdout and stderr are written to
+    stdout_file: Option<StdioFile>,
+    stderr_file: Option<StdioFile>,
+}
+
+// Console output settings of configs created with `DefaultProcessConfig::default()`.
+static DEFAULT_OUTPUT: OnceLock<(StdoutMode, OutputPrefix)> = OnceLock::new();
+
+/// How the stdout and stderr of processes are written to the console.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum StdoutMode {
+    /// Writes are passed through to the console as they are.
+    #[default]
+    PassThrough,
+    /// Output is held back until a line is complete, so that lines from concurrently running
+    /// processes don't get mangled. Each line is prefixed according to the [`OutputPrefix`].
+    LineBuffered,
+}
+
+/// Prefix written in front of each line of a process' output in [`StdoutMode::LineBuffered`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputPrefix {
+    /// `[42] `
+    #[default]
+    Id,
+    /// `[42 (label)] ` if the process has set a label, otherwise just the id.
+    Label,
+    /// Lines are written without a prefix.
+    None,
+}
+
+impl OutputPrefix {
+    /// Returns the prefix of a process with the **id** and **label**.
+    pub fn format(&self, id: u64, label: Option<&str>) -> String {
+        match (self, label) {
+            (OutputPrefix::Id, _) | (OutputPrefix::Label, None) => format!("[{id}] "),
+            (OutputPrefix::Label, Some(label)) => format!("[{id} ({label})] "),
+            (OutputPrefix::None, _) => String::new(),
+        }
+    }
+}
+
+/// Host file that the stdout or stderr of processes is written to instead of the console.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct StdioFile {
+    pub path: String,
+    /// Appends to an existing file instead of truncating it.
+    pub append: bool,
+}
+
+impl std::str::FromStr for OutputPrefix {
+    type Err = String;
+
+    fn from_str(prefix: &str) -> Result<Self, Self::Err> {
+        match prefix {
+            "id" => Ok(OutputPrefix::Id),
+            "label" => Ok(OutputPrefix::Label),
+            "none" => Ok(OutputPrefix::None),
+            _ => Err(format!(
+                "unknown output prefix `{prefix}`, expected `id`, `label` or `none`"
+            )),
+        }
+    }
 }
 
 impl Debug for DefaultProcessConfig {
@@ -28,9 +118,24 @@ impl Debug for DefaultProcessConfig {
         f.debug_struct("EnvConfig")
             .field("max_memory", &self.max_memory)
             .field("max_fuel", &self.max_fuel)
+            .field("worker_affinity", &self.worker_affinity)
+            .field("trap_exit", &self.trap_exit)
+            .field("log_normal_exits", &self.log_normal_exits)
+            .field("deterministic_seed", &self.deterministic_seed)
+            .field("max_kv_store_size", &self.max_kv_store_size)
+            .field("max_message_size", &self.max_message_size)
+            .field("max_send_rate", &self.max_send_rate)
+            .field("block_when_rate_limited", &self.block_when_rate_limited)
+            .field("memory_budget", &self.memory_budget)
             .field("preopened_dirs", &self.preopened_dirs)
             .field("args", &self.command_line_arguments)
             .field("envs", &self.environment_variables)
+            .field("cwd", &self.cwd)
+            .field("max_open_fds", &self.max_open_fds)
+            .field("stdout_mode", &self.stdout_mode)
+            .field("output_prefix", &self.output_prefix)
+            .field("stdout_file", &self.stdout_file)
+            .field("stderr_file", &self.stderr_file)
             .finish()
     }
 }
@@ -51,6 +156,38 @@ impl ProcessConfig for DefaultProcessConfig {
     fn get_max_memory(&self) -> usize {
         self.max_memory
     }
+
+    fn set_worker_affinity(&mut self, worker: Option<usize>) {
+        self.worker_affinity = worker
+    }
+
+    fn get_worker_affinity(&self) -> Option<usize> {
+        self.worker_affinity
+    }
+
+    fn set_trap_exit(&mut self, trap_exit: bool) {
+        self.trap_exit = trap_exit
+    }
+
+    fn get_trap_exit(&self) -> bool {
+        self.trap_exit
+    }
+
+    fn set_deterministic_seed(&mut self, seed: Option<u64>) {
+        self.deterministic_seed = seed
+    }
+
+    fn get_deterministic_seed(&self) -> Option<u64> {
+        self.deterministic_seed
+    }
+
+    fn set_log_normal_exits(&mut self, log: bool) {
+        self.log_normal_exits = log
+    }
+
+    fn get_log_normal_exits(&self) -> bool {
+        self.log_normal_exits
+    }
 }
 
 impl LunaticWasiConfigCtx for DefaultProcessConfig {
@@ -58,6 +195,18 @@ impl LunaticWasiConfigCtx for DefaultProcessConfig {
         self.environment_variables.push((key, value));
     }
 
+    fn environment_variable(&self, key: &str) -> Option<&str> {
+        // Matches `getenv`, which returns the first definition
+        self.environment_variables
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value.as_str())
+    }
+
+    fn environment_variables(&self) -> &[(String, String)] {
+        &self.environment_variables
+    }
+
     fn add_command_line_argument(&mut self, argument: String) {
         self.command_line_arguments.push(argument);
     }
@@ -65,9 +214,32 @@ impl LunaticWasiConfigCtx for DefaultProcessConfig {
     fn preopen_dir(&mut self, dir: String) {
         self.preopened_dirs.push(dir);
     }
+
+    fn preopened_dirs(&self) -> &[String] {
+        &self.preopened_dirs
+    }
+
+    fn set_cwd(&mut self, cwd: String) {
+        self.cwd = Some(cwd);
+    }
+
+    fn max_open_fds(&self) -> Option<usize> {
+        self.max_open_fds
+    }
+
+    fn set_max_open_fds(&mut self, max: Option<usize>) {
+        self.max_open_fds = max;
+    }
 }
 
 impl DefaultProcessConfig {
+    /// Returns a builder that starts from the [`Default`] config.
+    pub fn builder() -> DefaultProcessConfigBuilder {
+        DefaultProcessConfigBuilder {
+            config: DefaultProcessConfig::default(),
+        }
+    }
+
     pub fn preopened_dirs(&self) -> &[String] {
         &self.preopened_dirs
     }
@@ -77,6 +249,69 @@ impl DefaultProcessConfig {
         self.preopened_dirs.push(dir.into())
     }
 
+    /// Set the working directory of processes spawned with this config.
+    ///
+    /// It needs to be inside one of the preopened directories.
+    pub fn set_cwd<S: Into<String>>(&mut self, cwd: S) {
+        self.cwd = Some(cwd.into())
+    }
+
+    pub fn cwd(&self) -> Option<&str> {
+        self.cwd.as_deref()
+    }
+
+    pub fn set_stdout_mode(&mut self, mode: StdoutMode) {
+        self.stdout_mode = mode;
+    }
+
+    pub fn stdout_mode(&self) -> StdoutMode {
+        self.stdout_mode
+    }
+
+    pub fn set_output_prefix(&mut self, prefix: OutputPrefix) {
+        self.output_prefix = prefix;
+    }
+
+    pub fn output_prefix(&self) -> OutputPrefix {
+        self.output_prefix
+    }
+
+    /// Writes the stdout of processes spawned with this config to the host file **path**.
+    ///
+    /// The file is opened when the process is spawned. If **append** is not set, it's truncated
+    /// by every process.
+    pub fn set_stdout_file<S: Into<String>>(&mut self, path: S, append: bool) {
+        self.stdout_file = Some(StdioFile {
+            path: path.into(),
+            append,
+        });
+    }
+
+    pub fn stdout_file(&self) -> Option<&StdioFile> {
+        self.stdout_file.as_ref()
+    }
+
+    /// Writes the stderr of processes spawned with this config to the host file **path**, see
+    /// [`set_stdout_file`](Self::set_stdout_file).
+    pub fn set_stderr_file<S: Into<String>>(&mut self, path: S, append: bool) {
+        self.stderr_file = Some(StdioFile {
+            path: path.into(),
+            append,
+        });
+    }
+
+    pub fn stderr_file(&self) -> Option<&StdioFile> {
+        self.stderr_file.as_ref()
+    }
+
+    /// Sets the stdout mode and output prefix of all configs created afterwards with
+    /// `DefaultProcessConfig::default()`, including the ones created by guests.
+    ///
+    /// Returns `false` if the defaults were already set.
+    pub fn set_default_output(mode: StdoutMode, prefix: OutputPrefix) -> bool {
+        DEFAULT_OUTPUT.set((mode, prefix)).is_ok()
+    }
+
     pub fn set_command_line_arguments(&mut self, args: Vec<String>) {
         self.command_line_arguments = args;
     }
@@ -118,19 +353,373 @@ impl ProcessConfigCtx for DefaultProcessConfig {
     fn set_can_spawn_processes(&mut self, can: bool) {
         self.can_spawn_processes = can
     }
+
+    fn detached(&self) -> bool {
+        self.detached
+    }
+
+    fn set_detached(&mut self, detached: bool) {
+        self.detached = detached
+    }
+
+    fn max_kv_store_size(&self) -> usize {
+        self.max_kv_store_size
+    }
+
+    fn set_max_kv_store_size(&mut self, size: usize) {
+        self.max_kv_store_size = size
+    }
+
+    fn max_message_size(&self) -> Option<usize> {
+        self.max_message_size
+    }
+
+    fn set_max_message_size(&mut self, size: Option<usize>) {
+        self.max_message_size = size
+    }
+
+    fn max_send_rate(&self) -> Option<u32> {
+        self.max_send_rate
+    }
+
+    fn set_max_send_rate(&mut self, rate: Option<u32>) {
+        self.max_send_rate = rate
+    }
+
+    fn block_when_rate_limited(&self) -> bool {
+        self.block_when_rate_limited
+    }
+
+    fn set_block_when_rate_limited(&mut self, block: bool) {
+        self.block_when_rate_limited = block
+    }
+
+    fn memory_budget(&self) -> Option<u64> {
+        self.memory_budget
+    }
+
+    fn set_memory_budget(&mut self, budget: Option<u64>) {
+        self.memory_budget = budget
+    }
 }
 
 impl Default for DefaultProcessConfig {
     fn default() -> Self {
+        let (stdout_mode, output_prefix) = DEFAULT_OUTPUT.get().copied().unwrap_or_default();
         Self {
             max_memory: u32::MAX as usize, // = 4 GB
             max_fuel: None,
             can_compile_modules: false,
             can_create_configs: false,
             can_spawn_processes: false,
+            detached: false,
+            worker_affinity: None,
+            trap_exit: false,
+            log_normal_exits: false,
+            deterministic_seed: None,
+            max_kv_store_size: 64 * 1024, // = 64 KB
+            max_message_size: None,
+            max_send_rate: None,
+            block_when_rate_limited: false,
+            memory_budget: None,
             preopened_dirs: vec![],
             command_line_arguments: vec![],
             environment_variables: vec![],
+            cwd: None,
+            max_open_fds: None,
+            stdout_mode,
+            output_prefix,
+            stdout_file: None,
+            stderr_file: None,
+        }
+    }
+}
+
+/// Builds a [`DefaultProcessConfig`], checking that the settings are consistent.
+///
+/// ```
+/// # use lunatic_runtime::DefaultProcessConfig;
+/// let config = DefaultProcessConfig::builder()
+///     .max_memory(64 * 1024 * 1024)
+///     .can_spawn_processes(true)
+///     .preopen_dir(".")
+///     .build()
+///     .unwrap();
+/// ```
+#[derive(Clone, Debug)]
+pub struct DefaultProcessConfigBuilder {
+    config: DefaultProcessConfig,
+}
+
+impl DefaultProcessConfigBuilder {
+    /// Maximum amount of memory that processes can use in bytes.
+    pub fn max_memory(mut self, max_memory: usize) -> Self {
+        self.config.max_memory = max_memory;
+        self
+    }
+
+    /// Maximum amount of compute expressed in units of 100k instructions.
+    pub fn max_fuel(mut self, max_fuel: Option<u64>) -> Self {
+        self.config.max_fuel = max_fuel;
+        self
+    }
+
+    /// Pins processes to a dedicated worker thread. This is only a hint, processes run on the
+    /// shared scheduler if the worker doesn't exist.
+    pub fn worker_affinity(mut self, worker: Option<usize>) -> Self {
+        self.config.worker_affinity = worker;
+        self
+    }
+
+    /// Processes that trap exits receive a message if a linked process fails, instead of failing
+    /// together with it.
+    pub fn trap_exit(mut self, trap_exit: bool) -> Self {
+        self.config.trap_exit = trap_exit;
+        self
+    }
+
+    /// Logs processes that finish normally at the debug level, failures are always logged.
+    pub fn log_normal_exits(mut self, log: bool) -> Self {
+        self.config.log_normal_exits = log;
+        self
+    }
+
+    /// Runs processes on a single thread in an order derived from **seed**, so that runs are
+    /// reproducible. Only meant for tracking down concurrency bugs.
+    pub fn deterministic_seed(mut self, seed: Option<u64>) -> Self {
+        self.config.deterministic_seed = seed;
+        self
+    }
+
+    pub fn can_compile_modules(mut self, can: bool) -> Self {
+        self.config.can_compile_modules = can;
+        self
+    }
+
+    pub fn can_create_configs(mut self, can: bool) -> Self {
+        self.config.can_create_configs = can;
+        self
+    }
+
+    pub fn can_spawn_processes(mut self, can: bool) -> Self {
+        self.config.can_spawn_processes = can;
+        self
+    }
+
+    /// Detached processes keep running after the process that spawned them finished, otherwise
+    /// they are killed together with it.
+    pub fn detached(mut self, detached: bool) -> Self {
+        self.config.detached = detached;
+        self
+    }
+
+    /// Maximum combined size of all keys and values that processes can keep in their
+    /// process-local key/value store in bytes.
+    pub fn max_kv_store_size(mut self, size: usize) -> Self {
+        self.config.max_kv_store_size = size;
+        self
+    }
+
+    /// Maximum size of messages that processes can send in bytes, `None` for unlimited.
+    pub fn max_message_size(mut self, size: Option<usize>) -> Self {
+        self.config.max_message_size = size;
+        self
+    }
+
+    /// Maximum number of messages per second that processes can send to each other process,
+    /// `None` for unlimited.
+    pub fn max_send_rate(mut self, rate: Option<u32>) -> Self {
+        self.config.max_send_rate = rate;
+        self
+    }
+
+    /// Sends above the rate limit wait until they are allowed instead of failing.
+    pub fn block_when_rate_limited(mut self, block: bool) -> Self {
+        self.config.block_when_rate_limited = block;
+        self
+    }
+
+    /// Host memory in bytes that processes see as available at most, so that a tenant only sees
+    /// its allotment. `None` reports all available host memory.
+    pub fn memory_budget(mut self, budget: Option<u64>) -> Self {
+        self.config.memory_budget = budget;
+        self
+    }
+
+    /// Grant access to the given directory.
+    pub fn preopen_dir<S: Into<String>>(mut self, dir: S) -> Self {
+        self.config.preopened_dirs.push(dir.into());
+        self
+    }
+
+    pub fn command_line_arguments(mut self, args: Vec<String>) -> Self {
+        self.config.command_line_arguments = args;
+        self
+    }
+
+    pub fn environment_variables(mut self, envs: Vec<(String, String)>) -> Self {
+        self.config.environment_variables = envs;
+        self
+    }
+
+    /// Working directory of the processes, needs to be inside a preopened directory.
+    pub fn cwd<S: Into<String>>(mut self, cwd: S) -> Self {
+        self.config.cwd = Some(cwd.into());
+        self
+    }
+
+    /// Maximum number of files and sockets that processes can open through WASI at the same
+    /// time, `None` for unlimited. Opening more fails with `EMFILE`.
+    pub fn max_open_fds(mut self, max: Option<usize>) -> Self {
+        self.config.max_open_fds = max;
+        self
+    }
+
+    pub fn stdout_mode(mut self, mode: StdoutMode) -> Self {
+        self.config.stdout_mode = mode;
+        self
+    }
+
+    pub fn output_prefix(mut self, prefix: OutputPrefix) -> Self {
+        self.config.output_prefix = prefix;
+        self
+    }
+
+    pub fn stdout_file<S: Into<String>>(mut self, path: S, append: bool) -> Self {
+        self.config.set_stdout_file(path, append);
+        self
+    }
+
+    pub fn stderr_file<S: Into<String>>(mut self, path: S, append: bool) -> Self {
+        self.config.set_stderr_file(path, append);
+        self
+    }
+
+    /// Returns the config or an error if the settings conflict.
+    pub fn build(self) -> Result<DefaultProcessConfig> {
+        let config = self.config;
+        if config.max_memory == 0 {
+            return Err(anyhow!("Maximum memory must be bigger than 0"));
+        }
+        if config.max_fuel == Some(0) {
+            return Err(anyhow!(
+                "Maximum fuel must be bigger than 0, use `None` for unlimited fuel"
+            ));
         }
+        if let Some(cwd) = config.cwd.as_deref() {
+            if !is_inside_preopened_dir(&config.preopened_dirs, cwd) {
+                return Err(anyhow!(
+                    "Working directory {} is not inside a preopened directory",
+                    cwd
+                ));
+            }
+        }
+        Ok(config)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lunatic_process::config::ProcessConfig;
+    use lunatic_process_api::ProcessConfigCtx;
+    use lunatic_wasi_api::LunaticWasiConfigCtx;
+
+    use super::{DefaultProcessConfig, OutputPrefix, StdioFile, StdoutMode};
+
+    #[test]
+    fn builder_defaults_match_default_config() {
+        let config = DefaultProcessConfig::builder().build().unwrap();
+        let default = DefaultProcessConfig::default();
+        assert_eq!(config.get_max_memory(), default.get_max_memory());
+        assert_eq!(config.get_max_fuel(), None);
+        assert!(!config.can_compile_modules());
+        assert!(!config.can_create_configs());
+        assert!(!config.can_spawn_processes());
+        assert!(!config.detached());
+        assert!(!config.get_log_normal_exits());
+        assert_eq!(config.max_message_size(), None);
+        assert_eq!(config.max_send_rate(), None);
+        assert!(!config.block_when_rate_limited());
+        assert_eq!(config.memory_budget(), None);
+        assert!(config.preopened_dirs().is_empty());
+        assert_eq!(config.cwd(), None);
+        assert_eq!(config.max_open_fds(), None);
+        assert_eq!(config.stdout_mode(), StdoutMode::PassThrough);
+        assert_eq!(config.output_prefix(), OutputPrefix::Id);
+    }
+
+    #[test]
+    fn builder_sets_all_options() {
+        let config = DefaultProcessConfig::builder()
+            .max_memory(1024 * 1024)
+            .max_fuel(Some(10))
+            .can_compile_modules(true)
+            .can_create_configs(true)
+            .can_spawn_processes(true)
+            .detached(true)
+            .log_normal_exits(true)
+            .max_kv_store_size(128)
+            .max_message_size(Some(256))
+            .max_send_rate(Some(100))
+            .block_when_rate_limited(true)
+            .memory_budget(Some(4096))
+            .preopen_dir("/tmp")
+            .command_line_arguments(vec!["main.wasm".to_string()])
+            .environment_variables(vec![("KEY".to_string(), "value".to_string())])
+            .cwd("/tmp/sub")
+            .max_open_fds(Some(8))
+            .stdout_mode(StdoutMode::LineBuffered)
+            .output_prefix(OutputPrefix::Label)
+            .stdout_file("/tmp/out.log", true)
+            .build()
+            .unwrap();
+        assert_eq!(config.get_max_memory(), 1024 * 1024);
+        assert_eq!(config.get_max_fuel(), Some(10));
+        assert!(config.can_compile_modules());
+        assert!(config.can_create_configs());
+        assert!(config.can_spawn_processes());
+        assert!(config.detached());
+        assert!(config.get_log_normal_exits());
+        assert_eq!(config.max_kv_store_size(), 128);
+        assert_eq!(config.max_message_size(), Some(256));
+        assert_eq!(config.max_send_rate(), Some(100));
+        assert!(config.block_when_rate_limited());
+        assert_eq!(config.memory_budget(), Some(4096));
+        assert_eq!(config.preopened_dirs(), ["/tmp"]);
+        assert_eq!(config.command_line_arguments(), &["main.wasm"]);
+        assert_eq!(
+            config.environment_variables(),
+            &[("KEY".to_string(), "value".to_string())]
+        );
+        assert_eq!(config.cwd(), Some("/tmp/sub"));
+        assert_eq!(config.max_open_fds(), Some(8));
+        assert_eq!(config.stdout_mode(), StdoutMode::LineBuffered);
+        assert_eq!(config.output_prefix(), OutputPrefix::Label);
+        assert_eq!(
+            config.stdout_file(),
+            Some(&StdioFile {
+                path: "/tmp/out.log".to_string(),
+                append: true
+            })
+        );
+        assert_eq!(config.stderr_file(), None);
+    }
+
+    #[test]
+    fn builder_rejects_conflicting_settings() {
+        assert!(DefaultProcessConfig::builder()
+            .max_memory(0)
+            .build()
+            .is_err());
+        assert!(DefaultProcessConfig::builder()
+            .max_fuel(Some(0))
+            .build()
+            .is_err());
+        // The working directory must be inside of the sandbox
+        assert!(DefaultProcessConfig::builder()
+            .preopen_dir("/tmp")
+            .cwd("/tmp/../etc")
+            .build()
+            .is_err());
     }
 }
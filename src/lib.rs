@@ -24,6 +24,9 @@ Wasm modules, checkout out the [lunatic crate](https://crates.io/crates/lunatic)
 * [`WasmProcess`](process::WasmProcess) - a handle to send signals and messages to spawned
   Wasm processes. It implements the [`Process`](process::Process) trait.
 
+* [`run_module`] - compiles a Wasm module and runs one of its functions to completion, returning
+  the [`ExitStatus`]. Useful for tests and simple CLIs that don't need to assemble the pieces.
+
 
 ## WebAssembly module requirements
 
@@ -31,8 +34,12 @@ TODO
 */
 
 mod config;
+mod run;
 pub mod state;
 
-pub use config::DefaultProcessConfig;
+pub use config::{
+    DefaultProcessConfig, DefaultProcessConfigBuilder, OutputPrefix, StdioFile, StdoutMode,
+};
 pub use lunatic_process::{Finished, Process, Signal, WasmProcess};
+pub use run::{run_module, ExitStatus};
 pub use state::DefaultProcessState;
@@ -1,11 +1,13 @@
-use std::{collections::HashMap, env, fs, path::Path, sync::Arc};
+use std::{collections::HashMap, env, fs, path::Path, sync::Arc, time::Duration};
 
 use anyhow::{anyhow, Context, Ok, Result};
 use clap::Parser;
+use dashmap::DashMap;
+use lunatic_common_api::blocking;
 use lunatic_distributed::{
-    control::{self, server::control_server, Scanner, TokenType},
-    distributed::{self, server::ServerCtx},
-    quic,
+    control::{self, message::NodeStats, server::control_server, Scanner, TokenType},
+    distributed::{self, admission::AllowAll, client::ConnectionPool, server::ServerCtx},
+    quic, NodeCapabilities,
 };
 use lunatic_process::{
     env::{Environments, LunaticEnvironments},
@@ -13,7 +15,7 @@ use lunatic_process::{
     wasm::spawn_wasm,
 };
 use lunatic_process_api::ProcessConfigCtx;
-use lunatic_runtime::{DefaultProcessConfig, DefaultProcessState};
+use lunatic_runtime::{DefaultProcessConfig, DefaultProcessState, OutputPrefix, StdoutMode};
 use tokio::sync::mpsc::channel;
 use uuid::Uuid;
 
@@ -36,6 +38,23 @@ struct Args {
     #[arg(long, requires = "control")]
     control_server: bool,
 
+    /// How long to keep retrying the connection to the control node at startup
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        requires = "control",
+        default_value_t = 30
+    )]
+    control_connect_timeout: u64,
+
+    /// Maximum number of connections kept open to each other node
+    #[arg(long, value_name = "COUNT", requires = "node", default_value_t = 1)]
+    node_connections: usize,
+
+    /// Close connections to other nodes that didn't send anything for SECONDS
+    #[arg(long, value_name = "SECONDS", requires = "node")]
+    node_connection_idle_timeout: Option<u64>,
+
     /// Use test Certificate Authority for bootstrapping QUIC connections
     #[arg(long, requires = "control")]
     test_ca: bool,
@@ -60,6 +79,48 @@ struct Args {
     #[arg(long)]
     bench: bool,
 
+    /// Reject modules using the WebAssembly SIMD proposal
+    #[arg(long)]
+    disable_simd: bool,
+
+    /// Reject modules using the WebAssembly bulk memory proposal (requires
+    /// --disable-reference-types)
+    #[arg(long, requires = "disable_reference_types")]
+    disable_bulk_memory: bool,
+
+    /// Reject modules using the WebAssembly reference types proposal
+    #[arg(long)]
+    disable_reference_types: bool,
+
+    /// Cranelift optimization level of compiled modules: `none` compiles fastest, `speed` and
+    /// `speed-and-size` generate faster code
+    #[arg(long, value_name = "LEVEL", default_value = "speed-and-size")]
+    opt_level: runtimes::wasmtime::OptLevel,
+
+    /// Maximum number of processes that can run at the same time inside an environment
+    #[arg(long, value_name = "COUNT")]
+    max_processes: Option<usize>,
+
+    /// Maximum number of blocking host operations (reads, writes and syncs of files in preopened
+    /// directories and DNS lookups) running at the same time
+    #[arg(long, value_name = "COUNT", default_value_t = blocking::DEFAULT_POOL_SIZE)]
+    blocking_threads: usize,
+
+    /// Terminate processes that have been waiting for a message for longer than SECONDS and
+    /// that no other process can reach (no links, monitors, names or held handles)
+    #[arg(long, value_name = "SECONDS")]
+    idle_timeout: Option<u64>,
+
+    /// Buffer the console output of processes by line and prefix each line with the process id
+    /// (`id`), the id and label (`label`) or nothing (`none`)
+    #[arg(long, value_name = "PREFIX")]
+    output_prefix: Option<OutputPrefix>,
+
+    /// Accept modules importing WASI functions that are not implemented, calling them returns
+    /// `ENOSYS`
+    #[arg(long)]
+    stub_missing_wasi: bool,
+
     /// Entry .wasm file
     #[arg(conflicts_with = "no_entry", index = 1)]
     wasm: Option<String>,
@@ -108,11 +169,27 @@ pub(crate) async fn execute() -> Result<()> {
     }
 
     // Create wasmtime runtime
-    let wasmtime_config = runtimes::wasmtime::default_config();
-    let runtime = runtimes::wasmtime::WasmtimeRuntime::new(&wasmtime_config)?;
-    let envs = Arc::new(LunaticEnvironments::default());
+    let features = runtimes::wasmtime::WasmFeatures {
+        simd: !args.disable_simd,
+        bulk_memory: !args.disable_bulk_memory,
+        reference_types: !args.disable_reference_types,
+    };
+    let mut runtime =
+        runtimes::wasmtime::WasmtimeRuntime::with_opt_level(features, args.opt_level)?;
+    runtime.set_stub_missing_wasi(args.stub_missing_wasi);
+    let envs = Arc::new(LunaticEnvironments::with_max_processes(args.max_processes));
+    blocking::set_pool_size(args.blocking_threads);
+    if let Some(prefix) = args.output_prefix {
+        DefaultProcessConfig::set_default_output(StdoutMode::LineBuffered, prefix);
+    }
 
     let env = envs.create(1);
+    let modules = Modules::<DefaultProcessState>::default();
+    // Shared by the main process and processes spawned on this node by other nodes
+    let registry = Arc::new(DashMap::new());
+    if let Some(idle_timeout) = args.idle_timeout {
+        envs.spawn_idle_reaper(Duration::from_secs(idle_timeout), registry.clone());
+    }
 
     let (distributed_state, control_client, node_id) =
         if let (Some(node_address), Some(control_address)) = (args.node, args.control) {
@@ -135,15 +212,25 @@ pub(crate) async fn execute() -> Result<()> {
                 node_address,
                 node_name.to_string(),
                 node_attributes,
+                NodeCapabilities::new::<DefaultProcessState>(&runtime)?,
                 control_address,
                 quic_client.clone(),
                 node_cert.serialize_request_pem().unwrap(),
+                Duration::from_secs(args.control_connect_timeout),
             )
             .await?;
 
-            let distributed_client =
-                distributed::Client::new(node_id, control_client.clone(), quic_client.clone())
-                    .await?;
+            let pool = ConnectionPool {
+                max_connections_per_node: args.node_connections,
+                idle_timeout: args.node_connection_idle_timeout.map(Duration::from_secs),
+            };
+            let distributed_client = distributed::Client::with_pool(
+                node_id,
+                control_client.clone(),
+                quic_client.clone(),
+                pool,
+            )
+            .await?;
 
             let dist = lunatic_distributed::DistributedProcessState::new(
                 node_id,
@@ -152,12 +239,21 @@ pub(crate) async fn execute() -> Result<()> {
             )
             .await?;
 
+            let node_envs = envs.clone();
+            let node_client = dist.node_client.clone();
+            control_client.report_node_stats_task(node_id, move || NodeStats {
+                process_count: node_envs.process_count() as u64,
+                node_health: node_client.node_health(),
+            });
+
             tokio::task::spawn(lunatic_distributed::distributed::server::node_server(
                 ServerCtx {
                     envs,
-                    modules: Modules::<DefaultProcessState>::default(),
+                    modules: modules.clone(),
                     distributed: dist.clone(),
                     runtime: runtime.clone(),
+                    registry: registry.clone(),
+                    admission: Arc::new(AllowAll),
                 },
                 node_address,
                 signed_cert_pem,
@@ -227,18 +323,24 @@ pub(crate) async fn execute() -> Result<()> {
     // Spawn main process
     let module = fs::read(path)?;
     let module: RawWasm = if let Some(dist) = distributed_state.as_ref() {
-        dist.control.add_module(module).await?
+        // Register only the content hash, other nodes fetch the bytes from this node
+        let hash = lunatic_distributed::module_hash(&module);
+        let id = dist.control.add_module_hash(dist.node_id(), hash).await?;
+        RawWasm::new(Some(id), module)
     } else {
         module.into()
     };
     let module = Arc::new(runtime.compile_module::<DefaultProcessState>(module)?);
+    if let Some(id) = module.source().id {
+        modules.insert(id, module.clone());
+    }
     let state = DefaultProcessState::new(
         env.clone(),
         distributed_state,
         runtime.clone(),
         module.clone(),
         Arc::new(config),
-        Default::default(),
+        registry,
     )
     .unwrap();
 
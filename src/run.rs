@@ -0,0 +1,89 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use lunatic_process::env::LunaticEnvironment;
+use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+use lunatic_process::wasm::spawn_wasm;
+use lunatic_process::ProcessFailure;
+use lunatic_process_api::ProcessConfigCtx;
+
+use crate::{DefaultProcessConfig, DefaultProcessState};
+
+/// The way a process spawned with [`run_module`] finished.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ExitStatus {
+    /// The entry function returned or the process called `proc_exit(0)`.
+    Success,
+    /// The process trapped, called `proc_exit` with a non-zero code or the entry function
+    /// couldn't be called. Contains the failure message.
+    Failure(String),
+    /// The process was killed, e.g. because a linked process failed.
+    Killed,
+}
+
+/// Compiles the Wasm module **bytes** and runs its **entry** function to completion.
+///
+/// **args** are passed to the process as WASI command line arguments. Like the initial process
+/// of the `lunatic` binary, the process is allowed to compile modules, create configurations and
+/// spawn sub-processes. This function sets up a new runtime and blocks until the process exits,
+/// so it can't be called from inside an async context.
+///
+/// An error is returned if the module can't be compiled or the process can't be spawned.
+pub fn run_module(bytes: Vec<u8>, entry: &str, args: Vec<String>) -> Result<ExitStatus> {
+    let mut config = DefaultProcessConfig::default();
+    config.set_can_compile_modules(true);
+    config.set_can_create_configs(true);
+    config.set_can_spawn_processes(true);
+    config.set_command_line_arguments(args);
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(async {
+            let runtime = WasmtimeRuntime::new(&default_config())?;
+            let module = Arc::new(runtime.compile_module::<DefaultProcessState>(bytes.into())?);
+            let env = Arc::new(LunaticEnvironment::new(0));
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(config),
+                Default::default(),
+            )?;
+            let (task, _) =
+                spawn_wasm(env, runtime, &module, state, entry, Vec::new(), None).await?;
+            Ok(match task.await? {
+                Ok(_) => ExitStatus::Success,
+                Err(err) => match err.downcast::<ProcessFailure>() {
+                    Ok(failure) => ExitStatus::Failure(failure.to_string()),
+                    // Processes only finish with a non `ProcessFailure` error if they were killed
+                    Err(_) => ExitStatus::Killed,
+                },
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn run_module_reports_exit_status() {
+        let module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "proc_exit" (func $proc_exit (param i32)))
+                (memory (export "memory") 1)
+                (func (export "exit_zero") (call $proc_exit (i32.const 0)))
+                (func (export "trap") unreachable))
+            "#,
+        )
+        .unwrap();
+
+        let status = run_module(module.clone(), "exit_zero", Vec::new()).unwrap();
+        assert_eq!(status, ExitStatus::Success);
+        let status = run_module(module, "trap", Vec::new()).unwrap();
+        assert!(matches!(status, ExitStatus::Failure(_)));
+    }
+}
@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::fmt::Debug;
 use std::sync::Arc;
 
@@ -8,25 +9,28 @@ use lunatic_distributed::{DistributedCtx, DistributedProcessState};
 use lunatic_error_api::{ErrorCtx, ErrorResource};
 use lunatic_networking_api::{DnsIterator, TlsConnection, TlsListener};
 use lunatic_networking_api::{NetworkingCtx, TcpConnection};
-use lunatic_process::env::{Environment, LunaticEnvironment};
+use lunatic_process::env::{Environment, LunaticEnvironment, ProcessStats};
 use lunatic_process::runtimes::wasmtime::{WasmtimeCompiledModule, WasmtimeRuntime};
 use lunatic_process::state::{ConfigResources, ProcessState};
 use lunatic_process::{
-    config::ProcessConfig,
+    config::{ProcessConfig, UNIT_OF_COMPUTE_IN_INSTRUCTIONS},
     state::{SignalReceiver, SignalSender},
 };
 use lunatic_process::{mailbox::MessageMailbox, message::Message};
-use lunatic_process_api::{ProcessConfigCtx, ProcessCtx};
-use lunatic_stdout_capture::StdoutCapture;
+use lunatic_process_api::{
+    KvStore, ModuleUploadResources, ProcessConfigCtx, ProcessCtx, ReplyRefResources, ResourceKind,
+    SendRateLimiter, SharedRegionResources,
+};
+use lunatic_stdout_capture::{LineBuffered, LinePrefix, StdoutCapture};
 use lunatic_timer_api::{TimerCtx, TimerResources};
-use lunatic_wasi_api::{build_wasi, LunaticWasiCtx};
+use lunatic_wasi_api::{build_wasi, open_stdio_file, LunaticWasiCtx};
 use tokio::net::{TcpListener, UdpSocket};
 use tokio::sync::mpsc::unbounded_channel;
 use tokio::sync::Mutex;
 use wasmtime::{Linker, ResourceLimiter};
 use wasmtime_wasi::WasiCtx;
 
-use crate::DefaultProcessConfig;
+use crate::{DefaultProcessConfig, StdoutMode};
 
 pub struct DefaultProcessState {
     // Process id
@@ -45,10 +49,23 @@ pub struct DefaultProcessState {
     // guest to reserve enough space and then the it's received. Both of those actions use
     // `message` as a temp space to store messages across host calls.
     message: Option<Message>,
+    // Process-local key/value store, see `lunatic::process::kv_set`
+    kv_store: KvStore,
+    // Rate limits of messages sent to other processes
+    send_rate_limiter: SendRateLimiter,
+    // Seed of the ids returned by `lunatic::process::unique_id`
+    unique_id_seed: u64,
+    // Instructions run before yielding, see `lunatic::process::set_quantum`
+    quantum: u64,
+    // Mirrors the `DieWhenLinkDies` signals the process sent to itself (inverted), see
+    // `lunatic::process::set_trap_exit`
+    trap_exit: bool,
     // Signals sent to the mailbox
     signal_mailbox: (SignalSender, SignalReceiver),
     // Messages sent to the process
     message_mailbox: MessageMailbox,
+    // Accounting data used for introspection
+    stats: ProcessStats,
     // Resources
     resources: Resources,
     // WASI
@@ -57,6 +74,12 @@ pub struct DefaultProcessState {
     wasi_stdout: Option<StdoutCapture>,
     // WASI stderr stream
     wasi_stderr: Option<StdoutCapture>,
+    // WASI working directory, relative paths are resolved against it
+    wasi_cwd: Option<String>,
+    // File descriptors moved into the next spawned process, see `lunatic::wasi::inherit_fd`
+    wasi_inherited_fds: Vec<(u32, u32)>,
+    // File descriptors opened by the process, see `max_open_fds` of the config
+    wasi_opened_fds: HashSet<u32>,
     // Set to true if the WASM module has been instantiated
     initialized: bool,
     // Shared process registry
@@ -75,7 +98,8 @@ impl DefaultProcessState {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
         let message_mailbox = MessageMailbox::default();
-        let state = Self {
+        let resources = Resources::recycled(&environment);
+        let mut state = Self {
             id: environment.get_next_process_id(),
             environment,
             distributed,
@@ -83,21 +107,60 @@ impl DefaultProcessState {
             module: Some(module),
             config: config.clone(),
             message: None,
+            kv_store: KvStore::default(),
+            send_rate_limiter: SendRateLimiter::default(),
+            unique_id_seed: 0,
+            quantum: UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+            trap_exit: config.get_trap_exit(),
             signal_mailbox,
+            stats: ProcessStats::new(message_mailbox.clone()),
             message_mailbox,
-            resources: Resources::default(),
+            resources,
             wasi: build_wasi(
                 Some(config.command_line_arguments()),
                 Some(config.environment_variables()),
                 config.preopened_dirs(),
+                config.cwd(),
             )?,
             wasi_stdout: None,
             wasi_stderr: None,
+            wasi_cwd: config.cwd().map(String::from),
+            wasi_inherited_fds: Vec::new(),
+            wasi_opened_fds: HashSet::new(),
             initialized: false,
             registry,
         };
+        state.apply_stdio()?;
         Ok(state)
     }
+
+    // Replaces the inherited stdout and stderr with line buffered streams or files, if the config
+    // asks for it. Files take precedence over the stdout mode.
+    fn apply_stdio(&mut self) -> Result<()> {
+        if self.config.stdout_mode() == StdoutMode::LineBuffered {
+            let stdout =
+                LineBuffered::with_prefix(self.output_prefix(), LineBuffered::stdout_sink());
+            self.wasi.set_stdout(Box::new(stdout));
+            let stderr =
+                LineBuffered::with_prefix(self.output_prefix(), LineBuffered::stderr_sink());
+            self.wasi.set_stderr(Box::new(stderr));
+        }
+        if let Some(file) = self.config.stdout_file() {
+            self.wasi
+                .set_stdout(open_stdio_file(&file.path, file.append)?);
+        }
+        if let Some(file) = self.config.stderr_file() {
+            self.wasi
+                .set_stderr(open_stdio_file(&file.path, file.append)?);
+        }
+        Ok(())
+    }
+
+    // The label is looked up on every write, so that it's picked up after the process sets it.
+    fn output_prefix(&self) -> LinePrefix {
+        let (id, prefix, stats) = (self.id, self.config.output_prefix(), self.stats.clone());
+        Box::new(move || prefix.format(id, stats.label().as_deref()))
+    }
 }
 
 impl ProcessState for DefaultProcessState {
@@ -111,7 +174,7 @@ impl ProcessState for DefaultProcessState {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
         let message_mailbox = MessageMailbox::default();
-        let state = Self {
+        let mut state = Self {
             id: self.environment.get_next_process_id(),
             environment: self.environment.clone(),
             distributed: self.distributed.clone(),
@@ -119,19 +182,30 @@ impl ProcessState for DefaultProcessState {
             module: Some(module),
             config: config.clone(),
             message: None,
+            kv_store: KvStore::default(),
+            send_rate_limiter: SendRateLimiter::default(),
+            unique_id_seed: 0,
+            quantum: UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+            trap_exit: config.get_trap_exit(),
             signal_mailbox,
+            stats: ProcessStats::new(message_mailbox.clone()),
             message_mailbox,
-            resources: Resources::default(),
+            resources: Resources::recycled(&self.environment),
             wasi: build_wasi(
                 Some(config.command_line_arguments()),
                 Some(config.environment_variables()),
                 config.preopened_dirs(),
+                config.cwd(),
             )?,
             wasi_stdout: None,
             wasi_stderr: None,
+            wasi_cwd: config.cwd().map(String::from),
+            wasi_inherited_fds: Vec::new(),
+            wasi_opened_fds: HashSet::new(),
             initialized: false,
             registry: self.registry.clone(),
         };
+        state.apply_stdio()?;
         Ok(state)
     }
 
@@ -149,17 +223,27 @@ impl ProcessState for DefaultProcessState {
             registry: Default::default(),
             config: Arc::new(config.clone()),
             message: None,
+            kv_store: KvStore::default(),
+            send_rate_limiter: SendRateLimiter::default(),
+            unique_id_seed: 0,
+            quantum: UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+            trap_exit: config.get_trap_exit(),
             signal_mailbox,
+            stats: ProcessStats::new(message_mailbox.clone()),
             message_mailbox,
             resources: Resources::default(),
             wasi: build_wasi(
                 Some(config.command_line_arguments()),
                 Some(config.environment_variables()),
                 config.preopened_dirs(),
+                config.cwd(),
             )
             .unwrap(),
             wasi_stdout: None,
             wasi_stderr: None,
+            wasi_cwd: config.cwd().map(String::from),
+            wasi_inherited_fds: Vec::new(),
+            wasi_opened_fds: HashSet::new(),
             initialized: false,
         }
     }
@@ -211,6 +295,10 @@ impl ProcessState for DefaultProcessState {
         &self.message_mailbox
     }
 
+    fn stats(&self) -> &ProcessStats {
+        &self.stats
+    }
+
     fn config_resources(&self) -> &ConfigResources<<DefaultProcessState as ProcessState>::Config> {
         &self.resources.configs
     }
@@ -226,6 +314,20 @@ impl ProcessState for DefaultProcessState {
     }
 }
 
+// Flush the captured output when a process finishes, even if it trapped or exited without
+// flushing it explicitly. The emptied resource tables are left to the next process spawned in
+// the environment.
+impl Drop for DefaultProcessState {
+    fn drop(&mut self) {
+        for stream in self.wasi_stdout.iter().chain(self.wasi_stderr.iter()) {
+            stream.flush().ok();
+        }
+        let mut resources = std::mem::take(&mut self.resources);
+        resources.clear();
+        self.environment.recycle_pool().put(resources);
+    }
+}
+
 impl Debug for DefaultProcessState {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.debug_struct("State")
@@ -237,7 +339,11 @@ impl Debug for DefaultProcessState {
 // Limit the maximum memory of the process depending on the environment it was spawned in.
 impl ResourceLimiter for DefaultProcessState {
     fn memory_growing(&mut self, _current: usize, desired: usize, _maximum: Option<usize>) -> bool {
-        desired <= self.config().get_max_memory()
+        let allowed = desired <= self.config().get_max_memory();
+        if allowed {
+            self.stats.set_memory_usage(desired);
+        }
+        allowed
     }
 
     fn table_growing(&mut self, _current: u32, desired: u32, _maximum: Option<u32>) -> bool {
@@ -279,6 +385,34 @@ impl ProcessCtx<DefaultProcessState> for DefaultProcessState {
         &mut self.message
     }
 
+    fn kv_store(&mut self) -> &mut KvStore {
+        &mut self.kv_store
+    }
+
+    fn send_rate_limiter(&mut self) -> &mut SendRateLimiter {
+        &mut self.send_rate_limiter
+    }
+
+    fn unique_id_seed(&mut self) -> &mut u64 {
+        &mut self.unique_id_seed
+    }
+
+    fn quantum(&self) -> u64 {
+        self.quantum
+    }
+
+    fn quantum_mut(&mut self) -> &mut u64 {
+        &mut self.quantum
+    }
+
+    fn trap_exit(&mut self) -> &mut bool {
+        &mut self.trap_exit
+    }
+
+    fn reply_ref_resources_mut(&mut self) -> &mut ReplyRefResources {
+        &mut self.resources.reply_refs
+    }
+
     fn module_resources(&self) -> &lunatic_process_api::ModuleResources<DefaultProcessState> {
         &self.resources.modules
     }
@@ -289,9 +423,105 @@ impl ProcessCtx<DefaultProcessState> for DefaultProcessState {
         &mut self.resources.modules
     }
 
+    fn module_upload_resources_mut(&mut self) -> &mut ModuleUploadResources {
+        &mut self.resources.module_uploads
+    }
+
+    fn shared_region_resources(&self) -> &SharedRegionResources {
+        &self.resources.shared_regions
+    }
+
+    fn shared_region_resources_mut(&mut self) -> &mut SharedRegionResources {
+        &mut self.resources.shared_regions
+    }
+
     fn environment(&self) -> Arc<dyn Environment> {
         self.environment.clone()
     }
+
+    fn resources(&self) -> Vec<(ResourceKind, u64)> {
+        let r = &self.resources;
+        let mut resources = Vec::new();
+        resources.extend(r.configs.iter().map(|(id, _)| (ResourceKind::Config, id)));
+        resources.extend(r.modules.iter().map(|(id, _)| (ResourceKind::Module, id)));
+        resources.extend(r.timers.ids().map(|id| (ResourceKind::Timer, id)));
+        resources.extend(
+            r.dns_iterators
+                .iter()
+                .map(|(id, _)| (ResourceKind::DnsIterator, id)),
+        );
+        resources.extend(
+            r.tcp_listeners
+                .iter()
+                .map(|(id, _)| (ResourceKind::TcpListener, id)),
+        );
+        resources.extend(
+            r.tcp_streams
+                .iter()
+                .map(|(id, _)| (ResourceKind::TcpStream, id)),
+        );
+        resources.extend(
+            r.tls_listeners
+                .iter()
+                .map(|(id, _)| (ResourceKind::TlsListener, id)),
+        );
+        resources.extend(
+            r.tls_streams
+                .iter()
+                .map(|(id, _)| (ResourceKind::TlsStream, id)),
+        );
+        resources.extend(
+            r.udp_sockets
+                .iter()
+                .map(|(id, _)| (ResourceKind::UdpSocket, id)),
+        );
+        resources.extend(r.errors.iter().map(|(id, _)| (ResourceKind::Error, id)));
+        resources.extend(
+            r.reply_refs
+                .iter()
+                .map(|(id, _)| (ResourceKind::ReplyRef, id)),
+        );
+        resources.extend(
+            r.module_uploads
+                .iter()
+                .map(|(id, _)| (ResourceKind::ModuleUpload, id)),
+        );
+        resources.extend(
+            r.shared_regions
+                .iter()
+                .map(|(id, _)| (ResourceKind::SharedRegion, id)),
+        );
+        resources
+    }
+
+    fn clone_resource(&mut self, kind: ResourceKind, id: u64) -> Result<u64> {
+        let r = &mut self.resources;
+        let missing = || anyhow::anyhow!("{kind:?} resource {id} doesn't exist");
+        let clone_id = match kind {
+            ResourceKind::Module => {
+                let module = r.modules.get(id).ok_or_else(missing)?.clone();
+                r.modules.add(module)
+            }
+            ResourceKind::TcpStream => {
+                let stream = r.tcp_streams.get(id).ok_or_else(missing)?.clone();
+                r.tcp_streams.add(stream)
+            }
+            ResourceKind::TlsStream => {
+                let stream = r.tls_streams.get(id).ok_or_else(missing)?.clone();
+                r.tls_streams.add(stream)
+            }
+            ResourceKind::UdpSocket => {
+                let socket = r.udp_sockets.get(id).ok_or_else(missing)?.clone();
+                r.udp_sockets.add(socket)
+            }
+            ResourceKind::SharedRegion => {
+                let region = r.shared_regions.get(id).ok_or_else(missing)?.clone();
+                r.shared_regions.add(region)
+            }
+            kind => return Err(anyhow::anyhow!("{kind:?} resources can't be cloned")),
+        };
+        Ok(clone_id)
+    }
 }
 
 impl NetworkingCtx for DefaultProcessState {
@@ -382,9 +612,25 @@ impl LunaticWasiCtx for DefaultProcessState {
     fn get_stderr(&self) -> Option<&StdoutCapture> {
         self.wasi_stderr.as_ref()
     }
+
+    fn cwd(&self) -> Option<&str> {
+        self.wasi_cwd.as_deref()
+    }
+
+    fn set_cwd(&mut self, cwd: String) {
+        self.wasi_cwd = Some(cwd);
+    }
+
+    fn inherited_fds_mut(&mut self) -> &mut Vec<(u32, u32)> {
+        &mut self.wasi_inherited_fds
+    }
+
+    fn opened_fds_mut(&mut self) -> &mut HashSet<u32> {
+        &mut self.wasi_opened_fds
+    }
 }
 
-#[derive(Default, Debug)]
+#[derive(Debug)]
 pub(crate) struct Resources {
     pub(crate) configs: HashMapId<DefaultProcessConfig>,
     pub(crate) modules: HashMapId<Arc<WasmtimeCompiledModule<DefaultProcessState>>>,
@@ -396,6 +642,54 @@ pub(crate) struct Resources {
     pub(crate) tls_streams: HashMapId<Arc<TlsConnection>>,
     pub(crate) udp_sockets: HashMapId<Arc<UdpSocket>>,
     pub(crate) errors: HashMapId<anyhow::Error>,
+    pub(crate) reply_refs: ReplyRefResources,
+    pub(crate) module_uploads: ModuleUploadResources,
+    pub(crate) shared_regions: SharedRegionResources,
+}
+
+// Resources are listed in the order they were created, so that `list_own_resources` is stable.
+impl Default for Resources {
+    fn default() -> Self {
+        Self {
+            configs: HashMapId::with_insertion_order(),
+            modules: HashMapId::with_insertion_order(),
+            timers: TimerResources::default(),
+            dns_iterators: HashMapId::with_insertion_order(),
+            tcp_listeners: HashMapId::with_insertion_order(),
+            tcp_streams: HashMapId::with_insertion_order(),
+            tls_listeners: HashMapId::with_insertion_order(),
+            tls_streams: HashMapId::with_insertion_order(),
+            udp_sockets: HashMapId::with_insertion_order(),
+            errors: HashMapId::with_insertion_order(),
+            reply_refs: HashMapId::with_insertion_order(),
+            module_uploads: HashMapId::with_insertion_order(),
+            shared_regions: HashMapId::with_insertion_order(),
+        }
+    }
+}
+
+impl Resources {
+    // Reuses the tables of a finished process of the environment, if one was left.
+    fn recycled(environment: &LunaticEnvironment) -> Self {
+        environment.recycle_pool().take().unwrap_or_default()
+    }
+
+    // Drops all resources, but keeps the memory allocated by the tables.
+    fn clear(&mut self) {
+        self.configs.clear();
+        self.modules.clear();
+        self.timers.clear();
+        self.dns_iterators.clear();
+        self.tcp_listeners.clear();
+        self.tcp_streams.clear();
+        self.tls_listeners.clear();
+        self.tls_streams.clear();
+        self.udp_sockets.clear();
+        self.errors.clear();
+        self.reply_refs.clear();
+        self.module_uploads.clear();
+        self.shared_regions.clear();
+    }
 }
 
 impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
@@ -434,11 +728,13 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
         runtime: WasmtimeRuntime,
         module: Arc<WasmtimeCompiledModule<Self>>,
         config: Arc<Self::Config>,
+        registry: Arc<DashMap<String, (u64, u64)>>,
     ) -> Result<Self> {
         let signal_mailbox = unbounded_channel();
         let signal_mailbox = (signal_mailbox.0, Arc::new(Mutex::new(signal_mailbox.1)));
         let message_mailbox = MessageMailbox::default();
-        let state = Self {
+        let resources = Resources::recycled(&environment);
+        let mut state = Self {
             id: environment.get_next_process_id(),
             environment,
             distributed: Some(distributed),
@@ -446,25 +742,100 @@ impl DistributedCtx<LunaticEnvironment> for DefaultProcessState {
             module: Some(module),
             config: config.clone(),
             message: None,
+            kv_store: KvStore::default(),
+            send_rate_limiter: SendRateLimiter::default(),
+            unique_id_seed: 0,
+            quantum: UNIT_OF_COMPUTE_IN_INSTRUCTIONS,
+            trap_exit: config.get_trap_exit(),
             signal_mailbox,
+            stats: ProcessStats::new(message_mailbox.clone()),
             message_mailbox,
-            resources: Resources::default(),
+            resources,
             wasi: build_wasi(
                 Some(config.command_line_arguments()),
                 Some(config.environment_variables()),
                 config.preopened_dirs(),
+                config.cwd(),
             )?,
             wasi_stdout: None,
             wasi_stderr: None,
+            wasi_cwd: config.cwd().map(String::from),
+            wasi_inherited_fds: Vec::new(),
+            wasi_opened_fds: HashSet::new(),
             initialized: false,
-            registry: Default::default(), // TODO move registry into env?
+            registry,
         };
+        state.apply_stdio()?;
         Ok(state)
     }
 }
 
 mod tests {
 
+    // Compiles the wat module and runs its exported `hello` function to completion.
+    #[cfg(test)]
+    async fn run_wat(
+        wat: &str,
+        config: crate::DefaultProcessConfig,
+    ) -> anyhow::Result<crate::state::DefaultProcessState> {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config())?;
+        let raw_module = wat::parse_str(wat)?;
+        let module = Arc::new(runtime.compile_module(raw_module.into())?);
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            Default::default(),
+        )?;
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None).await?;
+        join.await?
+    }
+
+    #[test]
+    fn compile_module_lists_unresolved_imports() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{
+            default_config, UnresolvedImports, WasmtimeRuntime,
+        };
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "process_id" (func (result i64)))
+                (import "lunatic::process" "does_not_exist" (func))
+                (import "wasi_snapshot_preview1" "fd_frobnicate" (func (param i32) (result i32))))
+            "#,
+        )
+        .unwrap();
+        let error = runtime
+            .compile_module::<DefaultProcessState>(raw_module.into())
+            .err()
+            .unwrap();
+        assert!(error
+            .to_string()
+            .contains("`lunatic::process::does_not_exist`"));
+        let unresolved = error.downcast::<UnresolvedImports>().unwrap();
+        assert_eq!(
+            unresolved,
+            UnresolvedImports(vec![
+                ("lunatic::process".to_string(), "does_not_exist".to_string()),
+                (
+                    "wasi_snapshot_preview1".to_string(),
+                    "fd_frobnicate".to_string()
+                ),
+            ])
+        );
+    }
+
     #[tokio::test]
     async fn import_filter_signature_matches() {
         use crate::state::DefaultProcessState;
@@ -499,4 +870,4939 @@ mod tests {
             .await
             .unwrap();
     }
+
+    #[tokio::test]
+    async fn list_processes_reports_live_processes() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::env::Environment;
+        use lunatic_process::runtimes::wasmtime::WasmtimeRuntime;
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let mut wasmtime_config = wasmtime::Config::new();
+        wasmtime_config.async_support(true).consume_fuel(true);
+        let runtime = WasmtimeRuntime::new(&wasmtime_config).unwrap();
+
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (memory (export "memory") 1)
+                (func (export "hello") (call $sleep_ms (i64.const 10000))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let registry = Arc::new(dashmap::DashMap::new());
+        let config = Arc::new(DefaultProcessConfig::default());
+
+        let mut ids = Vec::new();
+        for _ in 0..3 {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                config.clone(),
+                registry.clone(),
+            )
+            .unwrap();
+            let (_, process) = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                "hello",
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            ids.push(process.id());
+        }
+        registry.insert("named".to_string(), (env.id(), ids[0]));
+
+        let processes = env.list_processes(&registry);
+        assert_eq!(processes.len(), 3);
+        for id in ids.iter() {
+            let info = processes.iter().find(|info| info.id == *id).unwrap();
+            assert_eq!(info.memory_usage, 65536);
+            assert_eq!(info.mailbox_len, 0);
+            assert!(info.uptime.as_secs() < 10);
+        }
+        let named = processes.iter().find(|info| info.id == ids[0]).unwrap();
+        assert_eq!(named.name.as_deref(), Some("named"));
+    }
+
+    #[tokio::test]
+    async fn trapping_process_captures_backtrace() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process::ProcessFailure;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (func $crash unreachable)
+                (func (export "hello") (call $crash)))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+            .await
+            .unwrap();
+        let error = join.await.unwrap().unwrap_err();
+        let backtrace = error
+            .downcast_ref::<ProcessFailure>()
+            .and_then(|failure| failure.backtrace())
+            .unwrap();
+        assert!(backtrace.contains("crash"));
+    }
+
+    #[tokio::test]
+    async fn trap_backtrace_of_failed_spawn() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // The start function of the child module traps, so spawning it fails with the trap. The
+        // first line of the backtrace points to the start function.
+        let child =
+            wat::parse_str("(module $child (func $boom unreachable) (start $boom))").unwrap();
+        let child: String = child.iter().map(|byte| format!("\\{:02x}", byte)).collect();
+        let wat = format!(
+            r#"
+            (module
+                (import "lunatic::process" "compile_module"
+                    (func $compile_module (param i32 i32 i32) (result i32)))
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::error" "trap_backtrace"
+                    (func $trap_backtrace (param i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "hello")
+                (data (i32.const 16) "  0: child!boom ")
+                (data (i32.const 1024) "{child}")
+                (func (export "hello")
+                    (if (call $compile_module (i32.const 1024) (i32.const {len}) (i32.const 32))
+                        (then unreachable))
+                    (if (i32.ne
+                            (call $spawn (i64.const 0) (i64.const -1) (i64.load (i32.const 32))
+                                (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0)
+                                (i32.const 40))
+                            (i32.const 1))
+                        (then unreachable))
+                    (if (i32.lt_u
+                            (call $trap_backtrace (i64.load (i32.const 40)) (i32.const 64)
+                                (i32.const 16))
+                            (i32.const 16))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.const 64)) (i64.load (i32.const 16)))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.const 72)) (i64.load (i32.const 24)))
+                        (then unreachable))))
+            "#,
+            len = child.len() / 3,
+        );
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_compile_modules(true);
+        config.set_can_spawn_processes(true);
+        run_wat(&wat, config).await.unwrap();
+    }
+
+    // Collects the records of all tests, each test filters them by the id of its process
+    #[cfg(test)]
+    static CAPTURED_LOGS: std::sync::Mutex<Vec<(log::Level, String)>> =
+        std::sync::Mutex::new(Vec::new());
+
+    #[cfg(test)]
+    struct CaptureLogger;
+
+    #[cfg(test)]
+    impl log::Log for CaptureLogger {
+        fn enabled(&self, _metadata: &log::Metadata) -> bool {
+            true
+        }
+
+        fn log(&self, record: &log::Record) {
+            CAPTURED_LOGS
+                .lock()
+                .unwrap()
+                .push((record.level(), record.args().to_string()));
+        }
+
+        fn flush(&self) {}
+    }
+
+    #[cfg(test)]
+    fn capture_logs() {
+        static INSTALL: std::sync::Once = std::sync::Once::new();
+        INSTALL.call_once(|| {
+            log::set_boxed_logger(Box::new(CaptureLogger)).unwrap();
+            log::set_max_level(log::LevelFilter::Trace);
+        });
+    }
+
+    // Returns the records about the process, without the trace records of its lifecycle (e.g.
+    // "Process N spawned")
+    #[cfg(test)]
+    fn captured_logs(process_id: u64) -> Vec<(log::Level, String)> {
+        let prefix = format!("Process {process_id} ");
+        CAPTURED_LOGS
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(level, message)| *level < log::Level::Trace && message.starts_with(&prefix))
+            .cloned()
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn trapping_process_is_logged_as_error() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        capture_logs();
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (func (export "crash") unreachable)
+                (func (export "finish")))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut config = DefaultProcessConfig::default();
+        config.set_log_normal_exits(true);
+        let config = Arc::new(config);
+
+        let mut ids = Vec::new();
+        for function in ["crash", "finish"] {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                config.clone(),
+                Default::default(),
+            )
+            .unwrap();
+            let (join, process) = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                function,
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            ids.push(process.id());
+            join.await.unwrap().ok();
+        }
+
+        let crashed = captured_logs(ids[0]);
+        assert_eq!(crashed.len(), 1);
+        assert_eq!(crashed[0].0, log::Level::Error);
+        assert!(crashed[0].1.contains("Unreachable"));
+
+        let finished = captured_logs(ids[1]);
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].0, log::Level::Debug);
+        assert!(finished[0].1.contains("finished normally"));
+    }
+
+    #[tokio::test]
+    async fn host_call_traps_name_the_call() {
+        // The pointer is outside of the single memory page
+        let wat = r#"
+            (module
+                (import "lunatic::distributed" "get_nodes" (func $get_nodes (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (drop (call $get_nodes (i32.const 131072) (i32.const 4)))))
+        "#;
+        let error = run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap_err();
+        let message = error.to_string();
+        assert!(
+            message.contains("lunatic::distributed::get_nodes(nodes_ptr: 131072, nodes_len: 4)")
+        );
+        assert!(message.contains("outside of the guest memory (65536 bytes)"));
+    }
+
+    #[tokio::test]
+    async fn get_nodes_traps_cleanly_near_address_space_end() {
+        // Both arguments are `u32::MAX`, the range can't be wrapped into valid memory
+        let wat = r#"
+            (module
+                (import "lunatic::distributed" "get_nodes" (func $get_nodes (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (drop (call $get_nodes (i32.const -1) (i32.const -1)))))
+        "#;
+        let error = run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap_err();
+        let message = error.to_string();
+        assert!(message.contains(
+            "lunatic::distributed::get_nodes(nodes_ptr: 4294967295, nodes_len: 4294967295)"
+        ));
+    }
+
+    #[tokio::test]
+    async fn abort_stores_message_in_process_failure() {
+        use lunatic_process::ProcessFailure;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "abort" (func $abort (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "boom")
+                (func (export "hello") (call $abort (i32.const 0) (i32.const 4))))
+        "#;
+        let error = run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap_err();
+        let failure = error.downcast_ref::<ProcessFailure>().unwrap();
+        assert!(failure.to_string().contains("Process aborted: boom"));
+
+        // Invalid utf8 doesn't trap while decoding, only the length is reported
+        let wat = r#"
+            (module
+                (import "lunatic::process" "abort" (func $abort (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\ff\fe")
+                (func (export "hello") (call $abort (i32.const 0) (i32.const 2))))
+        "#;
+        let error = run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap_err();
+        assert!(error.to_string().contains("<2 bytes of invalid utf8>"));
+    }
+
+    #[tokio::test]
+    async fn line_buffered_stdout_separates_concurrent_lines() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_stdout_capture::LineBuffered;
+        use lunatic_wasi_api::LunaticWasiCtx;
+        use std::sync::Arc;
+
+        // Writes "one line\ntwo lines\n" in three parts that don't end at line boundaries,
+        // sleeping in between so that the output of both processes interleaves.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "one line\ntwo lines\n")
+                (data (i32.const 32) "\00\00\00\00\04\00\00\00\04\00\00\00\09\00\00\00\0d\00\00\00\06\00\00\00")
+                (func $write (param $iovec i32)
+                    (if (call $fd_write (i32.const 1) (local.get $iovec) (i32.const 1) (i32.const 64))
+                        (then unreachable)))
+                (func (export "hello")
+                    (call $write (i32.const 32))
+                    (call $sleep_ms (i64.const 20))
+                    (call $write (i32.const 40))
+                    (call $sleep_ms (i64.const 20))
+                    (call $write (i32.const 48))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let sink = Arc::new(std::sync::Mutex::new(Vec::<u8>::new()));
+
+        let mut processes = Vec::new();
+        for _ in 0..2 {
+            let mut state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(crate::DefaultProcessConfig::default()),
+                Default::default(),
+            )
+            .unwrap();
+            let id = state.id();
+            let stdout = LineBuffered::new(id.to_string(), sink.clone());
+            state.wasi_mut().set_stdout(Box::new(stdout));
+            let (join, _) = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                "hello",
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            processes.push((id, join));
+        }
+
+        for (_, join) in processes.iter_mut() {
+            join.await.unwrap().unwrap();
+        }
+        let output = String::from_utf8(sink.lock().unwrap().clone()).unwrap();
+        assert_eq!(output.lines().count(), 4);
+        for (id, _) in processes {
+            let lines: Vec<&str> = output
+                .lines()
+                .filter(|line| line.starts_with(&format!("[{id}] ")))
+                .collect();
+            assert_eq!(
+                lines,
+                [format!("[{id}] one line"), format!("[{id}] two lines")]
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn line_buffered_stdout_prefixes_lines_with_label() {
+        use crate::state::DefaultProcessState;
+        use crate::OutputPrefix;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_stdout_capture::LineBuffered;
+        use lunatic_wasi_api::LunaticWasiCtx;
+        use std::sync::Arc;
+
+        // Sets the label "worker" and then writes three lines with one call.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "set_process_label"
+                    (func $set_process_label (param i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "one\ntwo\nthree\n")
+                (data (i32.const 16) "worker")
+                (data (i32.const 32) "\00\00\00\00\0e\00\00\00")
+                (func (export "hello")
+                    (call $set_process_label (i32.const 16) (i32.const 6))
+                    (if (call $fd_write (i32.const 1) (i32.const 32) (i32.const 1) (i32.const 64))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let sink = Arc::new(std::sync::Mutex::new(Vec::<u8>::new()));
+
+        let config = crate::DefaultProcessConfig::builder()
+            .output_prefix(OutputPrefix::Label)
+            .build()
+            .unwrap();
+        let mut state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            Default::default(),
+        )
+        .unwrap();
+        let id = state.id();
+        let stdout = LineBuffered::with_prefix(state.output_prefix(), sink.clone());
+        state.wasi_mut().set_stdout(Box::new(stdout));
+        let (join, _) = spawn_wasm(
+            env.clone(),
+            runtime.clone(),
+            &module,
+            state,
+            "hello",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        join.await.unwrap().unwrap();
+
+        let output = String::from_utf8(sink.lock().unwrap().clone()).unwrap();
+        let prefix = format!("[{id} (worker)] ");
+        assert_eq!(output, format!("{prefix}one\n{prefix}two\n{prefix}three\n"));
+    }
+
+    #[tokio::test]
+    async fn missing_wasi_functions_can_be_stubbed() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime, WASI_ENOSYS};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        // `sock_open` is not part of `wasi_snapshot_preview1`
+        let raw_module = wat::parse_str(format!(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "sock_open"
+                    (func $sock_open (param i32 i32 i32) (result i32)))
+                (func (export "hello")
+                    (if (i32.ne (call $sock_open (i32.const 0) (i32.const 0) (i32.const 0))
+                                (i32.const {WASI_ENOSYS}))
+                        (then unreachable))))
+            "#
+        ))
+        .unwrap();
+
+        let mut runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        assert!(runtime
+            .compile_module::<DefaultProcessState>(raw_module.clone().into())
+            .is_err());
+
+        runtime.set_stub_missing_wasi(true);
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(crate::DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+            .await
+            .unwrap();
+        join.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn short_quantum_yields_more_often() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::atomic::{AtomicUsize, Ordering};
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        // Quanta are clamped to at least 10k instructions. The loop runs for a few million.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "set_quantum" (func $set_quantum (param i64) (result i64)))
+                (import "lunatic::process" "get_quantum" (func $get_quantum (result i64)))
+                (func (export "hello") (param $quantum i64) (local $i i32)
+                    (if (i64.ne (call $set_quantum (i64.const 0)) (i64.const 10000))
+                        (then unreachable))
+                    (if (i64.ne (call $set_quantum (local.get $quantum)) (call $get_quantum))
+                        (then unreachable))
+                    (loop $busy
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $busy (i32.lt_u (local.get $i) (i32.const 1000000))))))
+            "#,
+        )
+        .unwrap();
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+
+        // Counts how often a task sharing the single test thread gets to run while the process is
+        // busy, each tick means the process yielded.
+        let run = |quantum: u64, max_fuel: Option<u64>| {
+            let runtime = runtime.clone();
+            let module = module.clone();
+            async move {
+                let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+                let mut config = crate::DefaultProcessConfig::default();
+                config.set_max_fuel(max_fuel);
+                let state = DefaultProcessState::new(
+                    env.clone(),
+                    None,
+                    runtime.clone(),
+                    module.clone(),
+                    Arc::new(config),
+                    Default::default(),
+                )
+                .unwrap();
+                let ticks = Arc::new(AtomicUsize::new(0));
+                let ticker = {
+                    let ticks = ticks.clone();
+                    tokio::task::spawn(async move {
+                        loop {
+                            ticks.fetch_add(1, Ordering::SeqCst);
+                            tokio::task::yield_now().await;
+                        }
+                    })
+                };
+                let params = vec![Val::I64(quantum as i64)];
+                let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", params, None)
+                    .await
+                    .unwrap();
+                let result = join.await.unwrap();
+                ticker.abort();
+                (result.is_ok(), ticks.load(Ordering::SeqCst))
+            }
+        };
+
+        let (finished, short) = run(10_000, None).await;
+        assert!(finished);
+        let (finished, long) = run(10_000_000, None).await;
+        assert!(finished);
+        assert!(
+            short > 10 * long,
+            "{} ticks with short, {} with long quanta",
+            short,
+            long
+        );
+
+        // The fuel limit of 1M instructions still applies after changing the quantum
+        let (finished, _) = run(10_000, Some(10)).await;
+        assert!(!finished);
+    }
+
+    #[tokio::test]
+    async fn remaining_fuel_decreases_while_running() {
+        use lunatic_process::config::ProcessConfig;
+
+        let wat = r#"
+            (module
+                (import "lunatic::process" "remaining_fuel" (func $remaining_fuel (result i64)))
+                (func (export "hello")
+                    (local $before i64)
+                    (local $i i32)
+                    (local.set $before (call $remaining_fuel))
+                    (if (i64.gt_u (local.get $before) (i64.const 1000000))
+                        (then unreachable))
+                    (loop $spin
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $spin (i32.lt_u (local.get $i) (i32.const 1000))))
+                    (if (i64.ge_u (call $remaining_fuel) (local.get $before))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_max_fuel(Some(10));
+        run_wat(wat, config).await.unwrap();
+
+        // Without a fuel limit the sentinel u64::MAX is returned
+        let wat = r#"
+            (module
+                (import "lunatic::process" "remaining_fuel" (func $remaining_fuel (result i64)))
+                (func (export "hello")
+                    (if (i64.ne (call $remaining_fuel) (i64.const -1))
+                        (then unreachable))))
+        "#;
+        run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn suspended_child_is_linked_before_it_runs() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // The child traps right away. Because it's spawned suspended, the link is always
+        // established before it runs and the parent receives the `LinkDied` message.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "spawn_suspended"
+                    (func $spawn_suspended (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "start" (func $start (param i64) (result i32)))
+                (import "lunatic::process" "link" (func $link (param i64 i64)))
+                (import "lunatic::process" "die_when_link_dies" (func $die_when_link_dies (param i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (func (export "child") unreachable)
+                (func (export "hello")
+                    (call $die_when_link_dies (i32.const 0))
+                    (if (call $spawn_suspended (i64.const 0) (i64.const -1) (i64.const -1)
+                            (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 16))
+                        (then unreachable))
+                    (call $link (i64.const 7) (i64.load (i32.const 16)))
+                    (if (call $start (i64.load (i32.const 16)))
+                        (then unreachable))
+                    ;; 1 = signal turned into a message
+                    (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                                (i32.const 1))
+                        (then unreachable))
+                    (if (i64.ne (call $get_tag) (i64.const 7))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        run_wat(wat, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn child_receives_initial_message_first() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // The child doesn't wait for messages, the initial message must already be in the mailbox
+        // when it starts. If it's missing or different the child traps and takes the linked
+        // parent down with it.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "spawn_with_message"
+                    (func $spawn_with_message (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "data_size" (func $data_size (result i64)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (data (i32.const 8) "setup")
+                (func (export "child")
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 0))
+                        (then unreachable))
+                    (if (i64.ne (call $get_tag) (i64.const 42))
+                        (then unreachable))
+                    (if (i64.ne (call $data_size) (i64.const 5))
+                        (then unreachable))
+                    (drop (call $read_data (i32.const 32) (i32.const 5)))
+                    ;; "setu" as little endian i32
+                    (if (i32.ne (i32.load (i32.const 32)) (i32.const 0x75746573))
+                        (then unreachable))
+                    ;; "p"
+                    (if (i32.ne (i32.load8_u (i32.const 36)) (i32.const 112))
+                        (then unreachable)))
+                (func (export "hello")
+                    (call $create_data (i64.const 42) (i64.const 5))
+                    (drop (call $write_data (i32.const 8) (i32.const 5)))
+                    (if (call $spawn_with_message (i64.const 1) (i64.const -1) (i64.const -1)
+                            (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 16))
+                        (then unreachable))
+                    (call $sleep_ms (i64.const 100))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        run_wat(wat, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn join_all_links_waits_for_all_children() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // Three linked children finish after 50, 100 and 150 ms. A short join times out while
+        // they are still running, a long one returns once all of them are gone.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (import "lunatic::process" "join_all_links" (func $join_all_links (param i64) (result i32)))
+                (import "lunatic::process" "exists" (func $exists (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child1")
+                (data (i32.const 8) "child2")
+                (data (i32.const 16) "child3")
+                (func (export "child1") (call $sleep_ms (i64.const 50)))
+                (func (export "child2") (call $sleep_ms (i64.const 100)))
+                (func (export "child3") (call $sleep_ms (i64.const 150)))
+                (func $spawn_child (param $name i32) (param $id_ptr i32)
+                    (if (call $spawn (i64.const 1) (i64.const -1) (i64.const -1)
+                            (local.get $name) (i32.const 6) (i32.const 0) (i32.const 0)
+                            (local.get $id_ptr))
+                        (then unreachable)))
+                (func (export "hello")
+                    (call $spawn_child (i32.const 0) (i32.const 32))
+                    (call $spawn_child (i32.const 8) (i32.const 40))
+                    (call $spawn_child (i32.const 16) (i32.const 48))
+                    (if (i32.ne (call $join_all_links (i64.const 10)) (i32.const 9027))
+                        (then unreachable))
+                    (if (i32.ne (call $join_all_links (i64.const 5000)) (i32.const 0))
+                        (then unreachable))
+                    (if (call $exists (i64.load (i32.const 32)))
+                        (then unreachable))
+                    (if (call $exists (i64.load (i32.const 40)))
+                        (then unreachable))
+                    (if (call $exists (i64.load (i32.const 48)))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        let start = std::time::Instant::now();
+        run_wat(wat, config).await.unwrap();
+        assert!(start.elapsed() >= std::time::Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn trap_exit_parent_receives_child_crash_as_message() {
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // The parent never calls `die_when_link_dies`, but its config traps exits. Instead of
+        // being killed by the crashing child, it receives the `LinkDied` message and finishes
+        // normally.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (func (export "child") unreachable)
+                (func (export "hello")
+                    (if (call $spawn (i64.const 7) (i64.const -1) (i64.const -1)
+                            (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 16))
+                        (then unreachable))
+                    ;; 1 = signal turned into a message
+                    (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                                (i32.const 1))
+                        (then unreachable))
+                    (if (i64.ne (call $get_tag) (i64.const 7))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        config.set_trap_exit(true);
+        run_wat(wat, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn trap_exit_can_be_toggled_at_runtime() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // The process flips the flag on, off and ends up with **enabled**, before a linked child
+        // crashes. Only with the flag enabled the crash is delivered as a message.
+        let wat = |enabled: u32| {
+            format!(
+                r#"
+            (module
+                (import "lunatic::process" "set_trap_exit" (func $set_trap_exit (param i32)))
+                (import "lunatic::process" "get_trap_exit" (func $get_trap_exit (result i32)))
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (func (export "child") unreachable)
+                (func (export "hello")
+                    (if (call $get_trap_exit) (then unreachable))
+                    (call $set_trap_exit (i32.const 1))
+                    (if (i32.ne (call $get_trap_exit) (i32.const 1)) (then unreachable))
+                    (call $set_trap_exit (i32.const 0))
+                    (if (call $get_trap_exit) (then unreachable))
+                    (call $set_trap_exit (i32.const {enabled}))
+                    (if (call $spawn (i64.const 7) (i64.const -1) (i64.const -1)
+                            (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 16))
+                        (then unreachable))
+                    ;; 1 = signal turned into a message
+                    (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                                (i32.const 1))
+                        (then unreachable))))
+            "#
+            )
+        };
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        let killed = run_wat(&wat(0), config.clone()).await.unwrap_err();
+        assert!(killed.to_string().contains("Kill signal"), "{}", killed);
+        run_wat(&wat(1), config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn module_uploaded_in_chunks_can_be_spawned() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // The child module is uploaded in three chunks. Its entry function traps, so the parent
+        // receives a `LinkDied` message if the child was spawned from the uploaded module.
+        let child = wat::parse_str(
+            r#"(module (memory (export "memory") 1) (func (export "child") unreachable))"#,
+        )
+        .unwrap();
+        let child_data: String = child.iter().map(|byte| format!("\\{byte:02x}")).collect();
+        let wat = format!(
+            r#"
+            (module
+                (import "lunatic::process" "module_upload_begin"
+                    (func $begin (result i64)))
+                (import "lunatic::process" "module_upload_chunk"
+                    (func $chunk (param i64 i32 i32) (result i32)))
+                (import "lunatic::process" "module_upload_finish"
+                    (func $finish (param i64 i32) (result i32)))
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "die_when_link_dies" (func $die_when_link_dies (param i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (data (i32.const 100) "{child_data}")
+                (func (export "hello")
+                    (local $upload i64)
+                    (call $die_when_link_dies (i32.const 0))
+                    (local.set $upload (call $begin))
+                    (if (call $chunk (local.get $upload) (i32.const 100) (i32.const 10))
+                        (then unreachable))
+                    (if (call $chunk (local.get $upload) (i32.const 110) (i32.const 10))
+                        (then unreachable))
+                    (if (call $chunk (local.get $upload) (i32.const 120) (i32.const {rest}))
+                        (then unreachable))
+                    (if (call $finish (local.get $upload) (i32.const 16))
+                        (then unreachable))
+                    (if (call $spawn (i64.const 7) (i64.const -1) (i64.load (i32.const 16))
+                            (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 24))
+                        (then unreachable))
+                    ;; 1 = signal turned into a message
+                    (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                                (i32.const 1))
+                        (then unreachable))
+                    (if (i64.ne (call $get_tag) (i64.const 7))
+                        (then unreachable))))
+            "#,
+            rest = child.len() - 20
+        );
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_compile_modules(true);
+        config.set_can_spawn_processes(true);
+        run_wat(&wat, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn module_upload_is_dropped_past_max_memory() {
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // With a limit of two pages, the third chunk of one page fails and drops the upload, so
+        // the next chunk traps.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "module_upload_begin"
+                    (func $begin (result i64)))
+                (import "lunatic::process" "module_upload_chunk"
+                    (func $chunk (param i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (local $upload i64)
+                    (local.set $upload (call $begin))
+                    (if (call $chunk (local.get $upload) (i32.const 0) (i32.const 65536))
+                        (then unreachable))
+                    (if (call $chunk (local.get $upload) (i32.const 0) (i32.const 65536))
+                        (then unreachable))
+                    (if (i32.ne (call $chunk (local.get $upload) (i32.const 0) (i32.const 65536))
+                                (i32.const 1))
+                        (then unreachable))
+                    (drop (call $chunk (local.get $upload) (i32.const 0) (i32.const 1)))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_compile_modules(true);
+        config.set_max_memory(2 * 65536);
+        let error = run_wat(wat, config).await.unwrap_err();
+        assert!(
+            error.to_string().contains("Upload ID doesn't exist"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn send_and_yield_ping_pong_keeps_order() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // The parent sends 10 pings tagged 1..=10 with `send_and_yield`, the child answers each
+        // with a pong carrying the same tag. Every pong must match the last ping. Each side needs
+        // one host call per send instead of a send followed by a yield.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "send_and_yield" (func $send_and_yield (param i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                ;; One i64 param: the parent ID
+                (data (i32.const 200) "\7e")
+                (func (export "child") (param $parent i64)
+                    (loop $pong
+                        (if (call $receive (i32.const 0) (i32.const 0) (i64.const -1))
+                            (then unreachable))
+                        (call $create_data (call $get_tag) (i64.const 0))
+                        (if (call $send_and_yield (local.get $parent))
+                            (then unreachable))
+                        (br $pong)))
+                (func (export "hello")
+                    (local $child i64)
+                    (local $round i64)
+                    (i64.store (i32.const 201) (call $process_id))
+                    (if (call $spawn (i64.const 0) (i64.const -1) (i64.const -1)
+                            (i32.const 0) (i32.const 5) (i32.const 200) (i32.const 17)
+                            (i32.const 16))
+                        (then unreachable))
+                    (local.set $child (i64.load (i32.const 16)))
+                    (loop $ping
+                        (local.set $round (i64.add (local.get $round) (i64.const 1)))
+                        (call $create_data (local.get $round) (i64.const 0))
+                        (if (call $send_and_yield (local.get $child))
+                            (then unreachable))
+                        (if (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                            (then unreachable))
+                        (if (i64.ne (call $get_tag) (local.get $round))
+                            (then unreachable))
+                        (br_if $ping (i64.lt_u (local.get $round) (i64.const 10))))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        run_wat(wat, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn detached_child_outlives_parent() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::env::Environment;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::sync::Arc;
+
+        // The parent passes its id to the child, that detaches itself and notifies the parent.
+        // The parent finishes as soon as the notification arrives.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::process" "detach" (func $detach))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (func (export "child") (param $parent i64)
+                    (call $detach)
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $send (local.get $parent)))
+                    (call $sleep_ms (i64.const 10000)))
+                (func (export "hello")
+                    ;; i64 parameter with the parent id
+                    (i32.store8 (i32.const 32) (i32.const 0x7E))
+                    (i64.store (i32.const 33) (call $process_id))
+                    (if (call $spawn (i64.const 0) (i64.const -1) (i64.const -1)
+                            (i32.const 0) (i32.const 5) (i32.const 32) (i32.const 17) (i32.const 64))
+                        (then unreachable))
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            Default::default(),
+        )
+        .unwrap();
+        let parent_id = state.id();
+        let (join, _) = spawn_wasm(
+            env.clone(),
+            runtime,
+            &module,
+            state,
+            "hello",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        join.await.unwrap().unwrap();
+
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(env.get_process(parent_id + 1).is_some());
+    }
+
+    #[tokio::test]
+    async fn unique_ids_are_strictly_increasing() {
+        let wat = r#"
+            (module
+                (import "lunatic::process" "unique_id" (func $unique_id (result i64)))
+                (func (export "hello") (local $previous i64) (local $id i64) (local $i i32)
+                    (local.set $previous (call $unique_id))
+                    (loop $next
+                        (local.set $id (call $unique_id))
+                        (if (i64.le_u (local.get $id) (local.get $previous))
+                            (then unreachable))
+                        (local.set $previous (local.get $id))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $next (i32.lt_u (local.get $i) (i32.const 9))))))
+            "#;
+        let state = run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(state.unique_id_seed, 10);
+    }
+
+    #[tokio::test]
+    async fn pinned_process_stays_on_requested_worker() {
+        use lunatic_process::config::ProcessConfig;
+
+        // Traps if `worker_id` doesn't return the expected worker, before and after yielding to
+        // the scheduler a few times.
+        let wat = |expected: i64| {
+            format!(
+                r#"
+                (module
+                    (import "lunatic::process" "worker_id" (func $worker_id (result i64)))
+                    (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                    (memory (export "memory") 1)
+                    (func $check
+                        (if (i64.ne (call $worker_id) (i64.const {expected}))
+                            (then unreachable)))
+                    (func (export "hello")
+                        (call $check)
+                        (call $sleep_ms (i64.const 1))
+                        (call $check)
+                        (call $sleep_ms (i64.const 1))
+                        (call $check)))
+                "#
+            )
+        };
+
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_worker_affinity(Some(0));
+        run_wat(&wat(0), config).await.unwrap();
+        // Processes without affinity, or pinned to a worker that doesn't exist, run on the shared
+        // scheduler
+        run_wat(&wat(-1), crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_worker_affinity(Some(usize::MAX));
+        run_wat(&wat(-1), config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn pinned_process_reports_stable_current_worker() {
+        use lunatic_process::config::ProcessConfig;
+
+        // Remembers the first thread id and traps if it changes after yielding to the scheduler.
+        // An unpinned process may move between threads, so only the pinned one runs with checks.
+        let wat = |check: bool| {
+            format!(
+                r#"
+                (module
+                    (import "lunatic::process" "current_worker" (func $current_worker (result i32)))
+                    (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                    (memory (export "memory") 1)
+                    (global $first (mut i32) (i32.const 0))
+                    (func $check
+                        (if (i32.and (i32.const {check})
+                                     (i32.ne (call $current_worker) (global.get $first)))
+                            (then unreachable)))
+                    (func (export "hello")
+                        (global.set $first (call $current_worker))
+                        (call $sleep_ms (i64.const 1))
+                        (call $check)
+                        (call $sleep_ms (i64.const 1))
+                        (call $check)))
+                "#,
+                check = check as i32
+            )
+        };
+
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_worker_affinity(Some(0));
+        run_wat(&wat(true), config).await.unwrap();
+        run_wat(&wat(false), crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn available_memory_is_clamped_to_budget() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // Traps if the reported memory is below 1 MB, above **max** or, on Linux where it's read
+        // from `/proc/meminfo`, unknown.
+        let wat = |max: u64| {
+            format!(
+                r#"
+                (module
+                    (import "lunatic::process" "available_memory" (func $available_memory (result i64)))
+                    (func (export "hello") (local $available i64)
+                        (local.set $available (call $available_memory))
+                        (if (i64.lt_u (local.get $available) (i64.const 1048576))
+                            (then unreachable))
+                        (if (i64.gt_u (local.get $available) (i64.const {max}))
+                            (then unreachable))
+                        (if (i32.and (i32.const {linux})
+                                     (i64.eq (local.get $available) (i64.const -1)))
+                            (then unreachable))))
+                "#,
+                max = max as i64,
+                linux = cfg!(target_os = "linux") as i32
+            )
+        };
+
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_memory_budget(Some(1048576));
+        run_wat(&wat(1048576), config).await.unwrap();
+        run_wat(&wat(u64::MAX), crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn processes_read_shared_env_config_blob() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::env::LunaticEnvironment;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        // A buffer that is too small stays untouched, a big enough one receives the blob.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "env_config_blob"
+                    (func $env_config_blob (param i32 i32) (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "flags=on")
+                (func (export "hello")
+                    (if (i64.ne (call $env_config_blob (i32.const 100) (i32.const 4))
+                                (i64.const 8))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.const 100)) (i64.const 0))
+                        (then unreachable))
+                    (if (i64.ne (call $env_config_blob (i32.const 100) (i32.const 8))
+                                (i64.const 8))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.const 100)) (i64.load (i32.const 0)))
+                        (then unreachable))))
+        "#;
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(wat).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(LunaticEnvironment::new(0).with_config_blob(b"flags=on".to_vec()));
+        let config = Arc::new(crate::DefaultProcessConfig::default());
+
+        let mut processes = Vec::new();
+        for _ in 0..2 {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                config.clone(),
+                Default::default(),
+            )
+            .unwrap();
+            let (join, _) = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                "hello",
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            processes.push(join);
+        }
+        for join in processes {
+            join.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn cloned_tcp_stream_shares_connection() {
+        use std::io::{Read, Write};
+
+        // The server expects "ab" and answers "xy". The guest writes "a" through the original
+        // handle and "b" through the clone, then reads one byte through each.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = [0; 2];
+            stream.read_exact(&mut received).unwrap();
+            stream.write_all(b"xy").unwrap();
+            received
+        });
+
+        let wat = format!(
+            r#"
+            (module
+                (import "lunatic::networking" "tcp_connect"
+                    (func $tcp_connect (param i32 i32 i32 i32 i32 i64 i32) (result i32)))
+                (import "lunatic::networking" "tcp_write_vectored"
+                    (func $tcp_write_vectored (param i64 i32 i32 i32) (result i32)))
+                (import "lunatic::networking" "tcp_read"
+                    (func $tcp_read (param i64 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "clone_resource"
+                    (func $clone_resource (param i32 i64 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\7f\00\00\01")
+                (data (i32.const 4) "ab")
+                ;; ciovecs pointing to "a" and "b"
+                (data (i32.const 32) "\04\00\00\00\01\00\00\00")
+                (data (i32.const 40) "\05\00\00\00\01\00\00\00")
+                (func (export "hello")
+                    (local $stream i64)
+                    (local $clone i64)
+                    (if (call $tcp_connect (i32.const 4) (i32.const 0) (i32.const {port})
+                            (i32.const 0) (i32.const 0) (i64.const 5000) (i32.const 16))
+                        (then unreachable))
+                    (local.set $stream (i64.load (i32.const 16)))
+                    ;; 6 = TCP stream
+                    (if (call $clone_resource (i32.const 6) (local.get $stream) (i32.const 16))
+                        (then unreachable))
+                    (local.set $clone (i64.load (i32.const 16)))
+                    (if (i64.eq (local.get $stream) (local.get $clone))
+                        (then unreachable))
+                    ;; 5 = TCP listener, can't be cloned
+                    (if (i32.ne (call $clone_resource (i32.const 5) (i64.const 0) (i32.const 16))
+                                (i32.const 1))
+                        (then unreachable))
+                    (if (call $tcp_write_vectored (local.get $stream) (i32.const 32) (i32.const 1)
+                            (i32.const 16))
+                        (then unreachable))
+                    (if (call $tcp_write_vectored (local.get $clone) (i32.const 40) (i32.const 1)
+                            (i32.const 16))
+                        (then unreachable))
+                    (if (call $tcp_read (local.get $clone) (i32.const 100) (i32.const 1)
+                            (i32.const 16))
+                        (then unreachable))
+                    (if (call $tcp_read (local.get $stream) (i32.const 101) (i32.const 1)
+                            (i32.const 16))
+                        (then unreachable))
+                    (if (i32.ne (i32.load16_u (i32.const 100)) (i32.const 0x7978))
+                        (then unreachable))))
+            "#
+        );
+        run_wat(&wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+        assert_eq!(&server.join().unwrap(), b"ab");
+    }
+
+    #[tokio::test]
+    async fn socket_option_tcp_nodelay_takes_effect() {
+        // Connects to the listener, enables TCP_NODELAY (0) and reads it back. The unknown
+        // option 99 is rejected with an error.
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || listener.accept().unwrap());
+
+        let wat = format!(
+            r#"
+            (module
+                (import "lunatic::networking" "tcp_connect"
+                    (func $tcp_connect (param i32 i32 i32 i32 i32 i64 i32) (result i32)))
+                (import "lunatic::networking" "socket_set_option"
+                    (func $set_option (param i64 i32 i64 i32) (result i32)))
+                (import "lunatic::networking" "socket_get_option"
+                    (func $get_option (param i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\7f\00\00\01")
+                (func (export "hello")
+                    (local $stream i64)
+                    (if (call $tcp_connect (i32.const 4) (i32.const 0) (i32.const {port})
+                            (i32.const 0) (i32.const 0) (i64.const 5000) (i32.const 16))
+                        (then unreachable))
+                    (local.set $stream (i64.load (i32.const 16)))
+                    (if (call $set_option (local.get $stream) (i32.const 0) (i64.const 1)
+                            (i32.const 16))
+                        (then unreachable))
+                    (if (call $get_option (local.get $stream) (i32.const 0) (i32.const 24))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.const 24)) (i64.const 1))
+                        (then unreachable))
+                    (if (i32.ne (call $set_option (local.get $stream) (i32.const 99) (i64.const 1)
+                                    (i32.const 16))
+                                (i32.const 1))
+                        (then unreachable))
+                    (if (i32.ne (call $get_option (local.get $stream) (i32.const 99) (i32.const 24))
+                                (i32.const 1))
+                        (then unreachable))))
+            "#
+        );
+        run_wat(&wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+        server.join().unwrap();
+    }
+
+    #[tokio::test]
+    async fn accepted_stream_reports_client_address() {
+        // Connects to its own listener and compares the peer address of the accepted stream with
+        // the local address of the connecting one. Addresses are 7 bytes for IPv4.
+        let wat = r#"
+            (module
+                (import "lunatic::networking" "tcp_bind"
+                    (func $tcp_bind (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::networking" "tcp_accept"
+                    (func $tcp_accept (param i64 i32 i32) (result i32)))
+                (import "lunatic::networking" "tcp_connect"
+                    (func $tcp_connect (param i32 i32 i32 i32 i32 i64 i32) (result i32)))
+                (import "lunatic::networking" "socket_local_addr"
+                    (func $local_addr (param i32 i64 i32 i32) (result i64)))
+                (import "lunatic::networking" "socket_peer_addr"
+                    (func $peer_addr (param i32 i64 i32 i32) (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\7f\00\00\01")
+                (func $same_addr (param $a i32) (param $b i32) (result i32)
+                    (i32.and
+                        (i32.eq (i32.load (local.get $a)) (i32.load (local.get $b)))
+                        (i32.eq (i32.load (i32.add (local.get $a) (i32.const 3)))
+                                (i32.load (i32.add (local.get $b) (i32.const 3))))))
+                (func (export "hello")
+                    (if (call $tcp_bind (i32.const 4) (i32.const 0) (i32.const 0) (i32.const 0)
+                                        (i32.const 0) (i32.const 16))
+                        (then unreachable))
+                    ;; 5 = TCP listener, the port is at offset 5 of the address
+                    (if (i64.ne (call $local_addr (i32.const 5) (i64.load (i32.const 16))
+                                                  (i32.const 64) (i32.const 32))
+                                (i64.const 7))
+                        (then unreachable))
+                    (if (call $tcp_connect (i32.const 4) (i32.const 0) (i32.load16_u (i32.const 69))
+                            (i32.const 0) (i32.const 0) (i64.const 5000) (i32.const 24))
+                        (then unreachable))
+                    (if (call $tcp_accept (i64.load (i32.const 16)) (i32.const 32) (i32.const 40))
+                        (then unreachable))
+                    ;; 6 = TCP stream
+                    (if (i64.ne (call $peer_addr (i32.const 6) (i64.load (i32.const 32))
+                                                 (i32.const 80) (i32.const 32))
+                                (i64.const 7))
+                        (then unreachable))
+                    (if (i64.ne (call $local_addr (i32.const 6) (i64.load (i32.const 24))
+                                                  (i32.const 96) (i32.const 32))
+                                (i64.const 7))
+                        (then unreachable))
+                    (if (i32.eqz (call $same_addr (i32.const 80) (i32.const 96)))
+                        (then unreachable))
+                    ;; The peer of the connecting stream is the listener
+                    (drop (call $peer_addr (i32.const 6) (i64.load (i32.const 24))
+                                           (i32.const 112) (i32.const 32)))
+                    (if (i32.eqz (call $same_addr (i32.const 64) (i32.const 112)))
+                        (then unreachable))
+                    ;; A buffer that is too small only returns the length
+                    (if (i64.ne (call $peer_addr (i32.const 6) (i64.load (i32.const 32))
+                                                 (i32.const 200) (i32.const 4))
+                                (i64.const 7))
+                        (then unreachable))
+                    (if (i32.load (i32.const 200))
+                        (then unreachable))))
+        "#;
+        run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn list_own_resources_reports_kinds_and_ids() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // Creates a config, a TCP listener and a UDP socket. A buffer for one entry only gets the
+        // first one, a big enough buffer gets all three: config (1), TCP listener (5) and UDP
+        // socket (9), each with the ID 0.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (import "lunatic::process" "list_own_resources"
+                    (func $list (param i32 i32) (result i64)))
+                (import "lunatic::networking" "tcp_bind"
+                    (func $tcp_bind (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::networking" "udp_bind"
+                    (func $udp_bind (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\7f\00\00\01")
+                (func $check (param $offset i32) (param $kind i64)
+                    (if (i64.ne (i64.load (local.get $offset)) (local.get $kind))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.add (local.get $offset) (i32.const 8))) (i64.const 0))
+                        (then unreachable)))
+                (func (export "hello")
+                    (drop (call $create_config))
+                    (if (call $tcp_bind (i32.const 4) (i32.const 0) (i32.const 0) (i32.const 0)
+                                        (i32.const 0) (i32.const 8))
+                        (then unreachable))
+                    (if (call $udp_bind (i32.const 4) (i32.const 0) (i32.const 0) (i32.const 0)
+                                        (i32.const 0) (i32.const 8))
+                        (then unreachable))
+                    (if (i64.ne (call $list (i32.const 100) (i32.const 16)) (i64.const 3))
+                        (then unreachable))
+                    (call $check (i32.const 100) (i64.const 1))
+                    (if (i64.ne (i64.load (i32.const 116)) (i64.const 0))
+                        (then unreachable))
+                    (if (i64.ne (call $list (i32.const 200) (i32.const 48)) (i64.const 3))
+                        (then unreachable))
+                    (call $check (i32.const 200) (i64.const 1))
+                    (call $check (i32.const 216) (i64.const 5))
+                    (call $check (i32.const 232) (i64.const 9))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_create_configs(true);
+        run_wat(wat, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn kv_store_is_local_to_the_process() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::sync::Arc;
+
+        // `set` stores "value" under "key", reads it back and checks that a value bigger than the
+        // configured limit is rejected. `get` expects the key to be missing.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "kv_set" (func $kv_set (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "kv_get" (func $kv_get (param i32 i32 i32 i32) (result i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "keyvalue")
+                (func (export "set")
+                    (if (call $kv_set (i32.const 0) (i32.const 3) (i32.const 3) (i32.const 5))
+                        (then unreachable))
+                    ;; A buffer that is too small only returns the length
+                    (if (i64.ne (call $kv_get (i32.const 0) (i32.const 3) (i32.const 100) (i32.const 4))
+                                (i64.const 5))
+                        (then unreachable))
+                    (if (i32.load8_u (i32.const 100))
+                        (then unreachable))
+                    (if (i64.ne (call $kv_get (i32.const 0) (i32.const 3) (i32.const 100) (i32.const 5))
+                                (i64.const 5))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 3)) (i32.load (i32.const 100)))
+                        (then unreachable))
+                    (if (i32.ne (i32.load8_u (i32.const 7)) (i32.load8_u (i32.const 104)))
+                        (then unreachable))
+                    (if (i32.ne (call $kv_set (i32.const 0) (i32.const 3) (i32.const 0) (i32.const 20))
+                                (i32.const 1))
+                        (then unreachable)))
+                (func (export "get")
+                    (if (i64.ne (call $kv_get (i32.const 0) (i32.const 3) (i32.const 100) (i32.const 5))
+                                (i64.const -1))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let registry = Arc::new(dashmap::DashMap::new());
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_max_kv_store_size(16);
+        let config = Arc::new(config);
+
+        for function in ["set", "get"] {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                config.clone(),
+                registry.clone(),
+            )
+            .unwrap();
+            let (join, _) = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                function,
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            join.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn kv_set_traps_if_key_range_overflows() {
+        let wat = r#"
+            (module
+                (import "lunatic::process" "kv_set" (func $kv_set (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    ;; u32::MAX + 2 wraps around to 1
+                    (drop (call $kv_set (i32.const -1) (i32.const 2) (i32.const 0) (i32.const 0)))))
+        "#;
+        let error = run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("lunatic::process::kv_set"),
+            "{}",
+            error
+        );
+    }
+
+    #[tokio::test]
+    async fn kv_cas_only_swaps_matching_values() {
+        // Stores "old" under "key", then tries to swap it with "new" expecting "bad" and "old".
+        let wat = r#"
+            (module
+                (import "lunatic::process" "kv_set" (func $kv_set (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "kv_get" (func $kv_get (param i32 i32 i32 i32) (result i64)))
+                (import "lunatic::process" "kv_cas" (func $kv_cas (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "keyoldnewbad")
+                (func (export "hello")
+                    (if (call $kv_set (i32.const 0) (i32.const 3) (i32.const 3) (i32.const 3))
+                        (then unreachable))
+                    ;; Failed swap, the value stays "old"
+                    (if (call $kv_cas (i32.const 0) (i32.const 3) (i32.const 9) (i32.const 3) (i32.const 6) (i32.const 3))
+                        (then unreachable))
+                    (drop (call $kv_get (i32.const 0) (i32.const 3) (i32.const 100) (i32.const 3)))
+                    (if (i32.ne (i32.load16_u (i32.const 100)) (i32.load16_u (i32.const 3)))
+                        (then unreachable))
+                    ;; Successful swap
+                    (if (i32.ne (call $kv_cas (i32.const 0) (i32.const 3) (i32.const 3) (i32.const 3) (i32.const 6) (i32.const 3))
+                                (i32.const 1))
+                        (then unreachable))
+                    (drop (call $kv_get (i32.const 0) (i32.const 3) (i32.const 100) (i32.const 3)))
+                    (if (i32.ne (i32.load16_u (i32.const 100)) (i32.load16_u (i32.const 6)))
+                        (then unreachable))
+                    ;; A missing key never matches
+                    (if (call $kv_cas (i32.const 6) (i32.const 3) (i32.const 6) (i32.const 3) (i32.const 3) (i32.const 3))
+                        (then unreachable))))
+        "#;
+        run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn shared_region_is_visible_to_attached_processes() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        // The writer creates the region and leaves "hello" in it, the reader attaches to it by ID
+        // (the first region of the environment) and compares the content.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "shared_region_create"
+                    (func $create (param i64 i32) (result i64)))
+                (import "lunatic::process" "shared_region_attach"
+                    (func $attach (param i64) (result i64)))
+                (import "lunatic::process" "shared_region_size" (func $size (param i64) (result i64)))
+                (import "lunatic::process" "shared_region_read"
+                    (func $read (param i64 i64 i32 i32)))
+                (import "lunatic::process" "shared_region_write"
+                    (func $write (param i64 i64 i32 i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "hello")
+                (func (export "writer")
+                    (local $region i64)
+                    (local.set $region (call $create (i64.const 64) (i32.const 16)))
+                    (if (i64.ne (i64.load (i32.const 16)) (i64.const 1))
+                        (then unreachable))
+                    (call $write (local.get $region) (i64.const 10) (i32.const 0) (i32.const 5)))
+                (func (export "reader")
+                    (local $region i64)
+                    (if (i64.ne (call $attach (i64.const 2)) (i64.const -1))
+                        (then unreachable))
+                    (local.set $region (call $attach (i64.const 1)))
+                    (if (i64.ne (call $size (local.get $region)) (i64.const 64))
+                        (then unreachable))
+                    (call $read (local.get $region) (i64.const 10) (i32.const 100) (i32.const 5))
+                    (if (i32.ne (i32.load (i32.const 100)) (i32.load (i32.const 0)))
+                        (then unreachable))
+                    (if (i32.ne (i32.load8_u (i32.const 104)) (i32.load8_u (i32.const 4)))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut states = Vec::new();
+        for function in ["writer", "reader"] {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(crate::DefaultProcessConfig::default()),
+                Default::default(),
+            )
+            .unwrap();
+            let (join, _) = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                function,
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            // The finished writer keeps its handle, so the region stays alive for the reader
+            states.push(join.await.unwrap().unwrap());
+        }
+        assert_eq!(states[1].resources.shared_regions.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn await_name_waits_for_late_registration() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        // The server only registers itself after a while. The client gives up on a name that is
+        // never registered, but finds the server.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (import "lunatic::registry" "put" (func $put (param i32 i32 i64 i64)))
+                (import "lunatic::registry" "await_name"
+                    (func $await_name (param i32 i32 i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "servernever")
+                (func (export "server")
+                    (call $sleep_ms (i64.const 200))
+                    (call $put (i32.const 0) (i32.const 6) (i64.const 0) (call $process_id)))
+                (func (export "client")
+                    (if (i32.ne (call $await_name (i32.const 6) (i32.const 5) (i32.const 16)
+                                    (i32.const 24) (i64.const 50))
+                                (i32.const 9027))
+                        (then unreachable))
+                    (if (call $await_name (i32.const 0) (i32.const 6) (i32.const 16) (i32.const 24)
+                            (i64.const 5000))
+                        (then unreachable))
+                    (if (i64.eqz (i64.load (i32.const 24)))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let registry = Arc::new(dashmap::DashMap::new());
+        let start = Instant::now();
+        let mut joins = Vec::new();
+        for function in ["client", "server"] {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(crate::DefaultProcessConfig::default()),
+                registry.clone(),
+            )
+            .unwrap();
+            let (join, _) = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                function,
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            joins.push(join);
+        }
+        for join in joins {
+            join.await.unwrap().unwrap();
+        }
+        // Woken up by the registration instead of waiting for the timeout
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn children_are_killed_with_parent_unless_detached() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::env::Environment;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::sync::Arc;
+
+        // The parent spawns a child with its own config and a second one with a detached config,
+        // then finishes while both children are still sleeping.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (import "lunatic::process" "config_set_detached" (func $set_detached (param i64 i32)))
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                (func (export "child") (call $sleep_ms (i64.const 10000)))
+                (func (export "hello")
+                    (local $config i64)
+                    (local.set $config (call $create_config))
+                    (call $set_detached (local.get $config) (i32.const 1))
+                    (if (call $spawn (i64.const 0) (i64.const -1) (i64.const -1)
+                            (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 16))
+                        (then unreachable))
+                    (if (call $spawn (i64.const 0) (local.get $config) (i64.const -1)
+                            (i32.const 0) (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 24))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_create_configs(true);
+        config.set_can_spawn_processes(true);
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            Default::default(),
+        )
+        .unwrap();
+        let parent_id = state.id();
+        let (join, _) = spawn_wasm(
+            env.clone(),
+            runtime,
+            &module,
+            state,
+            "hello",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        join.await.unwrap().unwrap();
+
+        // Give the kill signal a moment to reach the child
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(env.get_process(parent_id + 1).is_none());
+        assert!(env.get_process(parent_id + 2).is_some());
+        assert_eq!(env.process_count(), 1);
+    }
+
+    #[tokio::test]
+    async fn call_waits_for_reply_or_times_out() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        // `responder` answers a "ping" request with "pong", `silent` never answers. `caller` calls
+        // the responder, `caller_timeout` the silent process and a process that doesn't exist.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "call" (func $call (param i64 i64) (result i32)))
+                (import "lunatic::message" "reply_ref" (func $reply_ref (result i64)))
+                (import "lunatic::message" "reply" (func $reply (param i64) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "pingpong")
+                (func (export "responder")
+                    (local $ref i64)
+                    (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1)))
+                    (drop (call $read_data (i32.const 16) (i32.const 4)))
+                    (if (i32.ne (i32.load (i32.const 16)) (i32.load (i32.const 0)))
+                        (then unreachable))
+                    (local.set $ref (call $reply_ref))
+                    (if (i64.eq (local.get $ref) (i64.const -1))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 4))
+                    (drop (call $write_data (i32.const 4) (i32.const 4)))
+                    (if (call $reply (local.get $ref))
+                        (then unreachable)))
+                (func (export "silent") (call $sleep_ms (i64.const 10000)))
+                (func (export "caller") (param $responder i64)
+                    (call $create_data (i64.const 0) (i64.const 4))
+                    (drop (call $write_data (i32.const 0) (i32.const 4)))
+                    (if (call $call (local.get $responder) (i64.const 5000))
+                        (then unreachable))
+                    (drop (call $read_data (i32.const 16) (i32.const 4)))
+                    (if (i32.ne (i32.load (i32.const 16)) (i32.load (i32.const 4)))
+                        (then unreachable)))
+                (func (export "caller_timeout") (param $silent i64)
+                    (call $create_data (i64.const 0) (i64.const 4))
+                    (drop (call $write_data (i32.const 0) (i32.const 4)))
+                    (if (i32.ne (call $call (local.get $silent) (i64.const 50)) (i32.const 9027))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 4))
+                    (if (i32.ne (call $call (i64.const 1000000) (i64.const 50)) (i32.const 1))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let config = Arc::new(crate::DefaultProcessConfig::default());
+        let spawn = |function: &'static str, params: Vec<Val>| {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                config.clone(),
+                Default::default(),
+            )
+            .unwrap();
+            spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                function,
+                params,
+                None,
+            )
+        };
+
+        let (_, responder) = spawn("responder", Vec::new()).await.unwrap();
+        let (join, _) = spawn("caller", vec![Val::I64(responder.id() as i64)])
+            .await
+            .unwrap();
+        join.await.unwrap().unwrap();
+
+        let (_, silent) = spawn("silent", Vec::new()).await.unwrap();
+        let (join, _) = spawn("caller_timeout", vec![Val::I64(silent.id() as i64)])
+            .await
+            .unwrap();
+        join.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn messages_over_max_size_are_rejected() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        // A 16 byte message is sent to itself, a 17 byte message is rejected.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "data_size" (func $data_size (result i64)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 0) (i32.const 16)))
+                    (if (call $send (call $process_id))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 0) (i32.const 17)))
+                    (if (i32.ne (call $send (call $process_id)) (i32.const 9028))
+                        (then unreachable))
+                    ;; Only the first message arrives
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 100))
+                        (then unreachable))
+                    (if (i64.ne (call $data_size) (i64.const 16))
+                        (then unreachable))
+                    (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const 100))
+                                (i32.const 9027))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_max_message_size(Some(16));
+        run_wat(wat, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn large_message_arrives_intact() {
+        // Fills the first MB with a pattern and sends it to itself in two writes. The received
+        // copy is read into the second MB and compared.
+        let wat = r#"
+            (module
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "data_size" (func $data_size (result i64)))
+                (memory (export "memory") 32)
+                (func (export "hello") (local $i i32)
+                    (loop $fill
+                        (i32.store (local.get $i) (i32.mul (local.get $i) (i32.const 2654435761)))
+                        (local.set $i (i32.add (local.get $i) (i32.const 4)))
+                        (br_if $fill (i32.lt_u (local.get $i) (i32.const 1048576))))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (if (i32.ne (call $write_data (i32.const 0) (i32.const 524288)) (i32.const 524288))
+                        (then unreachable))
+                    (if (i32.ne (call $write_data (i32.const 524288) (i32.const 524288)) (i32.const 524288))
+                        (then unreachable))
+                    (if (call $send (call $process_id))
+                        (then unreachable))
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 1000))
+                        (then unreachable))
+                    (if (i64.ne (call $data_size) (i64.const 1048576))
+                        (then unreachable))
+                    (if (i32.ne (call $read_data (i32.const 1048576) (i32.const 1048576)) (i32.const 1048576))
+                        (then unreachable))
+                    (local.set $i (i32.const 0))
+                    (loop $compare
+                        (if (i32.ne (i32.load (local.get $i))
+                                    (i32.load (i32.add (local.get $i) (i32.const 1048576))))
+                            (then unreachable))
+                        (local.set $i (i32.add (local.get $i) (i32.const 4)))
+                        (br_if $compare (i32.lt_u (local.get $i) (i32.const 1048576))))))
+        "#;
+        run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn traps_are_classified_by_reason() {
+        use lunatic_process::{ProcessFailure, TrapReason};
+
+        async fn trap_reason(wat: &str) -> Option<TrapReason> {
+            let error = run_wat(wat, crate::DefaultProcessConfig::default())
+                .await
+                .unwrap_err();
+            error.downcast_ref::<ProcessFailure>().unwrap().reason()
+        }
+
+        let stack_overflow = r#"
+            (module
+                (func $recurse (call $recurse))
+                (func (export "hello") (call $recurse)))
+        "#;
+        assert_eq!(
+            trap_reason(stack_overflow).await,
+            Some(TrapReason::StackOverflow)
+        );
+
+        let out_of_bounds = r#"
+            (module
+                (memory 1)
+                (func (export "hello") (drop (i32.load (i32.const 65536)))))
+        "#;
+        assert_eq!(
+            trap_reason(out_of_bounds).await,
+            Some(TrapReason::OutOfBoundsMemory)
+        );
+
+        let unreachable = r#"(module (func (export "hello") unreachable))"#;
+        assert_eq!(
+            trap_reason(unreachable).await,
+            Some(TrapReason::Unreachable)
+        );
+    }
+
+    #[tokio::test]
+    async fn sends_above_the_rate_limit_are_throttled() {
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::time::{Duration, Instant};
+
+        // Sends **count** messages to itself and checks the status of the last one. Within the
+        // rate, all messages pass.
+        let wat = |count: u32, last_status: u32| {
+            format!(
+                r#"
+            (module
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (func $send_to (param $process i64) (result i32)
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (call $send (local.get $process)))
+                (func (export "hello")
+                    (local $i i32)
+                    (loop $burst
+                        (if (call $send_to (call $process_id)) (then unreachable))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $burst (i32.lt_u (local.get $i) (i32.const {count}))))
+                    (if (i32.ne (call $send_to (call $process_id)) (i32.const {last_status}))
+                        (then unreachable))
+                    ;; Other receivers have their own bucket
+                    (if (call $send_to (i64.const 999)) (then unreachable))))
+            "#
+            )
+        };
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_max_send_rate(Some(20));
+        let start = Instant::now();
+        run_wat(&wat(19, 0), config.clone()).await.unwrap();
+        run_wat(&wat(20, 9029), config.clone()).await.unwrap();
+        assert!(start.elapsed() < Duration::from_millis(500));
+
+        // Ten messages above the rate need to wait for half a second
+        config.set_block_when_rate_limited(true);
+        let start = Instant::now();
+        run_wat(&wat(29, 0), config).await.unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(450));
+    }
+
+    #[tokio::test]
+    async fn timer_set_sends_tagged_message_to_self() {
+        let wat = r#"
+            (module
+                (import "lunatic::timer" "timer_set" (func $timer_set (param i64 i64) (result i64)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (func (export "hello")
+                    (drop (call $timer_set (i64.const 50) (i64.const 42)))
+                    ;; The timer doesn't block and hasn't fired yet
+                    (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const 10))
+                                (i32.const 9027))
+                        (then unreachable))
+                    (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const 1000))
+                                (i32.const 0))
+                        (then unreachable))
+                    (if (i64.ne (call $get_tag) (i64.const 42))
+                        (then unreachable))))
+        "#;
+        run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn timer_set_clamps_huge_delays() {
+        let wat = r#"
+            (module
+                (import "lunatic::timer" "timer_set" (func $timer_set (param i64 i64) (result i64)))
+                (import "lunatic::timer" "cancel_timer" (func $cancel_timer (param i64) (result i32)))
+                (func (export "hello")
+                    ;; u64::MAX milliseconds
+                    (if (i32.ne (call $cancel_timer (call $timer_set (i64.const -1) (i64.const 1)))
+                                (i32.const 1))
+                        (then unreachable))))
+        "#;
+        run_wat(wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn append_writes_from_two_fds_dont_overwrite_each_other() {
+        let dir = std::env::temp_dir().join(format!("lunatic-append-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let _ = std::fs::remove_file(dir.join("log.txt"));
+
+        // Opens `log.txt` twice with `FDFLAGS_APPEND` and interleaves writes from both fds.
+        // The host file is opened with `O_APPEND`, so every write seeks to the end atomically.
+        // Otherwise the second write through the first fd would land at its stale offset and
+        // clobber the data written through the other one.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "log.txt")
+                (data (i32.const 16) "first")
+                (data (i32.const 24) "second")
+                (data (i32.const 32) "third")
+                (func $open (param $fd_ptr i32)
+                    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 7)
+                            (i32.const 1) (i64.const 64) (i64.const 0) (i32.const 1)
+                            (local.get $fd_ptr))
+                        (then unreachable)))
+                (func $write (param $fd i32) (param $ptr i32) (param $len i32)
+                    (i32.store (i32.const 200) (local.get $ptr))
+                    (i32.store (i32.const 204) (local.get $len))
+                    (if (call $fd_write (local.get $fd) (i32.const 200) (i32.const 1) (i32.const 208))
+                        (then unreachable)))
+                (func (export "hello")
+                    (call $open (i32.const 100))
+                    (call $open (i32.const 104))
+                    (call $write (i32.load (i32.const 100)) (i32.const 16) (i32.const 5))
+                    (call $write (i32.load (i32.const 104)) (i32.const 24) (i32.const 6))
+                    (call $write (i32.load (i32.const 100)) (i32.const 32) (i32.const 5))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+        run_wat(wat, config).await.unwrap();
+
+        let content = std::fs::read_to_string(dir.join("log.txt")).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(content, "firstsecondthird");
+    }
+
+    #[tokio::test]
+    async fn stdio_is_redirected_to_files() {
+        let dir = std::env::temp_dir().join(format!("lunatic-stdio-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (stdout, stderr) = (dir.join("stdout.txt"), dir.join("stderr.txt"));
+        std::fs::write(&stdout, "old\n").unwrap();
+        std::fs::write(&stderr, "old\n").unwrap();
+
+        // Prints "out" to stdout and "err" to stderr
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "out\nerr\n")
+                (data (i32.const 16) "\00\00\00\00\04\00\00\00\04\00\00\00\04\00\00\00")
+                (func (export "hello")
+                    (if (call $fd_write (i32.const 1) (i32.const 16) (i32.const 1) (i32.const 32))
+                        (then unreachable))
+                    (if (call $fd_write (i32.const 2) (i32.const 24) (i32.const 1) (i32.const 32))
+                        (then unreachable))))
+        "#;
+        let config = crate::DefaultProcessConfig::builder()
+            .stdout_file(stdout.to_str().unwrap(), false)
+            .stderr_file(stderr.to_str().unwrap(), true)
+            .build()
+            .unwrap();
+        run_wat(wat, config).await.unwrap();
+
+        let stdout = std::fs::read_to_string(stdout).unwrap();
+        let stderr = std::fs::read_to_string(stderr).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(stdout, "out\n");
+        assert_eq!(stderr, "old\nerr\n");
+    }
+
+    #[tokio::test]
+    async fn spawn_fails_when_environment_is_full() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::env::{Environment, LunaticEnvironment};
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process::Signal;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (func (export "hello") (call $sleep_ms (i64.const 10000))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(LunaticEnvironment::with_max_processes(0, Some(2)));
+        let config = Arc::new(DefaultProcessConfig::default());
+
+        let spawn = || async {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                config.clone(),
+                Default::default(),
+            )
+            .unwrap();
+            spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                "hello",
+                Vec::new(),
+                None,
+            )
+            .await
+        };
+
+        let (join, first) = spawn().await.unwrap();
+        spawn().await.unwrap();
+        let error = spawn().await.unwrap_err();
+        assert!(error.to_string().contains("Resource exhausted"));
+        assert_eq!(env.process_count(), 2);
+
+        first.send(Signal::Kill);
+        let _ = join.await;
+        assert_eq!(env.process_count(), 1);
+        // Only one of the concurrent spawns gets the free slot
+        let (a, b) = tokio::join!(spawn(), spawn());
+        assert!(a.is_ok() != b.is_ok());
+        assert_eq!(env.process_count(), 2);
+    }
+
+    #[tokio::test]
+    async fn process_label_shows_up_in_listing() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::env::{Environment, LunaticEnvironment};
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "set_process_label" (func $set_label (param i32 i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "worker")
+                (func (export "hello")
+                    (call $set_label (i32.const 0) (i32.const 6))
+                    (call $sleep_ms (i64.const 10000))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(LunaticEnvironment::new(0));
+        let registry = Arc::new(dashmap::DashMap::new());
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            registry.clone(),
+        )
+        .unwrap();
+        let (_, process) = spawn_wasm(
+            env.clone(),
+            runtime,
+            &module,
+            state,
+            "hello",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        // Give the process a chance to run until it goes to sleep
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+
+        let processes = env.list_processes(&registry);
+        let info = processes
+            .iter()
+            .find(|info| info.id == process.id())
+            .unwrap();
+        assert_eq!(info.label.as_deref(), Some("worker"));
+    }
+
+    #[tokio::test]
+    async fn restored_snapshot_preserves_mailbox_and_label() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::env::LunaticEnvironment;
+        use lunatic_process::message::{DataMessage, Message, SerializedMessage};
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessCtx;
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        // `snapshot` labels itself, takes a snapshot and sends it to `restore`, which restores it.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "data_size" (func $data_size (result i64)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::process" "set_process_label" (func $set_label (param i32 i32)))
+                (import "lunatic::process" "kv_set" (func $kv_set (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "snapshot_state" (func $snapshot (param i32 i32) (result i64)))
+                (import "lunatic::process" "restore_state" (func $restore (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "worker")
+                (data (i32.const 8) "key")
+                (func (export "snapshot") (param $target i64)
+                    (local $len i64)
+                    (call $set_label (i32.const 0) (i32.const 6))
+                    (if (call $kv_set (i32.const 8) (i32.const 3) (i32.const 0) (i32.const 6))
+                        (then unreachable))
+                    (local.set $len (call $snapshot (i32.const 1024) (i32.const 60000)))
+                    (if (i64.gt_u (local.get $len) (i64.const 60000))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 1024) (i32.wrap_i64 (local.get $len))))
+                    (if (call $send (local.get $target))
+                        (then unreachable)))
+                (func (export "restore")
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                        (then unreachable))
+                    (drop (call $read_data (i32.const 1024) (i32.const 60000)))
+                    (if (call $restore (i32.const 1024) (i32.wrap_i64 (call $data_size)))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(LunaticEnvironment::new(0));
+        let registry = Arc::new(dashmap::DashMap::new());
+        let config = Arc::new(DefaultProcessConfig::default());
+
+        let target = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            config.clone(),
+            registry.clone(),
+        )
+        .unwrap();
+        let target_id = target.id();
+        let (restore, _) = spawn_wasm(
+            env.clone(),
+            runtime.clone(),
+            &module,
+            target,
+            "restore",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        // The messages are already waiting when the snapshot is taken
+        let source = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            config,
+            registry,
+        )
+        .unwrap();
+        let pending = vec![
+            SerializedMessage::Data {
+                tag: Some(7),
+                buffer: b"hello".to_vec(),
+                reply_to: None,
+            },
+            SerializedMessage::LinkDied(Some(3)),
+        ];
+        source
+            .message_mailbox()
+            .reinject(pending.iter().cloned().map(Message::from));
+        let params = vec![Val::I64(target_id as i64)];
+        let (snapshot, _) = spawn_wasm(env, runtime, &module, source, "snapshot", params, None)
+            .await
+            .unwrap();
+
+        let source = snapshot.await.unwrap().unwrap();
+        // Taking a snapshot doesn't remove the messages
+        assert_eq!(source.message_mailbox().snapshot().unwrap(), pending);
+
+        let mut restored = restore.await.unwrap().unwrap();
+        assert_eq!(restored.stats().label().as_deref(), Some("worker"));
+        assert_eq!(restored.message_mailbox().snapshot().unwrap(), pending);
+        assert_eq!(
+            restored.kv_store().get(b"key".as_slice()),
+            Some(&b"worker".to_vec())
+        );
+        // A message carrying resources can't be part of a snapshot
+        let mut with_resource = DataMessage::new(None, 0);
+        with_resource.add_resource(Arc::new(0u8));
+        restored
+            .message_mailbox()
+            .push(Message::Data(with_resource));
+        assert!(restored.message_mailbox().snapshot().is_none());
+    }
+
+    #[tokio::test]
+    async fn fdstat_reports_fs_flags() {
+        let dir = std::env::temp_dir().join(format!("lunatic-fdstat-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Opens one fd with `FDFLAGS_APPEND` and checks that `fd_fdstat_get` reports it. A second
+        // fd is opened without flags and gets `APPEND | NONBLOCK` set by `fd_fdstat_set_flags`.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_fdstat_get"
+                    (func $fdstat_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_fdstat_set_flags"
+                    (func $fdstat_set_flags (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "flags.txt")
+                (func $open (param $fdflags i32) (result i32)
+                    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 9)
+                            (i32.const 1) (i64.const 72) (i64.const 0) (local.get $fdflags)
+                            (i32.const 100))
+                        (then unreachable))
+                    (i32.load (i32.const 100)))
+                ;; Returns the `fs_flags` field of the fd's fdstat
+                (func $fs_flags (param $fd i32) (result i32)
+                    (if (call $fdstat_get (local.get $fd) (i32.const 200))
+                        (then unreachable))
+                    (i32.load16_u (i32.const 202)))
+                (func (export "hello")
+                    (local $fd i32)
+                    (if (i32.ne (call $fs_flags (call $open (i32.const 1))) (i32.const 1))
+                        (then unreachable))
+                    (local.set $fd (call $open (i32.const 0)))
+                    (if (i32.ne (call $fs_flags (local.get $fd)) (i32.const 0))
+                        (then unreachable))
+                    (if (call $fdstat_set_flags (local.get $fd) (i32.const 5))
+                        (then unreachable))
+                    (if (i32.ne (call $fs_flags (local.get $fd)) (i32.const 5))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+        let result = run_wat(wat, config).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn opening_files_above_the_limit_fails_with_emfile() {
+        use lunatic_wasi_api::LunaticWasiConfigCtx;
+
+        let dir = std::env::temp_dir().join(format!("lunatic-max-fds-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Two files can be open at the same time, the third one only after closing the first.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_close" (func $fd_close (param i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "abc")
+                ;; Creates the file named by the byte at **name** and returns the errno
+                (func $open (param $name i32) (result i32)
+                    (call $path_open (i32.const 3) (i32.const 0) (local.get $name) (i32.const 1)
+                        (i32.const 1) (i64.const 72) (i64.const 0) (i32.const 0) (i32.const 100)))
+                (func (export "hello")
+                    (local $first i32)
+                    (if (call $open (i32.const 0)) (then unreachable))
+                    (local.set $first (i32.load (i32.const 100)))
+                    (if (call $open (i32.const 1)) (then unreachable))
+                    ;; 33 = EMFILE
+                    (if (i32.ne (call $open (i32.const 2)) (i32.const 33))
+                        (then unreachable))
+                    (if (call $fd_close (local.get $first)) (then unreachable))
+                    (if (call $open (i32.const 2)) (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+        config.set_max_open_fds(Some(2));
+        let result = run_wat(wat, config).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn fd_datasync_and_fd_sync_make_data_durable() {
+        let dir = std::env::temp_dir().join(format!("lunatic-sync-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+
+        // Writes "synced" and calls `fd_datasync`, then reads it back through an independent fd.
+        // " all" is appended afterwards and synced together with the metadata by `fd_sync`.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read"
+                    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_datasync"
+                    (func $fd_datasync (param i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_sync" (func $fd_sync (param i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "sync.txt")
+                (data (i32.const 16) "synced")
+                (data (i32.const 24) " all")
+                ;; iovecs for both writes and the read into offset 64
+                (data (i32.const 32) "\10\00\00\00\06\00\00\00\18\00\00\00\04\00\00\00\40\00\00\00\06\00\00\00")
+                (func $open (param $oflags i32) (param $rights i64) (result i32)
+                    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 8)
+                            (local.get $oflags) (local.get $rights) (i64.const 0) (i32.const 0)
+                            (i32.const 100))
+                        (then unreachable))
+                    (i32.load (i32.const 100)))
+                (func (export "hello")
+                    (local $fd i32)
+                    (local $reader i32)
+                    ;; `O_CREAT | O_TRUNC` with the `fd_write`, `fd_datasync` and `fd_sync` rights
+                    (local.set $fd (call $open (i32.const 9) (i64.const 81)))
+                    (if (call $fd_write (local.get $fd) (i32.const 32) (i32.const 1) (i32.const 56))
+                        (then unreachable))
+                    (if (call $fd_datasync (local.get $fd))
+                        (then unreachable))
+                    ;; Independent fd with only the `fd_read` right
+                    (local.set $reader (call $open (i32.const 0) (i64.const 2)))
+                    (if (call $fd_read (local.get $reader) (i32.const 48) (i32.const 1) (i32.const 56))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 56)) (i32.const 6))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 64)) (i32.load (i32.const 16)))
+                        (then unreachable))
+                    (if (i32.ne (i32.load16_u (i32.const 68)) (i32.load16_u (i32.const 20)))
+                        (then unreachable))
+                    (if (call $fd_write (local.get $fd) (i32.const 40) (i32.const 1) (i32.const 56))
+                        (then unreachable))
+                    (if (call $fd_sync (local.get $fd))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+        let result = run_wat(wat, config).await;
+        let content = std::fs::read_to_string(dir.join("sync.txt"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(content.unwrap(), "synced all");
+    }
+
+    #[tokio::test]
+    async fn fd_prestat_enumerates_all_preopens() {
+        let dir = std::env::temp_dir().join(format!("lunatic-prestat-{}", std::process::id()));
+        let mut config = crate::DefaultProcessConfig::default();
+        for name in ["a", "b", "c"] {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+            config.preopen_dir(dir.join(name).to_str().unwrap());
+        }
+
+        // Scans fds starting at 3 like wasi-libc does, until `fd_prestat_get` returns `EBADF`.
+        // Every preopen must be a directory with a name that ends with the expected letter.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_prestat_get"
+                    (func $prestat_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_prestat_dir_name"
+                    (func $prestat_dir_name (param i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (local $fd i32)
+                    (local $errno i32)
+                    (local $name_len i32)
+                    (local.set $fd (i32.const 3))
+                    (block $done
+                        (loop $scan
+                            (local.set $errno (call $prestat_get (local.get $fd) (i32.const 0)))
+                            ;; EBADF
+                            (br_if $done (i32.eq (local.get $errno) (i32.const 8)))
+                            (if (local.get $errno)
+                                (then unreachable))
+                            ;; Preopen type 0 is a directory
+                            (if (i32.load8_u (i32.const 0))
+                                (then unreachable))
+                            (local.set $name_len (i32.load (i32.const 4)))
+                            (if (call $prestat_dir_name (local.get $fd) (i32.const 16)
+                                    (local.get $name_len))
+                                (then unreachable))
+                            ;; fd 3 is "a", fd 4 "b" and fd 5 "c"
+                            (if (i32.ne (i32.load8_u (i32.add (i32.const 15) (local.get $name_len)))
+                                        (i32.add (i32.const 94) (local.get $fd)))
+                                (then unreachable))
+                            (local.set $fd (i32.add (local.get $fd) (i32.const 1)))
+                            (br $scan)))
+                    (if (i32.ne (local.get $fd) (i32.const 6))
+                        (then unreachable))))
+        "#;
+        let result = run_wat(wat, config).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn path_rename_moves_file_inside_preopened_dir() {
+        let dir = std::env::temp_dir().join(format!("lunatic-rename-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a"), b"moved").unwrap();
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+
+        // Both paths are relative to the same preopen, which is passed as source and target.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_rename"
+                    (func $path_rename (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "ab")
+                (func (export "hello")
+                    (if (call $path_rename (i32.const 3) (i32.const 0) (i32.const 1)
+                            (i32.const 3) (i32.const 1) (i32.const 1))
+                        (then unreachable))))
+        "#;
+        let result = run_wat(wat, config).await;
+        let moved = std::fs::read(dir.join("b"));
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert_eq!(moved.unwrap(), b"moved");
+    }
+
+    #[tokio::test]
+    async fn fd_read_returns_zero_bytes_at_eof() {
+        let dir = std::env::temp_dir().join(format!("lunatic-fd-read-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("f"), [7u8; 100]).unwrap();
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+
+        // Opens the 100 byte file "f" and reads it with a 64 byte buffer until `fd_read` returns
+        // 0 bytes, like std's `read_to_end` does. The reads must return 64, 36 and 0 bytes.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read"
+                    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "f")
+                ;; iovec pointing to a 64 byte buffer at offset 100
+                (data (i32.const 8) "\64\00\00\00\40\00\00\00")
+                (func (export "hello")
+                    (local $fd i32)
+                    (local $reads i32)
+                    (local $total i32)
+                    ;; Rights: fd_read
+                    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 1)
+                            (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 20))
+                        (then unreachable))
+                    (local.set $fd (i32.load (i32.const 20)))
+                    (block $eof
+                        (loop $read
+                            (if (call $fd_read (local.get $fd) (i32.const 8) (i32.const 1)
+                                    (i32.const 16))
+                                (then unreachable))
+                            (local.set $reads (i32.add (local.get $reads) (i32.const 1)))
+                            (br_if $eof (i32.eqz (i32.load (i32.const 16))))
+                            (local.set $total (i32.add (local.get $total) (i32.load (i32.const 16))))
+                            (if (i32.gt_u (local.get $reads) (i32.const 3))
+                                (then unreachable))
+                            (br $read)))
+                    (if (i32.ne (local.get $total) (i32.const 100))
+                        (then unreachable))
+                    (if (i32.ne (local.get $reads) (i32.const 3))
+                        (then unreachable))))
+        "#;
+        let result = run_wat(wat, config).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    #[tokio::test]
+    async fn path_open_honors_oflags() {
+        let dir = std::env::temp_dir().join(format!("lunatic-oflags-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("f"), [7u8; 100]).unwrap();
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+
+        // oflags: CREAT = 1, DIRECTORY = 2, EXCL = 4, TRUNC = 8
+        // * Creating "n" succeeds.
+        // * Exclusively creating the existing "f" fails with EEXIST (20).
+        // * Opening "f" as a directory fails with ENOTDIR (54).
+        // * Opening "f" with TRUNC empties it.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "f")
+                (data (i32.const 1) "n")
+                (func $open (param $path i32) (param $oflags i32) (result i32)
+                    ;; Rights: fd_read, fd_write
+                    (call $path_open (i32.const 3) (i32.const 0) (local.get $path) (i32.const 1)
+                        (local.get $oflags) (i64.const 66) (i64.const 0) (i32.const 0)
+                        (i32.const 20)))
+                (func (export "hello")
+                    (if (call $open (i32.const 1) (i32.const 1))
+                        (then unreachable))
+                    (if (i32.ne (call $open (i32.const 0) (i32.const 5)) (i32.const 20))
+                        (then unreachable))
+                    (if (i32.ne (call $open (i32.const 0) (i32.const 2)) (i32.const 54))
+                        (then unreachable))
+                    (if (call $open (i32.const 0) (i32.const 8))
+                        (then unreachable))))
+        "#;
+        let result = run_wat(wat, config).await;
+        let created = dir.join("n").exists();
+        let truncated_len = std::fs::metadata(dir.join("f")).unwrap().len();
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+        assert!(created);
+        assert_eq!(truncated_len, 0);
+    }
+
+    #[tokio::test]
+    async fn spawned_child_inherits_fd() {
+        use lunatic_process_api::ProcessConfigCtx;
+
+        let dir = std::env::temp_dir().join(format!("lunatic-inherit-fd-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("f"), "hello").unwrap();
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        config.preopen_dir(dir.to_str().unwrap());
+
+        // `hello` opens "f" and passes it to a linked `child` at fd 3, replacing the child's
+        // preopen. Afterwards the file is not available anymore in the parent. If the child can't
+        // read "hello" from fd 3 it traps and takes the parent down with it.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_read"
+                    (func $fd_read (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::wasi" "inherit_fd" (func $inherit_fd (param i32 i32) (result i32)))
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "f")
+                ;; iovec pointing to a 64 byte buffer at offset 100
+                (data (i32.const 8) "\64\00\00\00\40\00\00\00")
+                (data (i32.const 32) "child")
+                (func (export "hello")
+                    (local $fd i32)
+                    ;; Rights: fd_read
+                    (if (call $path_open (i32.const 3) (i32.const 0) (i32.const 0) (i32.const 1)
+                            (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 20))
+                        (then unreachable))
+                    (local.set $fd (i32.load (i32.const 20)))
+                    (if (call $inherit_fd (local.get $fd) (i32.const 3))
+                        (then unreachable))
+                    ;; An unknown fd can't be inherited
+                    (if (i32.ne (call $inherit_fd (i32.const 1000) (i32.const 4)) (i32.const 1))
+                        (then unreachable))
+                    (if (call $spawn (i64.const 1) (i64.const -1) (i64.const -1) (i32.const 32)
+                            (i32.const 5) (i32.const 0) (i32.const 0) (i32.const 24))
+                        (then unreachable))
+                    ;; EBADF
+                    (if (i32.ne (call $fd_read (local.get $fd) (i32.const 8) (i32.const 1)
+                                    (i32.const 16))
+                                (i32.const 8))
+                        (then unreachable))
+                    (call $sleep_ms (i64.const 100)))
+                (func (export "child")
+                    (if (call $fd_read (i32.const 3) (i32.const 8) (i32.const 1) (i32.const 16))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 16)) (i32.const 5))
+                        (then unreachable))
+                    ;; "h"
+                    (if (i32.ne (i32.load8_u (i32.const 100)) (i32.const 104))
+                        (then unreachable))))
+        "#;
+        let result = run_wat(wat, config).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        result.unwrap();
+    }
+
+    // Spawns one of two children: `graceful` waits for the shutdown message and checks that it
+    // carries a grace period of 1000ms, `stuck` ignores it.
+    #[cfg(test)]
+    async fn spawn_shutdown_child(
+        function: &str,
+    ) -> (
+        tokio::task::JoinHandle<anyhow::Result<crate::state::DefaultProcessState>>,
+        std::sync::Arc<dyn lunatic_process::Process>,
+    ) {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (memory (export "memory") 1)
+                (func (export "graceful")
+                    (i64.store (i32.const 0) (i64.const -9223372036854775808))
+                    (if (call $receive (i32.const 0) (i32.const 1) (i64.const -1))
+                        (then unreachable))
+                    (drop (call $read_data (i32.const 8) (i32.const 8)))
+                    (if (i64.ne (i64.load (i32.const 8)) (i64.const 1000))
+                        (then unreachable)))
+                (func (export "stuck") (call $sleep_ms (i64.const 10000))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(crate::DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+        spawn_wasm(env, runtime, &module, state, function, Vec::new(), None)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn request_shutdown_lets_child_exit_within_grace() {
+        use std::time::Duration;
+
+        let (join, process) = spawn_shutdown_child("graceful").await;
+        lunatic_process::request_shutdown(process, Duration::from_millis(1000));
+        let result = tokio::time::timeout(Duration::from_millis(500), join)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[tokio::test]
+    async fn request_shutdown_kills_stuck_child_after_grace() {
+        use std::time::Duration;
+
+        let (join, process) = spawn_shutdown_child("stuck").await;
+        lunatic_process::request_shutdown(process, Duration::from_millis(50));
+        let result = tokio::time::timeout(Duration::from_millis(2000), join)
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "Process received Kill signal"
+        );
+    }
+
+    #[tokio::test]
+    async fn env_get_looks_up_single_variable() {
+        let wat = r#"
+            (module
+                (import "lunatic::wasi" "env_get" (func $env_get (param i32 i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "GREETING")
+                (data (i32.const 16) "MISSING")
+                (func (export "hello")
+                    (if (call $env_get (i32.const 0) (i32.const 8) (i32.const 100) (i32.const 16)
+                                       (i32.const 200))
+                        (then unreachable))
+                    ;; "hello" is 5 bytes long
+                    (if (i64.ne (i64.load (i32.const 200)) (i64.const 5))
+                        (then unreachable))
+                    ;; "hell" as a little endian i32
+                    (if (i32.ne (i32.load (i32.const 100)) (i32.const 0x6c6c6568))
+                        (then unreachable))
+                    (if (i32.ne (call $env_get (i32.const 16) (i32.const 7) (i32.const 100)
+                                               (i32.const 16) (i32.const 200))
+                                (i32.const 1))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_environment_variables(vec![("GREETING".to_string(), "hello".to_string())]);
+        run_wat(wat, config).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn env_nth_iterates_variables_in_config_order() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_stdout_capture::StdoutCapture;
+        use lunatic_wasi_api::LunaticWasiCtx;
+        use std::sync::Arc;
+
+        // Writes "key=value\n" to stdout for each index until `env_nth` returns 1. The four
+        // iovecs at 300 point to the key, "=", the value and "\n", only the lengths of the key and
+        // value are filled in.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::wasi" "env_nth"
+                    (func $env_nth (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 300) "\64\00\00\00\00\00\00\00\90\01\00\00\01\00\00\00")
+                (data (i32.const 316) "\c8\00\00\00\00\00\00\00\91\01\00\00\01\00\00\00")
+                (data (i32.const 400) "=\n")
+                (func (export "hello") (local $i i32)
+                    (block $done
+                        (loop $next
+                            (br_if $done (call $env_nth (local.get $i) (i32.const 100) (i32.const 64)
+                                                        (i32.const 200) (i32.const 64) (i32.const 16)))
+                            (i32.store (i32.const 304) (i32.wrap_i64 (i64.load (i32.const 16))))
+                            (i32.store (i32.const 320) (i32.wrap_i64 (i64.load (i32.const 24))))
+                            (drop (call $fd_write (i32.const 1) (i32.const 300) (i32.const 4)
+                                                  (i32.const 500)))
+                            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                            (br $next)))
+                    (if (i32.ne (local.get $i) (i32.const 3))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_environment_variables(vec![
+            ("B".to_string(), "2".to_string()),
+            ("A".to_string(), "1".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]);
+        let mut state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            Default::default(),
+        )
+        .unwrap();
+        let stdout = StdoutCapture::new(false);
+        state.set_stdout(stdout.clone());
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+            .await
+            .unwrap();
+        join.await.unwrap().unwrap();
+
+        assert_eq!(stdout.content(), "B=2\nA=1\nEMPTY=\n");
+    }
+
+    #[tokio::test]
+    async fn fd_write_writes_all_iovecs() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_stdout_capture::StdoutCapture;
+        use lunatic_wasi_api::LunaticWasiCtx;
+        use std::sync::Arc;
+
+        // Writes 2048 iovecs of "abc" with a single `fd_write`, more than the 1024 iovecs a single
+        // `writev` accepts on Linux. All of them must end up in the output and be reported as
+        // written.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "abc")
+                (func (export "hello") (local $i i32)
+                    (block $done
+                        (loop $next
+                            (br_if $done (i32.eq (local.get $i) (i32.const 2048)))
+                            (i32.store (i32.add (i32.const 1024) (i32.mul (local.get $i) (i32.const 8)))
+                                       (i32.const 0))
+                            (i32.store (i32.add (i32.const 1028) (i32.mul (local.get $i) (i32.const 8)))
+                                       (i32.const 3))
+                            (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                            (br $next)))
+                    (if (call $fd_write (i32.const 1) (i32.const 1024) (i32.const 2048) (i32.const 16))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 16)) (i32.const 6144))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(crate::DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+        let stdout = StdoutCapture::new(false);
+        state.set_stdout(stdout.clone());
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+            .await
+            .unwrap();
+        join.await.unwrap().unwrap();
+
+        assert_eq!(stdout.content(), "abc".repeat(2048));
+    }
+
+    #[tokio::test]
+    async fn finished_processes_recycle_resource_tables() {
+        use crate::state::{DefaultProcessState, Resources};
+        use lunatic_process::env::LunaticEnvironment;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::sync::Arc;
+
+        // Each process creates 20 configs. Recycled tables must start with ID 0 again, like new
+        // ones.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "create_config" (func $create_config (result i64)))
+                (func (export "hello") (local $i i32)
+                    (if (i64.ne (call $create_config) (i64.const 0))
+                        (then unreachable))
+                    (loop $next
+                        (drop (call $create_config))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $next (i32.lt_u (local.get $i) (i32.const 19))))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(LunaticEnvironment::new(0).with_recycle_capacity(2));
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_create_configs(true);
+        let config = Arc::new(config);
+        for _ in 0..5 {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                config.clone(),
+                Default::default(),
+            )
+            .unwrap();
+            let (join, _) = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                "hello",
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            join.await.unwrap().unwrap();
+        }
+
+        // The spawn-exit loop passed one set of tables from process to process. They come back
+        // empty, but keep the capacity they grew to.
+        let recycle_pool = env.recycle_pool();
+        assert_eq!(recycle_pool.len(), 1);
+        let resources = recycle_pool.take::<Resources>().unwrap();
+        assert!(resources.configs.is_empty());
+        assert!(resources.configs.capacity() >= 20);
+
+        // The pool stays bounded even if more tables are returned
+        for _ in 0..3 {
+            recycle_pool.put(Resources::default());
+        }
+        assert_eq!(recycle_pool.len(), 2);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn cpu_time_grows_only_while_running() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::env::LunaticEnvironment;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process::Signal;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        // `busy` spins until it gets killed, `idle` sleeps and must have barely run at all.
+        // Sampling `busy` requires another worker thread, it never leaves the one it runs on.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "cpu_time_ns" (func $cpu_time_ns (result i64)))
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (func (export "busy")
+                    (loop $spin (br $spin)))
+                (func (export "idle")
+                    (call $sleep_ms (i64.const 100))
+                    (if (i64.ge_u (call $cpu_time_ns) (i64.const 10_000_000))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(LunaticEnvironment::new(0));
+        let mut processes = Vec::new();
+        for function in ["busy", "idle"] {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(crate::DefaultProcessConfig::default()),
+                Default::default(),
+            )
+            .unwrap();
+            let spawned = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                function,
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            processes.push(spawned);
+        }
+        let (busy_id, idle_id) = (processes[0].1.id(), processes[1].1.id());
+
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let first = env.cpu_time(busy_id).unwrap();
+        tokio::time::sleep(Duration::from_millis(20)).await;
+        let second = env.cpu_time(busy_id).unwrap();
+        assert!(second > first, "{:?} <= {:?}", second, first);
+        assert!(env.cpu_time(idle_id).unwrap() < Duration::from_millis(10));
+
+        processes[0].1.send(Signal::Kill);
+        let mut processes = processes.into_iter();
+        let (busy, _) = processes.next().unwrap();
+        assert!(busy.await.unwrap().is_err());
+        let (idle, _) = processes.next().unwrap();
+        idle.await.unwrap().unwrap();
+        assert_eq!(env.cpu_time(busy_id), None);
+    }
+
+    #[tokio::test]
+    async fn mailbox_poll_wakes_early_on_message() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::env::LunaticEnvironment;
+        use lunatic_process::message::{DataMessage, Message};
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process::Signal;
+        use std::sync::Arc;
+        use std::time::{Duration, Instant};
+
+        // Sets a 1s timer and waits on the mailbox. The first message must be the one sent below
+        // with tag 2, and not the timer's with tag 1.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::timer" "timer_set" (func $timer_set (param i64 i64) (result i64)))
+                (import "lunatic::message" "mailbox_poll" (func $mailbox_poll (param i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (func (export "hello")
+                    (drop (call $timer_set (i64.const 1000) (i64.const 1)))
+                    (if (call $mailbox_poll (i64.const -1))
+                        (then unreachable))
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 0))
+                        (then unreachable))
+                    (if (i64.ne (call $get_tag) (i64.const 2))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+
+        let start = Instant::now();
+        let (join, process) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+            .await
+            .unwrap();
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        process.send(Signal::Message(Message::Data(DataMessage::new_from_vec(
+            Some(2),
+            Vec::new(),
+        ))));
+        join.await.unwrap().unwrap();
+        assert!(start.elapsed() < Duration::from_millis(1000));
+    }
+
+    #[tokio::test]
+    async fn mailbox_len_counts_pending_messages() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::env::LunaticEnvironment;
+        use lunatic_process::message::{DataMessage, Message};
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process::Signal;
+        use std::sync::Arc;
+
+        // Sleeps, so that the messages sent below are moved into the mailbox, and expects a depth
+        // of 3 before receiving any of them and of 2 afterwards.
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "sleep_ms" (func $sleep_ms (param i64)))
+                (import "lunatic::message" "mailbox_len" (func $mailbox_len (result i64)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (func (export "hello")
+                    (call $sleep_ms (i64.const 100))
+                    (if (i64.ne (call $mailbox_len) (i64.const 3))
+                        (then unreachable))
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 0))
+                        (then unreachable))
+                    (if (i64.ne (call $mailbox_len) (i64.const 2))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+
+        let (join, process) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+            .await
+            .unwrap();
+        for tag in 1..=3 {
+            process.send(Signal::Message(Message::Data(DataMessage::new_from_vec(
+                Some(tag),
+                Vec::new(),
+            ))));
+        }
+        join.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn set_cwd_resolves_relative_paths() {
+        use lunatic_wasi_api::LunaticWasiCtx;
+
+        let dir = std::env::temp_dir().join(format!("lunatic-cwd-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::write(dir.join("sub").join("file.txt"), "hello").unwrap();
+
+        // The cwd preopen (fd 4) follows the single preopened dir (fd 3). `file.txt` can only be
+        // opened through it after changing into `sub`, and `..` can't leave the sandbox.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "path_open"
+                    (func $path_open (param i32 i32 i32 i32 i32 i64 i64 i32 i32) (result i32)))
+                (import "lunatic::wasi" "set_cwd" (func $set_cwd (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "file.txt")
+                (data (i32.const 16) "sub")
+                (data (i32.const 32) "../..")
+                (func $open (result i32)
+                    (call $path_open (i32.const 4) (i32.const 0) (i32.const 0) (i32.const 8)
+                        (i32.const 0) (i64.const 2) (i64.const 0) (i32.const 0) (i32.const 100)))
+                (func (export "hello")
+                    (if (i32.eqz (call $open))
+                        (then unreachable))
+                    (if (call $set_cwd (i32.const 16) (i32.const 3))
+                        (then unreachable))
+                    (if (call $open)
+                        (then unreachable))
+                    (if (i32.ne (call $set_cwd (i32.const 32) (i32.const 5)) (i32.const 2))
+                        (then unreachable))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.preopen_dir(dir.to_str().unwrap());
+        config.set_cwd(dir.to_str().unwrap());
+        let result = run_wat(wat, config).await;
+        std::fs::remove_dir_all(&dir).unwrap();
+        let state = result.unwrap();
+        assert_eq!(state.cwd(), Some(dir.join("sub").to_str().unwrap()));
+    }
+
+    // Spawns the `hello` function of the wat module with **params** and waits until it's
+    // blocked inside of an async host call. Returns the mailbox of the process too, so that it
+    // can be inspected after the process is killed.
+    #[cfg(test)]
+    async fn spawn_blocked(
+        wat: &str,
+        params: Vec<wasmtime::Val>,
+    ) -> (
+        tokio::task::JoinHandle<anyhow::Result<crate::state::DefaultProcessState>>,
+        std::sync::Arc<dyn lunatic_process::Process>,
+        lunatic_process::mailbox::MessageMailbox,
+    ) {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(wat).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(crate::DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+        let mailbox = state.message_mailbox().clone();
+        let (join, process) = spawn_wasm(env, runtime, &module, state, "hello", params, None)
+            .await
+            .unwrap();
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        (join, process, mailbox)
+    }
+
+    #[tokio::test]
+    async fn killed_receive_releases_mailbox_waker() {
+        use lunatic_process::message::Message;
+        use lunatic_process::Signal;
+
+        let wat = r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (i64.store (i32.const 0) (i64.const 1))
+                    (drop (call $receive (i32.const 0) (i32.const 1) (i64.const -1)))))
+        "#;
+        let (join, process, mailbox) = spawn_blocked(wat, Vec::new()).await;
+        process.send(Signal::Kill);
+        assert!(join.await.unwrap().is_err());
+        // Without a registered waker the message is queued instead of handed to the dead process
+        mailbox.push(Message::LinkDied(Some(1)));
+        assert_eq!(mailbox.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn killed_mailbox_poll_releases_mailbox_waker() {
+        use lunatic_process::message::Message;
+        use lunatic_process::Signal;
+
+        let wat = r#"
+            (module
+                (import "lunatic::message" "mailbox_poll" (func $mailbox_poll (param i64) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (drop (call $mailbox_poll (i64.const -1)))))
+        "#;
+        let (join, process, mailbox) = spawn_blocked(wat, Vec::new()).await;
+        process.send(Signal::Kill);
+        assert!(join.await.unwrap().is_err());
+        mailbox.push(Message::LinkDied(None));
+        assert_eq!(mailbox.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn killed_tcp_accept_releases_listener() {
+        use lunatic_process::Signal;
+
+        // Find a free port for the guest to bind to
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let wat = r#"
+            (module
+                (import "lunatic::networking" "tcp_bind"
+                    (func $tcp_bind (param i32 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::networking" "tcp_accept"
+                    (func $tcp_accept (param i64 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "\7f\00\00\01")
+                (func (export "hello") (param $port i32)
+                    (if (call $tcp_bind (i32.const 4) (i32.const 0) (local.get $port) (i32.const 0)
+                                        (i32.const 0) (i32.const 8))
+                        (then unreachable))
+                    (drop (call $tcp_accept (i64.load (i32.const 8)) (i32.const 16) (i32.const 24)))))
+        "#;
+        let (join, process, _) = spawn_blocked(wat, vec![wasmtime::Val::I32(port as i32)]).await;
+        // The port is taken while the guest is blocked in `tcp_accept`
+        assert!(std::net::TcpListener::bind(("127.0.0.1", port)).is_err());
+        process.send(Signal::Kill);
+        assert!(join.await.unwrap().is_err());
+        assert!(std::net::TcpListener::bind(("127.0.0.1", port)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn runtime_version_matches_crate_version() {
+        let version = env!("CARGO_PKG_VERSION");
+        // Compares the written version byte by byte with the expected one at offset 0
+        let wat = format!(
+            r#"
+            (module
+                (import "lunatic::version" "runtime_version"
+                    (func $runtime_version (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "{version}")
+                (func (export "hello")
+                    (local $i i32)
+                    (if (i32.ne (call $runtime_version (i32.const 100) (i32.const 64))
+                                (i32.const {len}))
+                        (then unreachable))
+                    (loop $compare
+                        (if (i32.ne (i32.load8_u (local.get $i))
+                                    (i32.load8_u (i32.add (local.get $i) (i32.const 100))))
+                            (then unreachable))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $compare (i32.lt_u (local.get $i) (i32.const {len}))))))
+            "#,
+            len = version.len()
+        );
+        run_wat(&wat, crate::DefaultProcessConfig::default())
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn import_module_shares_compiled_module_across_environments() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_process::env::LunaticEnvironment;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessCtx;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (memory (export "memory") 1)
+                (func (export "hello")))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let config = Arc::new(DefaultProcessConfig::default());
+        let new_state = |env: &Arc<LunaticEnvironment>| {
+            DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                config.clone(),
+                Default::default(),
+            )
+            .unwrap()
+        };
+
+        let env_a = Arc::new(LunaticEnvironment::new(1));
+        let env_b = Arc::new(LunaticEnvironment::new(2));
+        let mut state_a = new_state(&env_a);
+        let mut state_b = new_state(&env_b);
+        let module_id = state_a.module_resources_mut().add(module.clone());
+
+        assert_eq!(state_b.import_module(&state_a, module_id + 1), None);
+        let imported_id = state_b.import_module(&state_a, module_id).unwrap();
+        let imported = state_b.module_resources().get(imported_id).unwrap().clone();
+        assert!(Arc::ptr_eq(&imported, &module));
+
+        let (join, _) = spawn_wasm(
+            env_b.clone(),
+            runtime.clone(),
+            &imported,
+            new_state(&env_b),
+            "hello",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+        join.await.unwrap().unwrap();
+    }
+
+    // Spawns a process that seeds its generator with **seed** and writes 16 random bytes to
+    // stdout, mapped to printable characters. Returns the captured output.
+    #[cfg(test)]
+    async fn seeded_random_output(seed: i64) -> String {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_stdout_capture::StdoutCapture;
+        use lunatic_wasi_api::LunaticWasiCtx;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "random_get"
+                    (func $random_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (import "lunatic::wasi" "seed_random" (func $seed_random (param i64)))
+                (memory (export "memory") 1)
+                (func (export "hello") (param $seed i64)
+                    (local $i i32)
+                    (call $seed_random (local.get $seed))
+                    (if (call $random_get (i32.const 0) (i32.const 16))
+                        (then unreachable))
+                    (loop $map
+                        (i32.store8 (local.get $i)
+                            (i32.add (i32.and (i32.load8_u (local.get $i)) (i32.const 0x3f))
+                                     (i32.const 0x30)))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $map (i32.lt_u (local.get $i) (i32.const 16))))
+                    ;; iovec pointing to the 16 bytes
+                    (i32.store (i32.const 32) (i32.const 0))
+                    (i32.store (i32.const 36) (i32.const 16))
+                    (if (call $fd_write (i32.const 1) (i32.const 32) (i32.const 1) (i32.const 48))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(crate::DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+        let stdout = StdoutCapture::new(false);
+        state.set_stdout(stdout.clone());
+        let params = vec![wasmtime::Val::I64(seed)];
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", params, None)
+            .await
+            .unwrap();
+        join.await.unwrap().unwrap();
+        stdout.content()
+    }
+
+    #[tokio::test]
+    async fn seed_random_makes_random_get_deterministic() {
+        let first = seeded_random_output(42).await;
+        assert_eq!(first.len(), 16);
+        assert_eq!(first, seeded_random_output(42).await);
+        assert_ne!(first, seeded_random_output(7).await);
+    }
+
+    // Spawns three children on the deterministic scheduler with **seed**. Each child sends five
+    // messages tagged with its number to the parent, spinning between them. The parent writes the
+    // tags in the order they arrive to stdout, which is returned.
+    #[cfg(test)]
+    async fn deterministic_interleaving(seed: u64) -> String {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::config::ProcessConfig;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessConfigCtx;
+        use lunatic_stdout_capture::StdoutCapture;
+        use lunatic_wasi_api::LunaticWasiCtx;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::process" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "send" (func $send (param i64) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "child")
+                ;; Two i64 params: the parent ID and the tag
+                (data (i32.const 200) "\7e")
+                (data (i32.const 217) "\7e")
+                (func (export "child") (param $parent i64) (param $tag i64)
+                    (local $round i32)
+                    (local $spin i32)
+                    (loop $rounds
+                        (local.set $spin (i32.const 0))
+                        (loop $spinning
+                            (local.set $spin (i32.add (local.get $spin) (i32.const 1)))
+                            (br_if $spinning (i32.lt_u (local.get $spin)
+                                                       (i32.mul (i32.const 50000)
+                                                                (i32.add (local.get $round)
+                                                                         (i32.const 1))))))
+                        (call $create_data (local.get $tag) (i64.const 0))
+                        (drop (call $send (local.get $parent)))
+                        (local.set $round (i32.add (local.get $round) (i32.const 1)))
+                        (br_if $rounds (i32.lt_u (local.get $round) (i32.const 5)))))
+                (func (export "hello")
+                    (local $i i32)
+                    (i64.store (i32.const 201) (call $process_id))
+                    (loop $spawning
+                        (i64.store (i32.const 218)
+                            (i64.extend_i32_u (i32.add (local.get $i) (i32.const 1))))
+                        (if (call $spawn (i64.const 0) (i64.const -1) (i64.const -1)
+                                (i32.const 0) (i32.const 5) (i32.const 200) (i32.const 34)
+                                (i32.const 16))
+                            (then unreachable))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $spawning (i32.lt_u (local.get $i) (i32.const 3))))
+                    (local.set $i (i32.const 0))
+                    (loop $receiving
+                        (if (call $receive (i32.const 0) (i32.const 0) (i64.const -1))
+                            (then unreachable))
+                        (i32.store8 (i32.add (i32.const 100) (local.get $i))
+                            (i32.add (i32.wrap_i64 (call $get_tag)) (i32.const 0x30)))
+                        (local.set $i (i32.add (local.get $i) (i32.const 1)))
+                        (br_if $receiving (i32.lt_u (local.get $i) (i32.const 15))))
+                    ;; iovec pointing to the 15 tags
+                    (i32.store (i32.const 32) (i32.const 100))
+                    (i32.store (i32.const 36) (i32.const 15))
+                    (if (call $fd_write (i32.const 1) (i32.const 32) (i32.const 1) (i32.const 48))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+        config.set_deterministic_seed(Some(seed));
+        let mut state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            Default::default(),
+        )
+        .unwrap();
+        let stdout = StdoutCapture::new(false);
+        state.set_stdout(stdout.clone());
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+            .await
+            .unwrap();
+        join.await.unwrap().unwrap();
+        stdout.content()
+    }
+
+    #[tokio::test]
+    async fn deterministic_scheduler_reproduces_interleaving() {
+        let first = deterministic_interleaving(42).await;
+        assert_eq!(first.len(), 15);
+        assert_eq!(first, deterministic_interleaving(42).await);
+    }
+
+    #[tokio::test]
+    async fn args_and_environ_get_reject_bad_pointers() {
+        // WASI pointers are checked by wasmtime-wasi. A pointer list that doesn't fit at the end
+        // of memory returns an errno instead of trapping, a misaligned one must not trap either.
+        let wat = r#"
+            (module
+                (import "wasi_snapshot_preview1" "args_get"
+                    (func $args_get (param i32 i32) (result i32)))
+                (import "wasi_snapshot_preview1" "environ_get"
+                    (func $environ_get (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    ;; 2 pointers don't fit into the last 4 bytes of memory
+                    (if (i32.eqz (call $args_get (i32.const 65532) (i32.const 0)))
+                        (then unreachable))
+                    (if (i32.eqz (call $environ_get (i32.const 65532) (i32.const 0)))
+                        (then unreachable))
+                    ;; The string buffer starts at the very last byte
+                    (if (i32.eqz (call $args_get (i32.const 0) (i32.const 65535)))
+                        (then unreachable))
+                    (if (i32.eqz (call $environ_get (i32.const 0) (i32.const 65535)))
+                        (then unreachable))
+                    ;; Misaligned pointer lists
+                    (drop (call $args_get (i32.const 1) (i32.const 100)))
+                    (drop (call $environ_get (i32.const 3) (i32.const 100)))))
+        "#;
+        let mut config = crate::DefaultProcessConfig::default();
+        config.set_command_line_arguments(vec!["main.wasm".to_string(), "arg".to_string()]);
+        config.set_environment_variables(vec![
+            ("A".to_string(), "1".to_string()),
+            ("B".to_string(), "2".to_string()),
+        ]);
+        run_wat(wat, config).await.unwrap();
+    }
+
+    // Returns a local address with a currently unused UDP port.
+    #[cfg(test)]
+    fn free_udp_addr() -> std::net::SocketAddr {
+        std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+    }
+
+    // Registers a node with the control server at **control_addr** and starts its node server.
+    // Returns the context shared by the node server, to inspect the node's modules and processes.
+    #[cfg(test)]
+    async fn start_node(
+        name: &str,
+        control_addr: std::net::SocketAddr,
+        runtime: lunatic_process::runtimes::wasmtime::WasmtimeRuntime,
+        admission: std::sync::Arc<dyn lunatic_distributed::distributed::admission::AdmissionPolicy>,
+    ) -> lunatic_distributed::distributed::server::ServerCtx<
+        crate::state::DefaultProcessState,
+        lunatic_process::env::LunaticEnvironment,
+    > {
+        use crate::state::DefaultProcessState;
+        use lunatic_distributed::distributed::server::{self, ServerCtx};
+        use lunatic_distributed::{
+            control, distributed, quic, DistributedProcessState, NodeCapabilities,
+        };
+        use lunatic_process::env::LunaticEnvironments;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let node_addr = free_udp_addr();
+        let ca_cert = server::root_cert(true, None).unwrap();
+        let node_cert = server::gen_node_cert(name).unwrap();
+        let quic_client = quic::new_quic_client(&ca_cert).unwrap();
+        let capabilities = NodeCapabilities::new::<DefaultProcessState>(&runtime).unwrap();
+        let (node_id, control_client, signed_cert) = control::Client::register(
+            node_addr,
+            name.to_string(),
+            Default::default(),
+            capabilities,
+            control_addr,
+            quic_client.clone(),
+            node_cert.serialize_request_pem().unwrap(),
+            Duration::from_secs(5),
+        )
+        .await
+        .unwrap();
+        let node_client = distributed::Client::new(node_id, control_client.clone(), quic_client)
+            .await
+            .unwrap();
+        let dist = DistributedProcessState::new(node_id, control_client, node_client)
+            .await
+            .unwrap();
+
+        let ctx = ServerCtx {
+            envs: Arc::new(LunaticEnvironments::default()),
+            modules: Default::default(),
+            distributed: dist,
+            runtime,
+            registry: Default::default(),
+            admission,
+        };
+        tokio::task::spawn(server::node_server(
+            ctx.clone(),
+            node_addr,
+            signed_cert,
+            node_cert.serialize_private_key_pem(),
+        ));
+        ctx
+    }
+
+    #[tokio::test]
+    async fn remote_spawn_fetches_module_from_holder_node() {
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_distributed::distributed::message::Spawn;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::runtimes::RawWasm;
+        use std::sync::Arc;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let (dist_a, dist_b) = (&node_a.distributed, &node_b.distributed);
+
+        // Only node A holds the module, the control server just knows its hash
+        let bytes = wat::parse_str(r#"(module (func (export "hello")))"#).unwrap();
+        let hash = lunatic_distributed::module_hash(&bytes);
+        let module_id = dist_a
+            .control
+            .add_module_hash(dist_a.node_id(), hash.clone())
+            .await
+            .unwrap();
+        node_a
+            .modules
+            .compile(runtime, RawWasm::new(Some(module_id), bytes))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(dist_a.control.get_module(module_id).await.is_none());
+        assert!(node_b.modules.get(module_id).is_none());
+
+        let spawn = Spawn {
+            environment_id: 1,
+            module_id,
+            function: "hello".to_string(),
+            params: Vec::new(),
+            config: bincode::serialize(&DefaultProcessConfig::default()).unwrap(),
+            initial_message: None,
+        };
+        dist_a
+            .node_client
+            .spawn(dist_b.node_id(), spawn)
+            .await
+            .unwrap();
+
+        // Node B fetched the module from node A and is now a holder too
+        assert!(node_b.modules.get(module_id).is_some());
+        let holders = dist_b.control.lookup_module(module_id).await.unwrap();
+        assert_eq!(holders.hash, hash);
+        assert_eq!(holders.nodes, vec![dist_a.node_id(), dist_b.node_id()]);
+    }
+
+    #[tokio::test]
+    async fn admission_policy_rejects_unlisted_module() {
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::{
+            AdmissionPolicy, AllowAll, SpawnRequest,
+        };
+        use lunatic_distributed::distributed::message::{ClientError, Spawn};
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::runtimes::RawWasm;
+        use std::sync::Arc;
+
+        // Only admits modules with an allowlisted content hash
+        struct Allowlist(Vec<String>);
+
+        impl AdmissionPolicy for Allowlist {
+            fn admit(&self, request: &SpawnRequest) -> Result<(), String> {
+                let hash = request.module_hash();
+                if self.0.contains(&hash) {
+                    Ok(())
+                } else {
+                    Err(format!("module {hash} is not allowlisted"))
+                }
+            }
+        }
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let allowlist = Arc::new(Allowlist(vec![lunatic_distributed::module_hash(b"")]));
+        let node_b = start_node("node-b", control_addr, runtime.clone(), allowlist).await;
+        let (dist_a, dist_b) = (&node_a.distributed, &node_b.distributed);
+
+        let bytes = wat::parse_str(r#"(module (func (export "hello")))"#).unwrap();
+        let hash = lunatic_distributed::module_hash(&bytes);
+        let module_id = dist_a
+            .control
+            .add_module_hash(dist_a.node_id(), hash.clone())
+            .await
+            .unwrap();
+        node_a
+            .modules
+            .compile(runtime, RawWasm::new(Some(module_id), bytes))
+            .await
+            .unwrap()
+            .unwrap();
+
+        let spawn = Spawn {
+            environment_id: 1,
+            module_id,
+            function: "hello".to_string(),
+            params: Vec::new(),
+            config: bincode::serialize(&DefaultProcessConfig::default()).unwrap(),
+            initial_message: None,
+        };
+        let result = dist_a.node_client.spawn(dist_b.node_id(), spawn).await;
+        match result {
+            Err(ClientError::SpawnRejected(reason)) => assert!(reason.contains(&hash)),
+            other => panic!("Expected the spawn to be rejected, got {:?}", other),
+        }
+        // The rejected module was not compiled
+        assert!(node_b.modules.get(module_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn captured_output_survives_trap() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_stdout_capture::StdoutCapture;
+        use lunatic_wasi_api::LunaticWasiCtx;
+        use std::sync::Arc;
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "wasi_snapshot_preview1" "fd_write"
+                    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "no newline")
+                (func (export "hello")
+                    ;; iovec pointing to the 10 bytes
+                    (i32.store (i32.const 16) (i32.const 0))
+                    (i32.store (i32.const 20) (i32.const 10))
+                    (drop (call $fd_write (i32.const 1) (i32.const 16) (i32.const 1) (i32.const 24)))
+                    (drop (call $fd_write (i32.const 2) (i32.const 16) (i32.const 1) (i32.const 24)))
+                    unreachable))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut state = DefaultProcessState::new(
+            env.clone(),
+            None,
+            runtime.clone(),
+            module.clone(),
+            Arc::new(crate::DefaultProcessConfig::default()),
+            Default::default(),
+        )
+        .unwrap();
+        let stdout = StdoutCapture::new(true);
+        state.set_stdout(stdout.clone());
+        let stderr = stdout.next();
+        state.set_stderr(stderr.clone());
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+            .await
+            .unwrap();
+        assert!(join.await.unwrap().is_err());
+
+        assert_eq!(stdout.content(), "no newline");
+        assert_eq!(stderr.content(), "no newline");
+    }
+
+    #[tokio::test]
+    async fn cluster_broadcast_reaches_named_process_on_every_node() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        // Wait until node A knows about node B
+        while node_a.distributed.control.node_count() < 2 {
+            node_a.distributed.control.refresh_nodes().await.unwrap();
+        }
+
+        // `listen` waits for a message tagged 42 and `broadcast` sends it to the "cache" process
+        // on all nodes, expecting both nodes to deliver it.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::distributed" "cluster_broadcast"
+                    (func $cluster_broadcast (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "cache")
+                (func (export "listen")
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                        (then unreachable))
+                    (if (i64.ne (call $get_tag) (i64.const 42))
+                        (then unreachable)))
+                (func (export "broadcast")
+                    (call $create_data (i64.const 42) (i64.const 0))
+                    (if (i32.ne (call $cluster_broadcast (i32.const 0) (i32.const 5)) (i32.const 2))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+
+        let mut processes = Vec::new();
+        for (node, function) in [
+            (&node_a, "listen"),
+            (&node_b, "listen"),
+            (&node_a, "broadcast"),
+        ] {
+            let env = node.envs.get(1).unwrap_or_else(|| node.envs.create(1));
+            let state = DefaultProcessState::new(
+                env.clone(),
+                Some(node.distributed.clone()),
+                runtime.clone(),
+                module.clone(),
+                Arc::new(DefaultProcessConfig::default()),
+                node.registry.clone(),
+            )
+            .unwrap();
+            if function == "listen" {
+                let entry = (node.distributed.node_id(), state.id());
+                node.registry.insert("cache".to_string(), entry);
+            }
+            let (join, _) = spawn_wasm(
+                env,
+                runtime.clone(),
+                &module,
+                state,
+                function,
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            processes.push(join);
+        }
+        for process in processes {
+            process.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn node_capabilities_reports_remote_features() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmFeatures, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let no_simd = WasmtimeRuntime::with_features(WasmFeatures {
+            simd: false,
+            ..Default::default()
+        })
+        .unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, no_simd, Arc::new(AllowAll)).await;
+        // Wait until node A knows about node B
+        while node_a.distributed.control.node_count() < 2 {
+            node_a.distributed.control.refresh_nodes().await.unwrap();
+        }
+
+        // Node B only has bulk memory (bit 1) and reference types (bit 2) enabled. Unknown nodes
+        // return u64::MAX and nothing is written if the buffer is too small.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::distributed" "node_capabilities"
+                    (func $node_capabilities (param i64 i32 i32) (result i64)))
+                (memory (export "memory") 1)
+                (func (export "hello") (param $node_b i64) (local $len i64)
+                    (local.set $len (call $node_capabilities (local.get $node_b) (i32.const 0) (i32.const 4096)))
+                    (if (i64.gt_u (local.get $len) (i64.const 4096))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 0)) (i32.const 6))
+                        (then unreachable))
+                    (i32.store (i32.const 0) (i32.const 0))
+                    (if (i64.ne (call $node_capabilities (local.get $node_b) (i32.const 0) (i32.const 1))
+                            (local.get $len))
+                        (then unreachable))
+                    (if (i32.load (i32.const 0))
+                        (then unreachable))
+                    (if (i64.ne (call $node_capabilities (i64.const 999) (i32.const 0) (i32.const 4096))
+                            (i64.const -1))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = node_a.envs.create(1);
+        let state = DefaultProcessState::new(
+            env.clone(),
+            Some(node_a.distributed.clone()),
+            runtime.clone(),
+            module.clone(),
+            Arc::new(DefaultProcessConfig::default()),
+            node_a.registry.clone(),
+        )
+        .unwrap();
+        let params = vec![Val::I64(node_b.distributed.node_id() as i64)];
+        let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", params, None)
+            .await
+            .unwrap();
+        join.await.unwrap().unwrap();
+
+        let capabilities = node_a
+            .distributed
+            .node_capabilities(node_b.distributed.node_id())
+            .unwrap();
+        assert_eq!(capabilities.version, lunatic_distributed::VERSION);
+        assert!(!capabilities.simd && capabilities.bulk_memory && capabilities.reference_types);
+        assert!(capabilities
+            .host_apis
+            .iter()
+            .any(|namespace| namespace == "lunatic::distributed"));
+    }
+
+    #[tokio::test]
+    async fn remote_spawn_returns_cluster_wide_process_id() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::runtimes::RawWasm;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        while node_a.distributed.control.node_count() < 2 {
+            node_a.distributed.control.refresh_nodes().await.unwrap();
+        }
+
+        // `main` spawns `listen` on the other node and sends it a message, using the node it
+        // spawned on and the returned process id. The spawn only writes 8 bytes to `id_ptr`.
+        let bytes = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::distributed" "module_id" (func $module_id (result i64)))
+                (import "lunatic::distributed" "spawn"
+                    (func $spawn (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::distributed" "send" (func $send (param i64 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "listen")
+                (func (export "listen")
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                        (then unreachable)))
+                (func (export "main") (param $node i64)
+                    (i64.store (i32.const 24) (i64.const -1))
+                    (if (call $spawn (local.get $node) (i64.const -1) (call $module_id)
+                                     (i32.const 0) (i32.const 6) (i32.const 0) (i32.const 0)
+                                     (i32.const 16))
+                        (then unreachable))
+                    (if (i64.ne (i64.load (i32.const 24)) (i64.const -1))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (if (call $send (local.get $node) (i64.load (i32.const 16)))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let hash = lunatic_distributed::module_hash(&bytes);
+        let dist_a = &node_a.distributed;
+        let module_id = dist_a
+            .control
+            .add_module_hash(dist_a.node_id(), hash)
+            .await
+            .unwrap();
+        let module = node_a
+            .modules
+            .compile(runtime.clone(), RawWasm::new(Some(module_id), bytes))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut config = DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+
+        let env = node_a.envs.create(1);
+        let state = DefaultProcessState::new(
+            env.clone(),
+            Some(dist_a.clone()),
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            node_a.registry.clone(),
+        )
+        .unwrap();
+        let params = vec![Val::I64(node_b.distributed.node_id() as i64)];
+        let (main, _) = spawn_wasm(env, runtime, &module, state, "main", params, None)
+            .await
+            .unwrap();
+        main.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_child_receives_initial_message_first() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::runtimes::RawWasm;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        while node_a.distributed.control.node_count() < 2 {
+            node_a.distributed.control.refresh_nodes().await.unwrap();
+        }
+
+        // `main` passes its own cluster-wide id as initial message to `listen` on the other node.
+        // `listen` expects the message without waiting and replies to the id it contains.
+        let bytes = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "data_size" (func $data_size (result i64)))
+                (import "lunatic::message" "get_tag" (func $get_tag (result i64)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::process" "process_id" (func $process_id (result i64)))
+                (import "lunatic::distributed" "node_id" (func $node_id (result i64)))
+                (import "lunatic::distributed" "module_id" (func $module_id (result i64)))
+                (import "lunatic::distributed" "spawn_with_message"
+                    (func $spawn_with_message (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+                (import "lunatic::distributed" "send" (func $send (param i64 i64) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "listen")
+                (func (export "listen")
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 0))
+                        (then unreachable))
+                    (if (i64.ne (call $get_tag) (i64.const 42))
+                        (then unreachable))
+                    (if (i64.ne (call $data_size) (i64.const 16))
+                        (then unreachable))
+                    (drop (call $read_data (i32.const 64) (i32.const 16)))
+                    (call $create_data (i64.const 43) (i64.const 0))
+                    (if (call $send (i64.load (i32.const 64)) (i64.load (i32.const 72)))
+                        (then unreachable)))
+                (func (export "main") (param $node i64)
+                    (i64.store (i32.const 32) (call $node_id))
+                    (i64.store (i32.const 40) (call $process_id))
+                    (call $create_data (i64.const 42) (i64.const 16))
+                    (drop (call $write_data (i32.const 32) (i32.const 16)))
+                    (if (call $spawn_with_message (local.get $node) (i64.const -1) (call $module_id)
+                            (i32.const 0) (i32.const 6) (i32.const 0) (i32.const 0) (i32.const 16))
+                        (then unreachable))
+                    ;; Wait for the reply tagged 43
+                    (i64.store (i32.const 48) (i64.const 43))
+                    (if (call $receive (i32.const 48) (i32.const 1) (i64.const 5000))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let hash = lunatic_distributed::module_hash(&bytes);
+        let dist_a = &node_a.distributed;
+        let module_id = dist_a
+            .control
+            .add_module_hash(dist_a.node_id(), hash)
+            .await
+            .unwrap();
+        let module = node_a
+            .modules
+            .compile(runtime.clone(), RawWasm::new(Some(module_id), bytes))
+            .await
+            .unwrap()
+            .unwrap();
+        let mut config = DefaultProcessConfig::default();
+        config.set_can_spawn_processes(true);
+
+        let env = node_a.envs.create(1);
+        let state = DefaultProcessState::new(
+            env.clone(),
+            Some(dist_a.clone()),
+            runtime.clone(),
+            module.clone(),
+            Arc::new(config),
+            node_a.registry.clone(),
+        )
+        .unwrap();
+        let params = vec![Val::I64(node_b.distributed.node_id() as i64)];
+        let (main, _) = spawn_wasm(env, runtime, &module, state, "main", params, None)
+            .await
+            .unwrap();
+        main.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn node_connections_are_pooled() {
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::{self, admission::AllowAll, client::ConnectionPool};
+        use lunatic_distributed::quic;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let dist_a = &node_a.distributed;
+        while dist_a.control.node_count() < 2 {
+            dist_a.control.refresh_nodes().await.unwrap();
+        }
+
+        let pool = ConnectionPool {
+            max_connections_per_node: 2,
+            idle_timeout: Some(Duration::from_millis(200)),
+        };
+        let quic_client =
+            quic::new_quic_client(&distributed::server::root_cert(true, None).unwrap()).unwrap();
+        let client = distributed::Client::with_pool(
+            dist_a.node_id(),
+            dist_a.control.clone(),
+            quic_client,
+            pool,
+        )
+        .await
+        .unwrap();
+        let node_b_id = node_b.distributed.node_id();
+
+        // Messages to a missing environment are dropped by the receiving node
+        let sends: Vec<_> = (0..100)
+            .map(|_| {
+                let client = client.clone();
+                tokio::task::spawn(async move {
+                    client
+                        .message_process(node_b_id, 1, 1, None, vec![0; 1024])
+                        .await
+                })
+            })
+            .collect();
+        for send in sends {
+            send.await.unwrap().unwrap();
+        }
+        let open = client.open_connections(node_b_id);
+        assert!((1..=2).contains(&open), "{} connections open", open);
+
+        // Idle connections are closed and opened again on the next send
+        tokio::time::sleep(Duration::from_millis(500)).await;
+        assert_eq!(client.open_connections(node_b_id), 0);
+        client
+            .message_process(node_b_id, 1, 1, None, vec![0; 1024])
+            .await
+            .unwrap();
+        assert_eq!(client.open_connections(node_b_id), 1);
+    }
+
+    #[tokio::test]
+    async fn is_distributed_depends_on_cluster() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_process::env::LunaticEnvironments;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+
+        let bytes = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::distributed" "is_distributed" (func $is_distributed (result i32)))
+                (func (export "main") (param $expected i32)
+                    (if (i32.ne (call $is_distributed) (local.get $expected))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(bytes.into()).unwrap());
+        let standalone_envs = LunaticEnvironments::default();
+        for (dist, envs, expected) in [
+            (Some(node.distributed.clone()), &*node.envs, 1),
+            (None, &standalone_envs, 0),
+        ] {
+            let env = envs.create(1);
+            let state = DefaultProcessState::new(
+                env.clone(),
+                dist,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(DefaultProcessConfig::default()),
+                Default::default(),
+            )
+            .unwrap();
+            let params = vec![Val::I32(expected)];
+            let (main, _) = spawn_wasm(env, runtime.clone(), &module, state, "main", params, None)
+                .await
+                .unwrap();
+            main.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn distributed_messages_over_max_size_are_rejected() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process_api::ProcessConfigCtx;
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        while node_a.distributed.control.node_count() < 2 {
+            node_a.distributed.control.refresh_nodes().await.unwrap();
+        }
+
+        // `send` sends a 16 byte message to `listen` on the other node, a 17 byte message is
+        // rejected before it's transmitted, by `send` as well as by `cluster_broadcast`.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "write_data" (func $write_data (param i32 i32) (result i32)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "data_size" (func $data_size (result i64)))
+                (import "lunatic::distributed" "send" (func $send (param i64 i64) (result i32)))
+                (import "lunatic::distributed" "cluster_broadcast"
+                    (func $cluster_broadcast (param i32 i32) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "listen")
+                    (if (call $receive (i32.const 0) (i32.const 0) (i64.const 5000))
+                        (then unreachable))
+                    (if (i64.ne (call $data_size) (i64.const 16))
+                        (then unreachable))
+                    (if (i32.ne (call $receive (i32.const 0) (i32.const 0) (i64.const 100))
+                                (i32.const 9027))
+                        (then unreachable)))
+                (func (export "send") (param $node i64) (param $process i64)
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 0) (i32.const 16)))
+                    (if (call $send (local.get $node) (local.get $process))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 0) (i32.const 17)))
+                    (if (i32.ne (call $send (local.get $node) (local.get $process)) (i32.const 9028))
+                        (then unreachable))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (drop (call $write_data (i32.const 0) (i32.const 17)))
+                    (if (i32.ne (call $cluster_broadcast (i32.const 0) (i32.const 0)) (i32.const -1))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let mut config = DefaultProcessConfig::default();
+        config.set_max_message_size(Some(16));
+        let config = Arc::new(config);
+
+        let env = node_b.envs.create(1);
+        let listener = DefaultProcessState::new(
+            env.clone(),
+            Some(node_b.distributed.clone()),
+            runtime.clone(),
+            module.clone(),
+            config.clone(),
+            node_b.registry.clone(),
+        )
+        .unwrap();
+        let listener_id = listener.id();
+        let (listen, _) = spawn_wasm(
+            env,
+            runtime.clone(),
+            &module,
+            listener,
+            "listen",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let env = node_a.envs.create(1);
+        let sender = DefaultProcessState::new(
+            env.clone(),
+            Some(node_a.distributed.clone()),
+            runtime.clone(),
+            module.clone(),
+            config,
+            node_a.registry.clone(),
+        )
+        .unwrap();
+        let params = vec![
+            Val::I64(node_b.distributed.node_id() as i64),
+            Val::I64(listener_id as i64),
+        ];
+        let (send, _) = spawn_wasm(env, runtime, &module, sender, "send", params, None)
+            .await
+            .unwrap();
+        send.await.unwrap().unwrap();
+        listen.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn barrier_releases_processes_across_nodes_together() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_distributed::distributed::server::ServerCtx;
+        use lunatic_process::env::LunaticEnvironment;
+        use lunatic_process::runtimes::wasmtime::{
+            default_config, WasmtimeCompiledModule, WasmtimeRuntime,
+        };
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+        use std::time::Duration;
+        use tokio::task::JoinHandle;
+
+        async fn spawn_on(
+            node: &ServerCtx<DefaultProcessState, LunaticEnvironment>,
+            module: &Arc<WasmtimeCompiledModule<DefaultProcessState>>,
+            function: &str,
+        ) -> JoinHandle<anyhow::Result<DefaultProcessState>> {
+            let env = node.envs.create(1);
+            let state = DefaultProcessState::new(
+                env.clone(),
+                Some(node.distributed.clone()),
+                node.runtime.clone(),
+                module.clone(),
+                Arc::new(DefaultProcessConfig::default()),
+                node.registry.clone(),
+            )
+            .unwrap();
+            let runtime = node.runtime.clone();
+            let (join, _) = spawn_wasm(env, runtime, module, state, function, Vec::new(), None)
+                .await
+                .unwrap();
+            join
+        }
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+
+        // `wait` joins a barrier of three processes, `give_up` waits on a barrier nobody else
+        // joins and times out.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::distributed" "barrier_wait"
+                    (func $barrier_wait (param i32 i32 i64 i64 i32) (result i32)))
+                (memory (export "memory") 1)
+                (data (i32.const 0) "sync")
+                (data (i32.const 8) "lonely")
+                (func (export "wait")
+                    (if (call $barrier_wait (i32.const 0) (i32.const 4) (i64.const 3)
+                                            (i64.const 5000) (i32.const 16))
+                        (then unreachable)))
+                (func (export "give_up")
+                    (if (i32.ne (call $barrier_wait (i32.const 8) (i32.const 6) (i64.const 2)
+                                                    (i64.const 50) (i32.const 16))
+                                (i32.const 9027))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+
+        let first = spawn_on(&node_a, &module, "wait").await;
+        let second = spawn_on(&node_a, &module, "wait").await;
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(!first.is_finished());
+        assert!(!second.is_finished());
+
+        let third = spawn_on(&node_b, &module, "wait").await;
+        first.await.unwrap().unwrap();
+        second.await.unwrap().unwrap();
+        third.await.unwrap().unwrap();
+
+        let give_up = spawn_on(&node_b, &module, "give_up").await;
+        give_up.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn remote_monitor_receives_trap_reason() {
+        use crate::state::DefaultProcessState;
+        use crate::DefaultProcessConfig;
+        use lunatic_distributed::control::server::{control_server, root_cert};
+        use lunatic_distributed::distributed::admission::AllowAll;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::state::ProcessState;
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+        use wasmtime::Val;
+
+        let control_addr = free_udp_addr();
+        let ca_cert = root_cert(true, None, None).unwrap();
+        tokio::task::spawn(control_server(control_addr, ca_cert));
+
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let node_a = start_node("node-a", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        let node_b = start_node("node-b", control_addr, runtime.clone(), Arc::new(AllowAll)).await;
+        while node_a.distributed.control.node_count() < 2 {
+            node_a.distributed.control.refresh_nodes().await.unwrap();
+        }
+
+        // `watch` monitors `target` on the other node and then sends it a message, which makes
+        // it trap. The DOWN message must carry the trap reason.
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "create_data" (func $create_data (param i64 i64)))
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (import "lunatic::message" "read_data" (func $read_data (param i32 i32) (result i32)))
+                (import "lunatic::distributed" "send" (func $send (param i64 i64) (result i32)))
+                (import "lunatic::distributed" "monitor" (func $monitor (param i64 i64)))
+                (memory (export "memory") 1)
+                ;; DOWN_TAG
+                (data (i32.const 100) "\01\00\00\00\00\00\00\80")
+                (func (export "target")
+                    (drop (call $receive (i32.const 0) (i32.const 0) (i64.const 5000)))
+                    unreachable)
+                (func (export "watch") (param $node i64) (param $process i64)
+                    (call $monitor (local.get $node) (local.get $process))
+                    (call $create_data (i64.const 0) (i64.const 0))
+                    (if (call $send (local.get $node) (local.get $process))
+                        (then unreachable))
+                    (if (call $receive (i32.const 100) (i32.const 1) (i64.const 5000))
+                        (then unreachable))
+                    (drop (call $read_data (i32.const 0) (i32.const 16)))
+                    (if (i64.ne (i64.load (i32.const 0)) (local.get $process))
+                        (then unreachable))
+                    ;; Code 1 is a trap and detail 1 an `unreachable` instruction
+                    (if (i32.ne (i32.load (i32.const 8)) (i32.const 1))
+                        (then unreachable))
+                    (if (i32.ne (i32.load (i32.const 12)) (i32.const 1))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let config = Arc::new(DefaultProcessConfig::default());
+
+        let env = node_b.envs.create(1);
+        let target = DefaultProcessState::new(
+            env.clone(),
+            Some(node_b.distributed.clone()),
+            runtime.clone(),
+            module.clone(),
+            config.clone(),
+            node_b.registry.clone(),
+        )
+        .unwrap();
+        let target_id = target.id();
+        spawn_wasm(
+            env,
+            runtime.clone(),
+            &module,
+            target,
+            "target",
+            Vec::new(),
+            None,
+        )
+        .await
+        .unwrap();
+
+        let env = node_a.envs.create(1);
+        let watcher = DefaultProcessState::new(
+            env.clone(),
+            Some(node_a.distributed.clone()),
+            runtime.clone(),
+            module.clone(),
+            config,
+            node_a.registry.clone(),
+        )
+        .unwrap();
+        let params = vec![
+            Val::I64(node_b.distributed.node_id() as i64),
+            Val::I64(target_id as i64),
+        ];
+        let (watch, _) = spawn_wasm(env, runtime, &module, watcher, "watch", params, None)
+            .await
+            .unwrap();
+        watch.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    async fn modules_run_with_every_opt_level() {
+        use crate::state::DefaultProcessState;
+        use lunatic_process::runtimes::wasmtime::{OptLevel, WasmFeatures, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use std::sync::Arc;
+
+        // Computes 10! in a loop
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (func (export "hello") (local $i i64) (local $result i64)
+                    (local.set $i (i64.const 10))
+                    (local.set $result (i64.const 1))
+                    (loop $next
+                        (local.set $result (i64.mul (local.get $result) (local.get $i)))
+                        (local.set $i (i64.sub (local.get $i) (i64.const 1)))
+                        (br_if $next (i64.gt_u (local.get $i) (i64.const 1))))
+                    (if (i64.ne (local.get $result) (i64.const 3628800))
+                        (then unreachable))))
+            "#,
+        )
+        .unwrap();
+        for opt_level in [OptLevel::None, OptLevel::Speed, OptLevel::SpeedAndSize] {
+            let runtime =
+                WasmtimeRuntime::with_opt_level(WasmFeatures::default(), opt_level).unwrap();
+            let module = Arc::new(runtime.compile_module(raw_module.clone().into()).unwrap());
+            let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(crate::DefaultProcessConfig::default()),
+                Default::default(),
+            )
+            .unwrap();
+            let (join, _) = spawn_wasm(env, runtime, &module, state, "hello", Vec::new(), None)
+                .await
+                .unwrap();
+            join.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn idle_reaper_only_terminates_unreferenced_processes() {
+        use crate::state::DefaultProcessState;
+        use dashmap::DashMap;
+        use lunatic_process::env::Environment;
+        use lunatic_process::runtimes::wasmtime::{default_config, WasmtimeRuntime};
+        use lunatic_process::wasm::spawn_wasm;
+        use lunatic_process::Signal;
+        use std::sync::Arc;
+        use std::time::Duration;
+
+        // Waits forever on a message that never comes
+        let raw_module = wat::parse_str(
+            r#"
+            (module
+                (import "lunatic::message" "receive" (func $receive (param i32 i32 i64) (result i32)))
+                (memory (export "memory") 1)
+                (func (export "hello")
+                    (drop (call $receive (i32.const 0) (i32.const 0) (i64.const -1)))))
+            "#,
+        )
+        .unwrap();
+        let runtime = WasmtimeRuntime::new(&default_config()).unwrap();
+        let module = Arc::new(runtime.compile_module(raw_module.into()).unwrap());
+        let env = Arc::new(lunatic_process::env::LunaticEnvironment::new(0));
+        let mut processes = Vec::new();
+        for _ in 0..2 {
+            let state = DefaultProcessState::new(
+                env.clone(),
+                None,
+                runtime.clone(),
+                module.clone(),
+                Arc::new(crate::DefaultProcessConfig::default()),
+                Default::default(),
+            )
+            .unwrap();
+            let process = spawn_wasm(
+                env.clone(),
+                runtime.clone(),
+                &module,
+                state,
+                "hello",
+                Vec::new(),
+                None,
+            )
+            .await
+            .unwrap();
+            processes.push(process);
+        }
+        // Only the handle of the second process is kept around
+        let (referenced_join, referenced) = processes.pop().unwrap();
+        let (unreferenced_join, unreferenced) = processes.pop().unwrap();
+        let unreferenced_id = unreferenced.id();
+        drop(unreferenced);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let reaped = env.reap_idle(Duration::from_millis(50), &DashMap::new());
+        assert_eq!(reaped, vec![unreferenced_id]);
+        let result = unreferenced_join.await.unwrap();
+        assert!(result.unwrap_err().to_string().contains("idle"));
+
+        assert!(env.get_process(referenced.id()).is_some());
+        assert!(!referenced_join.is_finished());
+        referenced.send(Signal::Kill);
+        assert!(referenced_join.await.unwrap().is_err());
+    }
 }
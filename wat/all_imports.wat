@@ -4,6 +4,7 @@
     (import "lunatic::error" "string_size" (func (param i64) (result i32)))
     (import "lunatic::error" "to_string" (func (param i64 i32)))
     (import "lunatic::error" "drop" (func (param i64)))
+    (import "lunatic::error" "trap_backtrace" (func (param i64 i32 i32) (result i32)))
 
     (import "lunatic::message" "create_data" (func (param i64 i64)))
     (import "lunatic::message" "write_data" (func (param i32 i32) (result i32)))
@@ -16,11 +17,22 @@
     (import "lunatic::message" "push_udp_socket" (func (param i64) (result i64)))
     (import "lunatic::message" "take_udp_socket" (func (param i64) (result i64)))
     (import "lunatic::message" "send" (func (param i64) (result i32)))
+    (import "lunatic::message" "send_and_yield" (func (param i64) (result i32)))
     (import "lunatic::message" "send_receive_skip_search" (func (param i64 i64) (result i32)))
+    (import "lunatic::message" "call" (func (param i64 i64) (result i32)))
+    (import "lunatic::message" "reply_ref" (func (result i64)))
+    (import "lunatic::message" "reply" (func (param i64) (result i32)))
     (import "lunatic::message" "receive" (func (param i32 i32 i64) (result i32)))
+    (import "lunatic::message" "peek" (func (param i32 i32 i32 i32) (result i32)))
+    (import "lunatic::message" "mailbox_len" (func (result i64)))
+    (import "lunatic::message" "mailbox_high_water" (func (result i64)))
+    (import "lunatic::message" "mailbox_reset_high_water" (func))
+    (import "lunatic::message" "mailbox_poll" (func (param i64) (result i32)))
 
     (import "lunatic::timer" "send_after" (func (param i64 i64) (result i64)))
     (import "lunatic::timer" "cancel_timer" (func (param i64) (result i32)))
+    (import "lunatic::timer" "timer_set" (func (param i64 i64) (result i64)))
+    (import "lunatic::timer" "timer_cancel" (func (param i64) (result i32)))
 
     (import "lunatic::networking" "resolve" (func (param i32 i32 i64 i32) (result i32)))
     (import "lunatic::networking" "drop_dns_iterator" (func (param i64)))
@@ -41,6 +53,10 @@
     (import "lunatic::networking" "set_peek_timeout" (func (param i64 i64)))
     (import "lunatic::networking" "get_peek_timeout" (func (param i64) (result i64)))
     (import "lunatic::networking" "tcp_flush" (func (param i64 i32) (result i32)))
+    (import "lunatic::networking" "socket_set_option" (func (param i64 i32 i64 i32) (result i32)))
+    (import "lunatic::networking" "socket_get_option" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::networking" "socket_local_addr" (func (param i32 i64 i32 i32) (result i64)))
+    (import "lunatic::networking" "socket_peer_addr" (func (param i32 i64 i32 i32) (result i64)))
     (import "lunatic::networking" "udp_bind" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::networking" "drop_udp_socket" (func (param i64)))
     (import "lunatic::networking" "udp_local_addr" (func (param i64 i32) (result i32)))
@@ -57,6 +73,9 @@
 
     (import "lunatic::process" "compile_module" (func (param i32 i32 i32) (result i32)))
     (import "lunatic::process" "drop_module" (func (param i64)))
+    (import "lunatic::process" "module_upload_begin" (func (result i64)))
+    (import "lunatic::process" "module_upload_chunk" (func (param i64 i32 i32) (result i32)))
+    (import "lunatic::process" "module_upload_finish" (func (param i64 i32) (result i32)))
     (import "lunatic::process" "create_config" (func (result i64)))
     (import "lunatic::process" "drop_config" (func (param i64)))
     (import "lunatic::process" "config_set_max_memory" (func (param i64 i64)))
@@ -69,34 +88,92 @@
     (import "lunatic::process" "config_set_can_create_configs" (func (param i64 i32)))
     (import "lunatic::process" "config_can_spawn_processes" (func (param i64) (result i32)))
     (import "lunatic::process" "config_set_can_spawn_processes" (func (param i64 i32)))
+    (import "lunatic::process" "config_set_worker_affinity" (func (param i64 i64)))
+    (import "lunatic::process" "config_get_worker_affinity" (func (param i64) (result i64)))
+    (import "lunatic::process" "config_trap_exit" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_trap_exit" (func (param i64 i32)))
+    (import "lunatic::process" "config_detached" (func (param i64) (result i32)))
+    (import "lunatic::process" "config_set_detached" (func (param i64 i32)))
     (import "lunatic::process" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "spawn_suspended" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "spawn_with_message" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "start" (func (param i64) (result i32)))
     (import "lunatic::process" "sleep_ms" (func (param i64)))
     (import "lunatic::process" "die_when_link_dies" (func (param i32)))
+    (import "lunatic::process" "set_trap_exit" (func (param i32)))
+    (import "lunatic::process" "get_trap_exit" (func (result i32)))
+    (import "lunatic::process" "detach" (func))
     (import "lunatic::process" "process_id" (func (result i64)))
+    (import "lunatic::process" "env_config_blob" (func (param i32 i32) (result i64)))
+    (import "lunatic::process" "unique_id" (func (result i64)))
+    (import "lunatic::process" "worker_id" (func (result i64)))
+    (import "lunatic::process" "current_worker" (func (result i32)))
+    (import "lunatic::process" "available_memory" (func (result i64)))
+    (import "lunatic::process" "list_own_resources" (func (param i32 i32) (result i64)))
+    (import "lunatic::process" "clone_resource" (func (param i32 i64 i32) (result i32)))
+    (import "lunatic::process" "set_process_label" (func (param i32 i32)))
+    (import "lunatic::process" "snapshot_state" (func (param i32 i32) (result i64)))
+    (import "lunatic::process" "restore_state" (func (param i32 i32) (result i32)))
+    (import "lunatic::process" "abort" (func (param i32 i32)))
+    (import "lunatic::process" "remaining_fuel" (func (result i64)))
+    (import "lunatic::process" "set_quantum" (func (param i64) (result i64)))
+    (import "lunatic::process" "get_quantum" (func (result i64)))
+    (import "lunatic::process" "cpu_time_ns" (func (result i64)))
+    (import "lunatic::process" "kv_set" (func (param i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "kv_get" (func (param i32 i32 i32 i32) (result i64)))
+    (import "lunatic::process" "kv_cas" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::process" "shared_region_create" (func (param i64 i32) (result i64)))
+    (import "lunatic::process" "shared_region_attach" (func (param i64) (result i64)))
+    (import "lunatic::process" "shared_region_size" (func (param i64) (result i64)))
+    (import "lunatic::process" "shared_region_read" (func (param i64 i64 i32 i32)))
+    (import "lunatic::process" "shared_region_write" (func (param i64 i64 i32 i32)))
+    (import "lunatic::process" "drop_shared_region" (func (param i64)))
     (import "lunatic::process" "link" (func (param i64 i64)))
     (import "lunatic::process" "unlink" (func (param i64)))
+    (import "lunatic::process" "join_all_links" (func (param i64) (result i32)))
+    (import "lunatic::process" "monitor" (func (param i64)))
     (import "lunatic::process" "kill" (func (param i64)))
+    (import "lunatic::process" "request_shutdown" (func (param i64 i64) (result i32)))
     (import "lunatic::process" "exists" (func (param i64) (result i32)))
 
     (import "lunatic::version" "major" (func (result i32)))
     (import "lunatic::version" "minor" (func (result i32)))
     (import "lunatic::version" "patch" (func (result i32)))
+    (import "lunatic::version" "runtime_version" (func (param i32 i32) (result i32)))
 
     (import "lunatic::wasi" "config_add_environment_variable" (func (param i64 i32 i32 i32 i32)))
     (import "lunatic::wasi" "config_add_command_line_argument" (func (param i64 i32 i32)))
     (import "lunatic::wasi" "config_preopen_dir" (func (param i64 i32 i32)))
+    (import "lunatic::wasi" "config_set_cwd" (func (param i64 i32 i32)))
+    (import "lunatic::wasi" "env_get" (func (param i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::wasi" "env_nth" (func (param i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::wasi" "set_cwd" (func (param i32 i32) (result i32)))
+    (import "lunatic::wasi" "seed_random" (func (param i64)))
+    (import "lunatic::wasi" "inherit_fd" (func (param i32 i32) (result i32)))
 
     (import "lunatic::registry" "put" (func (param i32 i32 i64 i64)))
     (import "lunatic::registry" "get" (func (param i32 i32 i32 i32) (result i32)))
+    (import "lunatic::registry" "await_name" (func (param i32 i32 i32 i32 i64) (result i32)))
     (import "lunatic::registry" "remove" (func (param i32 i32)))
 
     (import "lunatic::distributed" "nodes_count" (func (result i32)))
     (import "lunatic::distributed" "get_nodes" (func (param i32 i32) (result i32)))
+    (import "lunatic::distributed" "control_status" (func (result i32)))
+    (import "lunatic::distributed" "cluster_process_count" (func (result i64)))
+    (import "lunatic::distributed" "node_process_count" (func (param i64) (result i64)))
+    (import "lunatic::distributed" "node_capabilities" (func (param i64 i32 i32) (result i64)))
+    (import "lunatic::distributed" "cluster_topology" (func (param i32 i32) (result i64)))
+    (import "lunatic::distributed" "is_distributed" (func (result i32)))
     (import "lunatic::distributed" "node_id" (func (result i64)))
     (import "lunatic::distributed" "module_id" (func (result i64)))
     (import "lunatic::distributed" "spawn" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_balanced" (func (param i64 i64 i32 i32 i32 i32 i32 i32) (result i32)))
+    (import "lunatic::distributed" "spawn_with_message" (func (param i64 i64 i64 i32 i32 i32 i32 i32) (result i32)))
     (import "lunatic::distributed" "send" (func (param i64 i64) (result i32)))
+    (import "lunatic::distributed" "cluster_broadcast" (func (param i32 i32) (result i32)))
     (import "lunatic::distributed" "send_receive_skip_search" (func (param i64 i64 i64) (result i32)))
+    (import "lunatic::distributed" "monitor" (func (param i64 i64)))
+    (import "lunatic::distributed" "barrier_wait" (func (param i32 i32 i64 i64 i32) (result i32)))
 
     (import "lunatic::metrics" "counter" (func (param i32 i32 i64)))
     (import "lunatic::metrics" "increment_counter" (func (param i32 i32)))